@@ -0,0 +1,66 @@
+use crate::DnsPacket;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::acl::Acl;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+
+// For networks whose IPv6 is broken: clients in the ACL get an empty AAAA
+// answer (NODATA) for names that also have an A record, so dual-stack hosts
+// connect over IPv4 instead of waiting for IPv6 to time out. Names with only
+// AAAA records keep them.
+pub struct AaaaFilter {
+    clients: Acl,
+}
+
+impl AaaaFilter {
+    pub fn new(clients: Acl) -> AaaaFilter {
+        AaaaFilter { clients }
+    }
+}
+
+impl Handler for AaaaFilter {
+    fn name(&self) -> &str {
+        "filter-aaaa"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let question = request.question;
+        if question.qtype != QueryType::AAAA || !self.clients.allows(request.src.ip()) {
+            next.run(request, response);
+            return;
+        }
+
+        next.run(request, response);
+        if !response
+            .answers
+            .iter()
+            .any(|answer| answer.query_type() == QueryType::AAAA)
+        {
+            return;
+        }
+
+        // ask the rest of the chain for the A records as well
+        let mut a_question = question.clone();
+        a_question.qtype = QueryType::A;
+        let a_request = Request {
+            question: &a_question,
+            ..*request
+        };
+        let mut a_response = DnsPacket::new();
+        next.run(&a_request, &mut a_response);
+
+        let has_a = a_response.header.response_code == ResponseCode::NOERR
+            && a_response
+                .answers
+                .iter()
+                .any(|answer| answer.query_type() == QueryType::A);
+        if has_a {
+            // the CNAMEs leading to the name stay
+            response
+                .answers
+                .retain(|answer| answer.query_type() != QueryType::AAAA);
+        }
+    }
+}
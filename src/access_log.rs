@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::anonymize;
+use crate::calendar::civil_from_days;
+use crate::calendar::unix_now;
+use crate::http::HttpRequest;
+use crate::retention::LogFile;
+
+// Access log of the http-listen listeners, one line per request in the
+// Combined Log Format web servers write, so the log pipelines already in
+// place for those take it as is, with the time taken to answer (in seconds,
+// like nginx's $request_time) on the end:
+//
+//   192.0.2.1 - - [17/Oct/2026:10:00:00 +0000] "GET /dns-query?dns=... HTTP/2.0" 200 61 "-" "curl/8.5.0" 0.004
+//
+// It is separate from the DNS query log (log-level) and the audit log, and
+// process wide like the audit log.
+
+static ACCESS: OnceLock<AccessLog> = OnceLock::new();
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub struct AccessLog {
+    file: LogFile,
+}
+
+impl AccessLog {
+    // Opens path for appending, so it has to run while the file is still
+    // reachable, before the chroot.
+    pub fn open(path: &Path) -> Result<AccessLog, String> {
+        Ok(AccessLog {
+            file: LogFile::open("access-log", path)?,
+        })
+    }
+
+    // Makes this the log record writes to.
+    pub fn install(self) {
+        let _ = ACCESS.set(self);
+    }
+}
+
+// The file of the installed log, for the retention task.
+pub fn log_file() -> Option<&'static LogFile> {
+    ACCESS.get().map(|access| &access.file)
+}
+
+// The time in the log's form, in UTC.
+fn timestamp(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// text between the quotes of a field, with quotes, backslashes and control
+// characters escaped as Apache does
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Writes a line for a request from client over protocol ("HTTP/1.1" or
+// "HTTP/2.0"), if there is an access log. request is None when it couldn't
+// be read; bytes is the size of the response body.
+pub fn record(
+    client: IpAddr,
+    request: Option<&HttpRequest>,
+    protocol: &str,
+    status: u16,
+    bytes: usize,
+    elapsed: Duration,
+) {
+    let Some(access) = ACCESS.get() else {
+        return;
+    };
+    let request_line = match request {
+        Some(request) => {
+            let mut target = request.path.clone();
+            // the query string holds the name asked for, or the whole query
+            if !request.query.is_empty() && !anonymize::hides_names() {
+                target.push('?');
+                target.push_str(&request.query);
+            }
+            escape(&format!("{} {} {}", request.method, target, protocol))
+        }
+        None => "-".to_string(),
+    };
+    let header = |name: &str| {
+        request
+            .and_then(|request| request.header(name))
+            .map_or("-".to_string(), escape)
+    };
+    let line = format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {:.3}",
+        anonymize::client(client),
+        timestamp(unix_now()),
+        request_line,
+        status,
+        // as %b has it, "-" for no body
+        match bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        },
+        header("referer"),
+        header("user-agent"),
+        elapsed.as_secs_f64()
+    );
+    access.file.write_line(&line);
+}
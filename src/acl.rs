@@ -0,0 +1,68 @@
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // Accepts "10.0.0.0/8", "2001:db8::/32" or a bare address as a host route.
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("bad address {:?}: {}", text, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("bad prefix length in {:?}", text))?,
+            None => max,
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    entries: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn new() -> Acl {
+        Acl {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, cidr: Cidr) {
+        self.entries.push(cidr);
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.entries.iter().any(|cidr| cidr.contains(ip))
+    }
+}
@@ -0,0 +1,179 @@
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::hash::RandomState;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::digest;
+use crate::name::Name;
+
+// How client addresses and query names are written to logs, traces and the
+// stats, so the server can keep those on without keeping who asked for what:
+// the address cut down to its network, or a pseudonym that is the same for a
+// client within a rotation period, and names as salted hashes, the same for
+// a name as long as the salt is, so they can still be counted. What the
+// server does with a query still goes by the real address and name; only
+// what it writes down changes.
+//
+// Process wide like the audit log, as everything that writes a client or a
+// name down goes through it.
+
+static ANONYMIZE: OnceLock<Anonymizer> = OnceLock::new();
+
+static NAMES: OnceLock<NameHasher> = OnceLock::new();
+
+// Bytes of the HMAC a pseudonym or hash keeps, enough that clients, or
+// names, don't collide.
+const PSEUDONYM_LEN: usize = 8;
+
+// "hash-query-names [salt=<text>]"; without a salt, a random one for as
+// long as the server runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameHashConfig {
+    pub salt: Option<String>,
+}
+
+// "anonymize-clients truncate [ipv4-prefix=<len>] [ipv6-prefix=<len>]" or
+// "anonymize-clients hmac [rotate=<time>]".
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnonymizeConfig {
+    Truncate { ipv4_prefix: u8, ipv6_prefix: u8 },
+    Hmac { rotate: Duration },
+}
+
+struct Key {
+    bytes: [u8; 32],
+    // when the current period started
+    since: Instant,
+}
+
+pub struct Anonymizer {
+    config: AnonymizeConfig,
+    key: Mutex<Key>,
+}
+
+// A key nobody can guess, from the OS's randomness that std seeds its hash
+// maps with, the one source of it to hand without a crate.
+fn random_key() -> [u8; 32] {
+    let mut seed = Vec::with_capacity(32);
+    for i in 0..4 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        seed.extend_from_slice(&hasher.finish().to_be_bytes());
+    }
+    digest::sha256(&seed)
+}
+
+impl Anonymizer {
+    pub fn new(config: AnonymizeConfig) -> Anonymizer {
+        Anonymizer {
+            config,
+            key: Mutex::new(Key {
+                bytes: random_key(),
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    // Makes this what client writes with.
+    pub fn install(self) {
+        let _ = ANONYMIZE.set(self);
+    }
+
+    fn client(&self, addr: IpAddr) -> String {
+        match self.config {
+            AnonymizeConfig::Truncate {
+                ipv4_prefix,
+                ipv6_prefix,
+            } => match addr {
+                IpAddr::V4(addr) => {
+                    let mask = u32::MAX.checked_shl(32 - ipv4_prefix as u32).unwrap_or(0);
+                    IpAddr::V4((u32::from(addr) & mask).into()).to_string()
+                }
+                IpAddr::V6(addr) => {
+                    let mask = u128::MAX.checked_shl(128 - ipv6_prefix as u32).unwrap_or(0);
+                    IpAddr::V6((u128::from(addr) & mask).into()).to_string()
+                }
+            },
+            AnonymizeConfig::Hmac { rotate } => {
+                let mut key = self.key.lock().unwrap();
+                // each period's key is the hash of the last one's, so the
+                // keys of past periods, and who their pseudonyms were, are
+                // gone for good
+                while key.since.elapsed() >= rotate {
+                    key.bytes = digest::sha256(&key.bytes);
+                    key.since += rotate;
+                }
+                let data = match addr {
+                    IpAddr::V4(addr) => addr.octets().to_vec(),
+                    IpAddr::V6(addr) => addr.octets().to_vec(),
+                };
+                let mac = digest::hmac_sha256(&key.bytes, &data);
+                mac[..PSEUDONYM_LEN]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect()
+            }
+        }
+    }
+}
+
+pub struct NameHasher {
+    salt: Vec<u8>,
+}
+
+impl NameHasher {
+    pub fn new(config: NameHashConfig) -> NameHasher {
+        let salt = match config.salt {
+            Some(salt) => salt.into_bytes(),
+            None => random_key().to_vec(),
+        };
+        NameHasher { salt }
+    }
+
+    // Makes this what name writes with.
+    pub fn install(self) {
+        let _ = NAMES.set(self);
+    }
+}
+
+// Whether names are hashed, for what writes them down in other forms than
+// name does.
+pub fn hides_names() -> bool {
+    NAMES.get().is_some()
+}
+
+// The name as logs, traces and the stats are to show it: in form, or as the
+// hash of its lowercase absolute form, e.g. "www.example.com.", which anyone
+// with the salt can work out for a name they want to look for.
+pub fn name(name: &Name, form: fn(&Name) -> String) -> String {
+    let Some(hasher) = NAMES.get() else {
+        return form(name);
+    };
+    let fqdn = name.to_lowercase().to_fqdn();
+    let mac = digest::hmac_sha256(&hasher.salt, fqdn.as_bytes());
+    mac[..PSEUDONYM_LEN]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The client as logs, traces and the stats are to show it.
+pub fn client(addr: IpAddr) -> String {
+    match ANONYMIZE.get() {
+        Some(anonymizer) => anonymizer.client(addr),
+        None => addr.to_string(),
+    }
+}
+
+// As client, with the port when clients aren't anonymized.
+pub fn peer(addr: SocketAddr) -> String {
+    match ANONYMIZE.get() {
+        Some(anonymizer) => anonymizer.client(addr.ip()),
+        None => addr.to_string(),
+    }
+}
@@ -0,0 +1,700 @@
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::acl::Acl;
+use crate::acl::Cidr;
+use crate::anonymize;
+use crate::blocklist::Blocklist;
+use crate::blocklist::Bypass;
+use crate::cache::Cache;
+use crate::connections::ConnectionPolicy;
+use crate::connections::Connections;
+use crate::connections::Deadline;
+use crate::ha::Standby;
+use crate::health::UpstreamHealth;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+use crate::json::Json;
+use crate::latency::Latencies;
+use crate::latency::Summary;
+use crate::name::Name;
+use crate::sinkhole::Sinkhole;
+use crate::stats::Stats;
+use crate::threat::ThreatAction;
+use crate::threat::ThreatFeeds;
+use crate::zone::LocalZones;
+use crate::zone::ZoneEntry;
+use crate::zone::parse_record;
+use crate::zonefile;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// API connections served at the same time, and of those from one client.
+// Counted apart from the DNS listeners', so clients filling those can't keep
+// a standby's health checks out.
+const MAX_CONNECTIONS: usize = 32;
+const MAX_CONNECTIONS_PER_CLIENT: usize = 8;
+
+// Sinkhole clients listed in /stats, those with the most connections.
+const MAX_SINKHOLE_CLIENTS: usize = 100;
+
+// HTTP management API. Every request but /health and /health/ready must
+// carry "Authorization: Bearer <token>".
+//
+//     GET    /health
+//     GET    /health/ready
+//     GET    /stats
+//     GET    /zones                     POST /zones {"apex": ...}
+//     DELETE /zones/<apex>
+//     GET    /records[?name=<name>]     POST /records {"name", "type", "ttl", "data", "weight"}
+//     PUT    /records/<name>/<type> {"ttl", "data": [...]}
+//     DELETE /records/<name>[/<type>]
+//     GET    /blocklist                 POST /blocklist {"domain": ...}
+//     DELETE /blocklist/<domain>
+//     GET    /bypass                    POST /bypass {"clients": <cidr>, "lists": [...]}
+//     DELETE /bypass?clients=<cidr|acl>
+//     GET    /cache[?name=<name>|suffix=<domain>][&type=<type>]
+//     GET    /cache/<name>[/<type>]
+//     DELETE /cache[/<name>]
+pub struct Api {
+    token: String,
+    zones: Arc<LocalZones>,
+    blocklist: Arc<Blocklist>,
+    threats: Arc<ThreatFeeds>,
+    sinkhole: Option<Arc<Sinkhole>>,
+    cache: Arc<Cache>,
+    stats: Arc<Stats>,
+    health: Arc<UpstreamHealth>,
+    latencies: Arc<Latencies>,
+    standby: Option<Arc<Standby>>,
+}
+
+fn entry_json(entry: &ZoneEntry) -> Json {
+    let mut fields = vec![
+        ("name", entry.record.domain().to_string().into()),
+        ("type", entry.record.query_type().name().into()),
+        ("ttl", (entry.record.ttl() as u64).into()),
+        ("data", entry.record.rdata_string().into()),
+    ];
+    if let Some(weight) = entry.weight {
+        fields.push(("weight", (weight as u64).into()));
+    }
+    if entry.generated {
+        fields.push(("generated", true.into()));
+    }
+    if entry.leased {
+        fields.push(("leased", true.into()));
+    }
+    Json::object(fields)
+}
+
+fn field<'a>(body: &'a Json, key: &str) -> Result<&'a str, String> {
+    body.get(key)
+        .and_then(Json::as_str)
+        .ok_or_else(|| format!("missing string field {:?}", key))
+}
+
+fn ttl_field(body: &Json) -> Result<u32, String> {
+    body.get("ttl")
+        .and_then(Json::as_u64)
+        .and_then(|ttl| u32::try_from(ttl).ok())
+        .ok_or_else(|| "missing or bad \"ttl\"".to_string())
+}
+
+fn type_field(text: &str) -> Result<QueryType, String> {
+    match QueryType::from_name(text) {
+        QueryType::UNKNOWN(_) | QueryType::OPT | QueryType::ANY => {
+            Err(format!("unsupported type {:?}", text))
+        }
+        qtype => Ok(qtype),
+    }
+}
+
+// Like type_field, but ANY answers are cached too.
+fn cache_type(text: &str) -> Result<QueryType, String> {
+    match QueryType::from_name(text) {
+        QueryType::UNKNOWN(_) | QueryType::OPT => Err(format!("unsupported type {:?}", text)),
+        qtype => Ok(qtype),
+    }
+}
+
+// Constant time, so the token can't be guessed byte by byte.
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+impl Api {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        token: String,
+        zones: Arc<LocalZones>,
+        blocklist: Arc<Blocklist>,
+        threats: Arc<ThreatFeeds>,
+        sinkhole: Option<Arc<Sinkhole>>,
+        cache: Arc<Cache>,
+        stats: Arc<Stats>,
+        health: Arc<UpstreamHealth>,
+        latencies: Arc<Latencies>,
+        standby: Option<Arc<Standby>>,
+    ) -> Api {
+        Api {
+            token,
+            zones,
+            blocklist,
+            threats,
+            sinkhole,
+            cache,
+            stats,
+            health,
+            latencies,
+            standby,
+        }
+    }
+
+    // Serves each connection on its own thread, so one that is slow to send
+    // its request doesn't hold up the others, /health among them.
+    // Connections over the limits are closed right away.
+    pub fn run(&self, listener: TcpListener) {
+        let mut policy = ConnectionPolicy::new();
+        policy.max = MAX_CONNECTIONS;
+        policy.per_client = MAX_CONNECTIONS_PER_CLIENT;
+        let connections = Connections::new(policy);
+
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let Some(slot) = stream
+                    .peer_addr()
+                    .ok()
+                    .and_then(|src| connections.admit(src.ip()))
+                else {
+                    continue;
+                };
+                scope.spawn(move || {
+                    let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                    // the whole request has IO_TIMEOUT to arrive
+                    let response = match HttpRequest::read(Deadline::new(&stream, IO_TIMEOUT)) {
+                        Ok(request) => self.handle(&request),
+                        Err(e) => HttpResponse::error(400, &e),
+                    };
+                    let _ = response.write_to(&stream);
+                    drop(slot);
+                });
+            }
+        });
+    }
+
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        // tells no more than that the server runs, for load balancers and
+        // secondaries
+        if request.method == "GET" && request.path == "/health" {
+            return HttpResponse::json(200, &Json::object(vec![("status", "ok".into())]));
+        }
+        if request.method == "GET" && request.path == "/health/ready" {
+            return self.readiness();
+        }
+
+        let authorized = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| token_matches(token, &self.token));
+        if !authorized {
+            let mut response = HttpResponse::error(401, "unauthorized");
+            response
+                .headers
+                .push(("WWW-Authenticate".to_string(), "Bearer".to_string()));
+            return response;
+        }
+
+        match self.route(request) {
+            Ok(response) => response,
+            Err(e) => HttpResponse::error(400, &e),
+        }
+    }
+
+    // Whether the server is worth sending queries to, for load balancers:
+    // not while it stands by for a primary, which it refuses them for, nor
+    // while every upstream it has queried is down. A server that hasn't
+    // needed an upstream yet is ready.
+    fn readiness(&self) -> HttpResponse {
+        let upstreams = self.health.snapshot();
+        let up = upstreams
+            .iter()
+            .filter(|(_, health)| health.down_since.is_none())
+            .count();
+        let reason = if self
+            .standby
+            .as_ref()
+            .is_some_and(|standby| !standby.is_active())
+        {
+            Some("standing by for the primary")
+        } else if !upstreams.is_empty() && up == 0 {
+            Some("no upstream is reachable")
+        } else {
+            None
+        };
+
+        let mut fields = vec![(
+            "status",
+            match reason {
+                Some(_) => "not ready".into(),
+                None => "ready".into(),
+            },
+        )];
+        if let Some(reason) = reason {
+            fields.push(("reason", reason.into()));
+        }
+        fields.push((
+            "upstreams",
+            Json::object(vec![
+                ("up", (up as u64).into()),
+                ("down", ((upstreams.len() - up) as u64).into()),
+            ]),
+        ));
+        let status = match reason {
+            Some(_) => 503,
+            None => 200,
+        };
+        HttpResponse::json(status, &Json::object(fields))
+    }
+
+    fn route(&self, request: &HttpRequest) -> Result<HttpResponse, String> {
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["stats"]) => Ok(HttpResponse::json(200, &self.stats_json())),
+
+            ("GET", ["zones"]) => {
+                let zones = self
+                    .zones
+                    .zones()
+                    .iter()
+                    .map(|apex| apex.to_string().into())
+                    .collect();
+                Ok(HttpResponse::json(200, &Json::Array(zones)))
+            }
+            ("POST", ["zones"]) => {
+                let body = request.json()?;
+                let apex = Name::from_unicode(field(&body, "apex")?)?;
+                self.zones.add_zone(apex)?;
+                Ok(HttpResponse::new(201, "application/json", Vec::new()))
+            }
+            ("DELETE", ["zones", apex]) => {
+                let apex = Name::from_unicode(apex)?;
+                Ok(self.deleted(self.zones.remove_zone(&apex)?))
+            }
+
+            ("GET", ["records"]) => {
+                let name = match request.query_param("name") {
+                    Some(name) => Some(Name::from_unicode(&name)?),
+                    None => None,
+                };
+                let entries = self
+                    .zones
+                    .entries()
+                    .iter()
+                    .filter(|entry| {
+                        name.as_ref()
+                            .is_none_or(|name| entry.record.domain() == name)
+                    })
+                    .map(entry_json)
+                    .collect();
+                Ok(HttpResponse::json(200, &Json::Array(entries)))
+            }
+            ("POST", ["records"]) => {
+                let body = request.json()?;
+                let qtype = type_field(field(&body, "type")?)?;
+                let data = zonefile::words(field(&body, "data")?)?;
+                let data: Vec<&str> = data.iter().map(String::as_str).collect();
+                let record = parse_record(field(&body, "name")?, qtype, ttl_field(&body)?, &data)?;
+                let weight = match body.get("weight") {
+                    Some(weight) => Some(
+                        weight
+                            .as_u64()
+                            .and_then(|weight| u32::try_from(weight).ok())
+                            .ok_or("bad \"weight\"")?,
+                    ),
+                    None => None,
+                };
+                self.zones.add(record, weight)?;
+                Ok(HttpResponse::new(201, "application/json", Vec::new()))
+            }
+            ("PUT", ["records", name, qtype]) => {
+                let body = request.json()?;
+                let qtype = type_field(qtype)?;
+                let ttl = ttl_field(&body)?;
+                let data = body
+                    .get("data")
+                    .and_then(Json::as_array)
+                    .ok_or("missing array field \"data\"")?;
+
+                // validate everything before touching the zone
+                let mut records = Vec::new();
+                for item in data {
+                    let item = item.as_str().ok_or("\"data\" must hold strings")?;
+                    let fields = zonefile::words(item)?;
+                    let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+                    records.push(parse_record(name, qtype, ttl, &fields)?);
+                }
+
+                let name = Name::from_unicode(name)?;
+                self.zones.remove(&name, Some(qtype))?;
+                for record in records {
+                    self.zones.add(record, None)?;
+                }
+                Ok(HttpResponse::new(204, "application/json", Vec::new()))
+            }
+            ("DELETE", ["records", name]) => {
+                let name = Name::from_unicode(name)?;
+                Ok(self.deleted(self.zones.remove(&name, None)? > 0))
+            }
+            ("DELETE", ["records", name, qtype]) => {
+                let name = Name::from_unicode(name)?;
+                let qtype = type_field(qtype)?;
+                Ok(self.deleted(self.zones.remove(&name, Some(qtype))? > 0))
+            }
+
+            ("GET", ["blocklist"]) => {
+                let domains = self
+                    .blocklist
+                    .domains()
+                    .iter()
+                    .map(|domain| domain.to_string().into())
+                    .collect();
+                Ok(HttpResponse::json(200, &Json::Array(domains)))
+            }
+            ("POST", ["blocklist"]) => {
+                let body = request.json()?;
+                let domain = Name::from_unicode(field(&body, "domain")?)?;
+                self.blocklist.add(domain);
+                Ok(HttpResponse::new(201, "application/json", Vec::new()))
+            }
+            ("DELETE", ["blocklist", domain]) => {
+                let domain = Name::from_unicode(domain)?;
+                Ok(self.deleted(self.blocklist.remove(&domain)))
+            }
+
+            ("GET", ["bypass"]) => {
+                let bypasses = self
+                    .blocklist
+                    .bypasses()
+                    .iter()
+                    .map(|bypass| {
+                        Json::object(vec![
+                            ("clients", bypass.label.as_str().into()),
+                            (
+                                "lists",
+                                Json::Array(
+                                    bypass
+                                        .lists
+                                        .iter()
+                                        .map(|list| list.as_str().into())
+                                        .collect(),
+                                ),
+                            ),
+                        ])
+                    })
+                    .collect();
+                Ok(HttpResponse::json(200, &Json::Array(bypasses)))
+            }
+            ("POST", ["bypass"]) => {
+                let body = request.json()?;
+                let label = field(&body, "clients")?;
+                let mut clients = Acl::new();
+                clients.add(Cidr::parse(label)?);
+                let lists = match body.get("lists") {
+                    Some(lists) => lists
+                        .as_array()
+                        .and_then(|lists| {
+                            lists
+                                .iter()
+                                .map(|list| list.as_str().map(str::to_string))
+                                .collect::<Option<Vec<String>>>()
+                        })
+                        .ok_or("\"lists\" must be an array of strings")?,
+                    None => Vec::new(),
+                };
+                self.blocklist.add_bypass(Bypass {
+                    label: label.to_string(),
+                    clients: Some(clients),
+                    policy: None,
+                    lists,
+                })?;
+                Ok(HttpResponse::new(201, "application/json", Vec::new()))
+            }
+            ("DELETE", ["bypass"]) => {
+                let label = request
+                    .query_param("clients")
+                    .ok_or("missing query parameter \"clients\"")?;
+                Ok(self.deleted(self.blocklist.remove_bypass(&label)))
+            }
+
+            ("GET", ["cache"]) => {
+                let name = match request.query_param("name") {
+                    Some(name) => Some(Name::from_unicode(&name)?),
+                    None => None,
+                };
+                let suffix = match request.query_param("suffix") {
+                    Some(suffix) => Some(Name::from_unicode(&suffix)?),
+                    None => None,
+                };
+                let qtype = match request.query_param("type") {
+                    Some(qtype) => Some(cache_type(&qtype)?),
+                    None => None,
+                };
+                Ok(HttpResponse::json(
+                    200,
+                    &self.cache_json(|owner, owner_qtype| {
+                        name.as_ref().is_none_or(|name| owner == name)
+                            && suffix
+                                .as_ref()
+                                .is_none_or(|suffix| owner.is_subdomain_of(suffix))
+                            && qtype.is_none_or(|qtype| qtype.to_num() == owner_qtype)
+                    }),
+                ))
+            }
+            ("GET", ["cache", name, rest @ ..]) if rest.len() <= 1 => {
+                let name = Name::from_unicode(name)?;
+                let qtype = match rest.first() {
+                    Some(qtype) => Some(cache_type(qtype)?),
+                    None => None,
+                };
+                let entries = self.cache_json(|owner, owner_qtype| {
+                    *owner == name && qtype.is_none_or(|qtype| qtype.to_num() == owner_qtype)
+                });
+                let cached = entries
+                    .as_array()
+                    .is_some_and(|entries| !entries.is_empty());
+                Ok(HttpResponse::json(
+                    200,
+                    &Json::object(vec![("cached", cached.into()), ("entries", entries)]),
+                ))
+            }
+            ("DELETE", ["cache"]) => {
+                self.cache.clear();
+                Ok(HttpResponse::new(204, "application/json", Vec::new()))
+            }
+            ("DELETE", ["cache", name]) => {
+                let name = Name::from_unicode(name)?;
+                Ok(self.deleted(self.cache.remove(&name) > 0))
+            }
+
+            _ => Ok(HttpResponse::error(404, "no such endpoint")),
+        }
+    }
+
+    fn deleted(&self, found: bool) -> HttpResponse {
+        if found {
+            HttpResponse::new(204, "application/json", Vec::new())
+        } else {
+            HttpResponse::error(404, "not found")
+        }
+    }
+
+    // Cache entries whose (name, qtype) pass filter, with remaining TTLs.
+    fn cache_json(&self, filter: impl Fn(&Name, u16) -> bool) -> Json {
+        Json::Array(
+            self.cache
+                .dump()
+                .into_iter()
+                .filter(|(name, qtype, _)| filter(name, *qtype))
+                .map(|(name, qtype, answers)| {
+                    let ttl = answers.iter().map(|answer| answer.ttl()).min().unwrap_or(0);
+                    Json::object(vec![
+                        ("name", name.to_string().into()),
+                        ("type", QueryType::from_num(qtype).name().into()),
+                        ("ttl", (ttl as u64).into()),
+                        (
+                            "answers",
+                            Json::Array(
+                                answers
+                                    .iter()
+                                    .map(|answer| {
+                                        Json::object(vec![
+                                            ("name", answer.domain().to_string().into()),
+                                            ("type", answer.query_type().name().into()),
+                                            ("ttl", (answer.ttl() as u64).into()),
+                                            ("data", answer.rdata_string().into()),
+                                        ])
+                                    })
+                                    .collect(),
+                            ),
+                        ),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn stats_json(&self) -> Json {
+        let rcodes = [
+            ResponseCode::NOERR,
+            ResponseCode::FORMERR,
+            ResponseCode::SERVFAIL,
+            ResponseCode::NAMERR,
+            ResponseCode::NOTIMP,
+            ResponseCode::REFUSED,
+            ResponseCode::NOTAUTH,
+            ResponseCode::BADVERS,
+        ];
+
+        Json::object(vec![
+            ("uptime", self.stats.uptime_secs().into()),
+            ("queries", self.stats.queries().into()),
+            (
+                "responses",
+                Json::Object(
+                    rcodes
+                        .iter()
+                        .map(|rcode| (format!("{:?}", rcode), self.stats.responses(*rcode).into()))
+                        .collect(),
+                ),
+            ),
+            (
+                "cache",
+                Json::object(vec![
+                    ("entries", (self.cache.len() as u64).into()),
+                    ("hits", self.cache.hits().into()),
+                    ("misses", self.cache.misses().into()),
+                    ("shared_hits", self.cache.shared_hits().into()),
+                ]),
+            ),
+            ("blocked", self.blocklist.blocked().into()),
+            ("rewritten", self.blocklist.rewritten().into()),
+            (
+                "threats",
+                Json::Array(
+                    self.threats
+                        .stats()
+                        .into_iter()
+                        .map(|feed| {
+                            let action = match feed.action {
+                                ThreatAction::Block => "block",
+                                ThreatAction::Alert => "alert",
+                            };
+                            Json::object(vec![
+                                ("feed", feed.name.into()),
+                                ("category", feed.category.into()),
+                                ("action", action.into()),
+                                ("domains", (feed.domains as u64).into()),
+                                ("hits", feed.hits.into()),
+                                ("updated", feed.updated.map_or(Json::Null, Json::from)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "sinkhole",
+                self.sinkhole.as_ref().map_or(Json::Null, |sinkhole| {
+                    Json::object(vec![
+                        ("answers", sinkhole.answered().into()),
+                        ("connections", sinkhole.accepted().into()),
+                        (
+                            "clients",
+                            Json::Array(
+                                sinkhole
+                                    .clients()
+                                    .into_iter()
+                                    .take(MAX_SINKHOLE_CLIENTS)
+                                    .map(|client| {
+                                        Json::object(vec![
+                                            ("address", anonymize::client(client.addr).into()),
+                                            ("connections", client.connections.into()),
+                                            ("last_seen", client.last_seen.into()),
+                                            (
+                                                "last_name",
+                                                client.last_name.map_or(Json::Null, |name| {
+                                                    anonymize::name(&name, Name::to_fqdn).into()
+                                                }),
+                                            ),
+                                        ])
+                                    })
+                                    .collect(),
+                            ),
+                        ),
+                    ])
+                }),
+            ),
+            (
+                "upstreams",
+                Json::Array(
+                    self.health
+                        .snapshot()
+                        .iter()
+                        .map(|(server, health)| {
+                            Json::object(vec![
+                                ("address", server.to_string().into()),
+                                ("up", health.down_since.is_none().into()),
+                                ("successes", health.successes.into()),
+                                ("failures", health.failures.into()),
+                                (
+                                    "rtt_ms",
+                                    health.srtt.map_or(Json::Null, |srtt| {
+                                        (srtt.as_millis() as u64).into()
+                                    }),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "latency",
+                Json::object(vec![
+                    (
+                        "servers",
+                        Json::Array(
+                            self.latencies
+                                .servers()
+                                .iter()
+                                .map(|(server, summary)| {
+                                    latency_json("address", server.to_string(), summary)
+                                })
+                                .collect(),
+                        ),
+                    ),
+                    (
+                        "zones",
+                        Json::Array(
+                            self.latencies
+                                .zones()
+                                .iter()
+                                .map(|(zone, summary)| {
+                                    latency_json("zone", zone.to_fqdn(), summary)
+                                })
+                                .collect(),
+                        ),
+                    ),
+                ]),
+            ),
+        ])
+    }
+}
+
+// One upstream's or zone's round trip time percentiles in milliseconds.
+fn latency_json(key: &str, value: String, summary: &Summary) -> Json {
+    let millis = |rtt: Duration| Json::Number((rtt.as_secs_f64() * 1e6).round() / 1000.0);
+    Json::object(vec![
+        (key, value.into()),
+        ("count", summary.count.into()),
+        ("p50_ms", millis(summary.p50)),
+        ("p95_ms", millis(summary.p95)),
+        ("p99_ms", millis(summary.p99)),
+    ])
+}
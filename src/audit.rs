@@ -0,0 +1,122 @@
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::DnsQuestion;
+use crate::anonymize;
+use crate::calendar::civil_from_days;
+use crate::client_policy::ClientPolicy;
+use crate::json::Json;
+use crate::name::Name;
+use crate::retention::LogFile;
+
+// Audit log of the queries that didn't get the answer they would have got
+// without the server's policy: those blocked, rewritten or rate limited, one
+// JSON object per line with the rule responsible, so a false positive can be
+// traced back to the line of the config that caused it.
+//
+// Lines are written as the queries are answered, from whichever thread
+// answers them, so the log is process wide like the telemetry exporter.
+
+static AUDIT: OnceLock<AuditLog> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Blocked,
+    Rewritten,
+    RateLimited,
+    Threat,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Blocked => "blocked",
+            Action::Rewritten => "rewritten",
+            Action::RateLimited => "rate-limited",
+            Action::Threat => "threat",
+        }
+    }
+}
+
+pub struct AuditLog {
+    file: LogFile,
+}
+
+impl AuditLog {
+    // Opens path for appending, so it has to run while the file is still
+    // reachable, before the chroot.
+    pub fn open(path: &Path) -> Result<AuditLog, String> {
+        Ok(AuditLog {
+            file: LogFile::open("audit-log", path)?,
+        })
+    }
+
+    // Makes this the log record writes to.
+    pub fn install(self) {
+        let _ = AUDIT.set(self);
+    }
+}
+
+// The file of the installed log, for the retention task.
+pub fn log_file() -> Option<&'static LogFile> {
+    AUDIT.get().map(|audit| &audit.file)
+}
+
+// The time in RFC 3339 form, in UTC to the millisecond.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Writes a line for question from client, if there is an audit log and the
+// client's policy doesn't opt out of it. rule is the config rule that applied
+// and outcome what the client got because of it.
+pub fn record(
+    client: IpAddr,
+    policy: Option<&ClientPolicy>,
+    question: &DnsQuestion,
+    action: Action,
+    rule: &dyn Display,
+    outcome: &dyn Display,
+) {
+    let Some(audit) = AUDIT.get() else {
+        return;
+    };
+    if policy.is_some_and(|policy| !policy.audit) {
+        return;
+    }
+    let mut fields = vec![
+        ("time", timestamp(SystemTime::now()).into()),
+        ("client", anonymize::client(client).into()),
+    ];
+    if let Some(policy) = policy {
+        fields.push(("policy", policy.name.as_str().into()));
+    }
+    fields.extend([
+        (
+            "name",
+            anonymize::name(&question.name, Name::to_fqdn).into(),
+        ),
+        ("type", question.qtype.name().into()),
+        ("action", action.name().into()),
+        ("rule", rule.to_string().into()),
+        ("outcome", outcome.to_string().into()),
+    ]);
+    audit.file.write_line(&Json::object(fields));
+}
@@ -0,0 +1,395 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::acl::Acl;
+use crate::audit;
+use crate::audit::Action;
+use crate::calendar::TimeZone;
+use crate::calendar::unix_now;
+use crate::client_policy::ClientPolicy;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::rules::Pattern;
+use crate::rules::RuleSet;
+use crate::schedule::Schedule;
+use crate::sinkhole::Sinkhole;
+
+// TTL of the records rewrites answer with
+const REWRITE_TTL: u32 = 300;
+
+// What a rewrite answers a name with: its own addresses, or an alias whose
+// records the rest of the chain looks up.
+#[derive(Debug, Clone)]
+pub enum Rewrite {
+    Addresses(Vec<IpAddr>),
+    Alias(Name),
+}
+
+impl std::fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rewrite::Addresses(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+                write!(f, "{}", addrs.join(" "))
+            }
+            Rewrite::Alias(target) => write!(f, "{}", target.to_fqdn()),
+        }
+    }
+}
+
+// Where and when a group's domains are blocked: for the clients in the ACL
+// and of the client policy (everyone without either) while the schedule is in
+// effect (always without one).
+#[derive(Debug, Clone)]
+pub struct GroupRule {
+    pub clients: Option<Acl>,
+    pub policy: Option<String>,
+    pub schedule: Option<Schedule>,
+}
+
+// What the block lines, and the domains added through the management API,
+// are called among the lists a bypass names; groups go by their own names.
+pub const BLOCK_LIST: &str = "block";
+
+// Clients that some or all lists don't apply to, such as an admin's laptop.
+#[derive(Debug, Clone)]
+pub struct Bypass {
+    // the ACL's name, the network or policy=<name>, as it was given
+    pub label: String,
+    pub clients: Option<Acl>,
+    pub policy: Option<String>,
+    // BLOCK_LIST and group names; empty for every list
+    pub lists: Vec<String>,
+}
+
+// Whether a client at client with policy is one of clients and of policy
+// (None for either being everyone).
+fn client_matches(
+    clients: &Option<Acl>,
+    policy: &Option<String>,
+    client: IpAddr,
+    client_policy: Option<&ClientPolicy>,
+) -> bool {
+    clients.as_ref().is_none_or(|acl| acl.allows(client))
+        && policy
+            .as_ref()
+            .is_none_or(|name| client_policy.is_some_and(|policy| policy.name == *name))
+}
+
+impl Bypass {
+    fn skips(&self, list: &str) -> bool {
+        self.lists.is_empty() || self.lists.iter().any(|known| known == list)
+    }
+}
+
+// Domains blocked only by the group's rules. A group without rules is blocked
+// for everyone all the time.
+struct BlockGroup {
+    name: String,
+    domains: RuleSet<()>,
+    rules: Vec<GroupRule>,
+}
+
+impl BlockGroup {
+    fn applies(
+        &self,
+        client: IpAddr,
+        policy: Option<&ClientPolicy>,
+        zone: &TimeZone,
+        now: i64,
+    ) -> bool {
+        self.rules.is_empty()
+            || self.rules.iter().any(|rule| {
+                client_matches(&rule.clients, &rule.policy, client, policy)
+                    && rule
+                        .schedule
+                        .as_ref()
+                        .is_none_or(|schedule| schedule.is_active(zone, now))
+            })
+    }
+}
+
+// The one of name and the domains above it that is in domains, if any.
+pub fn listed(domains: &HashSet<Name>, name: &Name) -> Option<Name> {
+    let mut suffix = Some(name.clone());
+    while let Some(name) = suffix {
+        if domains.contains(&name) {
+            return Some(name);
+        }
+        suffix = name.parent();
+    }
+    None
+}
+
+// Domains (and everything below them) answered with NXDOMAIN, either always
+// or, for those in groups, for some clients at some times. Wildcard and regex
+// rules block the names they match, and rewrites answer names with other data.
+// Clients with a bypass are let through the lists it names.
+pub struct Blocklist {
+    domains: RwLock<HashSet<Name>>,
+    patterns: RuleSet<()>,
+    rewrites: RuleSet<Rewrite>,
+    groups: Vec<BlockGroup>,
+    bypasses: RwLock<Vec<Bypass>>,
+    // what the groups' schedules are in
+    time_zone: TimeZone,
+    // what blocked names are answered with instead of NXDOMAIN
+    sinkhole: Option<Arc<Sinkhole>>,
+    blocked: AtomicU64,
+    rewritten: AtomicU64,
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist {
+            domains: RwLock::new(HashSet::new()),
+            patterns: RuleSet::new(),
+            rewrites: RuleSet::new(),
+            groups: Vec::new(),
+            bypasses: RwLock::new(Vec::new()),
+            time_zone: TimeZone::utc(),
+            sinkhole: None,
+            blocked: AtomicU64::new(0),
+            rewritten: AtomicU64::new(0),
+        }
+    }
+
+    // Patterns other than plain domains, which are fixed once loaded.
+    pub fn add_pattern(&mut self, pattern: Pattern) {
+        self.patterns.add(pattern, ());
+    }
+
+    pub fn add_rewrite(&mut self, pattern: Pattern, rewrite: Rewrite) {
+        self.rewrites.add(pattern, rewrite);
+    }
+
+    pub fn add_to_group(&mut self, group: &str, pattern: Pattern) {
+        let index = match self.groups.iter().position(|known| known.name == group) {
+            Some(index) => index,
+            None => {
+                self.groups.push(BlockGroup {
+                    name: group.to_string(),
+                    domains: RuleSet::new(),
+                    rules: Vec::new(),
+                });
+                self.groups.len() - 1
+            }
+        };
+        self.groups[index].domains.add(pattern, ());
+    }
+
+    pub fn add_group_rule(&mut self, group: &str, rule: GroupRule) -> Result<(), String> {
+        let group = self
+            .groups
+            .iter_mut()
+            .find(|known| known.name == group)
+            .ok_or_else(|| format!("unknown block group {:?}", group))?;
+        group.rules.push(rule);
+        Ok(())
+    }
+
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|known| known.name == group)
+    }
+
+    // Adds bypass, replacing the one with the same label. Every list it
+    // names has to exist.
+    pub fn add_bypass(&self, bypass: Bypass) -> Result<(), String> {
+        if let Some(list) = bypass
+            .lists
+            .iter()
+            .find(|list| *list != BLOCK_LIST && !self.has_group(list))
+        {
+            return Err(format!("unknown block list {:?}", list));
+        }
+        let mut bypasses = self.bypasses.write().unwrap();
+        bypasses.retain(|known| known.label != bypass.label);
+        bypasses.push(bypass);
+        Ok(())
+    }
+
+    pub fn remove_bypass(&self, label: &str) -> bool {
+        let mut bypasses = self.bypasses.write().unwrap();
+        let before = bypasses.len();
+        bypasses.retain(|known| known.label != label);
+        bypasses.len() < before
+    }
+
+    pub fn bypasses(&self) -> Vec<Bypass> {
+        self.bypasses.read().unwrap().clone()
+    }
+
+    pub fn set_time_zone(&mut self, zone: TimeZone) {
+        self.time_zone = zone;
+    }
+
+    pub fn set_sinkhole(&mut self, sinkhole: Arc<Sinkhole>) {
+        self.sinkhole = Some(sinkhole);
+    }
+
+    pub fn add(&self, domain: Name) -> bool {
+        self.domains.write().unwrap().insert(domain)
+    }
+
+    pub fn remove(&self, domain: &Name) -> bool {
+        self.domains.write().unwrap().remove(domain)
+    }
+
+    pub fn domains(&self) -> Vec<Name> {
+        let mut domains: Vec<Name> = self.domains.read().unwrap().iter().cloned().collect();
+        domains.sort_by_key(|domain| domain.to_ascii().to_ascii_lowercase());
+        domains
+    }
+
+    // number of queries answered by the blocklist so far
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    // number of queries answered by a rewrite so far
+    pub fn rewritten(&self) -> u64 {
+        self.rewritten.load(Ordering::Relaxed)
+    }
+
+    // The rule blocking name for client right now, as the config would have
+    // it: a block line, or a block-group line of a group that applies to
+    // client at the moment. Lists client bypasses are skipped.
+    pub fn blocking_rule(
+        &self,
+        name: &Name,
+        client: IpAddr,
+        policy: Option<&ClientPolicy>,
+    ) -> Option<String> {
+        let bypasses = self.bypasses.read().unwrap();
+        let bypassed = |list: &str| {
+            bypasses.iter().any(|bypass| {
+                client_matches(&bypass.clients, &bypass.policy, client, policy)
+                    && bypass.skips(list)
+            })
+        };
+
+        if !bypassed(BLOCK_LIST) {
+            if let Some(domain) = listed(&self.domains.read().unwrap(), name) {
+                return Some(format!("block {}", domain.to_unicode()));
+            }
+            if let Some((pattern, _)) = self.patterns.find_rule(name) {
+                return Some(format!("block {}", pattern));
+            }
+        }
+        let now = unix_now();
+        self.groups.iter().find_map(|group| {
+            if bypassed(&group.name) {
+                return None;
+            }
+            let (pattern, _) = group.domains.find_rule(name)?;
+            group
+                .applies(client, policy, &self.time_zone, now)
+                .then(|| format!("block-group {} {}", group.name, pattern))
+        })
+    }
+}
+
+impl Handler for Blocklist {
+    fn name(&self) -> &str {
+        "blocklist"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let name = &request.question.name;
+        let client = request.src.ip();
+        if let Some(rule) = self.blocking_rule(name, client, request.policy) {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+            let outcome = match &self.sinkhole {
+                Some(sinkhole) => {
+                    sinkhole.answer(request.question, response);
+                    sinkhole.outcome()
+                }
+                None => {
+                    response.header.response_code = ResponseCode::NAMERR;
+                    "NXDOMAIN".to_string()
+                }
+            };
+            audit::record(
+                client,
+                request.policy,
+                request.question,
+                Action::Blocked,
+                &rule,
+                &outcome,
+            );
+            return;
+        }
+        if let Some((pattern, rewrite)) = self.rewrites.find_rule(name) {
+            self.rewritten.fetch_add(1, Ordering::Relaxed);
+            audit::record(
+                client,
+                request.policy,
+                request.question,
+                Action::Rewritten,
+                &format_args!("rewrite {}", pattern),
+                rewrite,
+            );
+            rewrite_answer(rewrite, request, response, next);
+            return;
+        }
+        next.run(request, response);
+    }
+}
+
+fn rewrite_answer(rewrite: &Rewrite, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+    let question = request.question;
+    response.header.response_code = ResponseCode::NOERR;
+    match rewrite {
+        Rewrite::Addresses(addrs) => {
+            // other types get an empty answer
+            for addr in addrs {
+                match (addr, question.qtype) {
+                    (IpAddr::V4(addr), QueryType::A) => response.answers.push(DnsRecord::A {
+                        domain: question.name.clone(),
+                        addr: *addr,
+                        ttl: REWRITE_TTL,
+                    }),
+                    (IpAddr::V6(addr), QueryType::AAAA) => response.answers.push(DnsRecord::AAAA {
+                        domain: question.name.clone(),
+                        ttl: REWRITE_TTL,
+                        addr: *addr,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        Rewrite::Alias(target) => {
+            response.answers.push(DnsRecord::CNAME {
+                domain: question.name.clone(),
+                ttl: REWRITE_TTL,
+                host: target.clone(),
+            });
+            if question.qtype == QueryType::CNAME {
+                return;
+            }
+            // the target's records come from the rest of the chain, as if
+            // the client had followed the CNAME itself
+            let mut target_question = question.clone();
+            target_question.name = target.clone();
+            let target_request = Request {
+                question: &target_question,
+                ..*request
+            };
+            let mut target_response = DnsPacket::new();
+            next.run(&target_request, &mut target_response);
+            response.header.response_code = target_response.header.response_code;
+            response.answers.extend(target_response.answers);
+            response.nameservers.extend(target_response.nameservers);
+        }
+    }
+}
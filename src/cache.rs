@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::RandomState;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::BufHandler;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::random::Rng;
+use crate::redis::Redis;
+use crate::redis::Reply;
+use crate::redis::glob_escape;
+use crate::telemetry;
+use crate::telemetry::SpanKind;
+
+// Entries are spread over this many separately locked maps by owner name, so
+// queries for different names rarely wait on each other.
+const SHARDS: usize = 16;
+
+type Shard = HashMap<(Name, u16), CacheEntry>;
+
+struct CacheEntry {
+    answers: Vec<DnsRecord>,
+    inserted: Instant,
+    expires: Instant,
+}
+
+impl CacheEntry {
+    // the answers with TTLs counted down by the time spent in the cache
+    fn current_answers(&self, now: Instant) -> Vec<DnsRecord> {
+        let age = now.duration_since(self.inserted).as_secs() as u32;
+        let mut answers = self.answers.clone();
+        for answer in answers.iter_mut() {
+            answer.set_ttl(answer.ttl().saturating_sub(age));
+        }
+        answers
+    }
+}
+
+// How the TTLs of answers are adjusted before they are cached: clamped to
+// [min_ttl, max_ttl], unless the question falls under an override, which
+// sets the TTL outright. The cached copy then loses up to jitter percent of
+// its TTL, at random, so entries cached at the same moment don't all expire
+// and get fetched again at the same moment.
+#[derive(Debug, Clone)]
+pub struct TtlPolicy {
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    // (domain, ttl); the longest matching domain wins
+    pub overrides: Vec<(Name, u32)>,
+    // percent, 0 to MAX_JITTER
+    pub jitter: u32,
+}
+
+// More would have entries expire long before their time.
+pub const MAX_JITTER: u32 = 50;
+
+impl TtlPolicy {
+    pub fn new() -> TtlPolicy {
+        TtlPolicy {
+            min_ttl: 0,
+            max_ttl: u32::MAX,
+            overrides: Vec::new(),
+            jitter: 0,
+        }
+    }
+
+    pub fn apply(&self, qname: &Name, records: &mut [DnsRecord]) {
+        let forced = self
+            .overrides
+            .iter()
+            .filter(|(domain, _)| qname.is_subdomain_of(domain))
+            .max_by_key(|(domain, _)| domain.label_count())
+            .map(|(_, ttl)| *ttl);
+
+        harmonize_ttls(records);
+        for record in records.iter_mut() {
+            let ttl = forced.unwrap_or_else(|| record.ttl().clamp(self.min_ttl, self.max_ttl));
+            record.set_ttl(ttl);
+        }
+    }
+}
+
+// The records of one RRset should share a TTL (RFC 2181 section 5.2); when
+// an upstream sends differing ones, all of them get the lowest.
+fn harmonize_ttls(records: &mut [DnsRecord]) {
+    let mut lowest: HashMap<(Name, u16), u32> = HashMap::new();
+    for record in records.iter() {
+        let ttl = lowest
+            .entry((record.domain().clone(), record.query_type().to_num()))
+            .or_insert(u32::MAX);
+        *ttl = (*ttl).min(record.ttl());
+    }
+    for record in records.iter_mut() {
+        let key = (record.domain().clone(), record.query_type().to_num());
+        record.set_ttl(lowest[&key]);
+    }
+}
+
+// The order the records of each RRset are answered in. RRsets themselves
+// keep their place, so a CNAME still comes before its target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RrsetOrder {
+    // as the upstream sent them
+    AsReceived,
+    // by rdata, the same on every answer
+    Sorted,
+    // shuffled on every answer
+    Random,
+}
+
+impl RrsetOrder {
+    pub fn from_name(name: &str) -> Option<RrsetOrder> {
+        match name {
+            "as-received" => Some(RrsetOrder::AsReceived),
+            "sorted" => Some(RrsetOrder::Sorted),
+            "random" => Some(RrsetOrder::Random),
+            _ => None,
+        }
+    }
+}
+
+// What sorted orders by: addresses and MX priorities as numbers, everything
+// else by its text.
+fn rdata_key(record: &DnsRecord) -> Vec<u8> {
+    match record {
+        DnsRecord::A { addr, .. } => addr.octets().to_vec(),
+        DnsRecord::AAAA { addr, .. } => addr.octets().to_vec(),
+        DnsRecord::MX { priority, host, .. } => {
+            let mut key = priority.to_be_bytes().to_vec();
+            key.extend(host.to_lowercase().wire());
+            key
+        }
+        _ => record.rdata_string().to_ascii_lowercase().into_bytes(),
+    }
+}
+
+// Reorders the records within each RRset; an RRset split up in records is
+// gathered where it first appears.
+fn order_rrsets(records: &mut Vec<DnsRecord>, order: RrsetOrder, rng: &Mutex<Rng>) {
+    if order == RrsetOrder::AsReceived {
+        return;
+    }
+
+    let mut rrsets: Vec<((Name, QueryType), Vec<DnsRecord>)> = Vec::new();
+    for record in records.drain(..) {
+        let key = (record.domain().clone(), record.query_type());
+        match rrsets.iter_mut().find(|(known, _)| *known == key) {
+            Some((_, rrset)) => rrset.push(record),
+            None => rrsets.push((key, vec![record])),
+        }
+    }
+
+    for (_, mut rrset) in rrsets {
+        match order {
+            RrsetOrder::Sorted => rrset.sort_by_key(rdata_key),
+            RrsetOrder::Random => {
+                let mut rng = rng.lock().unwrap();
+                for i in (1..rrset.len()).rev() {
+                    rrset.swap(i, rng.below(i as u64 + 1) as usize);
+                }
+            }
+            RrsetOrder::AsReceived => {}
+        }
+        records.extend(rrset);
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// A cache shared by several servers in Redis, behind the one each keeps in
+// memory. An entry is a key per name and type, e.g.
+// "dns-server:www.example.com./1", holding the time it was stored in
+// milliseconds since the epoch (8 bytes) and the answers in wire form. Redis
+// expires it with the shortest TTL; a server that finds it counts the TTLs
+// down by its age.
+pub struct SharedCache {
+    redis: Redis,
+}
+
+impl SharedCache {
+    pub fn new(redis: Redis) -> SharedCache {
+        SharedCache { redis }
+    }
+
+    fn key(&self, name: &Name, qtype: u16) -> Vec<u8> {
+        format!(
+            "{}{}/{}",
+            self.redis.prefix(),
+            name.to_lowercase().to_fqdn(),
+            qtype
+        )
+        .into_bytes()
+    }
+
+    fn get(&self, name: &Name, qtype: u16) -> Option<Vec<DnsRecord>> {
+        let Ok(Reply::Bulk(value)) = self.redis.command(&[b"GET", &self.key(name, qtype)]) else {
+            return None;
+        };
+        let (stored, data) = value.split_first_chunk::<8>()?;
+        let age = unix_millis().saturating_sub(u64::from_be_bytes(*stored)) / 1000;
+
+        let mut buf = BufHandler::from_bytes(data);
+        let mut answers = Vec::new();
+        while buf.get_pos() < data.len() {
+            let mut answer = DnsRecord::read(&mut buf).ok()?;
+            let ttl = (answer.ttl() as u64)
+                .checked_sub(age)
+                .filter(|&ttl| ttl > 0)?;
+            answer.set_ttl(ttl as u32);
+            answers.push(answer);
+        }
+        (!answers.is_empty()).then_some(answers)
+    }
+
+    fn insert(&self, name: &Name, qtype: u16, answers: &[DnsRecord], ttl: u32) {
+        let mut buf = BufHandler::with_size(crate::TCP_MESSAGE_SIZE);
+        if answers.iter().any(|answer| answer.write(&mut buf).is_err()) {
+            return;
+        }
+        let mut value = unix_millis().to_be_bytes().to_vec();
+        value.extend_from_slice(buf.written());
+        let millis = (ttl as u64 * 1000).to_string();
+        let _ = self.redis.command(&[
+            b"SET",
+            &self.key(name, qtype),
+            &value,
+            b"PX",
+            millis.as_bytes(),
+        ]);
+    }
+
+    // Deletes the entries matching the key pattern, returning how many.
+    fn remove_matching(&self, pattern: &[u8]) -> usize {
+        let Ok(keys) = self.redis.scan(pattern) else {
+            return 0;
+        };
+        let mut removed = 0;
+        for batch in keys.chunks(100) {
+            let mut args: Vec<&[u8]> = vec![b"DEL"];
+            args.extend(batch.iter().map(|key| key.as_slice()));
+            if let Ok(Reply::Integer(count)) = self.redis.command(&args) {
+                removed += count as usize;
+            }
+        }
+        removed
+    }
+
+    fn remove(&self, name: &Name) -> usize {
+        let mut pattern = glob_escape(self.redis.prefix().as_bytes());
+        pattern.extend(glob_escape(name.to_lowercase().to_fqdn().as_bytes()));
+        pattern.extend_from_slice(b"/*");
+        self.remove_matching(&pattern)
+    }
+
+    fn clear(&self) {
+        let mut pattern = glob_escape(self.redis.prefix().as_bytes());
+        pattern.push(b'*');
+        self.remove_matching(&pattern);
+    }
+}
+
+// Remembers the answers produced by the stages after it, for as long as the
+// shortest TTL among them allows. The TTL policy is applied first, both to
+// what is stored and to the answer passed back, and every answer, fresh or
+// cached, has its RRsets put in the configured order. With a shared cache,
+// answers are stored in both, and looked up in the shared one when this one
+// doesn't have them. Client policies with upstreams of their own get a view
+// of their own, a cache apart that isn't shared, so their answers don't
+// reach anyone else.
+pub struct Cache {
+    shards: Vec<Mutex<Shard>>,
+    shared: Option<SharedCache>,
+    // by policy name, made on the policy's first query
+    views: RwLock<HashMap<String, Arc<Cache>>>,
+    hasher: RandomState,
+    ttl_policy: TtlPolicy,
+    order: RrsetOrder,
+    rng: Mutex<Rng>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // the hits that came from the shared cache
+    shared_hits: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(ttl_policy: TtlPolicy, order: RrsetOrder) -> Cache {
+        Cache {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            shared: None,
+            views: RwLock::new(HashMap::new()),
+            hasher: RandomState::new(),
+            ttl_policy,
+            order,
+            rng: Mutex::new(Rng::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            shared_hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_shared(&mut self, shared: SharedCache) {
+        self.shared = Some(shared);
+    }
+
+    // All the types cached for a name are in the same shard.
+    fn shard(&self, name: &Name) -> &Mutex<Shard> {
+        &self.shards[self.hasher.hash_one(name) as usize % SHARDS]
+    }
+
+    // The entries in every view.
+    pub fn len(&self) -> usize {
+        let views: usize = self
+            .views
+            .read()
+            .unwrap()
+            .values()
+            .map(|view| view.len())
+            .sum();
+        views
+            + self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().len())
+                .sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn shared_hits(&self) -> u64 {
+        self.shared_hits.load(Ordering::Relaxed)
+    }
+
+    // Empties the shared cache too, for every server using it, and the
+    // policies' views.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().clear();
+        }
+        self.views.write().unwrap().clear();
+        if let Some(shared) = &self.shared {
+            shared.clear();
+        }
+    }
+
+    // Drops every entry for name, whatever the type, here, in the policies'
+    // views and in the shared cache. Returns how many.
+    pub fn remove(&self, name: &Name) -> usize {
+        let mut entries = self.shard(name).lock().unwrap();
+        let before = entries.len();
+        entries.retain(|(owner, _), _| owner != name);
+        let removed = before - entries.len();
+        drop(entries);
+
+        let views: usize = self
+            .views
+            .read()
+            .unwrap()
+            .values()
+            .map(|view| view.remove(name))
+            .sum();
+        removed + views + self.shared.as_ref().map_or(0, |shared| shared.remove(name))
+    }
+
+    // The view of the policy called name.
+    fn view(&self, name: &str) -> Arc<Cache> {
+        if let Some(view) = self.views.read().unwrap().get(name) {
+            return view.clone();
+        }
+        self.views
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Cache::new(self.ttl_policy.clone(), self.order)))
+            .clone()
+    }
+
+    // Every live entry as (name, qtype, answers), TTLs counted down like get
+    // does, sorted by name. Only those in memory, not the shared cache's, and
+    // not the policies' views.
+    pub fn dump(&self) -> Vec<(Name, u16, Vec<DnsRecord>)> {
+        let now = Instant::now();
+
+        let mut dump = Vec::new();
+        for shard in self.shards.iter() {
+            let entries = shard.lock().unwrap();
+            dump.extend(
+                entries.iter().filter(|(_, entry)| entry.expires > now).map(
+                    |((name, qtype), entry)| (name.clone(), *qtype, entry.current_answers(now)),
+                ),
+            );
+        }
+        dump.sort_by_key(|(name, qtype, _)| (name.to_ascii().to_ascii_lowercase(), *qtype));
+        dump
+    }
+
+    // The cached answers, with TTLs counted down by the time they have spent
+    // in the cache. What comes from the shared cache is kept here as well.
+    pub fn get(&self, name: &Name, qtype: u16) -> Option<Vec<DnsRecord>> {
+        let key = (name.clone(), qtype);
+        let mut entries = self.shard(&key.0).lock().unwrap();
+        let now = Instant::now();
+
+        match entries.get(&key) {
+            Some(entry) if entry.expires > now => return Some(entry.current_answers(now)),
+            Some(_) => {
+                entries.remove(&key);
+            }
+            None => {}
+        }
+        drop(entries);
+
+        let answers = self.shared.as_ref()?.get(name, qtype)?;
+        self.shared_hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(ttl) = answers.iter().map(|record| record.ttl()).min() {
+            self.insert_local(name, qtype, answers.clone(), ttl);
+        }
+        Some(answers)
+    }
+
+    pub fn insert(&self, name: &Name, qtype: u16, mut answers: Vec<DnsRecord>) {
+        self.ttl_policy.apply(name, &mut answers);
+        let Some(mut ttl) = answers.iter().map(|record| record.ttl()).min() else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        // every record loses the same, so RRsets keep sharing a TTL
+        let spread = ttl as u64 * self.ttl_policy.jitter as u64 / 100;
+        if spread > 0 {
+            let cut = (self.rng.lock().unwrap().below(spread + 1) as u32)
+                .min(ttl.saturating_sub(self.ttl_policy.min_ttl.max(1)));
+            for record in answers.iter_mut() {
+                record.set_ttl(record.ttl() - cut);
+            }
+            ttl -= cut;
+        }
+
+        if let Some(shared) = &self.shared {
+            shared.insert(name, qtype, &answers, ttl);
+        }
+        self.insert_local(name, qtype, answers, ttl);
+    }
+
+    fn insert_local(&self, name: &Name, qtype: u16, answers: Vec<DnsRecord>, ttl: u32) {
+        let now = Instant::now();
+        self.shard(name).lock().unwrap().insert(
+            (name.clone(), qtype),
+            CacheEntry {
+                answers,
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+}
+
+impl Handler for Cache {
+    fn name(&self) -> &str {
+        "cache"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let question = request.question;
+
+        // cached data is only for clients we'd resolve for anyway
+        if !request.recursion_allowed {
+            next.run(request, response);
+            return;
+        }
+        let view = request
+            .policy
+            .filter(|policy| policy.own_upstreams)
+            .map(|policy| self.view(&policy.name));
+        let cache = view.as_deref().unwrap_or(self);
+
+        let span = telemetry::span("cache lookup", SpanKind::Internal);
+        let cached = cache.get(&question.name, question.qtype.to_num());
+        span.set("dns.cache.hit", cached.is_some());
+        drop(span);
+
+        if let Some(answers) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            response.answers = answers;
+            order_rrsets(&mut response.answers, self.order, &self.rng);
+            return;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        next.run(request, response);
+
+        if response.header.response_code == ResponseCode::NOERR && !response.answers.is_empty() {
+            self.ttl_policy.apply(&question.name, &mut response.answers);
+            cache.insert(
+                &question.name,
+                question.qtype.to_num(),
+                response.answers.clone(),
+            );
+        }
+        order_rrsets(&mut response.answers, self.order, &self.rng);
+    }
+}
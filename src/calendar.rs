@@ -0,0 +1,306 @@
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Dates in the proleptic Gregorian calendar, counted in days since
+// 1970-01-01, and local time through POSIX TZ rules.
+
+// Howard Hinnant's days_from_civil.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// (year, month, day) of a day count, the inverse of days_from_civil.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// 0 for Sunday; 1970-01-01 was a Thursday.
+pub fn weekday(days: i64) -> i64 {
+    (days + 4).rem_euclid(7)
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// The day of a year a daylight saving time rule switches on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RuleDay {
+    // Jn: 1 to 365, February 29th is never counted
+    Julian(i64),
+    // n: 0 to 365, counting February 29th in leap years
+    Zero(i64),
+    // Mm.w.d: day d (0 Sunday) of week w (5 is the last) of month m
+    Month(i64, i64, i64),
+}
+
+impl RuleDay {
+    fn days(self, year: i64) -> i64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match self {
+            RuleDay::Julian(n) => {
+                let leap_day = i64::from(is_leap(year) && n >= 60);
+                jan1 + n - 1 + leap_day
+            }
+            RuleDay::Zero(n) => jan1 + n,
+            RuleDay::Month(month, week, day) => {
+                let first = days_from_civil(year, month, 1);
+                let mut date = 1 + (day - weekday(first)).rem_euclid(7) + (week - 1) * 7;
+                while date > days_in_month(year, month) {
+                    date -= 7;
+                }
+                first + date - 1
+            }
+        }
+    }
+}
+
+// When daylight saving time starts and ends, each as a day and the local
+// time of day in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DstRule {
+    start: (RuleDay, i64),
+    end: (RuleDay, i64),
+}
+
+// A time zone as a POSIX TZ string describes it, e.g. "CET-1CEST,M3.5.0,M10.5.0/3".
+// Offsets are kept the usual way round, seconds east of UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeZone {
+    std_offset: i64,
+    dst_offset: i64,
+    dst: Option<DstRule>,
+}
+
+// A little cursor over a TZ string.
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Option<i64> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    // "CET", or "<+0330>" for names that aren't letters
+    fn name(&mut self) -> Option<()> {
+        if self.eat(b'<') {
+            while !self.eat(b'>') {
+                self.peek()?;
+                self.pos += 1;
+            }
+            return Some(());
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        (self.pos - start >= 3).then_some(())
+    }
+
+    // [+-]hh[:mm[:ss]] in seconds
+    fn time(&mut self) -> Option<i64> {
+        let sign = if self.eat(b'-') {
+            -1
+        } else {
+            self.eat(b'+');
+            1
+        };
+        let mut seconds = self.number()? * 3600;
+        if self.eat(b':') {
+            seconds += self.number()? * 60;
+            if self.eat(b':') {
+                seconds += self.number()?;
+            }
+        }
+        Some(sign * seconds)
+    }
+
+    fn rule_day(&mut self) -> Option<RuleDay> {
+        if self.eat(b'J') {
+            return Some(RuleDay::Julian(self.number()?));
+        }
+        if self.eat(b'M') {
+            let month = self.number()?;
+            self.eat(b'.').then_some(())?;
+            let week = self.number()?;
+            self.eat(b'.').then_some(())?;
+            let day = self.number()?;
+            let valid = (1..=12).contains(&month) && (1..=5).contains(&week) && day <= 6;
+            return valid.then_some(RuleDay::Month(month, week, day));
+        }
+        Some(RuleDay::Zero(self.number()?))
+    }
+
+    fn transition(&mut self) -> Option<(RuleDay, i64)> {
+        let day = self.rule_day()?;
+        let time = if self.eat(b'/') { self.time()? } else { 7200 };
+        Some((day, time))
+    }
+}
+
+impl TimeZone {
+    pub fn utc() -> TimeZone {
+        TimeZone {
+            std_offset: 0,
+            dst_offset: 0,
+            dst: None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<TimeZone, String> {
+        let bad = || format!("bad time zone {:?}", text);
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+
+        parser.name().ok_or_else(bad)?;
+        // POSIX counts west of UTC as positive
+        let std_offset = -parser.time().ok_or_else(bad)?;
+        if parser.peek().is_none() {
+            return Ok(TimeZone {
+                std_offset,
+                dst_offset: std_offset,
+                dst: None,
+            });
+        }
+
+        parser.name().ok_or_else(bad)?;
+        let dst_offset = match parser.peek() {
+            Some(b',') | None => std_offset + 3600,
+            Some(_) => -parser.time().ok_or_else(bad)?,
+        };
+        // the US rules, which is what POSIX leaves it to
+        let mut rule = DstRule {
+            start: (RuleDay::Month(3, 2, 0), 7200),
+            end: (RuleDay::Month(11, 1, 0), 7200),
+        };
+        if parser.eat(b',') {
+            rule.start = parser.transition().ok_or_else(bad)?;
+            parser.eat(b',').then_some(()).ok_or_else(bad)?;
+            rule.end = parser.transition().ok_or_else(bad)?;
+        }
+        if parser.peek().is_some() {
+            return Err(bad());
+        }
+
+        Ok(TimeZone {
+            std_offset,
+            dst_offset,
+            dst: Some(rule),
+        })
+    }
+
+    // The rules at the end of a TZif file; version 2 and later end with a
+    // TZ string for the times after their last transition.
+    fn from_tzif(path: &str) -> Option<TimeZone> {
+        let data = std::fs::read(path).ok()?;
+        if !data.starts_with(b"TZif") {
+            return None;
+        }
+        let footer = data.strip_suffix(b"\n")?;
+        let start = footer.iter().rposition(|&c| c == b'\n')? + 1;
+        TimeZone::parse(std::str::from_utf8(&footer[start..]).ok()?).ok()
+    }
+
+    // The system's time zone: TZ, holding either rules or the name of a zone
+    // in the tz database, or else /etc/localtime. UTC if neither is usable.
+    pub fn local() -> TimeZone {
+        if let Ok(tz) = std::env::var("TZ") {
+            let tz = tz.trim_start_matches(':');
+            if let Ok(zone) = TimeZone::parse(tz) {
+                return zone;
+            }
+            let path = if tz.starts_with('/') {
+                tz.to_string()
+            } else {
+                format!("/usr/share/zoneinfo/{}", tz)
+            };
+            if let Some(zone) = TimeZone::from_tzif(&path) {
+                return zone;
+            }
+        }
+        TimeZone::from_tzif("/etc/localtime").unwrap_or_else(TimeZone::utc)
+    }
+
+    // Seconds east of UTC at the instant unix (seconds since the epoch).
+    pub fn offset_at(&self, unix: i64) -> i64 {
+        let Some(rule) = self.dst else {
+            return self.std_offset;
+        };
+        let (year, _, _) = civil_from_days((unix + self.std_offset).div_euclid(86400));
+        // the start is given in standard time, the end in daylight time
+        let start = rule.start.0.days(year) * 86400 + rule.start.1 - self.std_offset;
+        let end = rule.end.0.days(year) * 86400 + rule.end.1 - self.dst_offset;
+        let in_dst = if start < end {
+            start <= unix && unix < end
+        } else {
+            // southern hemisphere, daylight time spans the new year
+            !(end <= unix && unix < start)
+        };
+        if in_dst {
+            self.dst_offset
+        } else {
+            self.std_offset
+        }
+    }
+
+    // Local wall clock time at unix as days since 1970-01-01 and seconds
+    // into that day.
+    pub fn local_time(&self, unix: i64) -> (i64, i64) {
+        let local = unix + self.offset_at(unix);
+        (local.div_euclid(86400), local.rem_euclid(86400))
+    }
+}
@@ -0,0 +1,55 @@
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::DnsRecord;
+use crate::QueryClass;
+use crate::QueryType;
+use crate::ResponseCode;
+
+// Names under which CHAOS class TXT queries identify the server (RFC 4892).
+const VERSION_NAMES: [&str; 2] = ["version.bind", "version.server"];
+const ID_NAMES: [&str; 2] = ["id.server", "hostname.bind"];
+
+// Answers CHAOS class queries for the server's version and identity. Either
+// one left unset is refused like any other CHAOS name.
+pub struct Chaos {
+    version: Option<String>,
+    id: Option<String>,
+}
+
+impl Chaos {
+    pub fn new(version: Option<String>, id: Option<String>) -> Chaos {
+        Chaos { version, id }
+    }
+
+    pub fn answer(&self, question: &DnsQuestion, response: &mut DnsPacket) {
+        let name = question.name.to_ascii();
+        let text = if VERSION_NAMES
+            .iter()
+            .any(|known| name.eq_ignore_ascii_case(known))
+        {
+            self.version.as_ref()
+        } else if ID_NAMES
+            .iter()
+            .any(|known| name.eq_ignore_ascii_case(known))
+        {
+            self.id.as_ref()
+        } else {
+            None
+        };
+
+        let Some(text) = text else {
+            response.header.response_code = ResponseCode::REFUSED;
+            return;
+        };
+
+        response.header.authoritative_answer = true;
+        if let QueryType::TXT | QueryType::ANY = question.qtype {
+            response.answers.push(DnsRecord::TXT {
+                domain: question.name.clone(),
+                ttl: 0,
+                class: QueryClass::CH,
+                data: vec![text.clone().into_bytes()],
+            });
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::DnsRecord;
+use crate::EDNS_TCP_KEEPALIVE;
+use crate::EdnsOption;
+use crate::QueryClass;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::TCP_MESSAGE_SIZE;
+use crate::UDP_MESSAGE_SIZE;
+use crate::anonymize;
+use crate::hexdump;
+use crate::name::Name;
+use crate::pool;
+use crate::random::Rng;
+use crate::response_size::DEFAULT_UDP_SIZE;
+use crate::socks::Socks5Proxy;
+use crate::telemetry;
+use crate::telemetry::SpanKind;
+use crate::udp;
+use crate::udp::UdpIo;
+use crate::view::PacketView;
+
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Sockets per address family shared by every outgoing query.
+const POOL_SIZE: usize = 4;
+
+// A pooled socket is swapped for one on a fresh random port after this many
+// queries or this long, whichever comes first, so an attacker who learns a
+// port, say by asking for a name of theirs, has little time to aim forged
+// replies at it (RFC 5452 section 9.2).
+const SOCKET_QUERIES: u32 = 32;
+const SOCKET_LIFETIME: Duration = Duration::from_secs(30);
+
+struct Waiter {
+    question: DnsQuestion,
+    reply: mpsc::Sender<DnsPacket>,
+}
+
+// Queries in flight on one socket, by id and server.
+type Waiters = Mutex<HashMap<(u16, SocketAddr), Waiter>>;
+
+struct PooledSocket {
+    socket: UdpSocket,
+    waiting: Arc<Waiters>,
+    // queries sent from it
+    sent: AtomicU32,
+    opened: Instant,
+    // queries using it right now
+    users: Arc<AtomicUsize>,
+    // replaced by a fresh one; its reader stops once no query uses it
+    retired: Arc<AtomicBool>,
+}
+
+// A fixed number of bound UDP sockets, each with a thread reading replies
+// and handing them to the query waiting for that id from that server, and
+// replaced by a new one every so often. Saves a bind and a close per query.
+pub struct SocketPool {
+    bind_addr: SocketAddr,
+    sockets: Vec<Mutex<Arc<PooledSocket>>>,
+    next: AtomicUsize,
+    rng: Mutex<Rng>,
+}
+
+fn receive_loop(
+    socket: UdpSocket,
+    waiting: Arc<Waiters>,
+    users: Arc<AtomicUsize>,
+    retired: Arc<AtomicBool>,
+) {
+    let io = *UDP_IO.read().unwrap();
+    udp::serve_until(
+        &socket,
+        io,
+        |reply, src| {
+            dispatch(reply, src, &waiting);
+            None
+        },
+        || retired.load(Ordering::SeqCst) && users.load(Ordering::SeqCst) == 0,
+    );
+}
+
+impl PooledSocket {
+    fn open(bind_addr: SocketAddr) -> Result<Arc<PooledSocket>, String> {
+        let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
+        let receiver = socket.try_clone().map_err(|e| e.to_string())?;
+        let waiting = Arc::new(Mutex::new(HashMap::new()));
+        let users = Arc::new(AtomicUsize::new(0));
+        let retired = Arc::new(AtomicBool::new(false));
+
+        let (receiver_waiting, receiver_users, receiver_retired) =
+            (waiting.clone(), users.clone(), retired.clone());
+        thread::spawn(move || {
+            receive_loop(receiver, receiver_waiting, receiver_users, receiver_retired)
+        });
+
+        Ok(Arc::new(PooledSocket {
+            socket,
+            waiting,
+            sent: AtomicU32::new(0),
+            opened: Instant::now(),
+            users,
+            retired,
+        }))
+    }
+
+    // Once retired and unused, wakes the reader up with an empty datagram so
+    // it sees it is done and the socket is closed.
+    fn wake_if_done(&self) {
+        if !self.retired.load(Ordering::SeqCst) || self.users.load(Ordering::SeqCst) != 0 {
+            return;
+        }
+        let Ok(mut addr) = self.socket.local_addr() else {
+            return;
+        };
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = self.socket.send_to(&[], addr);
+    }
+}
+
+// Hands a reply to the query waiting for it, if any.
+fn dispatch(reply: &[u8], src: SocketAddr, waiting: &Waiters) {
+    hexdump::trace("upstream reply from", src, "udp", reply);
+    // stray and spoofed replies are turned away before anything is allocated
+    let Ok(view) = PacketView::parse(reply) else {
+        return;
+    };
+
+    let mut waiting = waiting.lock().unwrap();
+    let key = (view.header.id, src);
+
+    // a reply has to echo the question too, not just guess the id
+    let matches = waiting.get(&key).is_some_and(|waiter| {
+        view.questions().next().is_some_and(|question| {
+            question.qtype == waiter.question.qtype
+                && question.qclass == waiter.question.qclass
+                && question.name.eq_name(&waiter.question.name)
+        })
+    });
+    if !matches {
+        return;
+    }
+
+    let mut buf_handler = pool::buffer_from(reply);
+    let Ok(packet) = DnsPacket::from_buffer(&mut buf_handler) else {
+        return;
+    };
+    if let Some(waiter) = waiting.remove(&key) {
+        let _ = waiter.reply.send(packet);
+    }
+}
+
+impl SocketPool {
+    pub fn new(bind_addr: SocketAddr, size: usize) -> Result<SocketPool, String> {
+        let mut sockets = Vec::with_capacity(size);
+        for _ in 0..size {
+            sockets.push(Mutex::new(PooledSocket::open(bind_addr)?));
+        }
+
+        Ok(SocketPool {
+            bind_addr,
+            sockets,
+            next: AtomicUsize::new(0),
+            rng: Mutex::new(Rng::new()),
+        })
+    }
+
+    // The next socket in turn, replaced first if it has been used for long
+    // enough. A socket that can't be replaced is used a while longer.
+    fn socket(&self) -> Arc<PooledSocket> {
+        let slot = &self.sockets[self.next.fetch_add(1, Ordering::Relaxed) % self.sockets.len()];
+        let mut current = slot.lock().unwrap();
+        let sent = current.sent.fetch_add(1, Ordering::Relaxed);
+        if sent >= SOCKET_QUERIES || current.opened.elapsed() >= SOCKET_LIFETIME {
+            match PooledSocket::open(self.bind_addr) {
+                Ok(fresh) => {
+                    let old = std::mem::replace(&mut *current, fresh);
+                    old.retired.store(true, Ordering::SeqCst);
+                    old.wake_if_done();
+                    current.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => eprintln!("bind {}: {}", self.bind_addr, e),
+            }
+        }
+        // taken while the slot is locked, so a socket can't be retired and
+        // stopped between being handed out and being used
+        current.users.fetch_add(1, Ordering::SeqCst);
+        current.clone()
+    }
+
+    // Sends packet to server under a fresh random id and waits for the reply.
+    pub fn query(&self, packet: &mut DnsPacket, server: SocketAddr) -> Result<DnsPacket, String> {
+        let question = packet.questions.first().cloned().ok_or("no question")?;
+        let pooled = self.socket();
+        let (reply, replies) = mpsc::channel();
+
+        let id = loop {
+            let id = self.rng.lock().unwrap().below(1 << 16) as u16;
+            let mut waiting = pooled.waiting.lock().unwrap();
+            if let Entry::Vacant(slot) = waiting.entry((id, server)) {
+                slot.insert(Waiter {
+                    question: question.clone(),
+                    reply: reply.clone(),
+                });
+                break id;
+            }
+        };
+        packet.header.id = id;
+
+        let mut buf_handler = pool::buffer(UDP_MESSAGE_SIZE);
+        let sent = packet.write(&mut buf_handler).and_then(|_| {
+            hexdump::trace("upstream query to", server, "udp", buf_handler.written());
+            pooled
+                .socket
+                .send_to(buf_handler.written(), server)
+                .map_err(|e| format!("{}: {}", server, e))
+        });
+
+        let result = match sent {
+            Ok(_) => replies
+                .recv_timeout(QUERY_TIMEOUT)
+                .map_err(|_| format!("{}: timed out", server)),
+            Err(e) => Err(e),
+        };
+        pooled.waiting.lock().unwrap().remove(&(id, server));
+        pooled.users.fetch_sub(1, Ordering::SeqCst);
+        pooled.wake_if_done();
+
+        result
+    }
+}
+
+static POOLS: Mutex<Vec<(SocketAddr, Arc<SocketPool>)>> = Mutex::new(Vec::new());
+
+// Source addresses used when a query doesn't ask for one, at most one per
+// address family.
+static DEFAULT_SOURCES: RwLock<Vec<IpAddr>> = RwLock::new(Vec::new());
+
+// How pooled sockets read replies.
+static UDP_IO: RwLock<UdpIo> = RwLock::new(UdpIo::Plain);
+
+pub fn set_default_sources(sources: Vec<IpAddr>) {
+    *DEFAULT_SOURCES.write().unwrap() = sources;
+}
+
+pub fn set_udp_io(io: UdpIo) {
+    *UDP_IO.write().unwrap() = io;
+}
+
+// The EDNS UDP payload size upstream queries advertise, so larger answers
+// come back over UDP rather than truncated.
+static PAYLOAD_SIZE: AtomicU16 = AtomicU16::new(DEFAULT_UDP_SIZE);
+
+// Sets the size upstream queries advertise, no more than the sockets read.
+pub fn set_payload_size(size: u16) {
+    let size = size.min(udp::RECV_SIZE as u16);
+    PAYLOAD_SIZE.store(size, Ordering::Relaxed);
+}
+
+// The process wide pool bound to source (or the default source for server's
+// address family), created on first use.
+fn pool(server: SocketAddr, source: Option<IpAddr>) -> Result<Arc<SocketPool>, String> {
+    let source = source
+        .or_else(|| {
+            DEFAULT_SOURCES
+                .read()
+                .unwrap()
+                .iter()
+                .copied()
+                .find(|source| source.is_ipv4() == server.is_ipv4())
+        })
+        .unwrap_or(if server.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        });
+    if source.is_ipv4() != server.is_ipv4() {
+        return Err(format!("{}: can't be reached from {}", server, source));
+    }
+
+    let bind_addr = SocketAddr::new(source, 0);
+    let mut pools = POOLS.lock().unwrap();
+    if let Some((_, pool)) = pools.iter().find(|(addr, _)| *addr == bind_addr) {
+        return Ok(pool.clone());
+    }
+    let pool = Arc::new(
+        SocketPool::new(bind_addr, POOL_SIZE).map_err(|e| format!("bind {}: {}", source, e))?,
+    );
+    pools.push((bind_addr, pool.clone()));
+    Ok(pool)
+}
+
+// A query for qname, with an OPT record advertising PAYLOAD_SIZE if edns.
+fn query_packet(qname: &Name, qtype: QueryType, edns: bool) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.recursion_desired = true;
+    packet.questions.push(DnsQuestion {
+        name: qname.clone(),
+        qtype,
+        qclass: QueryClass::IN,
+    });
+    if edns {
+        packet.additionals.push(DnsRecord::OPT {
+            payload_size: PAYLOAD_SIZE.load(Ordering::Relaxed),
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: Vec::new(),
+        });
+    }
+    packet
+}
+
+// Sends a single query to server over UDP and waits for the reply.
+pub fn lookup(qname: &Name, qtype: QueryType, server: SocketAddr) -> Result<DnsPacket, String> {
+    lookup_from(qname, qtype, server, None)
+}
+
+// Like lookup, sending from the given source address.
+pub fn lookup_from(
+    qname: &Name,
+    qtype: QueryType,
+    server: SocketAddr,
+    source: Option<IpAddr>,
+) -> Result<DnsPacket, String> {
+    round_trip(qname, qtype, server, "udp", || {
+        let pool = pool(server, source)?;
+        let reply = pool.query(&mut query_packet(qname, qtype, true), server)?;
+        // a server that doesn't know EDNS answers FORMERR without an OPT
+        // record (RFC 6891 section 7), so it's asked again without
+        if reply.header.response_code == ResponseCode::FORMERR && reply.edns().is_none() {
+            return pool.query(&mut query_packet(qname, qtype, false), server);
+        }
+        Ok(reply)
+    })
+}
+
+// Runs exchange, the sending of one query upstream and the wait for its
+// reply, as a span of the current trace and a sample of upstream latency.
+fn round_trip(
+    qname: &Name,
+    qtype: QueryType,
+    server: SocketAddr,
+    transport: &str,
+    exchange: impl FnOnce() -> Result<DnsPacket, String>,
+) -> Result<DnsPacket, String> {
+    let span = telemetry::span("upstream query", SpanKind::Client);
+    span.set("server.address", server.ip().to_string());
+    span.set("server.port", server.port() as i64);
+    span.set("network.transport", transport);
+    span.set("dns.question.name", anonymize::name(qname, Name::to_ascii));
+    span.set("dns.question.type", format!("{:?}", qtype));
+
+    let started = Instant::now();
+    let result = exchange();
+    telemetry::record_upstream(started.elapsed());
+    match &result {
+        Ok(reply) => span.set(
+            "dns.response.code",
+            format!("{:?}", reply.header.response_code),
+        ),
+        Err(e) => span.fail(e),
+    }
+    result
+}
+
+// Sends a single query to server over TCP, through proxy if one is given.
+pub fn lookup_tcp(
+    qname: &Name,
+    qtype: QueryType,
+    server: SocketAddr,
+    proxy: Option<&Socks5Proxy>,
+) -> Result<DnsPacket, String> {
+    round_trip(qname, qtype, server, "tcp", || {
+        exchange_tcp(qname, qtype, server, proxy)
+    })
+}
+
+// An idle connection kept for the next query to its server.
+struct IdleConnection {
+    server: SocketAddr,
+    proxy: Option<Socks5Proxy>,
+    stream: TcpStream,
+    // when it is closed
+    until: Instant,
+}
+
+// Connections to upstreams stay open after a query for the next one to the
+// same server, saving a handshake (RFC 7766 section 6.2.1): for as long as
+// the server's edns-tcp-keepalive says, up to a limit, or, from servers that
+// don't say, as long as servers usually keep quiet connections open. Only so
+// many are kept in all.
+const TCP_IDLE: Duration = Duration::from_secs(5);
+const MAX_TCP_IDLE: Duration = Duration::from_secs(60);
+const MAX_IDLE_TCP: usize = 64;
+
+static IDLE_TCP: Mutex<Vec<IdleConnection>> = Mutex::new(Vec::new());
+
+// An open connection to server through proxy, if one is idle.
+fn take_idle(server: SocketAddr, proxy: Option<&Socks5Proxy>) -> Option<TcpStream> {
+    let mut idle = IDLE_TCP.lock().unwrap();
+    let now = Instant::now();
+    idle.retain(|connection| connection.until > now);
+    let index = idle
+        .iter()
+        .position(|connection| connection.server == server && connection.proxy.as_ref() == proxy)?;
+    Some(idle.swap_remove(index).stream)
+}
+
+// Keeps stream open for the next query to server, for as long as reply's
+// edns-tcp-keepalive allows (RFC 7828 section 3.3.2).
+fn put_idle(server: SocketAddr, proxy: Option<&Socks5Proxy>, stream: TcpStream, reply: &DnsPacket) {
+    let keepalive = match reply.edns() {
+        Some(DnsRecord::OPT { options, .. }) => options
+            .iter()
+            .find(|option| option.code == EDNS_TCP_KEEPALIVE)
+            .and_then(|option| <[u8; 2]>::try_from(&option.data[..]).ok())
+            .map(|timeout| Duration::from_millis(u16::from_be_bytes(timeout) as u64 * 100)),
+        _ => None,
+    };
+    let timeout = keepalive.map_or(TCP_IDLE, |timeout| timeout.min(MAX_TCP_IDLE));
+    if timeout.is_zero() {
+        return;
+    }
+
+    let mut idle = IDLE_TCP.lock().unwrap();
+    if idle.len() >= MAX_IDLE_TCP {
+        idle.remove(0);
+    }
+    idle.push(IdleConnection {
+        server,
+        proxy: proxy.cloned(),
+        stream,
+        until: Instant::now() + timeout,
+    });
+}
+
+fn connect_tcp(server: SocketAddr, proxy: Option<&Socks5Proxy>) -> Result<TcpStream, String> {
+    let fail = |e: std::io::Error| format!("{}: {}", server, e);
+
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(server, QUERY_TIMEOUT)?,
+        None => TcpStream::connect_timeout(&server, QUERY_TIMEOUT).map_err(fail)?,
+    };
+    stream.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(fail)?;
+    stream
+        .set_write_timeout(Some(QUERY_TIMEOUT))
+        .map_err(fail)?;
+    Ok(stream)
+}
+
+// Asks with EDNS and edns-tcp-keepalive, and again without EDNS if the
+// server doesn't know it.
+fn exchange_tcp(
+    qname: &Name,
+    qtype: QueryType,
+    server: SocketAddr,
+    proxy: Option<&Socks5Proxy>,
+) -> Result<DnsPacket, String> {
+    let mut packet = query_packet(qname, qtype, true);
+    if let Some(DnsRecord::OPT { options, .. }) = packet.additionals.last_mut() {
+        // empty in queries, the server fills in its timeout
+        options.push(EdnsOption {
+            code: EDNS_TCP_KEEPALIVE,
+            data: Vec::new(),
+        });
+    }
+    let reply = exchange_tcp_packet(packet, server, proxy)?;
+    if reply.header.response_code == ResponseCode::FORMERR && reply.edns().is_none() {
+        return exchange_tcp_packet(query_packet(qname, qtype, false), server, proxy);
+    }
+    Ok(reply)
+}
+
+// Asks on an idle connection to server if there is one, and on a new one if
+// not, or if the server has closed the idle one in the meantime. The
+// connection is kept for the next query.
+fn exchange_tcp_packet(
+    mut packet: DnsPacket,
+    server: SocketAddr,
+    proxy: Option<&Socks5Proxy>,
+) -> Result<DnsPacket, String> {
+    packet.header.id = Rng::new().below(1 << 16) as u16;
+    let mut buf_handler = pool::buffer(TCP_MESSAGE_SIZE);
+    packet.write(&mut buf_handler)?;
+    let len = buf_handler.get_pos();
+    let mut message = Vec::with_capacity(2 + len);
+    message.extend_from_slice(&(len as u16).to_be_bytes());
+    message.extend_from_slice(&buf_handler.buf[0..len]);
+
+    if let Some(mut stream) = take_idle(server, proxy)
+        && let Ok(reply) = ask_tcp(&mut stream, server, &message, packet.header.id)
+    {
+        put_idle(server, proxy, stream, &reply);
+        return Ok(reply);
+    }
+    let mut stream = connect_tcp(server, proxy)?;
+    let reply = ask_tcp(&mut stream, server, &message, packet.header.id)?;
+    put_idle(server, proxy, stream, &reply);
+    Ok(reply)
+}
+
+// Writes message, a query with its length in front, and reads the reply.
+fn ask_tcp(
+    stream: &mut TcpStream,
+    server: SocketAddr,
+    message: &[u8],
+    id: u16,
+) -> Result<DnsPacket, String> {
+    let fail = |e: std::io::Error| format!("{}: {}", server, e);
+
+    hexdump::trace("upstream query to", server, "tcp", &message[2..]);
+    stream.write_all(message).map_err(fail)?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).map_err(fail)?;
+    let mut buf_handler = pool::buffer(u16::from_be_bytes(len) as usize);
+    stream.read_exact(&mut buf_handler.buf).map_err(fail)?;
+    hexdump::trace("upstream reply from", server, "tcp", &buf_handler.buf);
+
+    let reply = DnsPacket::from_buffer(&mut buf_handler)?;
+    if reply.header.id != id {
+        return Err(format!("{}: reply id does not match the query", server));
+    }
+    Ok(reply)
+}
@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::acl::Acl;
+
+// The EDNS option dnsmasq's --add-mac puts the client's MAC address in.
+pub const MAC_OPTION: u16 = 65001;
+
+// A group of clients with their own filtering, logging and upstreams, for
+// telling apart devices that share a resolver: by address, or by an EDNS
+// option a router in front of the server adds to their queries.
+#[derive(Debug, Clone)]
+pub struct ClientPolicy {
+    pub name: String,
+    // where its queries may come from, anywhere when None
+    pub clients: Option<Acl>,
+    // EDNS options (code and data) one of which its queries carry; when
+    // empty the address is enough
+    pub identifiers: Vec<(u16, Vec<u8>)>,
+    // whether its blocked, rewritten and rate limited queries are audited
+    pub audit: bool,
+    // whether forward rules of its own answer its queries, whose answers
+    // are then cached apart from the other clients'
+    pub own_upstreams: bool,
+    // a profile, which no client is identified by; only the queries to the
+    // listeners it's attached to get it
+    pub profile: bool,
+}
+
+impl ClientPolicy {
+    pub fn new(name: &str) -> ClientPolicy {
+        ClientPolicy {
+            name: name.to_string(),
+            clients: None,
+            identifiers: Vec::new(),
+            audit: true,
+            own_upstreams: false,
+            profile: false,
+        }
+    }
+
+    fn matches(&self, query: &DnsPacket, client: IpAddr) -> bool {
+        if self.profile {
+            return false;
+        }
+        if !self.clients.as_ref().is_none_or(|acl| acl.allows(client)) {
+            return false;
+        }
+        if self.identifiers.is_empty() {
+            return true;
+        }
+        let Some(DnsRecord::OPT { options, .. }) = query.edns() else {
+            return false;
+        };
+        options.iter().any(|option| {
+            self.identifiers
+                .iter()
+                .any(|(code, data)| option.code == *code && option.data == *data)
+        })
+    }
+}
+
+// The policies in the order they were defined; the first a query matches
+// is its client's.
+#[derive(Debug, Clone)]
+pub struct ClientPolicies {
+    policies: Vec<ClientPolicy>,
+}
+
+impl ClientPolicies {
+    pub fn new() -> ClientPolicies {
+        ClientPolicies {
+            policies: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, policy: ClientPolicy) {
+        self.policies.push(policy);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ClientPolicy> {
+        self.policies.iter().find(|policy| policy.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ClientPolicy> {
+        self.policies.iter_mut().find(|policy| policy.name == name)
+    }
+
+    pub fn identify(&self, query: &DnsPacket, client: IpAddr) -> Option<&ClientPolicy> {
+        self.policies
+            .iter()
+            .find(|policy| policy.matches(query, client))
+    }
+}
+
+// Parses a MAC address written as six colon or dash separated hex bytes.
+pub fn parse_mac(text: &str) -> Result<Vec<u8>, String> {
+    let bytes: Vec<u8> = text
+        .split([':', '-'])
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("bad MAC address {:?}", text))?;
+    if bytes.len() != 6 {
+        return Err(format!("bad MAC address {:?}", text));
+    }
+    Ok(bytes)
+}
+
+// Parses <code>:<value>, the value as text or, starting with 0x, as hex.
+pub fn parse_identifier(text: &str) -> Result<(u16, Vec<u8>), String> {
+    let bad = || format!("bad EDNS identifier {:?}, expected <code>:<value>", text);
+    let (code, value) = text.split_once(':').ok_or_else(bad)?;
+    let code = code.parse::<u16>().map_err(|_| bad())?;
+    let data = match value.strip_prefix("0x") {
+        Some(hex) if hex.is_ascii() && hex.len().is_multiple_of(2) => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| bad())?,
+        Some(_) => return Err(bad()),
+        None => value.as_bytes().to_vec(),
+    };
+    if data.is_empty() {
+        return Err(bad());
+    }
+    Ok((code, data))
+}
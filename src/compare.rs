@@ -0,0 +1,193 @@
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DnsHeader;
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::client;
+use crate::name::Name;
+use crate::replay::record_key;
+
+// Asks two servers the same question and lays their responses next to each
+// other, for looking into "my server returns something different" reports.
+// The diff reads like a unified one: lines both responses agree on start with
+// a space, lines only the first has with - and lines only the second has with
+// +. Records are compared regardless of order, and TTLs only by how long they
+// roughly are, since a cache counts them down.
+
+pub struct Answer {
+    pub server: SocketAddr,
+    pub elapsed: Duration,
+    pub response: Result<DnsPacket, String>,
+}
+
+// Asks server over UDP, and again over TCP if the reply is truncated.
+fn ask(name: &Name, qtype: QueryType, server: SocketAddr) -> Answer {
+    let started = Instant::now();
+    let response = client::lookup(name, qtype, server).and_then(|reply| {
+        if reply.header.truncation {
+            client::lookup_tcp(name, qtype, server, None)
+        } else {
+            Ok(reply)
+        }
+    });
+    Answer {
+        server,
+        elapsed: started.elapsed(),
+        response,
+    }
+}
+
+// Asks both servers at the same time.
+pub fn ask_both(name: &Name, qtype: QueryType, servers: [SocketAddr; 2]) -> [Answer; 2] {
+    thread::scope(|scope| {
+        servers
+            .map(|server| scope.spawn(move || ask(name, qtype, server)))
+            .map(|handle| handle.join().expect("query thread panicked"))
+    })
+}
+
+// Which of a few ranges ttl falls in; TTLs in the same one count as equal.
+fn ttl_bucket(ttl: u32) -> &'static str {
+    match ttl {
+        0 => "0",
+        1..60 => "<1m",
+        60..3600 => "1m-1h",
+        3600..86400 => "1h-1d",
+        _ => ">=1d",
+    }
+}
+
+fn flags(header: &DnsHeader) -> String {
+    let flags: Vec<&str> = [
+        (header.authoritative_answer, "aa"),
+        (header.truncation, "tc"),
+        (header.recursion_desired, "rd"),
+        (header.recursion_available, "ra"),
+        (header.authed_data, "ad"),
+        (header.checking_disabled, "cd"),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, flag)| flag)
+    .collect();
+    if flags.is_empty() {
+        "none".to_string()
+    } else {
+        flags.join(" ")
+    }
+}
+
+// A line both have, or a - and a + line if they differ.
+fn compare_line(lines: &mut Vec<String>, what: &str, first: String, second: String) {
+    if first == second {
+        lines.push(format!(" {} {}", what, first));
+    } else {
+        lines.push(format!("-{} {}", what, first));
+        lines.push(format!("+{} {}", what, second));
+    }
+}
+
+fn compare_section(
+    lines: &mut Vec<String>,
+    section: &str,
+    first: &[DnsRecord],
+    second: &[DnsRecord],
+) {
+    let keyed = |records: &[DnsRecord]| {
+        let mut keyed: Vec<(String, &'static str)> = records
+            .iter()
+            .filter(|record| record.query_type() != QueryType::OPT)
+            .map(|record| (record_key(record), ttl_bucket(record.ttl())))
+            .collect();
+        keyed.sort();
+        keyed
+    };
+    let (first, mut second) = (keyed(first), keyed(second));
+
+    for (key, bucket) in first {
+        match second.iter().position(|(other, _)| *other == key) {
+            Some(i) => {
+                let (_, other_bucket) = second.remove(i);
+                compare_line(
+                    lines,
+                    section,
+                    format!("{} ttl {}", key, bucket),
+                    format!("{} ttl {}", key, other_bucket),
+                );
+            }
+            None => lines.push(format!("-{} {} ttl {}", section, key, bucket)),
+        }
+    }
+    for (key, bucket) in second {
+        lines.push(format!("+{} {} ttl {}", section, key, bucket));
+    }
+}
+
+// The diff of two responses, one line per field or record.
+pub fn diff(first: &DnsPacket, second: &DnsPacket) -> Vec<String> {
+    let mut lines = Vec::new();
+    compare_line(
+        &mut lines,
+        "rcode",
+        format!("{:?}", first.header.response_code),
+        format!("{:?}", second.header.response_code),
+    );
+    compare_line(
+        &mut lines,
+        "flags",
+        flags(&first.header),
+        flags(&second.header),
+    );
+    compare_section(&mut lines, "answer", &first.answers, &second.answers);
+    compare_section(
+        &mut lines,
+        "authority",
+        &first.nameservers,
+        &second.nameservers,
+    );
+    compare_section(
+        &mut lines,
+        "additional",
+        &first.additionals,
+        &second.additionals,
+    );
+    lines
+}
+
+// Whether two upstreams' answers to a question of type qtype back each other
+// up: the same rcode and, if either has records of that type, at least one
+// the other has too. Owner names and the rest of the records aren't looked
+// at, since a CDN hands different resolvers different aliases and subsets of
+// its addresses.
+pub fn answers_agree(first: &DnsPacket, second: &DnsPacket, qtype: QueryType) -> bool {
+    let data = |packet: &DnsPacket| -> Vec<String> {
+        packet
+            .answers
+            .iter()
+            .filter(|record| record.query_type() == qtype)
+            .map(|record| record.rdata_string())
+            .collect()
+    };
+    let (first_data, second_data) = (data(first), data(second));
+    first.header.response_code == second.header.response_code
+        && (first_data.is_empty() && second_data.is_empty()
+            || first_data.iter().any(|data| second_data.contains(data)))
+}
+
+// Like diff, with a server that didn't answer shown by its error.
+pub fn diff_answers(first: &Answer, second: &Answer) -> Vec<String> {
+    match (&first.response, &second.response) {
+        (Ok(first), Ok(second)) => diff(first, second),
+        (first, second) => [('-', first), ('+', second)]
+            .into_iter()
+            .map(|(mark, response)| match response {
+                Ok(response) => format!("{}rcode {:?}", mark, response.header.response_code),
+                Err(e) => format!("{}error {}", mark, e),
+            })
+            .collect(),
+    }
+}
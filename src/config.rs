@@ -0,0 +1,1652 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::QueryType;
+use crate::UDP_MESSAGE_SIZE;
+use crate::acl::Acl;
+use crate::acl::Cidr;
+use crate::anonymize::AnonymizeConfig;
+use crate::anonymize::NameHashConfig;
+use crate::blocklist::BLOCK_LIST;
+use crate::blocklist::Blocklist;
+use crate::blocklist::Bypass;
+use crate::blocklist::GroupRule;
+use crate::blocklist::Rewrite;
+use crate::cache::MAX_JITTER;
+use crate::cache::RrsetOrder;
+use crate::cache::TtlPolicy;
+use crate::calendar::TimeZone;
+use crate::client_policy::ClientPolicies;
+use crate::client_policy::ClientPolicy;
+use crate::client_policy::MAC_OPTION;
+use crate::client_policy::parse_identifier;
+use crate::client_policy::parse_mac;
+use crate::connections::ConnectionPolicy;
+use crate::dhcp::LeaseFile;
+use crate::doh::HttpRoute;
+use crate::forwarder::CrossCheck;
+use crate::forwarder::ForwardRule;
+use crate::forwarder::Strategy;
+use crate::forwarder::Upstream;
+use crate::ha::HaConfig;
+use crate::log::LogLevel;
+use crate::mirror::MirrorConfig;
+use crate::name::Name;
+#[cfg(unix)]
+use crate::privileges;
+use crate::qtype_policy::QtypeAction;
+use crate::qtype_policy::QtypePolicy;
+use crate::qtype_policy::QtypeRule;
+use crate::quota::QuotaAction;
+use crate::quota::QuotaRule;
+use crate::redis::RedisConfig;
+use crate::resolvconf;
+use crate::response_size::Oversize;
+use crate::response_size::SizePolicy;
+use crate::retention::RetentionConfig;
+use crate::rules::Pattern;
+use crate::schedule::Schedule;
+use crate::sinkhole::SinkholeConfig;
+use crate::socks::Socks5Proxy;
+use crate::special::Designation;
+use crate::svcb;
+use crate::telemetry::OtlpConfig;
+use crate::threat::FeedConfig;
+use crate::threat::FeedSource;
+use crate::threat::ThreatAction;
+use crate::udp::UdpIo;
+use crate::zone::LocalZones;
+use crate::zone::parse_record;
+use crate::zone::parse_ttl;
+use crate::zone_store::StoreLocation;
+use crate::zone_watch::WatchedZone;
+use crate::zonefile;
+
+// Server configuration, read from a line based file:
+//
+//     # comment
+//     listen <addr:port> [profile=<name>] [<stage>...]
+//     http-listen <addr:port> [profile=<name>] [<stage>...]
+//     http-route <addr:port> [<host>]/<path> [profile=<name>]
+//     acl <name> <cidr>...
+//     allow-recursion <cidr>...
+//     qtype-policy refuse|drop|nodata|nxdomain <type>... [clients=<acl>]
+//     client-quota <soft-qps> <hard-qps> [hard=drop|refuse] [slip=<n>] [clients=<acl>]
+//         [ipv4-prefix=<len>] [ipv6-prefix=<len>]
+//     client-policy <name> [clients=<acl|cidr>] [mac=<mac>]... [edns=<code>:<value>]... [audit=yes|no]
+//     profile <name> [audit=yes|no]
+//     sortlist <cidr>...
+//     filter-aaaa <cidr>|all...
+//     forward <addr[:port]>... [strategy=<s>] [retries=<n>] [proxy=socks5://...|source=<ip>] [policy=<name>] [cross-check=strict|flag]
+//     forward-zone <domain> <addr[:port]>... [strategy=<s>] [retries=<n>] [proxy=socks5://...|source=<ip>] [policy=<name>] [cross-check=strict|flag]
+//     resolv-conf <path>|none
+//     mirror <addr[:port]> [sample=<fraction>]
+//     block <domain|wildcard|/regex/>...
+//     rewrite <domain|wildcard|/regex/> <address>...|<name>
+//     block-group <group> <domain|wildcard|/regex/>...
+//     block-group-apply <group> [clients=<acl>] [policy=<name>] [schedule=<name>]
+//     block-bypass <acl|cidr|policy=<name>> [block|<group>...]
+//     sinkhole <address>... [ports=<port>,...]
+//     threat-feed <name> http://<host>[:<port>]/<path>|<path> [category=<name>] [refresh=<time>] [action=block|alert]
+//     schedule <name> <days> <HH:MM-HH:MM>...
+//     timezone <POSIX TZ>
+//     zone <apex> [file=<path>]
+//     zone-store memory|sqlite <path>|postgres <conninfo>
+//     auto-reverse yes|no
+//     dhcp-leases <path> <domain>
+//     record <name> <type> <ttl> <rdata...> [weight=<n>]
+//     query-source <ip>...
+//     udp-io plain|mmsg|io-uring
+//     min-ttl <seconds>
+//     max-ttl <seconds>
+//     override-ttl <domain> <seconds>
+//     ttl-jitter <percent>
+//     rrset-order as-received|sorted|random
+//     cache-redis redis://[[<user>]:<password>@]<host>[:<port>][/<db>] [prefix=<text>]
+//     max-response-size udp|tcp <bytes>
+//     client-edns-size cap|honor
+//     oversize truncate|trim
+//     connection-limits [max=<n>] [per-client=<n>] [idle=<time>] [io=<time>]
+//     chaos-version <text>
+//     chaos-id <text>
+//     ddr <target> alpn=<id>[,<id>...] [<svc-param>...]
+//     api-listen <addr:port>
+//     api-token <token>
+//     otlp-endpoint http://<host>[:<port>][/<path>] [service=<name>] [sample=<fraction>]
+//     ha-primary http://<host>:<port> [interval=<time>] [failures=<n>] [replicate=records|cache|all]
+//         [token=<token>] [hook=<path>]
+//     log-level info|debug|trace
+//     audit-log <path>
+//     access-log <path>
+//     anonymize-clients truncate [ipv4-prefix=<len>] [ipv6-prefix=<len>]
+//     anonymize-clients hmac [rotate=<time>]
+//     hash-query-names [salt=<text>]
+//     log-retention [rotate=<time>] [max-size=<size>] [keep=<time>]
+//     user <name|uid>
+//     group <name|gid>
+//     allow-root yes|no
+//     chroot <dir>
+//     seccomp yes|no
+pub struct Config {
+    // empty means Listener::fallback()
+    pub listeners: Vec<Listener>,
+    pub forwarders: Vec<ForwardRule>,
+    // where to find upstreams when forwarding without any configured
+    pub resolv_conf: Option<PathBuf>,
+    // a server the queries are copied to, whose answers are only logged
+    pub mirror: Option<MirrorConfig>,
+    // default source addresses for upstream UDP queries, one per family
+    pub query_sources: Vec<IpAddr>,
+    // how the listeners and upstream sockets read and write UDP
+    pub udp_io: UdpIo,
+    pub blocklist: Blocklist,
+    // malware and phishing domain lists, answered apart from the blocklist
+    pub threat_feeds: Vec<FeedConfig>,
+    // where blocked names point instead of NXDOMAIN, with a listener there
+    pub sinkhole: Option<SinkholeConfig>,
+    // named time windows for the directives that take schedule=<name>
+    pub schedules: HashMap<String, Schedule>,
+    // what schedules are in, the system's time zone when None
+    pub time_zone: Option<TimeZone>,
+    // None leaves recursion open to every client
+    pub allow_recursion: Option<Acl>,
+    // named client groups for the directives that take clients=<acl>
+    pub acls: HashMap<String, Acl>,
+    pub qtype_policy: QtypePolicy,
+    // query rate limits per client address, the first matching one applies
+    pub client_quotas: Vec<QuotaRule>,
+    // groups of clients told apart by address or EDNS option
+    pub client_policies: ClientPolicies,
+    // preferred networks for ordering addresses in answers
+    pub sortlist: Vec<Cidr>,
+    // clients that get no AAAA records for names with A records
+    pub filter_aaaa: Acl,
+    pub zones: LocalZones,
+    // where the zones are kept
+    pub zone_store: StoreLocation,
+    // zones read from files, which are reloaded when the files change
+    pub zone_files: Vec<WatchedZone>,
+    // TTL bounds and overrides for cached answers
+    pub ttl_policy: TtlPolicy,
+    // the order of the records within each RRset of an answer
+    pub rrset_order: RrsetOrder,
+    // the Redis server of a cache shared with other servers
+    pub cache_redis: Option<RedisConfig>,
+    // how large responses may be and what happens to those that are larger
+    pub size_policy: SizePolicy,
+    // how many TCP and HTTP connections are served and for how long
+    pub connection_policy: ConnectionPolicy,
+    // serve PTR records for local A/AAAA records
+    pub auto_reverse: bool,
+    // lease files whose hosts are served under their domain
+    pub dhcp_leases: Vec<LeaseFile>,
+    // answers to CHAOS version.bind / id.server queries, refused when unset
+    pub chaos_version: Option<String>,
+    pub chaos_id: Option<String>,
+    // encrypted endpoints advertised at _dns.resolver.arpa, most preferred first
+    pub designations: Vec<Designation>,
+    // management API, off unless both are set
+    pub api_listen: Option<SocketAddr>,
+    pub api_token: Option<String>,
+    // OpenTelemetry export, off when None
+    pub otlp: Option<OtlpConfig>,
+    // the primary this server stands by for, None when it serves on its own
+    pub ha_primary: Option<HaConfig>,
+    // trace also dumps every packet in and out
+    pub log_level: LogLevel,
+    // JSON lines of the queries blocked, rewritten or rate limited
+    pub audit_log: Option<PathBuf>,
+    // a line per http-listen request, in the Combined Log Format
+    pub access_log: Option<PathBuf>,
+    // how client addresses are written down, as they are when None
+    pub anonymize_clients: Option<AnonymizeConfig>,
+    // query names written down as salted hashes, as they are when None
+    pub hash_query_names: Option<NameHashConfig>,
+    // rotation and expiry of the logs, which grow for as long as the server
+    // runs when None
+    pub log_retention: Option<RetentionConfig>,
+    // who to run as once the sockets are bound; as root only if allow_root
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub allow_root: bool,
+    // sandboxing, applied after the sockets are bound
+    pub chroot: Option<PathBuf>,
+    pub seccomp: bool,
+}
+
+// Stages a listener runs queries through when none are given.
+pub const DEFAULT_STAGES: [&str; 9] = [
+    "sortlist",
+    "threats",
+    "blocklist",
+    "filter-aaaa",
+    "local",
+    "special",
+    "cache",
+    "forward",
+    "recursor",
+];
+
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub stages: Vec<String>,
+    // serves DoH and the DNS JSON API over HTTP instead of DNS over UDP and TCP
+    pub http: bool,
+    // the client policy every query to it gets, whoever sends it
+    pub profile: Option<String>,
+    // for http listeners, the paths it answers on besides the default ones
+    pub routes: Vec<HttpRoute>,
+}
+
+impl Listener {
+    // Used when the config has no listen directive.
+    pub fn fallback() -> Listener {
+        Listener {
+            addr: "0.0.0.0:6969".parse().unwrap(),
+            stages: DEFAULT_STAGES
+                .iter()
+                .map(|stage| stage.to_string())
+                .collect(),
+            http: false,
+            profile: None,
+            routes: Vec::new(),
+        }
+    }
+}
+
+// Parses "1.1.1.1", "1.1.1.1:5353", "2606:4700::1111" or "[::1]:5353".
+pub fn parse_server_addr(text: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(addr) = text.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    text.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| format!("bad server address {:?}", text))
+}
+
+// A number of bytes, with an optional k, m or g suffix for KiB, MiB or GiB.
+fn parse_size(text: &str) -> Option<u64> {
+    let (digits, unit) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1 << 10),
+        (i, 'm' | 'M') => (&text[..i], 1 << 20),
+        (i, 'g' | 'G') => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            listeners: Vec::new(),
+            forwarders: Vec::new(),
+            resolv_conf: Some(PathBuf::from(resolvconf::DEFAULT_PATH)),
+            mirror: None,
+            query_sources: Vec::new(),
+            udp_io: UdpIo::preferred(),
+            blocklist: Blocklist::new(),
+            threat_feeds: Vec::new(),
+            sinkhole: None,
+            schedules: HashMap::new(),
+            time_zone: None,
+            allow_recursion: None,
+            acls: HashMap::new(),
+            qtype_policy: QtypePolicy::new(),
+            client_quotas: Vec::new(),
+            client_policies: ClientPolicies::new(),
+            sortlist: Vec::new(),
+            filter_aaaa: Acl::new(),
+            zones: LocalZones::new(),
+            zone_store: StoreLocation::Memory,
+            zone_files: Vec::new(),
+            ttl_policy: TtlPolicy::new(),
+            rrset_order: RrsetOrder::AsReceived,
+            cache_redis: None,
+            size_policy: SizePolicy::new(),
+            connection_policy: ConnectionPolicy::new(),
+            auto_reverse: false,
+            dhcp_leases: Vec::new(),
+            chaos_version: None,
+            chaos_id: None,
+            designations: Vec::new(),
+            api_listen: None,
+            api_token: None,
+            otlp: None,
+            ha_primary: None,
+            audit_log: None,
+            access_log: None,
+            anonymize_clients: None,
+            hash_query_names: None,
+            log_retention: None,
+            log_level: LogLevel::Info,
+            user: None,
+            group: None,
+            allow_root: false,
+            chroot: None,
+            seccomp: false,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (config, errors) = Config::parse(&text);
+        match errors.into_iter().next() {
+            Some((line, e)) => Err(located(path, line, &e)),
+            None => Ok(config),
+        }
+    }
+
+    // Everything wrong with the config file, not just the first problem, and
+    // also what only shows up once the server starts: listen addresses used
+    // twice, zones inside other zones, users and directories that don't
+    // exist. In line order, each with its line where it has one.
+    pub fn check(path: &str) -> Vec<String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return vec![format!("{}: {}", path, e)],
+        };
+        let (config, mut errors) = Config::parse(&text);
+
+        let mut listens: Vec<(SocketAddr, usize)> = Vec::new();
+        let mut zones: Vec<(Name, usize)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let tokens: Vec<&str> = line
+                .split('#')
+                .next()
+                .unwrap_or("")
+                .split_whitespace()
+                .collect();
+            match tokens.as_slice() {
+                ["listen" | "http-listen" | "api-listen", addr, ..] => {
+                    let Ok(addr) = addr.parse::<SocketAddr>() else {
+                        continue;
+                    };
+                    if let Some((_, first)) = listens.iter().find(|(known, _)| *known == addr) {
+                        errors.push((
+                            Some(line_no),
+                            format!("{} is already listened on at line {}", addr, first),
+                        ));
+                    }
+                    listens.push((addr, line_no));
+                }
+                ["zone", apex, ..] => {
+                    let Ok(apex) = Name::from_unicode(apex) else {
+                        continue;
+                    };
+                    for (other, other_line) in zones.iter() {
+                        let problem = if apex == *other {
+                            "duplicates"
+                        } else if apex.is_subdomain_of(other) || other.is_subdomain_of(&apex) {
+                            "overlaps"
+                        } else {
+                            continue;
+                        };
+                        errors.push((
+                            Some(line_no),
+                            format!(
+                                "zone {} {} zone {} at line {}",
+                                apex, problem, other, other_line
+                            ),
+                        ));
+                    }
+                    zones.push((apex, line_no));
+                }
+                ["chroot", dir] if !Path::new(dir).is_dir() => {
+                    errors.push((Some(line_no), format!("chroot {} is not a directory", dir)));
+                }
+                ["audit-log" | "access-log", path] | ["zone-store", "sqlite", path] => {
+                    let dir = Path::new(path)
+                        .parent()
+                        .filter(|dir| !dir.as_os_str().is_empty())
+                        .unwrap_or(Path::new("."));
+                    if !dir.is_dir() {
+                        errors.push((
+                            Some(line_no),
+                            format!("{} directory {} doesn't exist", tokens[0], dir.display()),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(user) = &config.user
+            && let Err(e) = privileges::lookup_ids(user, config.group.as_deref())
+        {
+            errors.push((None, e));
+        }
+
+        // problems of the file as a whole go last
+        errors.sort_by_key(|(line, _)| line.unwrap_or(usize::MAX));
+        errors
+            .iter()
+            .map(|(line, e)| located(path, *line, e))
+            .collect()
+    }
+
+    // The config in text, with every problem found on the way and the line
+    // it is on. Lines that fail are skipped, so the config is only usable if
+    // there are none.
+    fn parse(text: &str) -> (Config, Vec<(Option<usize>, String)>) {
+        let mut config = Config::new();
+        let mut errors = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            if let Err(e) = config.parse_line(line) {
+                errors.push((Some(i + 1), e));
+            }
+        }
+
+        for rule in config.forwarders.iter() {
+            if rule.cross_check.is_some() && rule.servers.len() < 2 {
+                errors.push((
+                    None,
+                    format!(
+                        "cross-check for {} needs two upstreams at least",
+                        rule.domain.to_fqdn()
+                    ),
+                ));
+            }
+        }
+        if config.ttl_policy.min_ttl > config.ttl_policy.max_ttl {
+            errors.push((None, "min-ttl is above max-ttl".to_string()));
+        }
+        if config.api_listen.is_some() && config.api_token.is_none() {
+            errors.push((None, "api-listen needs an api-token".to_string()));
+        }
+        if let Some(ha) = &mut config.ha_primary {
+            if ha.token.is_none() {
+                ha.token = config.api_token.clone();
+            }
+            if (ha.replicate_records || ha.replicate_cache) && ha.token.is_none() {
+                errors.push((
+                    None,
+                    "ha-primary replicate needs token= or an api-token".to_string(),
+                ));
+            }
+            if config.seccomp && ha.hook.is_some() {
+                errors.push((
+                    None,
+                    "ha-primary hook can't be run with seccomp".to_string(),
+                ));
+            }
+        }
+        // the filter doesn't let files be opened
+        if config.seccomp && !config.dhcp_leases.is_empty() {
+            errors.push((None, "dhcp-leases can't be read with seccomp".to_string()));
+        }
+        if config.seccomp
+            && config
+                .threat_feeds
+                .iter()
+                .any(|feed| matches!(feed.source, FeedSource::File(_)))
+        {
+            errors.push((
+                None,
+                "threat-feed files can't be read with seccomp".to_string(),
+            ));
+        }
+        if config.seccomp && config.log_retention.is_some() {
+            errors.push((
+                None,
+                "log-retention can't rotate logs with seccomp".to_string(),
+            ));
+        }
+        if config.seccomp && config.zone_store != StoreLocation::Memory {
+            errors.push((None, "zone-store can't be used with seccomp".to_string()));
+        }
+        if let Err(e) = config.zones.set_auto_reverse(config.auto_reverse) {
+            errors.push((None, e));
+        }
+
+        (config, errors)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = strip_comment(line);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        let Some((&directive, args)) = tokens.split_first() else {
+            return Ok(());
+        };
+
+        match directive {
+            "listen" | "http-listen" => {
+                let Some((addr, mut stages)) = args.split_first() else {
+                    return Err(format!(
+                        "usage: {} <addr:port> [profile=<name>] [<stage>...]",
+                        directive
+                    ));
+                };
+                let addr = addr
+                    .parse()
+                    .map_err(|e| format!("bad {} address {:?}: {}", directive, addr, e))?;
+                let mut profile = None;
+                if let Some((first, rest)) = stages.split_first()
+                    && let Some(name) = first.strip_prefix("profile=")
+                {
+                    profile = Some(self.policy_name(name)?);
+                    stages = rest;
+                }
+
+                let stages: Vec<String> = if stages.is_empty() {
+                    DEFAULT_STAGES
+                        .iter()
+                        .map(|stage| stage.to_string())
+                        .collect()
+                } else {
+                    stages.iter().map(|stage| stage.to_string()).collect()
+                };
+                if let Some(stage) = stages
+                    .iter()
+                    .find(|stage| !DEFAULT_STAGES.contains(&stage.as_str()))
+                {
+                    return Err(format!("unknown stage {:?}", stage));
+                }
+
+                self.listeners.push(Listener {
+                    addr,
+                    stages,
+                    http: directive == "http-listen",
+                    profile,
+                    routes: Vec::new(),
+                });
+            }
+            "http-route" => {
+                let [addr, spec, options @ ..] = args else {
+                    return Err(
+                        "usage: http-route <addr:port> [<host>]/<path> [profile=<name>]"
+                            .to_string(),
+                    );
+                };
+                let addr: SocketAddr = addr
+                    .parse()
+                    .map_err(|e| format!("bad http-route address {:?}: {}", addr, e))?;
+                let mut route = HttpRoute::parse(spec)?;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("profile", name)) => route.profile = Some(self.policy_name(name)?),
+                        _ => return Err(format!("unknown http-route option {:?}", option)),
+                    }
+                }
+                let listener = self
+                    .listeners
+                    .iter_mut()
+                    .find(|listener| listener.http && listener.addr == addr)
+                    .ok_or_else(|| format!("no http-listen {} above", addr))?;
+                if listener
+                    .routes
+                    .iter()
+                    .any(|known| known.host == route.host && known.path == route.path)
+                {
+                    return Err(format!("http-route {} {} given twice", addr, spec));
+                }
+                listener.routes.push(route);
+            }
+            "forward" => {
+                if args.is_empty() {
+                    return Err(
+                        "usage: forward <addr[:port]>... [strategy=<s>] [proxy=socks5://...|source=<ip>]"
+                            .to_string(),
+                    );
+                }
+                self.parse_forward(Name::root(), args)?;
+            }
+            "resolv-conf" => {
+                self.resolv_conf = match args {
+                    ["none"] => None,
+                    [path] => Some(PathBuf::from(path)),
+                    _ => return Err("usage: resolv-conf <path>|none".to_string()),
+                };
+            }
+            "mirror" => {
+                let [server, options @ ..] = args else {
+                    return Err("usage: mirror <addr[:port]> [sample=<fraction>]".to_string());
+                };
+                let mut mirror = MirrorConfig {
+                    server: parse_server_addr(server, 53)?,
+                    sample: 1.0,
+                };
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("sample", fraction)) => {
+                            mirror.sample = fraction
+                                .parse::<f64>()
+                                .ok()
+                                .filter(|fraction| (0.0..=1.0).contains(fraction))
+                                .ok_or_else(|| format!("bad sample fraction {:?}", fraction))?;
+                        }
+                        _ => return Err(format!("unknown mirror option {:?}", option)),
+                    }
+                }
+                self.mirror = Some(mirror);
+            }
+            "forward-zone" => {
+                let Some((domain, servers)) = args.split_first().filter(|(_, s)| !s.is_empty())
+                else {
+                    return Err(
+                        "usage: forward-zone <domain> <addr[:port]>... [strategy=<s>] [proxy=socks5://...|source=<ip>]"
+                            .to_string(),
+                    );
+                };
+                self.parse_forward(Name::from_unicode(domain)?, servers)?;
+            }
+            "query-source" => {
+                if args.is_empty() {
+                    return Err("usage: query-source <ip>...".to_string());
+                }
+                for arg in args {
+                    let source = arg
+                        .parse::<IpAddr>()
+                        .map_err(|e| format!("bad source address {:?}: {}", arg, e))?;
+                    if self
+                        .query_sources
+                        .iter()
+                        .any(|known| known.is_ipv4() == source.is_ipv4())
+                    {
+                        return Err("one query-source per address family".to_string());
+                    }
+                    self.query_sources.push(source);
+                }
+            }
+            "block" => {
+                if args.is_empty() {
+                    return Err("usage: block <domain|wildcard|/regex/>...".to_string());
+                }
+                for arg in args {
+                    match Pattern::parse(arg)? {
+                        Pattern::Domain(domain) => {
+                            self.blocklist.add(domain);
+                        }
+                        pattern => self.blocklist.add_pattern(pattern),
+                    }
+                }
+            }
+            "rewrite" => {
+                let usage = "usage: rewrite <domain|wildcard|/regex/> <address>...|<name>";
+                let [pattern, targets @ ..] = args else {
+                    return Err(usage.to_string());
+                };
+                let pattern = Pattern::parse(pattern)?;
+                let addrs: Result<Vec<IpAddr>, _> =
+                    targets.iter().map(|target| target.parse()).collect();
+                let rewrite = match (addrs, targets) {
+                    (Ok(addrs), _) if !addrs.is_empty() => Rewrite::Addresses(addrs),
+                    (_, [target]) => Rewrite::Alias(Name::from_unicode(target)?),
+                    _ => return Err(usage.to_string()),
+                };
+                self.blocklist.add_rewrite(pattern, rewrite);
+            }
+            "block-group" => {
+                let [group, domains @ ..] = args else {
+                    return Err(
+                        "usage: block-group <group> <domain|wildcard|/regex/>...".to_string()
+                    );
+                };
+                if domains.is_empty() {
+                    return Err(
+                        "usage: block-group <group> <domain|wildcard|/regex/>...".to_string()
+                    );
+                }
+                if *group == BLOCK_LIST {
+                    return Err(format!(
+                        "{:?} is the block lines' name, not a group's",
+                        group
+                    ));
+                }
+                for domain in domains {
+                    self.blocklist.add_to_group(group, Pattern::parse(domain)?);
+                }
+            }
+            "block-bypass" => {
+                let [clients, lists @ ..] = args else {
+                    return Err(
+                        "usage: block-bypass <acl|cidr|policy=<name>> [block|<group>...]"
+                            .to_string(),
+                    );
+                };
+                let (acl, policy) = match clients.strip_prefix("policy=") {
+                    Some(name) => (None, Some(self.policy_name(name)?)),
+                    None => (Some(self.acl_or_cidr(clients)?), None),
+                };
+                self.blocklist.add_bypass(Bypass {
+                    label: clients.to_string(),
+                    clients: acl,
+                    policy,
+                    lists: lists.iter().map(|list| list.to_string()).collect(),
+                })?;
+            }
+            "sinkhole" => {
+                let usage = "usage: sinkhole <address>... [ports=<port>,...]";
+                let mut sinkhole = SinkholeConfig {
+                    addrs: Vec::new(),
+                    ports: vec![80, 443],
+                };
+                for arg in args {
+                    if let Some(ports) = arg.strip_prefix("ports=") {
+                        sinkhole.ports = ports
+                            .split(',')
+                            .map(|port| port.parse::<u16>().ok().filter(|port| *port > 0))
+                            .collect::<Option<Vec<u16>>>()
+                            .ok_or_else(|| format!("bad {}", arg))?;
+                        continue;
+                    }
+                    let addr = arg
+                        .parse::<IpAddr>()
+                        .map_err(|e| format!("bad sinkhole address {:?}: {}", arg, e))?;
+                    if sinkhole
+                        .addrs
+                        .iter()
+                        .any(|known| known.is_ipv4() == addr.is_ipv4())
+                    {
+                        return Err("one sinkhole address per address family".to_string());
+                    }
+                    sinkhole.addrs.push(addr);
+                }
+                if sinkhole.addrs.is_empty() {
+                    return Err(usage.to_string());
+                }
+                self.sinkhole = Some(sinkhole);
+            }
+            "threat-feed" => {
+                let [name, location, options @ ..] = args else {
+                    return Err("usage: threat-feed <name> http://<host>[:<port>]/<path>|<path> [category=<name>] [refresh=<time>] [action=block|alert]".to_string());
+                };
+                if self.threat_feeds.iter().any(|feed| feed.name == *name) {
+                    return Err(format!("threat-feed {} given twice", name));
+                }
+                let mut feed = FeedConfig::parse(name, location)?;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("category", category)) if !category.is_empty() => {
+                            feed.category = category.to_string();
+                        }
+                        Some(("refresh", time)) => {
+                            feed.refresh = parse_ttl(time)
+                                .ok()
+                                .filter(|seconds| *seconds > 0)
+                                .map(|seconds| Duration::from_secs(seconds as u64))
+                                .ok_or_else(|| format!("bad {}", option))?;
+                        }
+                        Some(("action", "block")) => feed.action = ThreatAction::Block,
+                        Some(("action", "alert")) => feed.action = ThreatAction::Alert,
+                        _ => return Err(format!("unknown threat-feed option {:?}", option)),
+                    }
+                }
+                self.threat_feeds.push(feed);
+            }
+            "block-group-apply" => {
+                let [group, options @ ..] = args else {
+                    return Err(
+                        "usage: block-group-apply <group> [clients=<acl>] [policy=<name>] [schedule=<name>]"
+                            .to_string(),
+                    );
+                };
+                let mut rule = GroupRule {
+                    clients: None,
+                    policy: None,
+                    schedule: None,
+                };
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("clients", name)) => {
+                            let acl = self
+                                .acls
+                                .get(name)
+                                .ok_or_else(|| format!("unknown acl {:?}", name))?;
+                            rule.clients = Some(acl.clone());
+                        }
+                        Some(("policy", name)) => rule.policy = Some(self.policy_name(name)?),
+                        Some(("schedule", name)) => {
+                            let schedule = self
+                                .schedules
+                                .get(name)
+                                .ok_or_else(|| format!("unknown schedule {:?}", name))?;
+                            rule.schedule = Some(schedule.clone());
+                        }
+                        _ => return Err(format!("unknown block-group-apply option {:?}", option)),
+                    }
+                }
+                self.blocklist.add_group_rule(group, rule)?;
+            }
+            "schedule" => {
+                let [name, days, times @ ..] = args else {
+                    return Err("usage: schedule <name> <days> <HH:MM-HH:MM>...".to_string());
+                };
+                if times.is_empty() {
+                    return Err("usage: schedule <name> <days> <HH:MM-HH:MM>...".to_string());
+                }
+                self.schedules
+                    .entry(name.to_string())
+                    .or_insert_with(Schedule::new)
+                    .add(days, times)?;
+            }
+            "timezone" => {
+                let [zone] = args else {
+                    return Err("usage: timezone <POSIX TZ>".to_string());
+                };
+                self.time_zone = Some(TimeZone::parse(zone)?);
+            }
+            "acl" => {
+                let [name, cidrs @ ..] = args else {
+                    return Err("usage: acl <name> <cidr>...".to_string());
+                };
+                if cidrs.is_empty() {
+                    return Err("usage: acl <name> <cidr>...".to_string());
+                }
+                let acl = self.acls.entry(name.to_string()).or_default();
+                for cidr in cidrs {
+                    acl.add(Cidr::parse(cidr)?);
+                }
+            }
+            "qtype-policy" => self.parse_qtype_policy(args)?,
+            "client-quota" => self.parse_client_quota(args)?,
+            "client-policy" | "profile" => self.parse_client_policy(directive, args)?,
+            "allow-recursion" => {
+                if args.is_empty() {
+                    return Err("usage: allow-recursion <cidr>...".to_string());
+                }
+                let acl = self.allow_recursion.get_or_insert_with(Acl::new);
+                for arg in args {
+                    acl.add(Cidr::parse(arg)?);
+                }
+            }
+            "filter-aaaa" => {
+                if args.is_empty() {
+                    return Err("usage: filter-aaaa <cidr>|all...".to_string());
+                }
+                for arg in args {
+                    if *arg == "all" {
+                        self.filter_aaaa.add(Cidr::parse("0.0.0.0/0")?);
+                        self.filter_aaaa.add(Cidr::parse("::/0")?);
+                    } else {
+                        self.filter_aaaa.add(Cidr::parse(arg)?);
+                    }
+                }
+            }
+            "sortlist" => {
+                if args.is_empty() {
+                    return Err("usage: sortlist <cidr>...".to_string());
+                }
+                for arg in args {
+                    self.sortlist.push(Cidr::parse(arg)?);
+                }
+            }
+            "zone" => {
+                let usage = || "usage: zone <apex> [file=<path>]".to_string();
+                let (apex, path) = match args {
+                    [apex] => (apex, None),
+                    [apex, file] => (apex, Some(file.strip_prefix("file=").ok_or_else(usage)?)),
+                    _ => return Err(usage()),
+                };
+                let apex = Name::from_unicode(apex)?;
+                self.zones.add_zone(apex.clone())?;
+                if let Some(path) = path {
+                    let zone = WatchedZone::load(apex, Path::new(path))?;
+                    for record in zone.records() {
+                        self.zones.add(record.clone(), None)?;
+                    }
+                    self.zone_files.push(zone);
+                }
+            }
+            "min-ttl" | "max-ttl" => {
+                let [seconds] = args else {
+                    return Err(format!("usage: {} <seconds>", directive));
+                };
+                let seconds = seconds
+                    .parse::<u32>()
+                    .map_err(|e| format!("bad {} {:?}: {}", directive, seconds, e))?;
+                if directive == "min-ttl" {
+                    self.ttl_policy.min_ttl = seconds;
+                } else {
+                    self.ttl_policy.max_ttl = seconds;
+                }
+            }
+            "override-ttl" => {
+                let [domain, seconds] = args else {
+                    return Err("usage: override-ttl <domain> <seconds>".to_string());
+                };
+                let seconds = seconds
+                    .parse::<u32>()
+                    .map_err(|e| format!("bad override-ttl {:?}: {}", seconds, e))?;
+                self.ttl_policy
+                    .overrides
+                    .push((Name::from_unicode(domain)?, seconds));
+            }
+            "ttl-jitter" => {
+                let [percent] = args else {
+                    return Err("usage: ttl-jitter <percent>".to_string());
+                };
+                let percent = percent
+                    .trim_end_matches('%')
+                    .parse::<u32>()
+                    .map_err(|e| format!("bad ttl-jitter {:?}: {}", percent, e))?;
+                if percent > MAX_JITTER {
+                    return Err(format!("ttl-jitter is at most {}%", MAX_JITTER));
+                }
+                self.ttl_policy.jitter = percent;
+            }
+            "rrset-order" => {
+                let [order] = args else {
+                    return Err("usage: rrset-order as-received|sorted|random".to_string());
+                };
+                self.rrset_order = RrsetOrder::from_name(order)
+                    .ok_or_else(|| format!("unknown rrset-order {:?}", order))?;
+            }
+            "cache-redis" => {
+                let usage = "usage: cache-redis redis://[[<user>]:<password>@]<host>[:<port>][/<db>] [prefix=<text>]";
+                let [url, options @ ..] = args else {
+                    return Err(usage.to_string());
+                };
+                let mut redis = RedisConfig::parse(url)?;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("prefix", "")) => return Err("prefix can't be empty".to_string()),
+                        Some(("prefix", prefix)) => redis.prefix = prefix.to_string(),
+                        _ => return Err(format!("unknown cache-redis option {:?}", option)),
+                    }
+                }
+                self.cache_redis = Some(redis);
+            }
+            "max-response-size" => {
+                let [transport, bytes] = args else {
+                    return Err("usage: max-response-size udp|tcp <bytes>".to_string());
+                };
+                let bytes = bytes
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|bytes| *bytes as usize >= UDP_MESSAGE_SIZE)
+                    .ok_or_else(|| format!("bad max-response-size {:?}: 512 to 65535", bytes))?;
+                match *transport {
+                    "udp" => self.size_policy.udp_max = bytes,
+                    "tcp" => self.size_policy.tcp_max = bytes,
+                    _ => return Err(format!("unknown transport {:?}", transport)),
+                }
+            }
+            "client-edns-size" => {
+                self.size_policy.honor_client = match args {
+                    ["cap"] => false,
+                    ["honor"] => true,
+                    _ => return Err("usage: client-edns-size cap|honor".to_string()),
+                };
+            }
+            "oversize" => {
+                let [name] = args else {
+                    return Err("usage: oversize truncate|trim".to_string());
+                };
+                self.size_policy.oversize = Oversize::from_name(name)
+                    .ok_or_else(|| format!("unknown oversize {:?}", name))?;
+            }
+            "connection-limits" => {
+                if args.is_empty() {
+                    return Err("usage: connection-limits [max=<n>] [per-client=<n>] [idle=<time>] [io=<time>]".to_string());
+                }
+                let policy = &mut self.connection_policy;
+                for option in args {
+                    let count = |count: &str| {
+                        count
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or_else(|| format!("bad {}", option))
+                    };
+                    let time = |time: &str| {
+                        parse_ttl(time)
+                            .ok()
+                            .filter(|seconds| *seconds > 0)
+                            .map(|seconds| Duration::from_secs(seconds as u64))
+                            .ok_or_else(|| format!("bad {}", option))
+                    };
+                    match option.split_once('=') {
+                        Some(("max", n)) => policy.max = count(n)?,
+                        Some(("per-client", n)) => policy.per_client = count(n)?,
+                        Some(("idle", t)) => policy.idle = time(t)?,
+                        Some(("io", t)) => policy.io = time(t)?,
+                        _ => return Err(format!("unknown connection-limits option {:?}", option)),
+                    }
+                }
+            }
+            "zone-store" => {
+                self.zone_store = match args {
+                    ["memory"] => StoreLocation::Memory,
+                    ["sqlite", path] if cfg!(all(unix, feature = "sqlite")) => {
+                        StoreLocation::Sqlite(PathBuf::from(path))
+                    }
+                    ["postgres", conninfo @ ..]
+                        if !conninfo.is_empty() && cfg!(all(unix, feature = "postgres")) =>
+                    {
+                        StoreLocation::Postgres(conninfo.join(" "))
+                    }
+                    [store @ "sqlite", _] | [store @ "postgres", _, ..] => {
+                        return Err(format!(
+                            "zone-store {} needs a build with the {} feature",
+                            store, store
+                        ));
+                    }
+                    _ => {
+                        return Err("usage: zone-store memory|sqlite <path>|postgres <conninfo>"
+                            .to_string());
+                    }
+                };
+            }
+            "auto-reverse" => {
+                self.auto_reverse = match args {
+                    ["yes"] => true,
+                    ["no"] => false,
+                    _ => return Err("usage: auto-reverse yes|no".to_string()),
+                };
+            }
+            "record" => {
+                // quoted TXT strings keep their blanks
+                let words = zonefile::words(&line.trim_start()[directive.len()..])?;
+                let words: Vec<&str> = words.iter().map(String::as_str).collect();
+                self.parse_record(&words)?
+            }
+            "udp-io" => {
+                let [name] = args else {
+                    return Err("usage: udp-io plain|mmsg|io-uring".to_string());
+                };
+                self.udp_io =
+                    UdpIo::from_name(name).ok_or_else(|| format!("unknown udp-io {:?}", name))?;
+            }
+            "chaos-version" | "chaos-id" => {
+                if args.is_empty() {
+                    return Err(format!("usage: {} <text>", directive));
+                }
+                let text = Some(args.join(" "));
+                if directive == "chaos-version" {
+                    self.chaos_version = text;
+                } else {
+                    self.chaos_id = text;
+                }
+            }
+            "ddr" => self.parse_ddr(args)?,
+            "user" | "group" => {
+                let [name] = args else {
+                    return Err(format!("usage: {} <name|id>", directive));
+                };
+                if directive == "user" {
+                    self.user = Some(name.to_string());
+                } else {
+                    self.group = Some(name.to_string());
+                }
+            }
+            "allow-root" => {
+                self.allow_root = match args {
+                    ["yes"] => true,
+                    ["no"] => false,
+                    _ => return Err("usage: allow-root yes|no".to_string()),
+                };
+            }
+            "chroot" => {
+                let [dir] = args else {
+                    return Err("usage: chroot <dir>".to_string());
+                };
+                self.chroot = Some(PathBuf::from(dir));
+            }
+            "dhcp-leases" => {
+                let [path, domain] = args else {
+                    return Err("usage: dhcp-leases <path> <domain>".to_string());
+                };
+                self.dhcp_leases.push(LeaseFile {
+                    path: PathBuf::from(path),
+                    domain: Name::from_unicode(domain)?,
+                });
+            }
+            "seccomp" => {
+                self.seccomp = match args {
+                    ["yes"] => true,
+                    ["no"] => false,
+                    _ => return Err("usage: seccomp yes|no".to_string()),
+                };
+            }
+            "api-listen" => {
+                let [addr] = args else {
+                    return Err("usage: api-listen <addr:port>".to_string());
+                };
+                self.api_listen = Some(
+                    addr.parse()
+                        .map_err(|e| format!("bad api-listen address {:?}: {}", addr, e))?,
+                );
+            }
+            "api-token" => {
+                let [token] = args else {
+                    return Err("usage: api-token <token>".to_string());
+                };
+                self.api_token = Some(token.to_string());
+            }
+            "log-level" => {
+                let [level] = args else {
+                    return Err("usage: log-level info|debug|trace".to_string());
+                };
+                self.log_level = LogLevel::parse(level)?;
+            }
+            "audit-log" => {
+                let [path] = args else {
+                    return Err("usage: audit-log <path>".to_string());
+                };
+                self.audit_log = Some(PathBuf::from(path));
+            }
+            "access-log" => {
+                let [path] = args else {
+                    return Err("usage: access-log <path>".to_string());
+                };
+                self.access_log = Some(PathBuf::from(path));
+            }
+            "anonymize-clients" => self.parse_anonymize_clients(args)?,
+            "log-retention" => {
+                let mut retention = RetentionConfig::new();
+                for option in args {
+                    let time = |time: &str| {
+                        parse_ttl(time)
+                            .ok()
+                            .filter(|seconds| *seconds > 0)
+                            .map(|seconds| Duration::from_secs(seconds as u64))
+                            .ok_or_else(|| format!("bad {}", option))
+                    };
+                    match option.split_once('=') {
+                        Some(("rotate", value)) => retention.rotate = time(value)?,
+                        Some(("keep", value)) => retention.keep = time(value)?,
+                        Some(("max-size", value)) => {
+                            retention.max_size = Some(
+                                parse_size(value)
+                                    .filter(|size| *size > 0)
+                                    .ok_or_else(|| format!("bad {}", option))?,
+                            )
+                        }
+                        _ => return Err(format!("unknown log-retention option {:?}", option)),
+                    }
+                }
+                self.log_retention = Some(retention);
+            }
+            "hash-query-names" => {
+                let salt = match args {
+                    [] => None,
+                    [option] => match option.split_once('=') {
+                        Some(("salt", salt)) if !salt.is_empty() => Some(salt.to_string()),
+                        _ => return Err(format!("unknown hash-query-names option {:?}", option)),
+                    },
+                    _ => return Err("usage: hash-query-names [salt=<text>]".to_string()),
+                };
+                self.hash_query_names = Some(NameHashConfig { salt });
+            }
+            "otlp-endpoint" => {
+                let usage = "usage: otlp-endpoint http://<host>[:<port>][/<path>] [service=<name>] [sample=<fraction>]";
+                let [url, options @ ..] = args else {
+                    return Err(usage.to_string());
+                };
+                let mut otlp = OtlpConfig::parse(url)?;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("service", name)) if !name.is_empty() => {
+                            otlp.service = name.to_string();
+                        }
+                        Some(("sample", fraction)) => {
+                            otlp.sample = fraction
+                                .parse::<f64>()
+                                .ok()
+                                .filter(|fraction| (0.0..=1.0).contains(fraction))
+                                .ok_or_else(|| format!("bad sample fraction {:?}", fraction))?;
+                        }
+                        _ => return Err(format!("unknown otlp-endpoint option {:?}", option)),
+                    }
+                }
+                self.otlp = Some(otlp);
+            }
+            "ha-primary" => {
+                let usage = "usage: ha-primary http://<host>:<port> [interval=<time>] [failures=<n>] [replicate=records|cache|all] [token=<token>] [hook=<path>]";
+                let [url, options @ ..] = args else {
+                    return Err(usage.to_string());
+                };
+                let mut ha = HaConfig::parse(url)?;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("interval", time)) => {
+                            ha.interval = parse_ttl(time)
+                                .ok()
+                                .filter(|seconds| *seconds > 0)
+                                .map(|seconds| Duration::from_secs(seconds as u64))
+                                .ok_or_else(|| format!("bad interval {:?}", time))?;
+                        }
+                        Some(("failures", count)) => {
+                            ha.failures = count
+                                .parse::<u32>()
+                                .ok()
+                                .filter(|count| *count > 0)
+                                .ok_or_else(|| format!("bad failures {:?}", count))?;
+                        }
+                        Some(("replicate", what)) => {
+                            (ha.replicate_records, ha.replicate_cache) = match what {
+                                "records" => (true, false),
+                                "cache" => (false, true),
+                                "all" => (true, true),
+                                _ => return Err(format!("unknown replicate {:?}", what)),
+                            };
+                        }
+                        Some(("token", token)) if !token.is_empty() => {
+                            ha.token = Some(token.to_string());
+                        }
+                        Some(("hook", path)) if !path.is_empty() => {
+                            ha.hook = Some(PathBuf::from(path));
+                        }
+                        _ => return Err(format!("unknown ha-primary option {:?}", option)),
+                    }
+                }
+                self.ha_primary = Some(ha);
+            }
+            _ => return Err(format!("unknown directive {:?}", directive)),
+        }
+
+        Ok(())
+    }
+
+    // Repeated lines for the same domain add servers to its rule. A proxy or
+    // source applies to the servers on its own line only.
+    fn parse_forward(&mut self, domain: Name, args: &[&str]) -> Result<(), String> {
+        let mut addrs = Vec::new();
+        let mut strategy = None;
+        let mut proxy = None;
+        let mut source = None;
+        let mut retries = None;
+        let mut policy = None;
+        let mut cross_check = None;
+
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("strategy", value)) => {
+                    strategy = Some(
+                        Strategy::from_name(value)
+                            .ok_or_else(|| format!("unknown strategy {:?}", value))?,
+                    );
+                }
+                Some(("proxy", value)) => proxy = Some(Socks5Proxy::parse(value)?),
+                Some(("source", value)) => {
+                    source = Some(
+                        value
+                            .parse::<IpAddr>()
+                            .map_err(|e| format!("bad source address {:?}: {}", value, e))?,
+                    );
+                }
+                Some(("retries", value)) => {
+                    retries = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|e| format!("bad retries {:?}: {}", value, e))?,
+                    );
+                }
+                Some(("policy", name)) => policy = Some(self.policy_name(name)?),
+                Some(("cross-check", value)) => {
+                    cross_check = Some(
+                        CrossCheck::from_name(value)
+                            .ok_or_else(|| format!("unknown cross-check mode {:?}", value))?,
+                    );
+                }
+                Some((key, _)) => return Err(format!("unknown forward option {:?}", key)),
+                None => addrs.push(parse_server_addr(arg, 53)?),
+            }
+        }
+        // std can't pick the local address of an outgoing TCP connection
+        if proxy.is_some() && source.is_some() {
+            return Err("source= can't be combined with proxy=".to_string());
+        }
+        if let Some(addr) = addrs
+            .iter()
+            .find(|addr| source.is_some_and(|source| source.is_ipv4() != addr.is_ipv4()))
+        {
+            return Err(format!("{} is not in the address family of source=", addr));
+        }
+
+        let servers = addrs.into_iter().map(|addr| Upstream {
+            addr,
+            proxy: proxy.clone(),
+            source,
+        });
+
+        let rule = match self
+            .forwarders
+            .iter_mut()
+            .find(|rule| rule.domain == domain && rule.policy == policy)
+        {
+            Some(rule) => rule,
+            None => {
+                if let Some(name) = &policy {
+                    // its answers must not be cached for everyone
+                    self.client_policies.get_mut(name).unwrap().own_upstreams = true;
+                }
+                self.forwarders.push(ForwardRule {
+                    domain,
+                    servers: Vec::new(),
+                    strategy: Strategy::Failover,
+                    retries: None,
+                    policy,
+                    cross_check: None,
+                });
+                self.forwarders.last_mut().unwrap()
+            }
+        };
+        rule.servers.extend(servers);
+        if let Some(strategy) = strategy {
+            rule.strategy = strategy;
+        }
+        if retries.is_some() {
+            rule.retries = retries;
+        }
+        if cross_check.is_some() {
+            rule.cross_check = cross_check;
+        }
+
+        Ok(())
+    }
+
+    fn parse_qtype_policy(&mut self, args: &[&str]) -> Result<(), String> {
+        let usage = "usage: qtype-policy refuse|drop|nodata|nxdomain <type>... [clients=<acl>]";
+        let [action, rest @ ..] = args else {
+            return Err(usage.to_string());
+        };
+        let action = QtypeAction::from_name(action)
+            .ok_or_else(|| format!("unknown qtype-policy action {:?}", action))?;
+
+        let mut types = Vec::new();
+        let mut clients = None;
+        for arg in rest {
+            match arg.split_once('=') {
+                Some(("clients", name)) => {
+                    let acl = self
+                        .acls
+                        .get(name)
+                        .ok_or_else(|| format!("unknown acl {:?}", name))?;
+                    clients = Some(acl.clone());
+                }
+                Some((key, _)) => return Err(format!("unknown qtype-policy option {:?}", key)),
+                None => match QueryType::from_name(arg) {
+                    QueryType::UNKNOWN(0) => return Err(format!("unknown type {:?}", arg)),
+                    qtype => types.push(qtype),
+                },
+            }
+        }
+        if types.is_empty() {
+            return Err(usage.to_string());
+        }
+
+        self.qtype_policy.push(QtypeRule {
+            types,
+            action,
+            clients,
+        });
+        Ok(())
+    }
+
+    fn parse_anonymize_clients(&mut self, args: &[&str]) -> Result<(), String> {
+        let usage = "usage: anonymize-clients truncate [ipv4-prefix=<len>] [ipv6-prefix=<len>] | hmac [rotate=<time>]";
+        let anonymize = match args {
+            ["truncate", options @ ..] => {
+                let mut ipv4_prefix = 24;
+                let mut ipv6_prefix = 48;
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("ipv4-prefix", len)) => {
+                            ipv4_prefix = len
+                                .parse::<u8>()
+                                .ok()
+                                .filter(|len| *len <= 32)
+                                .ok_or_else(|| format!("bad ipv4-prefix {:?}", len))?;
+                        }
+                        Some(("ipv6-prefix", len)) => {
+                            ipv6_prefix = len
+                                .parse::<u8>()
+                                .ok()
+                                .filter(|len| *len <= 128)
+                                .ok_or_else(|| format!("bad ipv6-prefix {:?}", len))?;
+                        }
+                        _ => return Err(format!("unknown anonymize-clients option {:?}", option)),
+                    }
+                }
+                AnonymizeConfig::Truncate {
+                    ipv4_prefix,
+                    ipv6_prefix,
+                }
+            }
+            ["hmac", options @ ..] => {
+                let mut rotate = Duration::from_secs(86400);
+                for option in options {
+                    match option.split_once('=') {
+                        Some(("rotate", time)) => {
+                            rotate = parse_ttl(time)
+                                .ok()
+                                .filter(|seconds| *seconds > 0)
+                                .map(|seconds| Duration::from_secs(seconds as u64))
+                                .ok_or_else(|| format!("bad rotate {:?}", time))?;
+                        }
+                        _ => return Err(format!("unknown anonymize-clients option {:?}", option)),
+                    }
+                }
+                AnonymizeConfig::Hmac { rotate }
+            }
+            _ => return Err(usage.to_string()),
+        };
+        self.anonymize_clients = Some(anonymize);
+        Ok(())
+    }
+
+    fn parse_client_quota(&mut self, args: &[&str]) -> Result<(), String> {
+        let [soft, hard, options @ ..] = args else {
+            return Err(
+                "usage: client-quota <soft-qps> <hard-qps> [hard=drop|refuse] [slip=<n>] [clients=<acl>] [ipv4-prefix=<len>] [ipv6-prefix=<len>]"
+                    .to_string(),
+            );
+        };
+        let soft = soft
+            .parse::<u32>()
+            .map_err(|e| format!("bad soft quota {:?}: {}", soft, e))?;
+        let hard = hard
+            .parse::<u32>()
+            .map_err(|e| format!("bad hard quota {:?}: {}", hard, e))?;
+        if soft > hard {
+            return Err("the soft quota is above the hard one".to_string());
+        }
+
+        let mut hard_action = QuotaAction::Refuse;
+        let mut slip = 0;
+        let mut clients = None;
+        let mut ipv4_prefix = 32;
+        let mut ipv6_prefix = 128;
+        for option in options {
+            match option.split_once('=') {
+                Some(("hard", "drop")) => hard_action = QuotaAction::Drop,
+                Some(("hard", "refuse")) => hard_action = QuotaAction::Refuse,
+                Some(("hard", value)) => return Err(format!("unknown hard action {:?}", value)),
+                Some(("slip", n)) => {
+                    slip = n
+                        .parse::<u32>()
+                        .map_err(|e| format!("bad slip {:?}: {}", n, e))?;
+                }
+                Some(("clients", name)) => {
+                    let acl = self
+                        .acls
+                        .get(name)
+                        .ok_or_else(|| format!("unknown acl {:?}", name))?;
+                    clients = Some(acl.clone());
+                }
+                Some(("ipv4-prefix", len)) => {
+                    ipv4_prefix = len
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|len| *len <= 32)
+                        .ok_or_else(|| format!("bad ipv4-prefix {:?}", len))?;
+                }
+                Some(("ipv6-prefix", len)) => {
+                    ipv6_prefix = len
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|len| *len <= 128)
+                        .ok_or_else(|| format!("bad ipv6-prefix {:?}", len))?;
+                }
+                _ => return Err(format!("unknown client-quota option {:?}", option)),
+            }
+        }
+
+        if slip != 0 && hard_action != QuotaAction::Drop {
+            return Err("slip only applies with hard=drop".to_string());
+        }
+
+        self.client_quotas.push(QuotaRule {
+            soft,
+            hard,
+            hard_action,
+            slip,
+            clients,
+            ipv4_prefix,
+            ipv6_prefix,
+        });
+        Ok(())
+    }
+
+    // A profile is a client policy without the options that identify
+    // clients, as listeners assign it.
+    fn parse_client_policy(&mut self, directive: &str, args: &[&str]) -> Result<(), String> {
+        let usage = match directive {
+            "profile" => "usage: profile <name> [audit=yes|no]",
+            _ => {
+                "usage: client-policy <name> [clients=<acl|cidr>] [mac=<mac>]... [edns=<code>:<value>]... [audit=yes|no]"
+            }
+        };
+        let [name, options @ ..] = args else {
+            return Err(usage.to_string());
+        };
+        if self.client_policies.get(name).is_some() {
+            return Err(format!("client policy {:?} is defined twice", name));
+        }
+
+        let mut policy = ClientPolicy::new(name);
+        policy.profile = directive == "profile";
+        for option in options {
+            match option.split_once('=') {
+                Some(("clients", clients)) if !policy.profile => {
+                    policy.clients = Some(self.acl_or_cidr(clients)?);
+                }
+                Some(("mac", mac)) if !policy.profile => {
+                    policy.identifiers.push((MAC_OPTION, parse_mac(mac)?));
+                }
+                Some(("edns", identifier)) if !policy.profile => {
+                    policy.identifiers.push(parse_identifier(identifier)?);
+                }
+                Some(("audit", "yes")) => policy.audit = true,
+                Some(("audit", "no")) => policy.audit = false,
+                _ => return Err(format!("unknown {} option {:?}", directive, option)),
+            }
+        }
+        self.client_policies.push(policy);
+        Ok(())
+    }
+
+    // name, if it is a client policy's
+    fn policy_name(&self, name: &str) -> Result<String, String> {
+        match self.client_policies.get(name) {
+            Some(_) => Ok(name.to_string()),
+            None => Err(format!("unknown client policy {:?}", name)),
+        }
+    }
+
+    // The ACL called text, or one with text's network alone.
+    fn acl_or_cidr(&self, text: &str) -> Result<Acl, String> {
+        if let Some(acl) = self.acls.get(text) {
+            return Ok(acl.clone());
+        }
+        let cidr = Cidr::parse(text)
+            .map_err(|_| format!("{:?} is neither an acl nor an address", text))?;
+        let mut acl = Acl::new();
+        acl.add(cidr);
+        Ok(acl)
+    }
+
+    fn parse_ddr(&mut self, args: &[&str]) -> Result<(), String> {
+        let [target, params @ ..] = args else {
+            return Err("usage: ddr <target> alpn=<id>[,<id>...] [<svc-param>...]".to_string());
+        };
+        let target = Name::from_unicode(target)?;
+        if target.is_root() {
+            return Err("ddr needs a target name clients can verify".to_string());
+        }
+        let params = svcb::parse_params(params)?;
+
+        // DoH endpoints also have to say where their URI template is
+        let Some((_, alpn)) = params.iter().find(|(key, _)| *key == svcb::ALPN) else {
+            return Err("ddr needs alpn=".to_string());
+        };
+        let mut protocols = Vec::new();
+        let mut rest = alpn.as_slice();
+        while let Some((&len, tail)) = rest.split_first() {
+            let (id, tail) = tail.split_at(len as usize);
+            protocols.push(id);
+            rest = tail;
+        }
+        let doh = protocols.iter().any(|id| *id == b"h2" || *id == b"h3");
+        if doh && !params.iter().any(|(key, _)| *key == svcb::DOHPATH) {
+            return Err("ddr with alpn=h2 or h3 needs dohpath=".to_string());
+        }
+
+        self.designations.push(Designation { target, params });
+        Ok(())
+    }
+
+    fn parse_record(&mut self, args: &[&str]) -> Result<(), String> {
+        if args.len() < 4 {
+            return Err("usage: record <name> <type> <ttl> <rdata...> [weight=<n>]".to_string());
+        }
+
+        let qtype = QueryType::from_name(args[1]);
+        let ttl = args[2]
+            .parse::<u32>()
+            .map_err(|e| format!("bad ttl {:?}: {}", args[2], e))?;
+
+        // weight= is the only option, after the rdata; TXT data may hold
+        // = signs of its own
+        let mut rdata = &args[3..];
+        let mut weight = None;
+        if let [rest @ .., last] = rdata
+            && let Some(value) = last.strip_prefix("weight=")
+        {
+            weight = Some(
+                value
+                    .parse::<u32>()
+                    .map_err(|e| format!("bad weight {:?}: {}", value, e))?,
+            );
+            rdata = rest;
+        }
+
+        let record = parse_record(args[0], qtype, ttl, rdata)?;
+        self.zones.add(record, weight)?;
+
+        Ok(())
+    }
+}
+
+// The line up to a # outside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn located(path: &str, line: Option<usize>, message: &str) -> String {
+    match line {
+        Some(line) => format!("{}:{}: {}", path, line, message),
+        None => format!("{}: {}", path, message),
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::anonymize;
+use crate::log;
+use crate::log::LogLevel;
+
+// Limits on the TCP and HTTP connections clients hold open, so a few of them
+// can't use up the server's threads and file descriptors:
+// "connection-limits [max=<n>] [per-client=<n>] [idle=<time>] [io=<time>]".
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionPolicy {
+    // connections served at the same time over every listener
+    pub max: usize,
+    // of those, the most one client may have; IPv6 clients are their /64
+    pub per_client: usize,
+    // how long a TCP connection may wait for its next query
+    pub idle: Duration,
+    // how long a query or HTTP request may take to arrive once it started,
+    // and a response to be taken, however slowly the bytes trickle
+    pub io: Duration,
+}
+
+impl ConnectionPolicy {
+    pub fn new() -> ConnectionPolicy {
+        ConnectionPolicy {
+            max: 1024,
+            per_client: 64,
+            idle: Duration::from_secs(10),
+            io: Duration::from_secs(5),
+        }
+    }
+}
+
+// Whose connections count together: an address, or for IPv6 the /64 it's
+// in, which is usually one host or one site.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from_bits(v6.to_bits() & !((1u128 << 64) - 1))),
+    }
+}
+
+struct Open {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+// The connections open over every listener.
+pub struct Connections {
+    policy: ConnectionPolicy,
+    open: Mutex<Open>,
+}
+
+impl Connections {
+    pub fn new(policy: ConnectionPolicy) -> Connections {
+        Connections {
+            policy,
+            open: Mutex::new(Open {
+                total: 0,
+                per_client: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> &ConnectionPolicy {
+        &self.policy
+    }
+
+    // Counts a new connection from ip in, or None if it's one too many, in
+    // which case it should be closed right away. It counts until the
+    // returned slot is dropped.
+    pub fn admit(&self, ip: IpAddr) -> Option<Slot<'_>> {
+        let key = client_key(ip);
+        let mut open = self.open.lock().unwrap();
+        let from_client = open.per_client.get(&key).copied().unwrap_or(0);
+        if open.total >= self.policy.max || from_client >= self.policy.per_client {
+            drop(open);
+            if log::enabled(LogLevel::Debug) {
+                eprintln!(
+                    "connection from {} refused: too many open",
+                    anonymize::client(ip)
+                );
+            }
+            return None;
+        }
+        open.total += 1;
+        open.per_client.insert(key, from_client + 1);
+        Some(Slot {
+            connections: self,
+            key,
+        })
+    }
+}
+
+// A connection admitted by Connections::admit.
+pub struct Slot<'a> {
+    connections: &'a Connections,
+    key: IpAddr,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.per_client.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_client.remove(&self.key);
+            }
+        }
+    }
+}
+
+// Reads from a stream until a deadline, then fails with TimedOut, so a
+// client sending a byte at a time can't stretch a read out forever. Leaves
+// the stream's read timeout changed.
+pub struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    pub fn new(stream: &'a TcpStream, within: Duration) -> Deadline<'a> {
+        Deadline {
+            stream,
+            deadline: Instant::now() + within,
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
@@ -0,0 +1,125 @@
+// Detaching from the terminal for init systems that start the server in the
+// foreground and expect it to go to the background by itself.
+
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+
+unsafe extern "C" {
+    fn fork() -> c_int;
+    fn setsid() -> c_int;
+    fn umask(mask: u32) -> u32;
+    fn dup2(old: c_int, new: c_int) -> c_int;
+    fn getpid() -> c_int;
+    fn kill(pid: c_int, sig: c_int) -> c_int;
+    fn unlink(path: *const c_char) -> c_int;
+    fn signal(sig: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn _exit(status: c_int) -> !;
+}
+
+// The pidfile to remove on the way out. Set once before the signal handlers
+// go in, so they only ever read it.
+static PIDFILE: OnceLock<CString> = OnceLock::new();
+
+extern "C" fn on_signal(_: c_int) {
+    remove_pidfile();
+    unsafe { _exit(0) };
+}
+
+// Removes the pidfile written by daemonize, if there is one.
+pub fn remove_pidfile() {
+    if let Some(path) = PIDFILE.get() {
+        unsafe { unlink(path.as_ptr()) };
+    }
+}
+
+fn path_error(path: &Path, e: io::Error) -> String {
+    format!("{}: {}", path.display(), e)
+}
+
+fn check(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// Refuses to start over a pidfile whose process is still running; a stale
+// one is simply overwritten.
+fn check_running(pidfile: &Path) -> Result<(), String> {
+    let Ok(contents) = fs::read_to_string(pidfile) else {
+        return Ok(());
+    };
+    match contents.trim().parse::<c_int>() {
+        Ok(pid) if pid > 0 && unsafe { kill(pid, 0) } == 0 => Err(format!(
+            "{}: already running as pid {}",
+            pidfile.display(),
+            pid
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Forks into the background: the calling process exits, and the daemon
+// continues in a new session with stdin on /dev/null and stdout and stderr
+// appended to log (or /dev/null). Its pid goes into pidfile, which is
+// removed again when it is stopped with SIGTERM or SIGINT. It stays
+// in the working directory, so relative paths in the config keep working.
+//
+// Must be called before any threads are started.
+pub fn daemonize(pidfile: &Path, log: Option<&Path>) -> Result<(), String> {
+    // everything that can go wrong is tried while errors can still be seen
+    let pidfile = std::path::absolute(pidfile).map_err(|e| path_error(pidfile, e))?;
+    check_running(&pidfile)?;
+    let mut pid_out = File::create(&pidfile).map_err(|e| path_error(&pidfile, e))?;
+    let null = Path::new("/dev/null");
+    let stdin = File::open(null).map_err(|e| path_error(null, e))?;
+    let log = log.unwrap_or(null);
+    let output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .map_err(|e| path_error(log, e))?;
+
+    let path = CString::new(pidfile.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let _ = PIDFILE.set(path);
+
+    let mut detach = || -> io::Result<()> {
+        // the second fork makes sure the daemon can never get a terminal back
+        if check(unsafe { fork() })? > 0 {
+            unsafe { _exit(0) };
+        }
+        check(unsafe { setsid() })?;
+        if check(unsafe { fork() })? > 0 {
+            unsafe { _exit(0) };
+        }
+        unsafe { umask(0o022) };
+
+        writeln!(pid_out, "{}", unsafe { getpid() })?;
+        check(unsafe { dup2(stdin.as_raw_fd(), 0) })?;
+        check(unsafe { dup2(output.as_raw_fd(), 1) })?;
+        check(unsafe { dup2(output.as_raw_fd(), 2) })?;
+
+        for sig in [SIGINT, SIGTERM] {
+            unsafe { signal(sig, on_signal) };
+        }
+        Ok(())
+    };
+    detach().map_err(|e| {
+        remove_pidfile();
+        format!("daemonizing: {}", e)
+    })
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::DnsRecord;
+use crate::calendar::days_from_civil;
+use crate::name::Name;
+use crate::zone::LocalZones;
+use crate::zone::reverse_name;
+
+// How often the lease files are looked at for changes and expired leases.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Short, so a host that moves to another address is found there soon.
+const LEASE_TTL: u32 = 60;
+
+// A DHCP server's lease file, whose hosts are served as <hostname>.<domain>.
+#[derive(Debug, Clone)]
+pub struct LeaseFile {
+    pub path: PathBuf,
+    pub domain: Name,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub addr: IpAddr,
+    pub hostname: String,
+    // seconds since the epoch, None for leases that don't expire
+    pub expires: Option<u64>,
+}
+
+// "2024/01/02 10:00:00", which dhcpd writes in UTC.
+fn parse_isc_time(date: &str, time: &str) -> Option<u64> {
+    let date: Vec<i64> = date
+        .split('/')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let time: Vec<i64> = time
+        .split(':')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let ([year, month, day], [hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return None;
+    };
+    let days = days_from_civil(*year, *month, *day);
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+// dnsmasq: one lease per line, "<expiry> <mac> <ip> <hostname> <client-id>",
+// with an expiry of 0 for infinite leases and * for an unknown hostname.
+fn parse_dnsmasq(text: &str) -> Vec<Lease> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [expiry, _, addr, hostname, ..] = fields.as_slice() else {
+                return None;
+            };
+            if *hostname == "*" {
+                return None;
+            }
+            let expires = expiry.parse::<u64>().ok()?;
+            Some(Lease {
+                addr: addr.parse().ok()?,
+                hostname: hostname.to_string(),
+                expires: (expires != 0).then_some(expires),
+            })
+        })
+        .collect()
+}
+
+// ISC dhcpd: "lease <ip> { ... }" blocks, appended as leases change, so a
+// later block for an address replaces the earlier ones.
+fn parse_isc(text: &str) -> Vec<Lease> {
+    let mut leases: Vec<Lease> = Vec::new();
+
+    let mut rest = text;
+    while let Some(start) = rest.find("lease ") {
+        rest = &rest[start + "lease ".len()..];
+        let Some(open) = rest.find('{') else {
+            break;
+        };
+        let addr = rest[..open].trim().parse::<IpAddr>();
+        let Some(close) = rest.find('}') else {
+            break;
+        };
+        let body = &rest[open + 1..close];
+        rest = &rest[close + 1..];
+        let Ok(addr) = addr else {
+            continue;
+        };
+
+        let mut hostname = None;
+        let mut expires = None;
+        let mut active = true;
+        for statement in body.split(';') {
+            let words: Vec<&str> = statement.split_whitespace().collect();
+            match words.as_slice() {
+                ["client-hostname", name] => hostname = Some(name.trim_matches('"').to_string()),
+                ["ends", "never"] => expires = None,
+                ["ends", _weekday, date, time] => expires = parse_isc_time(date, time),
+                ["binding", "state", state] => active = *state == "active",
+                _ => {}
+            }
+        }
+
+        leases.retain(|lease| lease.addr != addr);
+        if let (true, Some(hostname)) = (active, hostname) {
+            leases.push(Lease {
+                addr,
+                hostname,
+                expires,
+            });
+        }
+    }
+
+    leases
+}
+
+// Either format; ISC files are told apart by their lease blocks.
+pub fn parse_leases(text: &str) -> Vec<Lease> {
+    let isc = text
+        .lines()
+        .any(|line| line.trim_start().starts_with("lease ") && line.contains('{'));
+    if isc {
+        parse_isc(text)
+    } else {
+        parse_dnsmasq(text)
+    }
+}
+
+// A and PTR records for the leases that are still running at now.
+fn lease_records(leases: &[Lease], domain: &Name, now: u64) -> Vec<DnsRecord> {
+    let mut records = Vec::new();
+    for lease in leases {
+        if lease.expires.is_some_and(|expires| expires <= now) {
+            continue;
+        }
+        // clients sometimes send a full name, the domain is ours to pick
+        let label = lease.hostname.split('.').next().unwrap_or_default();
+        let Ok(label) = Name::from_unicode(label) else {
+            continue;
+        };
+        if label.is_root() {
+            continue;
+        }
+        let Ok(host) = label.append(domain) else {
+            continue;
+        };
+
+        records.push(match lease.addr {
+            IpAddr::V4(addr) => DnsRecord::A {
+                domain: host.clone(),
+                addr,
+                ttl: LEASE_TTL,
+            },
+            IpAddr::V6(addr) => DnsRecord::AAAA {
+                domain: host.clone(),
+                ttl: LEASE_TTL,
+                addr,
+            },
+        });
+        records.push(DnsRecord::PTR {
+            domain: reverse_name(lease.addr),
+            ttl: LEASE_TTL,
+            host,
+        });
+    }
+    records
+}
+
+// What was last read from a lease file.
+struct FileState {
+    leases: Vec<Lease>,
+    modified: Option<SystemTime>,
+    error: Option<String>,
+}
+
+impl FileState {
+    fn new() -> FileState {
+        FileState {
+            leases: Vec::new(),
+            modified: None,
+            error: None,
+        }
+    }
+}
+
+// Keeps the local zones in step with the lease files.
+pub struct LeaseWatcher {
+    files: Vec<LeaseFile>,
+    zones: Arc<LocalZones>,
+}
+
+impl LeaseWatcher {
+    pub fn new(files: Vec<LeaseFile>, zones: Arc<LocalZones>) -> Result<LeaseWatcher, String> {
+        for file in files.iter() {
+            zones.add_zone(file.domain.clone())?;
+        }
+        Ok(LeaseWatcher { files, zones })
+    }
+
+    // The records of every file's current leases. Files are only parsed
+    // again once they were modified; one that can't be read keeps the leases
+    // last read from it.
+    fn read(&self, state: &mut [FileState]) -> Vec<DnsRecord> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut records = Vec::new();
+        for (file, state) in self.files.iter().zip(state.iter_mut()) {
+            let modified = fs::metadata(&file.path).and_then(|meta| meta.modified());
+            let result = match modified {
+                Ok(modified) if Some(modified) == state.modified => Ok(()),
+                Ok(modified) => fs::read_to_string(&file.path).map(|text| {
+                    state.leases = parse_leases(&text);
+                    state.modified = Some(modified);
+                }),
+                Err(e) => Err(e),
+            };
+
+            // report a failure once, not on every poll
+            let error = result.err().map(|e| e.to_string());
+            if let Some(e) = &error
+                && state.error.as_ref() != Some(e)
+            {
+                eprintln!("dhcp-leases {}: {}", file.path.display(), e);
+            }
+            state.error = error;
+
+            records.extend(lease_records(&state.leases, &file.domain, now));
+        }
+        records
+    }
+
+    // Reads the files now and whenever they might have changed. Runs in the
+    // serving process, after any chroot, so paths are as seen from inside it.
+    pub fn run(&self) {
+        let mut state: Vec<FileState> = self.files.iter().map(|_| FileState::new()).collect();
+        let mut served = Vec::new();
+        loop {
+            let records = self.read(&mut state);
+            if records != served {
+                match self.zones.set_leases(records.clone()) {
+                    Ok(()) => served = records,
+                    Err(e) => eprintln!("dhcp-leases: {}", e),
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
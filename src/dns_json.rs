@@ -0,0 +1,134 @@
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::DnsRecord;
+use crate::QueryClass;
+use crate::QueryType;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+use crate::json::Json;
+use crate::name::Name;
+
+// The JSON API of Google's /resolve and Cloudflare's /dns-query, for web apps
+// and scripts that would rather not encode DNS messages:
+//
+//     GET /resolve?name=example.com&type=AAAA[&cd=1]
+//
+//     {"Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+//      "Question": [{"name": "example.com.", "type": 28}],
+//      "Answer": [{"name": "example.com.", "type": 28, "TTL": 3600, "data": "2001:db8::1"}]}
+//
+// Other parameters those services take (do, edns_client_subnet,
+// random_padding) are ignored, as they allow.
+
+pub const CONTENT_TYPE: &str = "application/dns-json";
+
+// Whether request is for the API at all.
+pub fn is_api_path(path: &str) -> bool {
+    matches!(path, "/resolve" | "/dns-query")
+}
+
+// type= is a mnemonic or a number, A when left out.
+fn query_type(text: Option<String>) -> Result<QueryType, String> {
+    let Some(text) = text else {
+        return Ok(QueryType::A);
+    };
+    let qtype = match text.parse::<u16>() {
+        Ok(num) => QueryType::from_num(num),
+        Err(_) => QueryType::from_name(&text),
+    };
+    match qtype {
+        QueryType::UNKNOWN(0) | QueryType::OPT => Err(format!("unsupported type {:?}", text)),
+        qtype => Ok(qtype),
+    }
+}
+
+// The query the request's parameters ask for.
+pub fn query(request: &HttpRequest) -> Result<DnsPacket, String> {
+    let name = request
+        .query_param("name")
+        .ok_or_else(|| "missing parameter \"name\"".to_string())?;
+    let name = Name::from_unicode(name.strip_suffix('.').unwrap_or(&name))?;
+    let qtype = query_type(request.query_param("type"))?;
+    let checking_disabled = request
+        .query_param("cd")
+        .is_some_and(|cd| cd == "1" || cd == "true");
+
+    let mut packet = DnsPacket::new();
+    packet.header.recursion_desired = true;
+    packet.header.checking_disabled = checking_disabled;
+    packet.questions.push(DnsQuestion {
+        name,
+        qtype,
+        qclass: QueryClass::IN,
+    });
+    Ok(packet)
+}
+
+fn records_json(records: &[DnsRecord]) -> Json {
+    Json::Array(
+        records
+            .iter()
+            .filter(|record| record.query_type() != QueryType::OPT)
+            .map(|record| {
+                Json::object(vec![
+                    ("name", record.domain().to_fqdn().into()),
+                    ("type", (record.query_type().to_num() as u64).into()),
+                    ("TTL", (record.ttl() as u64).into()),
+                    ("data", record.rdata_string().into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+// packet the way the API returns it. Sections without records are left out.
+pub fn response(packet: &DnsPacket) -> HttpResponse {
+    let header = &packet.header;
+    let mut fields = vec![
+        ("Status", (header.response_code.to_num() as u64).into()),
+        ("TC", header.truncation.into()),
+        ("RD", header.recursion_desired.into()),
+        ("RA", header.recursion_available.into()),
+        ("AD", header.authed_data.into()),
+        ("CD", header.checking_disabled.into()),
+        (
+            "Question",
+            Json::Array(
+                packet
+                    .questions
+                    .iter()
+                    .map(|question| {
+                        Json::object(vec![
+                            ("name", question.name.to_fqdn().into()),
+                            ("type", (question.qtype.to_num() as u64).into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ];
+    for (key, records) in [
+        ("Answer", &packet.answers),
+        ("Authority", &packet.nameservers),
+        ("Additional", &packet.additionals),
+    ] {
+        if records
+            .iter()
+            .any(|record| record.query_type() != QueryType::OPT)
+        {
+            fields.push((key, records_json(records)));
+        }
+    }
+
+    let mut response = HttpResponse::json(200, &Json::object(fields));
+    response.content_type = CONTENT_TYPE.to_string();
+    with_cors(response)
+}
+
+// Lets scripts on any web page read the response.
+pub fn with_cors(mut response: HttpResponse) -> HttpResponse {
+    response
+        .headers
+        .push(("Access-Control-Allow-Origin".to_string(), "*".to_string()));
+    response
+}
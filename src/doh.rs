@@ -0,0 +1,164 @@
+use crate::BufHandler;
+use crate::DnsPacket;
+use crate::QueryType;
+use crate::TCP_MESSAGE_SIZE;
+use crate::dns_json;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+
+// DNS messages over HTTP (RFC 8484), next to the JSON API on the same
+// paths: a GET with the message in ?dns= as unpadded base64url, or a POST
+// with it as the body, answered with the response message. Which path
+// answers, and with which profile, is up to the listener's routes.
+
+pub const CONTENT_TYPE: &str = "application/dns-message";
+
+// A path an http-listen listener answers on, for one host or any:
+// "http-route <addr:port> [<host>]/<path> [profile=<name>]".
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRoute {
+    // lowercase, without a port; any host when None
+    pub host: Option<String>,
+    pub path: String,
+    // the client policy its queries get, the listener's when None
+    pub profile: Option<String>,
+}
+
+impl HttpRoute {
+    // Parses "dns.example.com/dns-query" or "/family".
+    pub fn parse(spec: &str) -> Result<HttpRoute, String> {
+        let Some(slash) = spec.find('/') else {
+            return Err(format!("http-route {:?} has no path", spec));
+        };
+        let (host, path) = spec.split_at(slash);
+        Ok(HttpRoute {
+            host: (!host.is_empty()).then(|| host.to_ascii_lowercase()),
+            path: path.to_string(),
+            profile: None,
+        })
+    }
+}
+
+// Which endpoint a request is for.
+pub enum Endpoint<'a> {
+    Route(&'a HttpRoute),
+    // one of the JSON API's paths, for a host without routes of its own
+    Default,
+    Unknown,
+}
+
+// The host a request is for, from Host (or HTTP/2's :authority), lowercase
+// and without a port.
+fn request_host(request: &HttpRequest) -> Option<String> {
+    let host = request.header("host")?;
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => host,
+    };
+    let host = host.trim_end_matches('.');
+    Some(host.to_ascii_lowercase())
+}
+
+// A route for the request's host and path comes first, then one for its
+// path on any host. A host that has routes of its own is served on those
+// alone, so a filtered virtual host can't be got around through the default
+// paths; other hosts also get /resolve and /dns-query.
+pub fn endpoint<'a>(routes: &'a [HttpRoute], request: &HttpRequest) -> Endpoint<'a> {
+    let host = request_host(request);
+    let for_host = |route: &&HttpRoute| route.host.is_some() && route.host == host;
+    if let Some(route) = routes
+        .iter()
+        .filter(for_host)
+        .find(|route| route.path == request.path)
+    {
+        return Endpoint::Route(route);
+    }
+    if routes.iter().any(|route| for_host(&route)) {
+        return Endpoint::Unknown;
+    }
+    if let Some(route) = routes
+        .iter()
+        .find(|route| route.host.is_none() && route.path == request.path)
+    {
+        return Endpoint::Route(route);
+    }
+    match dns_json::is_api_path(&request.path) {
+        true => Endpoint::Default,
+        false => Endpoint::Unknown,
+    }
+}
+
+// Whether the request carries a DNS message rather than JSON API
+// parameters.
+pub fn is_message(request: &HttpRequest) -> bool {
+    match request.method.as_str() {
+        "GET" => request.query_param("dns").is_some(),
+        "POST" => request
+            .header("content-type")
+            .is_some_and(|content_type| content_type.eq_ignore_ascii_case(CONTENT_TYPE)),
+        _ => false,
+    }
+}
+
+// Decodes unpadded base64url (RFC 4648 section 5), as ?dns= carries it.
+fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    // a single character left over can't be a whole byte
+    (count < 6).then_some(out)
+}
+
+// The query message of the request.
+pub fn query(request: &HttpRequest) -> Result<DnsPacket, String> {
+    let message = match request.query_param("dns") {
+        Some(dns) if request.method == "GET" => {
+            base64url_decode(&dns).ok_or_else(|| "bad base64url in dns".to_string())?
+        }
+        _ => request.body.clone(),
+    };
+    if message.len() > TCP_MESSAGE_SIZE {
+        return Err("message too large".to_string());
+    }
+    let mut buf_handler = BufHandler::from_bytes(&message);
+    let packet = DnsPacket::from_buffer(&mut buf_handler)?;
+    if packet.header.query || packet.questions.len() != 1 {
+        return Err("not a query with one question".to_string());
+    }
+    Ok(packet)
+}
+
+// The response message, fresh for as long as its shortest TTL, which for
+// NXDOMAIN and NODATA is the SOA's (RFC 8484 section 5.1).
+pub fn response(packet: &DnsPacket, message: &[u8]) -> HttpResponse {
+    let mut response = HttpResponse::new(200, CONTENT_TYPE, message.to_vec());
+    let ttl = packet
+        .answers
+        .iter()
+        .chain(packet.nameservers.iter())
+        .filter(|record| record.query_type() != QueryType::OPT)
+        .map(|record| record.ttl())
+        .min();
+    if let Some(ttl) = ttl {
+        response
+            .headers
+            .push(("Cache-Control".to_string(), format!("max-age={}", ttl)));
+    }
+    response
+}
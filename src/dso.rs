@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use crate::BufHandler;
+use crate::DnsHeader;
+use crate::OpCode;
+use crate::ResponseCode;
+
+// DSO TLV types (RFC 8490 section 10.3)
+pub const KEEPALIVE: u16 = 1;
+pub const RETRY_DELAY: u16 = 2;
+pub const ENCRYPTION_PADDING: u16 = 3;
+
+// What the server asks of clients in its Keepalive TLV: close after this long
+// without outstanding operations, and send something at least this often.
+pub const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(15);
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DsoTlv {
+    pub tlv_type: u16,
+    pub data: Vec<u8>,
+}
+
+// A DNS Stateful Operations message: a header with all counts zero followed
+// by TLVs, the first of which says what the message is for.
+#[derive(Debug)]
+pub struct DsoMessage {
+    pub header: DnsHeader,
+    pub tlvs: Vec<DsoTlv>,
+}
+
+impl DsoMessage {
+    pub fn response(id: u16, response_code: ResponseCode, tlvs: Vec<DsoTlv>) -> DsoMessage {
+        let mut header = DnsHeader::new();
+        header.id = id;
+        header.query = true;
+        header.opcode = OpCode::DSO;
+        header.response_code = response_code;
+        DsoMessage { header, tlvs }
+    }
+
+    // Reads the whole buffer, which holds exactly one message.
+    pub fn read(buf_handler: &mut BufHandler) -> Result<DsoMessage, String> {
+        let mut header = DnsHeader::new();
+        header.read(buf_handler)?;
+        if header.questions != 0
+            || header.answers != 0
+            || header.nameservers != 0
+            || header.additionals != 0
+        {
+            return Err("DSO message with non-zero section counts".to_string());
+        }
+
+        let mut tlvs = Vec::new();
+        while buf_handler.get_pos() < buf_handler.buf.len() {
+            let tlv_type = buf_handler.read_u16()?;
+            let len = buf_handler.read_u16()?;
+            let mut data = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                data.push(buf_handler.read()?);
+            }
+            tlvs.push(DsoTlv { tlv_type, data });
+        }
+
+        Ok(DsoMessage { header, tlvs })
+    }
+
+    pub fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        self.header.write(buf_handler)?;
+        for tlv in self.tlvs.iter() {
+            buf_handler.write_u16(tlv.tlv_type)?;
+            buf_handler.write_u16(tlv.data.len() as u16)?;
+            for byte in tlv.data.iter() {
+                buf_handler.write(*byte)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// What to do about a DSO message from a client.
+pub enum Outcome {
+    Reply(DsoMessage),
+    // a protocol error the client can't be told about; close the connection
+    Abort,
+}
+
+fn keepalive_tlv() -> DsoTlv {
+    let mut data = Vec::with_capacity(8);
+    data.extend_from_slice(&(INACTIVITY_TIMEOUT.as_millis() as u32).to_be_bytes());
+    data.extend_from_slice(&(KEEPALIVE_INTERVAL.as_millis() as u32).to_be_bytes());
+    DsoTlv {
+        tlv_type: KEEPALIVE,
+        data,
+    }
+}
+
+// Per connection DSO state. A session is established by the client's first
+// acknowledged Keepalive request (RFC 8490 section 5.1).
+pub struct DsoSession {
+    established: bool,
+}
+
+impl DsoSession {
+    pub fn new() -> DsoSession {
+        DsoSession { established: false }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    pub fn handle(&mut self, buf_handler: &mut BufHandler) -> Outcome {
+        let message = match DsoMessage::read(buf_handler) {
+            Ok(message) => message,
+            Err(_) => return Outcome::Abort,
+        };
+        let id = message.header.id;
+
+        // we never send DSO requests, so there is nothing to be a response to
+        if message.header.query {
+            return Outcome::Abort;
+        }
+
+        // Unacknowledged messages can't carry an error back, and the only
+        // ones a client may send (none of them Keepalive) aren't supported.
+        if id == 0 {
+            return Outcome::Abort;
+        }
+
+        let Some(primary) = message.tlvs.first() else {
+            return Outcome::Reply(DsoMessage::response(id, ResponseCode::FORMERR, Vec::new()));
+        };
+
+        match primary.tlv_type {
+            KEEPALIVE if primary.data.len() == 8 => {
+                self.established = true;
+                Outcome::Reply(DsoMessage::response(
+                    id,
+                    ResponseCode::NOERR,
+                    vec![keepalive_tlv()],
+                ))
+            }
+            // malformed, or only ever valid from the server or as an
+            // additional TLV
+            KEEPALIVE | RETRY_DELAY | ENCRYPTION_PADDING => {
+                Outcome::Reply(DsoMessage::response(id, ResponseCode::FORMERR, Vec::new()))
+            }
+            _ => Outcome::Reply(DsoMessage::response(
+                id,
+                ResponseCode::DSOTYPENI,
+                Vec::new(),
+            )),
+        }
+    }
+}
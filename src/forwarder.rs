@@ -0,0 +1,505 @@
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DnsPacket;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::anonymize;
+use crate::client::lookup_from;
+use crate::client::lookup_tcp;
+use crate::client_policy::ClientPolicy;
+use crate::compare;
+use crate::health::UpstreamHealth;
+use crate::latency::Latencies;
+use crate::log;
+use crate::log::LogLevel;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::pipeline::minimal_any;
+use crate::pipeline::negative_soa;
+use crate::random::Rng;
+use crate::socks::Socks5Proxy;
+
+// How long the fastest strategy trusts a measurement before it sends a real
+// query to that upstream again to re-measure it.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long the happy eyeballs strategy gives an upstream to answer before it
+// asks the next one as well, the connection attempt delay of RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// The order a rule tries its upstreams in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // in the order they were configured
+    Failover,
+    // starting one further along on every query
+    RoundRobin,
+    // shuffled on every query
+    Random,
+    // lowest smoothed round trip time first
+    Fastest,
+    // IPv6 and IPv4 alternating, each asked 250 ms after the one before
+    // unless that one answered, the first answer winning (RFC 8305)
+    HappyEyeballs,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "failover" => Some(Strategy::Failover),
+            "round-robin" => Some(Strategy::RoundRobin),
+            "random" => Some(Strategy::Random),
+            "fastest" => Some(Strategy::Fastest),
+            "happy-eyeballs" => Some(Strategy::HappyEyeballs),
+            _ => None,
+        }
+    }
+}
+
+// What a rule that asks two upstreams the same question does when their
+// answers don't agree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrossCheck {
+    // answers SERVFAIL, and so it does when only one answers
+    Strict,
+    // answers with the first upstream's answer and logs the disagreement
+    Flag,
+}
+
+impl CrossCheck {
+    pub fn from_name(name: &str) -> Option<CrossCheck> {
+        match name {
+            "strict" => Some(CrossCheck::Strict),
+            "flag" => Some(CrossCheck::Flag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    // queried over TCP through this proxy instead of over UDP
+    pub proxy: Option<Socks5Proxy>,
+    // local address UDP queries are sent from
+    pub source: Option<IpAddr>,
+}
+
+// Queries at or below domain go to servers. The rule with the longest
+// matching domain wins; the root matches everything.
+#[derive(Debug, Clone)]
+pub struct ForwardRule {
+    pub domain: Name,
+    pub servers: Vec<Upstream>,
+    pub strategy: Strategy,
+    // how many more upstreams a query goes to after one that failed or
+    // answered SERVFAIL or REFUSED; None tries every upstream once
+    pub retries: Option<usize>,
+    // the client policy the rule is for, every client's when None
+    pub policy: Option<String>,
+    // every query goes to two upstreams whose answers have to agree
+    pub cross_check: Option<CrossCheck>,
+}
+
+struct Route {
+    rule: ForwardRule,
+    next: AtomicUsize,
+    // when each upstream was last picked by the fastest strategy to be
+    // measured
+    probed: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Route {
+    fn new(rule: ForwardRule) -> Route {
+        let probed = vec![None; rule.servers.len()];
+        Route {
+            rule,
+            next: AtomicUsize::new(0),
+            probed: Mutex::new(probed),
+        }
+    }
+
+    // Indexes into rule.servers, in the order to try them for one query.
+    // Upstreams that are down are left out, unless all of them are.
+    fn order(
+        &self,
+        health: &UpstreamHealth,
+        latencies: &Latencies,
+        rng: &Mutex<Rng>,
+    ) -> Vec<usize> {
+        let count = self.rule.servers.len();
+        let mut order: Vec<usize> = (0..count).collect();
+
+        match self.rule.strategy {
+            Strategy::Failover => {}
+            Strategy::HappyEyeballs => {
+                // as configured within each family, IPv6 first
+                let (v6, v4): (Vec<usize>, Vec<usize>) = order
+                    .iter()
+                    .partition(|&&i| self.rule.servers[i].addr.is_ipv6());
+                let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+                order.clear();
+                loop {
+                    match (v6.next(), v4.next()) {
+                        (None, None) => break,
+                        (a, b) => order.extend(a.into_iter().chain(b)),
+                    }
+                }
+            }
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                order.rotate_left(start % count);
+            }
+            Strategy::Random => {
+                let mut rng = rng.lock().unwrap();
+                for i in (1..count).rev() {
+                    order.swap(i, rng.below(i as u64 + 1) as usize);
+                }
+            }
+            Strategy::Fastest => {
+                let mut probed = self.probed.lock().unwrap();
+                let now = Instant::now();
+
+                // An upstream that has not been measured for a while is
+                // tried first, once, so a server that got faster is noticed.
+                let stale = (0..count).find(|&i| {
+                    probed[i].is_none_or(|probed| now.duration_since(probed) >= PROBE_INTERVAL)
+                });
+                if let Some(i) = stale {
+                    probed[i] = Some(now);
+                }
+
+                order.sort_by_key(|&i| {
+                    (Some(i) != stale, latencies.rank(self.rule.servers[i].addr))
+                });
+            }
+        }
+
+        let up: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| health.is_up(self.rule.servers[i].addr))
+            .collect();
+        if up.is_empty() { order } else { up }
+    }
+}
+
+// Asks server, keeping its health and the round trip times of it and zone up
+// to date. A truncated UDP reply is asked again over TCP for the whole answer.
+fn ask(
+    health: &UpstreamHealth,
+    latencies: &Latencies,
+    server: &Upstream,
+    zone: &Name,
+    qname: &Name,
+    qtype: QueryType,
+) -> Result<DnsPacket, String> {
+    let started = Instant::now();
+    let reply = match server.proxy {
+        Some(ref proxy) => lookup_tcp(qname, qtype, server.addr, Some(proxy)),
+        None => lookup_from(qname, qtype, server.addr, server.source).and_then(|reply| {
+            if reply.header.truncation {
+                lookup_tcp(qname, qtype, server.addr, None)
+            } else {
+                Ok(reply)
+            }
+        }),
+    };
+    match reply {
+        Ok(_) => {
+            let rtt = started.elapsed();
+            health.record_success(server.addr, rtt);
+            latencies.record(server.addr, zone, rtt);
+        }
+        Err(_) => health.record_failure(server.addr),
+    }
+    reply
+}
+
+// Whether another upstream might do better than reply.
+fn retryable(reply: &DnsPacket) -> bool {
+    matches!(
+        reply.header.response_code,
+        ResponseCode::SERVFAIL | ResponseCode::REFUSED
+    )
+}
+
+// Sends queries on to upstream resolvers, picked by forwarding rule. Queries
+// no rule matches are passed down the chain untouched.
+pub struct Forwarder {
+    routes: Vec<Route>,
+    health: Arc<UpstreamHealth>,
+    latencies: Arc<Latencies>,
+    rng: Mutex<Rng>,
+}
+
+impl Forwarder {
+    pub fn new(
+        rules: Vec<ForwardRule>,
+        health: Arc<UpstreamHealth>,
+        latencies: Arc<Latencies>,
+    ) -> Forwarder {
+        Forwarder {
+            routes: rules
+                .into_iter()
+                .filter(|rule| !rule.servers.is_empty())
+                .map(Route::new)
+                .collect(),
+            health,
+            latencies,
+            rng: Mutex::new(Rng::new()),
+        }
+    }
+
+    // Asks servers one after the other until one answers with something
+    // other than SERVFAIL or REFUSED. If none does, the last of those is the
+    // answer.
+    fn ask_in_turn(
+        &self,
+        servers: Vec<Upstream>,
+        zone: &Name,
+        qname: &Name,
+        qtype: QueryType,
+    ) -> Option<DnsPacket> {
+        let mut fallback = None;
+        for server in servers.iter() {
+            match ask(&self.health, &self.latencies, server, zone, qname, qtype) {
+                Ok(packet) if retryable(&packet) => fallback = Some(packet),
+                Ok(packet) => return Some(packet),
+                Err(_) => {}
+            }
+        }
+        fallback
+    }
+
+    // Asks servers in turn, starting the next one when the one before has
+    // failed or has had ATTEMPT_DELAY to answer, and returns the first
+    // answer, SERVFAIL and REFUSED only if nothing better comes. Queries
+    // still out by then are left to finish on their own, so their round trip
+    // times are still measured.
+    fn race(
+        &self,
+        servers: Vec<Upstream>,
+        zone: &Name,
+        qname: &Name,
+        qtype: QueryType,
+    ) -> Option<DnsPacket> {
+        let (sender, receiver) = mpsc::channel();
+        let mut servers = servers.into_iter();
+        let mut pending = 0;
+        let mut fallback = None;
+        loop {
+            let next = servers.next();
+            let more = next.is_some();
+            if let Some(server) = next {
+                let (sender, health, latencies) =
+                    (sender.clone(), self.health.clone(), self.latencies.clone());
+                let (zone, qname) = (zone.clone(), qname.clone());
+                thread::spawn(move || {
+                    let reply = ask(&health, &latencies, &server, &zone, &qname, qtype);
+                    let _ = sender.send(reply);
+                });
+                pending += 1;
+            } else if pending == 0 {
+                return fallback;
+            }
+
+            let reply = if more {
+                match receiver.recv_timeout(ATTEMPT_DELAY) {
+                    Ok(reply) => reply,
+                    Err(_) => continue,
+                }
+            } else {
+                receiver.recv().ok()?
+            };
+            match reply {
+                Ok(packet) if retryable(&packet) => {
+                    fallback = Some(packet);
+                    pending -= 1;
+                }
+                Ok(packet) => return Some(packet),
+                Err(_) => pending -= 1,
+            }
+        }
+    }
+
+    // The rule with the longest domain qname is in, of those for every
+    // client and for policy; of two with the same domain the policy's.
+    // Asks servers two at a time, in order, until two of them have answered
+    // with something other than SERVFAIL or REFUSED, and compares the two
+    // answers. Without a second answer to compare the first with, strict
+    // gives up.
+    fn cross_check(
+        &self,
+        mode: CrossCheck,
+        servers: Vec<Upstream>,
+        zone: &Name,
+        qname: &Name,
+        qtype: QueryType,
+    ) -> Option<DnsPacket> {
+        let mut answers: Vec<(SocketAddr, DnsPacket)> = Vec::new();
+        let mut fallback = None;
+        let mut servers = servers.iter();
+        while answers.len() < 2 {
+            let batch: Vec<&Upstream> = servers.by_ref().take(2 - answers.len()).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let replies: Vec<Result<DnsPacket, String>> = thread::scope(|scope| {
+                let asks: Vec<_> = batch
+                    .iter()
+                    .map(|server| {
+                        scope.spawn(|| {
+                            ask(&self.health, &self.latencies, server, zone, qname, qtype)
+                        })
+                    })
+                    .collect();
+                asks.into_iter()
+                    .map(|ask| ask.join().expect("query thread panicked"))
+                    .collect()
+            });
+            for (server, reply) in batch.iter().zip(replies) {
+                match reply {
+                    Ok(packet) if retryable(&packet) => fallback = Some(packet),
+                    Ok(packet) => answers.push((server.addr, packet)),
+                    Err(_) => {}
+                }
+            }
+        }
+
+        let mut answers = answers.into_iter();
+        let (first, second) = match (answers.next(), answers.next()) {
+            (Some(first), Some(second)) => (first, second),
+            (Some((server, packet)), None) => {
+                if log::enabled(LogLevel::Info) {
+                    eprintln!(
+                        "forward: {} {:?}: only {} answered, nothing to check it against",
+                        anonymize::name(qname, Name::to_ascii),
+                        qtype,
+                        server
+                    );
+                }
+                return match mode {
+                    CrossCheck::Strict => None,
+                    CrossCheck::Flag => Some(packet),
+                };
+            }
+            _ => return fallback,
+        };
+        if compare::answers_agree(&first.1, &second.1, qtype) {
+            return Some(first.1);
+        }
+
+        if log::enabled(LogLevel::Info) {
+            eprintln!(
+                "forward: {} {:?}: {} and {} disagree:",
+                anonymize::name(qname, Name::to_ascii),
+                qtype,
+                first.0,
+                second.0
+            );
+            // the records would give the name away
+            if !anonymize::hides_names() {
+                for line in compare::diff(&first.1, &second.1) {
+                    eprintln!("  {}", line);
+                }
+            }
+        }
+        match mode {
+            CrossCheck::Strict => None,
+            CrossCheck::Flag => Some(first.1),
+        }
+    }
+
+    fn route(&self, qname: &Name, policy: Option<&ClientPolicy>) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| qname.is_subdomain_of(&route.rule.domain))
+            .filter(|route| {
+                route
+                    .rule
+                    .policy
+                    .as_ref()
+                    .is_none_or(|name| policy.is_some_and(|policy| policy.name == *name))
+            })
+            .max_by_key(|route| (route.rule.domain.label_count(), route.rule.policy.is_some()))
+    }
+}
+
+impl Handler for Forwarder {
+    fn name(&self) -> &str {
+        "forward"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let question = request.question;
+        let Some(route) = self.route(&question.name, request.policy) else {
+            next.run(request, response);
+            return;
+        };
+
+        if !request.recursion_allowed {
+            response.header.response_code = ResponseCode::REFUSED;
+            return;
+        }
+
+        if question.qtype == QueryType::ANY {
+            response.answers.push(minimal_any(&question.name));
+            return;
+        }
+
+        let order = route.order(&self.health, &self.latencies, &self.rng);
+        let mut budget = route
+            .rule
+            .retries
+            .map_or(order.len(), |retries| retries + 1);
+        if route.rule.cross_check.is_some() {
+            budget = budget.max(2);
+        }
+        let servers: Vec<Upstream> = order
+            .iter()
+            .take(budget)
+            .map(|&i| route.rule.servers[i].clone())
+            .collect();
+        let reply = if let Some(mode) = route.rule.cross_check {
+            self.cross_check(
+                mode,
+                servers,
+                &route.rule.domain,
+                &question.name,
+                question.qtype,
+            )
+        } else if route.rule.strategy == Strategy::HappyEyeballs {
+            self.race(servers, &route.rule.domain, &question.name, question.qtype)
+        } else {
+            self.ask_in_turn(servers, &route.rule.domain, &question.name, question.qtype)
+        };
+        // The upstream's rcode is the answer's, NXDOMAIN and SERVFAIL
+        // included. AA and RA stay ours: the answer isn't authoritative here,
+        // and recursion is what this server offers, whatever the upstream does.
+        match reply {
+            Some(packet) => {
+                response.header.response_code = packet.header.response_code;
+                if packet.answers.is_empty() {
+                    response
+                        .nameservers
+                        .extend(negative_soa(&packet.nameservers));
+                } else {
+                    response.answers = packet.answers;
+                }
+            }
+            None => response.header.response_code = ResponseCode::SERVFAIL,
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::thread::Scope;
+use std::time::Duration;
+
+use crate::hpack;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+
+// HTTP/2 (RFC 9113) over cleartext TCP with prior knowledge, for clients and
+// TLS-terminating proxies that send many requests over one connection. Each
+// request is answered on a thread of its own as soon as it is complete, so
+// responses go out in whatever order they are ready.
+//
+// A client may have MAX_CONCURRENT_STREAMS requests open at a time; further
+// streams are refused. Request bodies are limited to a DNS message by flow
+// control: the connection window is topped up as data arrives, but a
+// stream's never is. Responses wait for the client's windows. A connection
+// idle for IDLE_TIMEOUT is closed with a GOAWAY.
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAX_CONCURRENT_STREAMS: usize = 100;
+// also the window each stream starts with
+const MAX_BODY: usize = 65535;
+const MAX_HEADER_LIST_SIZE: usize = 16384;
+const HEADER_TABLE_SIZE: usize = 4096;
+// of what either side receives, unless told otherwise
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+const DEFAULT_WINDOW: i64 = 65535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+// Reads from stream for as long as what it sends matches the HTTP/2
+// preface, and returns what was read: PREFACE if it is an HTTP/2 client,
+// the start of an HTTP/1.1 request otherwise.
+pub fn read_preface(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut read = Vec::with_capacity(PREFACE.len());
+    let mut buf = [0; PREFACE.len()];
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        let n = stream.read(&mut buf[..PREFACE.len() - read.len()])?;
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+    Ok(read)
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+// Why the reading of a connection stopped.
+enum End {
+    // it was closed or broke
+    Gone,
+    // the client is owed a GOAWAY with this error code
+    GoAway(u32),
+}
+
+// The next frame, or None if nothing arrived for the read timeout.
+fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>, End> {
+    let mut head = [0; 9];
+    let mut filled = 0;
+    while filled < head.len() {
+        match reader.read(&mut head[filled..]) {
+            Ok(0) => return Err(End::Gone),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e)
+                if filled == 0
+                    && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return Ok(None);
+            }
+            Err(_) => return Err(End::Gone),
+        }
+    }
+
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    if len > DEFAULT_MAX_FRAME_SIZE {
+        return Err(End::GoAway(FRAME_SIZE_ERROR));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).map_err(|_| End::Gone)?;
+    Ok(Some(Frame {
+        kind: head[3],
+        flags: head[4],
+        stream_id: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7FFF_FFFF,
+        payload,
+    }))
+}
+
+// The payload of a DATA or HEADERS frame without its padding.
+fn unpadded(frame: &Frame) -> Result<&[u8], End> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&pad, rest) = frame
+        .payload
+        .split_first()
+        .ok_or(End::GoAway(FRAME_SIZE_ERROR))?;
+    let len = rest
+        .len()
+        .checked_sub(pad as usize)
+        .ok_or(End::GoAway(PROTOCOL_ERROR))?;
+    Ok(&rest[..len])
+}
+
+fn settings_frame() -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (id, value) in [
+        (SETTINGS_HEADER_TABLE_SIZE, HEADER_TABLE_SIZE),
+        (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+        (SETTINGS_INITIAL_WINDOW_SIZE, MAX_BODY),
+        (SETTINGS_MAX_FRAME_SIZE, DEFAULT_MAX_FRAME_SIZE),
+        (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE),
+    ] {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+    payload
+}
+
+// A request from the fields of its header block.
+fn request(headers: Vec<(String, String)>, body: Vec<u8>) -> Option<HttpRequest> {
+    let pseudo = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    let (method, path) = (pseudo(":method")?, pseudo(":path")?);
+    // :authority stands in for Host (RFC 9113 section 8.3.1)
+    let authority = pseudo(":authority");
+    let mut headers: Vec<(String, String)> = headers
+        .into_iter()
+        .filter(|(key, _)| !key.starts_with(':'))
+        .collect();
+    if let Some(authority) = authority
+        && !headers.iter().any(|(key, _)| key == "host")
+    {
+        headers.push(("host".to_string(), authority));
+    }
+    Some(HttpRequest::new(&method, &path, headers, body))
+}
+
+// A request whose body is still coming.
+struct Pending {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// The sending half of a connection, shared by the threads answering it.
+struct Output {
+    stream: TcpStream,
+    // how much the client will still take, on the whole connection and on
+    // each stream being answered
+    connection_window: i64,
+    windows: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    // nothing more can be sent
+    closed: bool,
+}
+
+impl Output {
+    fn frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        if self.closed {
+            return;
+        }
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        if self.stream.write_all(&frame).is_err() {
+            self.closed = true;
+        }
+    }
+
+    fn reset(&mut self, stream_id: u32, code: u32) {
+        self.windows.remove(&stream_id);
+        self.frame(RST_STREAM, 0, stream_id, &code.to_be_bytes());
+    }
+}
+
+struct Connection<'a> {
+    output: Mutex<Output>,
+    // signalled when a window grows or the connection closes
+    window_open: Condvar,
+    // requests being answered
+    active: AtomicUsize,
+    handle: &'a (dyn Fn(&HttpRequest) -> HttpResponse + Sync),
+}
+
+impl Connection<'_> {
+    fn respond(&self, stream_id: u32, response: HttpResponse) {
+        let status = response.status.to_string();
+        let length = response.body.len().to_string();
+        let names: Vec<String> = response
+            .headers
+            .iter()
+            .map(|(name, _)| name.to_ascii_lowercase())
+            .collect();
+        let mut fields = vec![
+            (":status", status.as_str()),
+            ("content-type", response.content_type.as_str()),
+            ("content-length", length.as_str()),
+        ];
+        fields.extend(
+            names
+                .iter()
+                .zip(response.headers.iter())
+                .map(|(name, (_, value))| (name.as_str(), value.as_str())),
+        );
+        let block = hpack::encode(&fields);
+
+        let mut output = self.output.lock().unwrap();
+        if !output.windows.contains_key(&stream_id) {
+            // reset by the client in the meantime
+            return;
+        }
+        let end_stream = if response.body.is_empty() {
+            END_STREAM
+        } else {
+            0
+        };
+        let max_frame_size = output.max_frame_size;
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let mut flags = if kind == HEADERS { end_stream } else { 0 };
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            output.frame(kind, flags, stream_id, chunk);
+            kind = CONTINUATION;
+        }
+
+        let mut body = &response.body[..];
+        while !body.is_empty() {
+            let window = loop {
+                let Some(&stream_window) = output.windows.get(&stream_id) else {
+                    return;
+                };
+                if output.closed {
+                    return;
+                }
+                let window = stream_window.min(output.connection_window);
+                if window > 0 {
+                    break window as usize;
+                }
+                output = self.window_open.wait(output).unwrap();
+            };
+            let len = body.len().min(window).min(output.max_frame_size);
+            let (chunk, rest) = body.split_at(len);
+            output.frame(
+                DATA,
+                if rest.is_empty() { END_STREAM } else { 0 },
+                stream_id,
+                chunk,
+            );
+            output.connection_window -= len as i64;
+            if let Some(stream_window) = output.windows.get_mut(&stream_id) {
+                *stream_window -= len as i64;
+            }
+            body = rest;
+        }
+        output.windows.remove(&stream_id);
+    }
+
+    // Answers the request on a thread of its own.
+    fn dispatch<'scope>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        stream_id: u32,
+        request: Option<HttpRequest>,
+    ) {
+        {
+            let mut output = self.output.lock().unwrap();
+            let window = output.initial_window;
+            output.windows.insert(stream_id, window);
+        }
+        self.active.fetch_add(1, Ordering::Relaxed);
+        scope.spawn(move || {
+            let response = match request {
+                Some(request) => (self.handle)(&request),
+                None => HttpResponse::error(400, "missing :method or :path"),
+            };
+            self.respond(stream_id, response);
+            self.active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    fn apply_settings(&self, payload: &[u8]) -> Result<(), End> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(End::GoAway(FRAME_SIZE_ERROR));
+        }
+        let mut output = self.output.lock().unwrap();
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return Err(End::GoAway(FLOW_CONTROL_ERROR));
+                    }
+                    // applies to the streams already open too
+                    let delta = value as i64 - output.initial_window;
+                    for window in output.windows.values_mut() {
+                        *window += delta;
+                    }
+                    output.initial_window = value as i64;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..(1 << 24)).contains(&(value as usize)) {
+                        return Err(End::GoAway(PROTOCOL_ERROR));
+                    }
+                    output.max_frame_size = value as usize;
+                }
+                // no pushes and no dynamic table for responses, so the rest
+                // don't matter
+                _ => {}
+            }
+        }
+        output.frame(SETTINGS, ACK, 0, &[]);
+        self.window_open.notify_all();
+        Ok(())
+    }
+
+    fn window_update(&self, frame: &Frame) -> Result<(), End> {
+        let Ok(increment) = <[u8; 4]>::try_from(&frame.payload[..]) else {
+            return Err(End::GoAway(FRAME_SIZE_ERROR));
+        };
+        let increment = (u32::from_be_bytes(increment) & 0x7FFF_FFFF) as i64;
+        let mut output = self.output.lock().unwrap();
+        if frame.stream_id == 0 {
+            output.connection_window += increment;
+            if increment == 0 {
+                return Err(End::GoAway(PROTOCOL_ERROR));
+            }
+            if output.connection_window > MAX_WINDOW {
+                return Err(End::GoAway(FLOW_CONTROL_ERROR));
+            }
+        } else if let Some(window) = output.windows.get_mut(&frame.stream_id) {
+            *window += increment;
+            if increment == 0 {
+                output.reset(frame.stream_id, PROTOCOL_ERROR);
+            } else if *window > MAX_WINDOW {
+                output.reset(frame.stream_id, FLOW_CONTROL_ERROR);
+            }
+        }
+        self.window_open.notify_all();
+        Ok(())
+    }
+
+    // Reads frames until the connection ends, starting the answer to every
+    // request that is complete.
+    fn read<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>, reader: &mut TcpStream) -> End {
+        let mut decoder = hpack::Decoder::new(HEADER_TABLE_SIZE, MAX_HEADER_LIST_SIZE);
+        let mut pending: HashMap<u32, Pending> = HashMap::new();
+        let mut last_stream_id = 0;
+        let mut going_away = false;
+
+        let mut step = || -> Result<(), End> {
+            let Some(frame) = read_frame(reader)? else {
+                if self.active.load(Ordering::Relaxed) == 0 && pending.is_empty() {
+                    return Err(End::GoAway(NO_ERROR));
+                }
+                return Ok(());
+            };
+            let id = frame.stream_id;
+
+            match frame.kind {
+                HEADERS => {
+                    let mut block = unpadded(&frame)?;
+                    if frame.flags & PRIORITY != 0 {
+                        block = block.get(5..).ok_or(End::GoAway(FRAME_SIZE_ERROR))?;
+                    }
+                    let mut block = block.to_vec();
+                    let mut end_headers = frame.flags & END_HEADERS != 0;
+                    while !end_headers {
+                        let next = read_frame(reader)?.ok_or(End::GoAway(PROTOCOL_ERROR))?;
+                        if next.kind != CONTINUATION || next.stream_id != id {
+                            return Err(End::GoAway(PROTOCOL_ERROR));
+                        }
+                        block.extend_from_slice(&next.payload);
+                        if block.len() > MAX_HEADER_LIST_SIZE {
+                            return Err(End::GoAway(PROTOCOL_ERROR));
+                        }
+                        end_headers = next.flags & END_HEADERS != 0;
+                    }
+                    // decoded even if the stream is refused, to keep the
+                    // table in step with the client's
+                    let headers = decoder
+                        .decode(&block)
+                        .map_err(|_| End::GoAway(COMPRESSION_ERROR))?;
+                    let end_stream = frame.flags & END_STREAM != 0;
+
+                    // trailers of a request whose body is coming
+                    if let Some(stream) = pending.remove(&id) {
+                        if !end_stream {
+                            return Err(End::GoAway(PROTOCOL_ERROR));
+                        }
+                        self.dispatch(scope, id, request(stream.headers, stream.body));
+                        return Ok(());
+                    }
+                    if id % 2 == 0 || id <= last_stream_id {
+                        return Err(End::GoAway(PROTOCOL_ERROR));
+                    }
+                    last_stream_id = id;
+                    if going_away {
+                        return Ok(());
+                    }
+                    if self.active.load(Ordering::Relaxed) + pending.len() >= MAX_CONCURRENT_STREAMS
+                    {
+                        self.output.lock().unwrap().reset(id, REFUSED_STREAM);
+                        return Ok(());
+                    }
+                    if end_stream {
+                        self.dispatch(scope, id, request(headers, Vec::new()));
+                    } else {
+                        pending.insert(
+                            id,
+                            Pending {
+                                headers,
+                                body: Vec::new(),
+                            },
+                        );
+                    }
+                }
+                DATA => {
+                    let data = unpadded(&frame)?;
+                    let mut output = self.output.lock().unwrap();
+                    // the connection window is given back right away, the
+                    // stream's never
+                    if !frame.payload.is_empty() {
+                        let increment = frame.payload.len() as u32;
+                        output.frame(WINDOW_UPDATE, 0, 0, &increment.to_be_bytes());
+                    }
+                    let Some(stream) = pending.get_mut(&id) else {
+                        if id > last_stream_id {
+                            return Err(End::GoAway(PROTOCOL_ERROR));
+                        }
+                        output.reset(id, STREAM_CLOSED);
+                        return Ok(());
+                    };
+                    if stream.body.len() + data.len() > MAX_BODY {
+                        pending.remove(&id);
+                        output.reset(id, FLOW_CONTROL_ERROR);
+                        return Ok(());
+                    }
+                    stream.body.extend_from_slice(data);
+                    drop(output);
+                    if frame.flags & END_STREAM != 0
+                        && let Some(stream) = pending.remove(&id)
+                    {
+                        self.dispatch(scope, id, request(stream.headers, stream.body));
+                    }
+                }
+                SETTINGS => {
+                    if id != 0 {
+                        return Err(End::GoAway(PROTOCOL_ERROR));
+                    }
+                    if frame.flags & ACK == 0 {
+                        self.apply_settings(&frame.payload)?;
+                    }
+                }
+                PING => {
+                    if id != 0 {
+                        return Err(End::GoAway(PROTOCOL_ERROR));
+                    }
+                    if frame.payload.len() != 8 {
+                        return Err(End::GoAway(FRAME_SIZE_ERROR));
+                    }
+                    if frame.flags & ACK == 0 {
+                        self.output
+                            .lock()
+                            .unwrap()
+                            .frame(PING, ACK, 0, &frame.payload);
+                    }
+                }
+                WINDOW_UPDATE => self.window_update(&frame)?,
+                RST_STREAM => {
+                    pending.remove(&id);
+                    self.output.lock().unwrap().windows.remove(&id);
+                    self.window_open.notify_all();
+                }
+                // the client is done; what it already asked is answered
+                GOAWAY => going_away = true,
+                PUSH_PROMISE | CONTINUATION => return Err(End::GoAway(PROTOCOL_ERROR)),
+                // PRIORITY and extensions
+                _ => {}
+            }
+            Ok(())
+        };
+
+        let end = loop {
+            if let Err(end) = step() {
+                break end;
+            }
+        };
+        if let End::GoAway(code) = end {
+            let mut payload = last_stream_id.to_be_bytes().to_vec();
+            payload.extend_from_slice(&code.to_be_bytes());
+            self.output.lock().unwrap().frame(GOAWAY, 0, 0, &payload);
+        }
+        end
+    }
+}
+
+// Serves an HTTP/2 connection whose preface has been read, answering every
+// request with handle.
+pub fn serve(stream: TcpStream, handle: &(dyn Fn(&HttpRequest) -> HttpResponse + Sync)) {
+    let (Ok(writer), Ok(())) = (
+        stream.try_clone(),
+        stream.set_read_timeout(Some(IDLE_TIMEOUT)),
+    ) else {
+        return;
+    };
+    let mut reader = stream;
+    let connection = Connection {
+        output: Mutex::new(Output {
+            stream: writer,
+            connection_window: DEFAULT_WINDOW,
+            windows: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            closed: false,
+        }),
+        window_open: Condvar::new(),
+        active: AtomicUsize::new(0),
+        handle,
+    };
+    connection
+        .output
+        .lock()
+        .unwrap()
+        .frame(SETTINGS, 0, 0, &settings_frame());
+
+    thread::scope(|scope| {
+        connection.read(scope, &mut reader);
+        // answers still waiting for a window won't get one
+        connection.output.lock().unwrap().closed = true;
+        connection.window_open.notify_all();
+    });
+    let _ = reader.shutdown(Shutdown::Both);
+}
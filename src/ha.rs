@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::cache::Cache;
+use crate::http;
+use crate::json::Json;
+use crate::log;
+use crate::log::LogLevel;
+use crate::name::Name;
+use crate::zone::LocalZones;
+use crate::zonefile;
+
+// How long the primary gets to answer a health check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long it gets to hand over its records or cache, which may be large.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the primary's data is copied while it's up.
+const REPLICATE_INTERVAL: Duration = Duration::from_secs(30);
+
+// The primary a secondary server stands by for:
+// "ha-primary http://<host>:<port> [interval=<seconds>] [failures=<n>]
+// [replicate=records|cache|all] [token=<token>] [hook=<path>]".
+#[derive(Debug, Clone, PartialEq)]
+pub struct HaConfig {
+    // "host:port" of the primary's api-listen, resolved when the server
+    // starts
+    pub authority: String,
+    pub interval: Duration,
+    // checks in a row that fail before this server takes over, and that
+    // succeed before it stands by again
+    pub failures: u32,
+    pub replicate_records: bool,
+    pub replicate_cache: bool,
+    // the primary's api-token, the server's own unless given
+    pub token: Option<String>,
+    // run with "active" or "standby" when the server takes over or stands by
+    pub hook: Option<PathBuf>,
+}
+
+impl HaConfig {
+    pub fn parse(url: &str) -> Result<HaConfig, String> {
+        let authority = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("ha-primary must be an http:// URL, not {:?}", url))?
+            .trim_end_matches('/');
+        let has_port = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed.contains("]:"),
+            None => authority.contains(':'),
+        };
+        if authority.contains('/') || !has_port {
+            return Err(format!(
+                "ha-primary must be http://<host>:<port> of the primary's api-listen, not {:?}",
+                url
+            ));
+        }
+        Ok(HaConfig {
+            authority: authority.to_string(),
+            interval: Duration::from_secs(2),
+            failures: 3,
+            replicate_records: false,
+            replicate_cache: false,
+            token: None,
+            hook: None,
+        })
+    }
+}
+
+// The records of a /records or /cache entry in the API's JSON.
+fn json_record(entry: &Json) -> Result<(DnsRecord, Option<u32>), String> {
+    let text = |key| {
+        entry
+            .get(key)
+            .and_then(Json::as_str)
+            .ok_or_else(|| format!("record without {:?}", key))
+    };
+    let ttl = entry
+        .get("ttl")
+        .and_then(Json::as_u64)
+        .ok_or("record without \"ttl\"")?;
+    let record = zonefile::parse_one(
+        text("name")?,
+        text("type")?,
+        &ttl.to_string(),
+        text("data")?,
+    )?;
+    let weight = entry
+        .get("weight")
+        .and_then(Json::as_u64)
+        .map(|weight| weight as u32);
+    Ok((record, weight))
+}
+
+// The same records, in any order.
+fn same_records(a: &[(DnsRecord, Option<u32>)], b: &[(DnsRecord, Option<u32>)]) -> bool {
+    a.len() == b.len()
+        && a.iter().all(|record| b.contains(record))
+        && b.iter().all(|record| a.contains(record))
+}
+
+// A secondary server: it answers REFUSED, so clients go to the primary, as
+// long as the primary's /health answers, and serves queries itself once it
+// doesn't. While the primary is up its zones and records, and its cache,
+// can be copied, so the secondary takes over with the same data.
+pub struct Standby {
+    addr: SocketAddr,
+    config: HaConfig,
+    zones: Arc<LocalZones>,
+    cache: Arc<Cache>,
+    active: AtomicBool,
+}
+
+impl Standby {
+    // Resolves the primary's address, so it has to run while the resolver
+    // configuration is still reachable.
+    pub fn new(
+        config: HaConfig,
+        zones: Arc<LocalZones>,
+        cache: Arc<Cache>,
+    ) -> Result<Standby, String> {
+        let addr = config
+            .authority
+            .to_socket_addrs()
+            .map_err(|e| format!("ha-primary {}: {}", config.authority, e))?
+            .next()
+            .ok_or_else(|| format!("ha-primary {}: no address", config.authority))?;
+        Ok(Standby {
+            addr,
+            config,
+            zones,
+            cache,
+            active: AtomicBool::new(false),
+        })
+    }
+
+    // Whether this server answers queries, which it does while the primary
+    // is down.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn primary_up(&self) -> bool {
+        http::get(
+            self.addr,
+            &self.config.authority,
+            "/health",
+            &[],
+            CHECK_TIMEOUT,
+        )
+        .is_ok_and(|reply| reply.status == 200)
+    }
+
+    fn fetch(&self, path: &str) -> Result<Json, String> {
+        let authorization = self
+            .config
+            .token
+            .as_ref()
+            .map(|token| format!("Bearer {}", token));
+        let headers: Vec<(&str, &str)> = authorization
+            .iter()
+            .map(|authorization| ("Authorization", authorization.as_str()))
+            .collect();
+        let reply = http::get(
+            self.addr,
+            &self.config.authority,
+            path,
+            &headers,
+            FETCH_TIMEOUT,
+        )?;
+        if reply.status != 200 {
+            return Err(format!("{} answered {}", path, reply.status));
+        }
+        Json::parse(&String::from_utf8_lossy(&reply.body)).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Makes the zones and configured records the primary's: the names whose
+    // records differ get the primary's instead. Records from leases and the
+    // generated ones are each server's own.
+    fn replicate_records(&self) -> Result<(), String> {
+        let apexes = self
+            .fetch("/zones")?
+            .as_array()
+            .ok_or("/zones: not an array")?
+            .iter()
+            .map(|apex| Name::from_unicode(apex.as_str().ok_or("/zones: not a name")?))
+            .collect::<Result<Vec<Name>, String>>()?;
+        let mut theirs: BTreeMap<Name, Vec<(DnsRecord, Option<u32>)>> = BTreeMap::new();
+        for entry in self
+            .fetch("/records")?
+            .as_array()
+            .ok_or("/records: not an array")?
+        {
+            if entry.get("generated").is_some() || entry.get("leased").is_some() {
+                continue;
+            }
+            let (record, weight) = json_record(entry)?;
+            theirs
+                .entry(record.domain().clone())
+                .or_default()
+                .push((record, weight));
+        }
+
+        let ours = self.zones.zones();
+        for apex in apexes.iter().filter(|apex| !ours.contains(apex)) {
+            self.zones.add_zone(apex.clone())?;
+        }
+        for apex in ours.iter().filter(|apex| !apexes.contains(apex)) {
+            self.zones.remove_zone(apex)?;
+        }
+
+        let mut ours: BTreeMap<Name, Vec<(DnsRecord, Option<u32>)>> = BTreeMap::new();
+        for entry in self.zones.entries() {
+            if !entry.generated && !entry.leased {
+                ours.entry(entry.record.domain().clone())
+                    .or_default()
+                    .push((entry.record, entry.weight));
+            }
+        }
+
+        let owners: BTreeSet<Name> = theirs.keys().chain(ours.keys()).cloned().collect();
+        let mut changed = 0;
+        for owner in owners {
+            let records = theirs.remove(&owner).unwrap_or_default();
+            if same_records(&records, &ours.remove(&owner).unwrap_or_default()) {
+                continue;
+            }
+            self.zones.remove(&owner, None)?;
+            for (record, weight) in records {
+                self.zones.add(record, weight)?;
+            }
+            changed += 1;
+        }
+        if changed > 0 && log::enabled(LogLevel::Info) {
+            eprintln!("ha-primary: copied the records of {} names", changed);
+        }
+        Ok(())
+    }
+
+    // Fills the cache with the primary's answers, with the TTLs they have
+    // left there. Entries that can't be read are skipped.
+    fn replicate_cache(&self) -> Result<(), String> {
+        let mut copied = 0;
+        for entry in self
+            .fetch("/cache")?
+            .as_array()
+            .ok_or("/cache: not an array")?
+        {
+            let (Some(name), Some(qtype), Some(answers)) = (
+                entry.get("name").and_then(Json::as_str),
+                entry.get("type").and_then(Json::as_str),
+                entry.get("answers").and_then(Json::as_array),
+            ) else {
+                continue;
+            };
+            let qtype = QueryType::from_name(qtype);
+            let Ok(answers) = answers
+                .iter()
+                .map(|answer| json_record(answer).map(|(record, _)| record))
+                .collect::<Result<Vec<DnsRecord>, String>>()
+            else {
+                continue;
+            };
+            if let (Ok(name), false) = (
+                Name::from_unicode(name),
+                matches!(qtype, QueryType::UNKNOWN(_)),
+            ) {
+                self.cache.insert(&name, qtype.to_num(), answers);
+                copied += 1;
+            }
+        }
+        if log::enabled(LogLevel::Debug) {
+            eprintln!("ha-primary: copied {} cache entries", copied);
+        }
+        Ok(())
+    }
+
+    fn replicate(&self) {
+        if self.config.replicate_records
+            && let Err(e) = self.replicate_records()
+            && log::enabled(LogLevel::Info)
+        {
+            eprintln!("ha-primary: copying records: {}", e);
+        }
+        if self.config.replicate_cache
+            && let Err(e) = self.replicate_cache()
+            && log::enabled(LogLevel::Info)
+        {
+            eprintln!("ha-primary: copying the cache: {}", e);
+        }
+    }
+
+    fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+        let (state, what) = if active {
+            ("active", "is down, answering queries")
+        } else {
+            ("standby", "is back, standing by")
+        };
+        eprintln!("ha-primary {} {}", self.config.authority, what);
+
+        if let Some(hook) = &self.config.hook {
+            match Command::new(hook).arg(state).status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => eprintln!("ha-primary hook {}: {}", hook.display(), status),
+                Err(e) => eprintln!("ha-primary hook {}: {}", hook.display(), e),
+            }
+        }
+    }
+
+    // Checks the primary every interval until the server stops, taking over
+    // or standing by after as many checks in a row as failures says. The
+    // data is copied every REPLICATE_INTERVAL while the primary is up, and
+    // once more before standing by again.
+    pub fn run(&self) {
+        let mut streak = 0;
+        let mut replicated: Option<Instant> = None;
+        loop {
+            let up = self.primary_up();
+            // a primary that's up while this server is active, or down
+            // while it stands by, is a reason to change over
+            if up == self.is_active() {
+                streak += 1;
+            } else {
+                streak = 0;
+            }
+            if streak >= self.config.failures {
+                streak = 0;
+                if up {
+                    self.replicate();
+                    replicated = Some(Instant::now());
+                }
+                self.set_active(!up);
+            }
+
+            if up
+                && !self.is_active()
+                && replicated.is_none_or(|at| at.elapsed() >= REPLICATE_INTERVAL)
+            {
+                self.replicate();
+                replicated = Some(Instant::now());
+            }
+            thread::sleep(self.config.interval);
+        }
+    }
+}
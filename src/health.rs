@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::QueryType;
+use crate::client::lookup;
+use crate::name::Name;
+
+// Consecutive failures after which an upstream is taken out of rotation.
+const FAILURE_THRESHOLD: u32 = 3;
+
+// How often upstreams that are out of rotation are probed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerHealth {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    // smoothed round trip time, None until the first reply
+    pub srtt: Option<Duration>,
+    // set while the server is out of rotation
+    pub down_since: Option<Instant>,
+}
+
+// Success rate and round trip times of every upstream queried so far. An
+// upstream failing FAILURE_THRESHOLD times in a row is marked down until a
+// background probe gets an answer out of it again.
+pub struct UpstreamHealth {
+    servers: Mutex<HashMap<SocketAddr, ServerHealth>>,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> UpstreamHealth {
+        UpstreamHealth {
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_success(&self, server: SocketAddr, rtt: Duration) {
+        let mut servers = self.servers.lock().unwrap();
+        let health = servers.entry(server).or_default();
+
+        health.successes += 1;
+        health.consecutive_failures = 0;
+        health.down_since = None;
+        health.srtt = Some(match health.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    pub fn record_failure(&self, server: SocketAddr) {
+        let mut servers = self.servers.lock().unwrap();
+        let health = servers.entry(server).or_default();
+
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= FAILURE_THRESHOLD && health.down_since.is_none() {
+            health.down_since = Some(Instant::now());
+        }
+    }
+
+    pub fn is_up(&self, server: SocketAddr) -> bool {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(&server)
+            .is_none_or(|health| health.down_since.is_none())
+    }
+
+    pub fn snapshot(&self) -> Vec<(SocketAddr, ServerHealth)> {
+        let mut servers: Vec<(SocketAddr, ServerHealth)> = self
+            .servers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(server, health)| (*server, *health))
+            .collect();
+        servers.sort_by_key(|(server, _)| *server);
+        servers
+    }
+
+    // Asks every down upstream for the root NS set every PROBE_INTERVAL and
+    // puts those that answer back into rotation. Never returns.
+    pub fn probe_loop(&self) {
+        loop {
+            thread::sleep(PROBE_INTERVAL);
+
+            let down: Vec<SocketAddr> = self
+                .snapshot()
+                .into_iter()
+                .filter(|(_, health)| health.down_since.is_some())
+                .map(|(server, _)| server)
+                .collect();
+
+            for server in down {
+                let started = Instant::now();
+                if lookup(&Name::root(), QueryType::NS, server).is_ok() {
+                    self.record_success(server, started.elapsed());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt::Display;
+use std::fmt::Write;
+
+use crate::BufHandler;
+use crate::DnsRecord;
+use crate::OpCode;
+use crate::QueryClass;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::log;
+use crate::log::LogLevel;
+use crate::name::escape_label;
+
+// Annotated hexdumps of DNS messages for the trace log: every field on a line
+// of its own with its offset, its bytes and what they decode to, e.g.
+//
+//     000c  03 77 77 77               qname www.example.com: label "www"
+//     0010  07 65 78 61 6d 70 6c 65   label "example"
+//     ...
+//
+// Meant for looking at what odd clients and servers actually send, so the
+// walk doesn't give up on a malformed message before showing how far it got.
+
+// bytes shown per line
+const LINE_BYTES: usize = 8;
+
+struct Dump<'a> {
+    data: &'a [u8],
+    pos: usize,
+    out: String,
+}
+
+impl<'a> Dump<'a> {
+    // Adds lines for bytes at the current position, the first one described.
+    fn line(&mut self, bytes: &[u8], description: &str) {
+        let chunks: Vec<&[u8]> = if bytes.is_empty() {
+            vec![bytes]
+        } else {
+            bytes.chunks(LINE_BYTES).collect()
+        };
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let line = format!(
+                "  {:04x}  {:<width$}  {}",
+                self.pos + i * LINE_BYTES,
+                hex.join(" "),
+                if i == 0 { description } else { "" },
+                width = LINE_BYTES * 3 - 1
+            );
+            let _ = writeln!(self.out, "{}", line.trim_end());
+        }
+    }
+
+    // Adds the next len bytes as one field.
+    fn field(&mut self, len: usize, description: &str) -> Result<(), String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or("End of buffer")?;
+        self.line(bytes, description);
+        self.pos += len;
+        Ok(())
+    }
+
+    fn peek_u16(&self) -> Result<u16, String> {
+        self.data
+            .get(self.pos..self.pos + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| "End of buffer".to_string())
+    }
+
+    fn peek_u32(&self) -> Result<u32, String> {
+        self.data
+            .get(self.pos..self.pos + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .ok_or_else(|| "End of buffer".to_string())
+    }
+
+    fn u16_field(&mut self, describe: impl FnOnce(u16) -> String) -> Result<u16, String> {
+        let value = self.peek_u16()?;
+        self.field(2, &describe(value))?;
+        Ok(value)
+    }
+
+    // A name label by label, up to its end or its compression pointer. The
+    // first line says what the name is for and the whole name.
+    fn name(&mut self, what: &str) -> Result<(), String> {
+        let mut prefix = format!("{} {}: ", what, name_at(self.data, self.pos));
+        loop {
+            let len = *self.data.get(self.pos).ok_or("End of buffer")? as usize;
+            if len == 0 {
+                return self.field(1, &format!("{}root", prefix));
+            }
+            if len & 0xC0 == 0xC0 {
+                let pointer = self.peek_u16()? as usize & 0x3FFF;
+                return self.field(
+                    2,
+                    &format!(
+                        "{}pointer to {:04x} ({})",
+                        prefix,
+                        pointer,
+                        name_at(self.data, pointer)
+                    ),
+                );
+            }
+            if len & 0xC0 != 0 {
+                return Err("Unsupported label type".to_string());
+            }
+            let label = self
+                .data
+                .get(self.pos + 1..self.pos + 1 + len)
+                .ok_or("End of buffer")?;
+            let mut text = String::new();
+            escape_label(label, &mut text);
+            self.field(1 + len, &format!("{}label \"{}\"", prefix, text))?;
+            prefix.clear();
+        }
+    }
+
+    fn question(&mut self) -> Result<(), String> {
+        self.name("qname")?;
+        self.u16_field(|qtype| format!("qtype {}", type_name(qtype)))?;
+        self.u16_field(|qclass| format!("qclass {}", class_name(qclass)))?;
+        Ok(())
+    }
+
+    fn record(&mut self, section: &str) -> Result<(), String> {
+        let start = self.pos;
+        self.name(&format!("{} record", section))?;
+        let rtype = self.u16_field(|rtype| format!("type {}", type_name(rtype)))?;
+        if rtype == QueryType::OPT.to_num() {
+            return self.opt();
+        }
+        self.u16_field(|class| format!("class {}", class_name(class)))?;
+        let ttl = self.peek_u32()?;
+        self.field(4, &format!("ttl {}", ttl))?;
+        let len = self.u16_field(|len| format!("rdata length {}", len))? as usize;
+        if len == 0 {
+            return Ok(());
+        }
+
+        // the parser decodes the rdata, compressed names and all
+        let mut buf_handler = BufHandler::from_bytes(self.data);
+        buf_handler.seek(start);
+        let description = match DnsRecord::read(&mut buf_handler) {
+            Ok(DnsRecord::UNKNOWN { .. }) | Err(_) => "rdata".to_string(),
+            Ok(record) => format!("rdata {}", record.rdata_string()),
+        };
+        self.field(len, &description)
+    }
+
+    // The rest of an OPT record (RFC 6891), whose class and TTL fields are
+    // used for EDNS.
+    fn opt(&mut self) -> Result<(), String> {
+        self.u16_field(|size| format!("udp payload size {}", size))?;
+        let extended = *self.data.get(self.pos).ok_or("End of buffer")?;
+        self.field(1, &format!("extended rcode {}", extended))?;
+        let version = *self.data.get(self.pos).ok_or("End of buffer")?;
+        self.field(1, &format!("edns version {}", version))?;
+        self.u16_field(|flags| {
+            if flags & 0x8000 != 0 {
+                "edns flags do".to_string()
+            } else {
+                "edns flags".to_string()
+            }
+        })?;
+        let len = self.u16_field(|len| format!("rdata length {}", len))? as usize;
+
+        let end = self.pos + len;
+        while self.pos < end {
+            let code = self.u16_field(|code| format!("option {}", option_name(code)))?;
+            let len = self.u16_field(|len| format!("option length {}", len))? as usize;
+            if self.pos + len > end {
+                return Err("Option runs past the record".to_string());
+            }
+            if len > 0 {
+                self.field(len, &format!("{} data", option_name(code)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The name at offset, for describing pointers and whole names.
+fn name_at(data: &[u8], offset: usize) -> String {
+    let mut buf_handler = BufHandler::from_bytes(data);
+    buf_handler.seek(offset);
+    match buf_handler.read_name() {
+        Ok(name) if name.is_root() => ".".to_string(),
+        Ok(name) => name.to_ascii(),
+        Err(e) => format!("unreadable: {}", e),
+    }
+}
+
+fn type_name(num: u16) -> String {
+    match QueryType::from_num(num) {
+        QueryType::UNKNOWN(_) => format!("TYPE{}", num),
+        qtype => qtype.name().to_string(),
+    }
+}
+
+fn class_name(num: u16) -> String {
+    match QueryClass::from_num(num) {
+        QueryClass::UNKNOWN(_) => format!("CLASS{}", num),
+        qclass => format!("{:?}", qclass),
+    }
+}
+
+fn option_name(code: u16) -> String {
+    match code {
+        3 => "NSID".to_string(),
+        8 => "client subnet".to_string(),
+        10 => "cookie".to_string(),
+        11 => "tcp keepalive".to_string(),
+        12 => "padding".to_string(),
+        15 => "extended error".to_string(),
+        _ => format!("code {}", code),
+    }
+}
+
+fn flags_description(flags: u16) -> String {
+    let mut parts = vec![
+        if flags & 0x8000 != 0 {
+            "response"
+        } else {
+            "query"
+        }
+        .to_string(),
+        format!("opcode {:?}", OpCode::from_num((flags >> 11 & 0xF) as u8)),
+    ];
+    for (bit, flag) in [
+        (10, "aa"),
+        (9, "tc"),
+        (8, "rd"),
+        (7, "ra"),
+        (6, "z"),
+        (5, "ad"),
+        (4, "cd"),
+    ] {
+        if flags >> bit & 1 == 1 {
+            parts.push(flag.to_string());
+        }
+    }
+    parts.push(format!("rcode {:?}", ResponseCode::from_num(flags & 0xF)));
+    parts.join(" ")
+}
+
+fn walk(dump: &mut Dump) -> Result<(), String> {
+    dump.u16_field(|id| format!("id {}", id))?;
+    dump.u16_field(|flags| format!("flags {}", flags_description(flags)))?;
+    let mut counts = [0; 4];
+    for (count, section) in
+        counts
+            .iter_mut()
+            .zip(["questions", "answers", "authority", "additional"])
+    {
+        *count = dump.u16_field(|count| format!("{} {}", section, count))?;
+    }
+
+    for _ in 0..counts[0] {
+        dump.question()?;
+    }
+    for (section, count) in ["answer", "authority", "additional"]
+        .into_iter()
+        .zip(&counts[1..])
+    {
+        for _ in 0..*count {
+            dump.record(section)?;
+        }
+    }
+    Ok(())
+}
+
+// The message in data as an annotated hexdump, one line per field. Whatever
+// can't be made sense of is dumped at the end with the reason.
+pub fn annotate(data: &[u8]) -> String {
+    let mut dump = Dump {
+        data,
+        pos: 0,
+        out: String::new(),
+    };
+    match walk(&mut dump) {
+        Err(e) => dump.line(&data[dump.pos..], &format!("unparsed: {}", e)),
+        Ok(()) if dump.pos < data.len() => dump.line(&data[dump.pos..], "trailing data"),
+        Ok(()) => {}
+    }
+    dump.out
+}
+
+// Writes data to stderr at the trace level, headed by what it is and who it
+// is from or for, e.g. "query from 192.0.2.1:5353 over udp".
+pub fn trace(what: &str, peer: impl Display, transport: &str, data: &[u8]) {
+    if !log::enabled(LogLevel::Trace) {
+        return;
+    }
+    // one write, so dumps from different threads don't interleave
+    eprint!(
+        "{} {} over {}, {} bytes\n{}",
+        what,
+        peer,
+        transport,
+        data.len(),
+        annotate(data)
+    );
+}
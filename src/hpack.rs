@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+// HPACK (RFC 7541), the header compression of HTTP/2. The decoder handles
+// everything a client may send; the encoder never adds to the client's
+// dynamic table and never uses Huffman coding, which keeps it trivial at the
+// cost of a few bytes per response.
+
+// RFC 7541 appendix A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Code lengths of the Huffman code of RFC 7541 appendix B, by symbol; 256 is
+// EOS. The code is canonical, so the lengths are all it takes to rebuild it.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, //
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28, //
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, //
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, //
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, //
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, //
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, //
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, //
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, //
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, //
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, //
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23, //
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, //
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, //
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, //
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, //
+    30,
+];
+
+const MAX_CODE_LEN: usize = 30;
+
+// The canonical code laid out for decoding: the symbols ordered by code, and
+// per length the first code of that length, how many there are and where
+// they start in the symbol order.
+struct HuffmanCode {
+    symbols: Vec<u16>,
+    first_code: [u32; MAX_CODE_LEN + 1],
+    count: [u32; MAX_CODE_LEN + 1],
+    first_index: [usize; MAX_CODE_LEN + 1],
+}
+
+fn huffman_code() -> &'static HuffmanCode {
+    static CODE: OnceLock<HuffmanCode> = OnceLock::new();
+    CODE.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..HUFFMAN_LENGTHS.len() as u16).collect();
+        symbols.sort_by_key(|&symbol| HUFFMAN_LENGTHS[symbol as usize]);
+
+        let mut count = [0; MAX_CODE_LEN + 1];
+        for &len in HUFFMAN_LENGTHS.iter() {
+            count[len as usize] += 1;
+        }
+        let mut first_code = [0; MAX_CODE_LEN + 1];
+        let mut first_index = [0; MAX_CODE_LEN + 1];
+        let (mut code, mut index) = (0, 0);
+        for len in 1..=MAX_CODE_LEN {
+            code = (code + count[len - 1]) << 1;
+            first_code[len] = code;
+            first_index[len] = index;
+            index += count[len] as usize;
+        }
+        HuffmanCode {
+            symbols,
+            first_code,
+            count,
+            first_index,
+        }
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    let huffman = huffman_code();
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0usize);
+    for byte in data {
+        for shift in (0..8).rev() {
+            code = code << 1 | (*byte as u32 >> shift & 1);
+            len += 1;
+            if len > MAX_CODE_LEN {
+                return Err("bad huffman code".to_string());
+            }
+            let offset = code.wrapping_sub(huffman.first_code[len]);
+            if offset < huffman.count[len] {
+                let symbol = huffman.symbols[huffman.first_index[len] + offset as usize];
+                if symbol == 256 {
+                    return Err("EOS in huffman string".to_string());
+                }
+                out.push(symbol as u8);
+                (code, len) = (0, 0);
+            }
+        }
+    }
+    // padding is the start of EOS, all ones, shorter than a byte
+    if len >= 8 || code != (1 << len) - 1 {
+        return Err("bad huffman padding".to_string());
+    }
+    Ok(out)
+}
+
+// An integer with an n bit prefix (RFC 7541 section 5.1), the prefix being
+// the low bits of data[*pos].
+fn integer(data: &[u8], pos: &mut usize, prefix_bits: u32) -> Result<usize, String> {
+    let max = (1usize << prefix_bits) - 1;
+    let first = *data.get(*pos).ok_or("header block cut short")? as usize & max;
+    *pos += 1;
+    if first < max {
+        return Ok(first);
+    }
+    let mut value = max;
+    for shift in (0..28).step_by(7) {
+        let byte = *data.get(*pos).ok_or("header block cut short")? as usize;
+        *pos += 1;
+        value += (byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("header integer too large".to_string())
+}
+
+fn string(data: &[u8], pos: &mut usize) -> Result<Vec<u8>, String> {
+    let huffman = *data.get(*pos).ok_or("header block cut short")? & 0x80 != 0;
+    let len = integer(data, pos, 7)?;
+    let bytes = data.get(*pos..*pos + len).ok_or("header block cut short")?;
+    *pos += len;
+    if huffman {
+        huffman_decode(bytes)
+    } else {
+        Ok(bytes.to_vec())
+    }
+}
+
+fn entry_size(name: &[u8], value: &[u8]) -> usize {
+    name.len() + value.len() + 32
+}
+
+// The decoding context of one connection. Header blocks must be decoded in
+// the order they arrive, including those of refused streams.
+pub struct Decoder {
+    // newest first
+    dynamic: VecDeque<(Vec<u8>, Vec<u8>)>,
+    size: usize,
+    max_size: usize,
+    // the table size the peer was told it may use
+    limit: usize,
+    // decoded size a header list may have
+    max_list_size: usize,
+}
+
+impl Decoder {
+    pub fn new(limit: usize, max_list_size: usize) -> Decoder {
+        Decoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+            max_list_size,
+        }
+    }
+
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.dynamic.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&name, &value);
+        }
+    }
+
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let size = entry_size(&name, &value);
+        self.evict(size);
+        // an entry larger than the table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic.push_front((name, value));
+        }
+    }
+
+    fn entry(&self, index: usize) -> Result<(Vec<u8>, Vec<u8>), String> {
+        match index {
+            0 => Err("header index 0".to_string()),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .dynamic
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| format!("header index {} out of range", index)),
+        }
+    }
+
+    // The header list in a complete header block.
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, String> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let first = block[pos];
+            let (name, value) = if first & 0x80 != 0 {
+                self.entry(integer(block, &mut pos, 7)?)?
+            } else if first & 0xE0 == 0x20 {
+                let size = integer(block, &mut pos, 5)?;
+                if size > self.limit {
+                    return Err("header table size over the limit".to_string());
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // with incremental indexing, without, or never indexed
+                let indexed = first & 0xC0 == 0x40;
+                let index = integer(block, &mut pos, if indexed { 6 } else { 4 })?;
+                let name = match index {
+                    0 => string(block, &mut pos)?,
+                    index => self.entry(index)?.0,
+                };
+                let value = string(block, &mut pos)?;
+                if indexed {
+                    self.insert(name.clone(), value.clone());
+                }
+                (name, value)
+            };
+
+            list_size += entry_size(&name, &value);
+            if list_size > self.max_list_size {
+                return Err("header list too large".to_string());
+            }
+            headers.push((
+                String::from_utf8_lossy(&name).into_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ));
+        }
+        Ok(headers)
+    }
+}
+
+fn encode_integer(out: &mut Vec<u8>, first: u8, prefix_bits: u32, value: usize) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        out.push(rest as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn encode_string(out: &mut Vec<u8>, text: &str) {
+    encode_integer(out, 0, 7, text.len());
+    out.extend_from_slice(text.as_bytes());
+}
+
+// headers as a header block: fields the static table has whole are indexed,
+// the rest literals without indexing. Names must be lowercase.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(name, value) in headers {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|entry| *entry == (name, value))
+        {
+            encode_integer(&mut out, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(known, _)| *known == name) {
+            Some(index) => encode_integer(&mut out, 0, 4, index + 1),
+            None => {
+                out.push(0);
+                encode_string(&mut out, name);
+            }
+        }
+        encode_string(&mut out, value);
+    }
+    out
+}
@@ -0,0 +1,328 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::json::Json;
+
+const MAX_HEADER_LINES: usize = 100;
+const MAX_BODY: usize = 1 << 20;
+// Largest response get() reads; a whole cache dump can be that big.
+const MAX_RESPONSE: usize = 64 << 20;
+
+// A minimal HTTP request, enough for the management API and the DNS JSON API.
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl HttpRequest {
+    // A request for target, the path with its query string. Header names
+    // must be lowercase.
+    pub fn new(
+        method: &str,
+        target: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> HttpRequest {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        HttpRequest {
+            method: method.to_string(),
+            path: percent_decode(path),
+            query: query.to_string(),
+            headers,
+            body,
+        }
+    }
+
+    pub fn read(stream: impl Read) -> Result<HttpRequest, String> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(_version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("bad request line {:?}", line.trim_end()));
+        };
+
+        let mut request = HttpRequest::new(method, target, Vec::new(), Vec::new());
+
+        loop {
+            line.clear();
+            reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if request.headers.len() >= MAX_HEADER_LINES {
+                return Err("too many headers".to_string());
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                request
+                    .headers
+                    .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+
+        if let Some(len) = request.header("content-length") {
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| "bad content-length".to_string())?;
+            if len > MAX_BODY {
+                return Err("body too large".to_string());
+            }
+            request.body = vec![0; len];
+            reader
+                .read_exact(&mut request.body)
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(request)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_param(&self, key: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|(name, _)| percent_decode(name) == key)
+            .map(|(_, value)| percent_decode(value))
+    }
+
+    pub fn json(&self) -> Result<Json, String> {
+        let text = std::str::from_utf8(&self.body).map_err(|_| "body is not utf-8".to_string())?;
+        Json::parse(text)
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: content_type.to_string(),
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn json(status: u16, body: &Json) -> HttpResponse {
+        HttpResponse::new(status, "application/json", body.to_string().into_bytes())
+    }
+
+    // {"error": message}
+    pub fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, &Json::object(vec![("error", message.into())]))
+    }
+
+    pub fn write_to(&self, mut out: impl Write) -> std::io::Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        );
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        out.write_all(head.as_bytes())?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+// Sends a POST to addr and returns the response status; the body of the
+// response is not needed by anyone so far. host goes in the Host header.
+pub fn post(
+    addr: SocketAddr,
+    host: &str,
+    path: &str,
+    content_type: &str,
+    body: &[u8],
+    timeout: Duration,
+) -> Result<u16, String> {
+    let fail = |e: std::io::Error| format!("{}: {}", addr, e);
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(fail)?;
+    stream.set_read_timeout(Some(timeout)).map_err(fail)?;
+    stream.set_write_timeout(Some(timeout)).map_err(fail)?;
+
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).map_err(fail)?;
+    stream.write_all(body).map_err(fail)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(fail)?;
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("{}: bad status line {:?}", addr, line.trim_end()))
+}
+
+// A response get() received. Header names are lowercase.
+pub struct HttpReply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpReply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Joins the chunks of a chunked body (RFC 9112 section 7.1), ignoring
+// extensions and trailers.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    loop {
+        let end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or("chunk without a size line")?;
+        let line = String::from_utf8_lossy(&body[..end]);
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| "bad chunk size")?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size {
+            return Err("chunk cut short".to_string());
+        }
+        out.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or(&[]);
+    }
+}
+
+// Sends a GET with headers to addr and returns the response. The body is
+// read to the end, as the server closes the connection after it, and joined
+// if it came in chunks. host goes in the Host header.
+pub fn get(
+    addr: SocketAddr,
+    host: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> Result<HttpReply, String> {
+    let fail = |e: std::io::Error| format!("{}: {}", addr, e);
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(fail)?;
+    stream.set_read_timeout(Some(timeout)).map_err(fail)?;
+    stream.set_write_timeout(Some(timeout)).map_err(fail)?;
+
+    let mut head = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", path, host);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).map_err(fail)?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE as u64)
+        .read_to_end(&mut response)
+        .map_err(fail)?;
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| format!("{}: response without a header end", addr))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("{}: bad status line", addr))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let mut reply = HttpReply {
+        status,
+        headers,
+        body: response[end + 4..].to_vec(),
+    };
+    if reply
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        reply.body = dechunk(&reply.body).map_err(|e| format!("{}: {}", addr, e))?;
+    }
+    Ok(reply)
+}
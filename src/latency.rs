@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::name::Name;
+
+// Buckets per doubling of the round trip time, and how many there are: the
+// first ends at 64µs, the last at about 67s. Bucket i ends at
+// 64µs * 2^((i + 1) / 4), so a percentile is never more than 19% off.
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+const BUCKETS: usize = 80;
+const FIRST_BOUND_MICROS: f64 = 64.0;
+
+// When a histogram holds this many round trips, every count is halved, so
+// it follows an upstream that got faster or slower within a few thousand
+// queries instead of averaging over all of them.
+const DECAY_AT: u32 = 2048;
+
+// Histograms kept at most, per upstream and per zone. A recursor meets many
+// more authoritative servers than that; those not heard from the longest
+// make room.
+const MAX_TRACKED: usize = 1024;
+
+// Round trip times in log spaced buckets.
+#[derive(Debug, Clone, Copy)]
+struct Histogram {
+    buckets: [u32; BUCKETS],
+    count: u32,
+    sum_micros: u64,
+    updated: Instant,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum_micros: 0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, rtt: Duration) {
+        if self.count >= DECAY_AT {
+            for count in self.buckets.iter_mut() {
+                *count /= 2;
+            }
+            self.count = self.buckets.iter().sum();
+            self.sum_micros /= 2;
+        }
+
+        let micros = rtt.as_micros() as f64;
+        let bucket = if micros <= FIRST_BOUND_MICROS {
+            0
+        } else {
+            ((micros / FIRST_BOUND_MICROS).log2() * BUCKETS_PER_DOUBLING) as usize
+        };
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum_micros += rtt.as_micros() as u64;
+        self.updated = Instant::now();
+    }
+
+    // The end of the bucket the q-th quantile falls in.
+    fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q * self.count as f64).ceil() as u32).max(1);
+        let mut seen = 0;
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&count| {
+                seen += count;
+                seen >= rank
+            })
+            .unwrap_or(BUCKETS - 1);
+        let micros = FIRST_BOUND_MICROS * ((bucket + 1) as f64 / BUCKETS_PER_DOUBLING).exp2();
+        Some(Duration::from_micros(micros as u64))
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            count: self.count as u64,
+            sum: Duration::from_micros(self.sum_micros),
+            p50: self.percentile(0.5).unwrap_or_default(),
+            p95: self.percentile(0.95).unwrap_or_default(),
+            p99: self.percentile(0.99).unwrap_or_default(),
+        }
+    }
+}
+
+// Where the round trips in a histogram stand now. Counts and sums decay with
+// the histogram, so they are not totals.
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+fn record<K: Clone + Eq + Hash>(histograms: &Mutex<HashMap<K, Histogram>>, key: K, rtt: Duration) {
+    let mut histograms = histograms.lock().unwrap();
+    if histograms.len() >= MAX_TRACKED && !histograms.contains_key(&key) {
+        let oldest = histograms
+            .iter()
+            .min_by_key(|(_, histogram)| histogram.updated)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            histograms.remove(&oldest);
+        }
+    }
+    histograms
+        .entry(key)
+        .or_insert_with(Histogram::new)
+        .record(rtt);
+}
+
+fn summaries<K: Clone + Ord>(histograms: &Mutex<HashMap<K, Histogram>>) -> Vec<(K, Summary)> {
+    let mut summaries: Vec<(K, Summary)> = histograms
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, histogram)| (key.clone(), histogram.summary()))
+        .collect();
+    summaries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    summaries
+}
+
+// Round trip time histograms of every upstream answering queries, forwarders
+// and authoritative servers alike, and of every zone they were asked about.
+// Only answers count; a query that failed has no round trip time.
+pub struct Latencies {
+    servers: Mutex<HashMap<SocketAddr, Histogram>>,
+    zones: Mutex<HashMap<Name, Histogram>>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies {
+            servers: Mutex::new(HashMap::new()),
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Records an answer from server to a query about a name in zone.
+    pub fn record(&self, server: SocketAddr, zone: &Name, rtt: Duration) {
+        record(&self.servers, server, rtt);
+        record(&self.zones, zone.clone(), rtt);
+    }
+
+    // What servers are ranked by when picking the fastest: the 95th
+    // percentile, so one that is usually quick but often slow loses to one
+    // that is steady. None until server has answered.
+    pub fn rank(&self, server: SocketAddr) -> Option<Duration> {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(&server)
+            .and_then(|histogram| histogram.percentile(0.95))
+    }
+
+    pub fn servers(&self) -> Vec<(SocketAddr, Summary)> {
+        summaries(&self.servers)
+    }
+
+    pub fn zones(&self) -> Vec<(Name, Summary)> {
+        summaries(&self.zones)
+    }
+}
@@ -0,0 +1,1600 @@
+#![allow(clippy::upper_case_acronyms, clippy::new_without_default)]
+
+pub mod aaaa_filter;
+pub mod access_log;
+pub mod acl;
+pub mod anonymize;
+pub mod api;
+pub mod audit;
+pub mod blocklist;
+pub mod cache;
+pub mod calendar;
+pub mod chaos;
+pub mod client;
+pub mod client_policy;
+pub mod compare;
+pub mod config;
+pub mod connections;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dhcp;
+pub mod digest;
+pub mod dns_json;
+pub mod doh;
+pub mod dso;
+pub mod forwarder;
+pub mod h2;
+pub mod ha;
+pub mod health;
+pub mod hexdump;
+pub mod hpack;
+pub mod http;
+pub mod json;
+pub mod latency;
+pub mod log;
+pub mod mirror;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
+pub mod name;
+pub mod nsec3;
+pub mod pcap;
+pub mod pipeline;
+pub mod pool;
+#[cfg(all(unix, feature = "postgres"))]
+pub mod postgres;
+#[cfg(unix)]
+pub mod privileges;
+pub mod qtype_policy;
+pub mod quota;
+pub mod random;
+pub mod redis;
+pub mod regex;
+pub mod replay;
+pub mod resolvconf;
+pub mod resolver;
+pub mod response_size;
+pub mod retention;
+pub mod rules;
+pub mod schedule;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub mod seccomp;
+pub mod server;
+#[cfg(windows)]
+pub mod service;
+pub mod sinkhole;
+pub mod socks;
+pub mod sortlist;
+pub mod special;
+#[cfg(all(unix, feature = "sqlite"))]
+pub mod sqlite;
+pub mod stats;
+pub mod svcb;
+#[cfg(target_os = "linux")]
+pub mod sys;
+pub mod telemetry;
+pub mod threat;
+pub mod udp;
+#[cfg(target_os = "linux")]
+pub mod uring;
+pub mod view;
+pub mod zone;
+pub mod zone_store;
+pub mod zone_watch;
+pub mod zonefile;
+
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use crate::name::Name;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OpCode {
+    QUERY,
+    IQUERY,
+    STATUS,
+    NOTIFY,
+    UPDATE,
+    DSO,
+    // kept as is so it can be echoed back
+    UNKNOWN(u8),
+}
+
+// 12 bits: the low 4 live in the header, the upper 8 in the OPT record
+// (RFC 6891), so codes from 16 up need EDNS.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ResponseCode {
+    NOERR,
+    FORMERR,
+    SERVFAIL,
+    NAMERR,
+    NOTIMP,
+    REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
+    // DSO TLV type not implemented (RFC 8490)
+    DSOTYPENI,
+    // unsupported EDNS version; also BADSIG in TSIG records
+    BADVERS,
+    BADKEY,
+    BADTIME,
+    BADMODE,
+    BADNAME,
+    BADALG,
+    BADTRUNC,
+    BADCOOKIE,
+    // unassigned, kept as is so it isn't taken for another code
+    UNKNOWN(u16),
+}
+
+impl ResponseCode {
+    pub fn from_num(num: u16) -> ResponseCode {
+        match num {
+            1 => ResponseCode::FORMERR,
+            2 => ResponseCode::SERVFAIL,
+            3 => ResponseCode::NAMERR,
+            4 => ResponseCode::NOTIMP,
+            5 => ResponseCode::REFUSED,
+            6 => ResponseCode::YXDOMAIN,
+            7 => ResponseCode::YXRRSET,
+            8 => ResponseCode::NXRRSET,
+            9 => ResponseCode::NOTAUTH,
+            10 => ResponseCode::NOTZONE,
+            11 => ResponseCode::DSOTYPENI,
+            16 => ResponseCode::BADVERS,
+            17 => ResponseCode::BADKEY,
+            18 => ResponseCode::BADTIME,
+            19 => ResponseCode::BADMODE,
+            20 => ResponseCode::BADNAME,
+            21 => ResponseCode::BADALG,
+            22 => ResponseCode::BADTRUNC,
+            23 => ResponseCode::BADCOOKIE,
+            0 => ResponseCode::NOERR,
+            _ => ResponseCode::UNKNOWN(num),
+        }
+    }
+
+    pub fn to_num(self) -> u16 {
+        match self {
+            ResponseCode::NOERR => 0,
+            ResponseCode::FORMERR => 1,
+            ResponseCode::SERVFAIL => 2,
+            ResponseCode::NAMERR => 3,
+            ResponseCode::NOTIMP => 4,
+            ResponseCode::REFUSED => 5,
+            ResponseCode::YXDOMAIN => 6,
+            ResponseCode::YXRRSET => 7,
+            ResponseCode::NXRRSET => 8,
+            ResponseCode::NOTAUTH => 9,
+            ResponseCode::NOTZONE => 10,
+            ResponseCode::DSOTYPENI => 11,
+            ResponseCode::BADVERS => 16,
+            ResponseCode::BADKEY => 17,
+            ResponseCode::BADTIME => 18,
+            ResponseCode::BADMODE => 19,
+            ResponseCode::BADNAME => 20,
+            ResponseCode::BADALG => 21,
+            ResponseCode::BADTRUNC => 22,
+            ResponseCode::BADCOOKIE => 23,
+            ResponseCode::UNKNOWN(num) => num,
+        }
+    }
+
+    pub fn is_extended(self) -> bool {
+        self.to_num() > 0xF
+    }
+}
+
+impl OpCode {
+    pub fn from_num(num: u8) -> OpCode {
+        match num {
+            0 => OpCode::QUERY,
+            1 => OpCode::IQUERY,
+            2 => OpCode::STATUS,
+            4 => OpCode::NOTIFY,
+            5 => OpCode::UPDATE,
+            6 => OpCode::DSO,
+            _ => OpCode::UNKNOWN(num),
+        }
+    }
+
+    pub fn to_num(self) -> u8 {
+        match self {
+            OpCode::QUERY => 0,
+            OpCode::IQUERY => 1,
+            OpCode::STATUS => 2,
+            OpCode::NOTIFY => 4,
+            OpCode::UPDATE => 5,
+            OpCode::DSO => 6,
+            OpCode::UNKNOWN(num) => num,
+        }
+    }
+}
+
+// The classic UDP message size limit.
+pub const UDP_MESSAGE_SIZE: usize = 512;
+
+// Largest message that fits the two byte length prefix used over TCP.
+pub const TCP_MESSAGE_SIZE: usize = 65535;
+
+pub struct BufHandler {
+    pub buf: Vec<u8>,
+    pos: usize,
+}
+
+impl BufHandler {
+    pub fn new() -> BufHandler {
+        BufHandler::with_size(UDP_MESSAGE_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> BufHandler {
+        BufHandler {
+            buf: vec![0; size],
+            pos: 0,
+        }
+    }
+
+    // For reading a message that has already been received.
+    pub fn from_bytes(data: &[u8]) -> BufHandler {
+        BufHandler {
+            buf: data.to_vec(),
+            pos: 0,
+        }
+    }
+
+    // The bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.buf[0..self.pos]
+    }
+
+    pub fn read(&mut self) -> Result<u8, String> {
+        if self.pos >= self.buf.len() {
+            return Err("End of buffer".to_string());
+        }
+        let value = self.buf[self.pos];
+        self.pos += 1;
+        Ok(value)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        Ok((self.read()? as u16) << 8 | (self.read()? as u16))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        Ok((self.read_u16()? as u32) << 16 | (self.read_u16()? as u32))
+    }
+
+    pub fn get_pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn read_name(&mut self) -> Result<Name, String> {
+        let mut wire = Vec::new();
+        let mut jumped = false;
+        let mut offset = self.pos;
+
+        loop {
+            let len = *self.buf.get(offset).ok_or("End of buffer")?;
+
+            // end of name
+            if len == 0 {
+                if !jumped {
+                    self.pos = offset + 1;
+                }
+                break;
+            }
+
+            // pointer (compression)
+            if len & 0xC0 == 0xC0 {
+                let b2 = *self.buf.get(offset + 1).ok_or("End of buffer")? as u16;
+                let pointer = (((len as u16) ^ 0xC0) << 8) | b2;
+
+                if !jumped {
+                    self.pos = offset + 2;
+                }
+                // pointers may only go backwards, which also rules out loops
+                if pointer as usize >= offset {
+                    return Err("Bad compression pointer".to_string());
+                }
+                offset = pointer as usize;
+                jumped = true;
+            } else if len & 0xC0 != 0 {
+                return Err("Unsupported label type".to_string());
+            } else {
+                let label = self
+                    .buf
+                    .get(offset..offset + 1 + len as usize)
+                    .ok_or("End of buffer")?;
+                // counting the root label still to come
+                if wire.len() + label.len() + 1 > name::MAX_NAME_LEN {
+                    return Err("Name too long".to_string());
+                }
+                wire.extend_from_slice(label);
+                offset += label.len();
+            }
+        }
+        Ok(Name::from_wire(wire))
+    }
+
+    // Kept as bytes: character-strings needn't be text, let alone UTF-8.
+    pub fn read_character_string(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.read()?;
+        let mut bytes = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            bytes.push(self.read()?);
+        }
+        Ok(bytes)
+    }
+
+    pub fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    pub fn write(&mut self, data: u8) -> Result<(), String> {
+        if self.pos >= self.buf.len() {
+            return Err("End of buffer".to_string());
+        }
+        self.buf[self.pos] = data;
+        self.pos += 1;
+        Ok(())
+    }
+
+    pub fn write_u16(&mut self, data: u16) -> Result<(), String> {
+        self.write((data >> 8) as u8)?;
+        self.write(data as u8)?;
+        Ok(())
+    }
+
+    pub fn write_u32(&mut self, data: u32) -> Result<(), String> {
+        self.write_u16((data >> 16) as u16)?;
+        self.write_u16(data as u16)?;
+
+        Ok(())
+    }
+
+    // Names go out uncompressed.
+    pub fn write_name(&mut self, name: &Name) -> Result<(), String> {
+        for &byte in name.wire() {
+            self.write(byte)?;
+        }
+        self.write(0)
+    }
+
+    pub fn write_character_string(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() > 255 {
+            return Err("Character string too long".to_string());
+        }
+        self.write(data.len() as u8)?;
+        for &byte in data {
+            self.write(byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DnsHeader {
+    pub id: u16,                     // 16 Byte
+    pub query: bool,                 // 1 Bit
+    pub opcode: OpCode,              // 4 Bit
+    pub authoritative_answer: bool,  // 1 Bit
+    pub truncation: bool,            // 1 Bit,
+    pub recursion_desired: bool,     // 1 Bit
+    pub recursion_available: bool,   // 1 Bit
+    pub z: bool,                     // 1 Bit, reserved
+    pub authed_data: bool,           // 1 Bit, AD (RFC 4035)
+    pub checking_disabled: bool,     // 1 Bit, CD (RFC 4035)
+    pub response_code: ResponseCode, // 4 Bit
+
+    pub questions: u16,   // 16 Byte
+    pub answers: u16,     // 16 Byte
+    pub nameservers: u16, // 16 Byte
+    pub additionals: u16, // 16 Byte
+}
+
+impl DnsHeader {
+    pub fn new() -> DnsHeader {
+        DnsHeader {
+            id: 0,
+            query: false,
+            opcode: OpCode::QUERY,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: false,
+            recursion_available: false,
+            z: false,
+            authed_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::NOERR,
+
+            questions: 0,
+            answers: 0,
+            nameservers: 0,
+            additionals: 0,
+        }
+    }
+
+    pub fn read(&mut self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        self.id = buf_handler.read_u16()?;
+        self.set_flags(buf_handler.read_u16()?);
+
+        self.questions = buf_handler.read_u16()?;
+        self.answers = buf_handler.read_u16()?;
+        self.nameservers = buf_handler.read_u16()?;
+        self.additionals = buf_handler.read_u16()?;
+
+        Ok(())
+    }
+
+    // Unpacks the second 16 bits of the header.
+    pub fn set_flags(&mut self, flags: u16) {
+        let a = (flags >> 8) as u8;
+        let b = (flags & 0xFF) as u8;
+
+        self.query = ((a >> 7) & 0x1) == 1;
+        self.opcode = OpCode::from_num((a >> 3) & 0xF);
+        self.authoritative_answer = (a >> 2 & 0x1) == 1;
+        self.truncation = (a >> 1 & 0x1) == 1;
+        self.recursion_desired = (a & 0x1) == 1;
+
+        self.recursion_available = ((b >> 7) & 0x1) == 1;
+        self.z = ((b >> 6) & 0x1) == 1;
+        self.authed_data = ((b >> 5) & 0x1) == 1;
+        self.checking_disabled = ((b >> 4) & 0x1) == 1;
+        // the upper bits are added once the OPT record has been read
+        self.response_code = ResponseCode::from_num((b & 0xF) as u16);
+    }
+
+    pub fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        buf_handler.write_u16(self.id)?;
+
+        buf_handler.write(
+            (self.query as u8) << 7
+                | self.opcode.to_num() << 3
+                | (self.authoritative_answer as u8) << 2
+                | (self.truncation as u8) << 1
+                | (self.recursion_desired as u8),
+        )?;
+
+        buf_handler.write(
+            (self.recursion_available as u8) << 7
+                | (self.z as u8) << 6
+                | (self.authed_data as u8) << 5
+                | (self.checking_disabled as u8) << 4
+                | (self.response_code.to_num() & 0xF) as u8,
+        )?;
+
+        buf_handler.write_u16(self.questions)?;
+        buf_handler.write_u16(self.answers)?;
+        buf_handler.write_u16(self.nameservers)?;
+        buf_handler.write_u16(self.additionals)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum QueryType {
+    A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    HINFO,
+    MX,
+    TXT,
+    AAAA,
+    NULL,
+    OPT,
+    SVCB,
+    ANY,
+    UNKNOWN(u16),
+}
+
+impl QueryType {
+    pub fn from_num(num: u16) -> QueryType {
+        match num {
+            1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            10 => QueryType::NULL,
+            13 => QueryType::HINFO,
+            15 => QueryType::MX,
+            16 => QueryType::TXT,
+            28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            64 => QueryType::SVCB,
+            255 => QueryType::ANY,
+            _ => QueryType::UNKNOWN(num),
+        }
+    }
+
+    pub fn to_num(self) -> u16 {
+        match self {
+            QueryType::A => 1,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
+            QueryType::HINFO => 13,
+            QueryType::MX => 15,
+            QueryType::TXT => 16,
+            QueryType::AAAA => 28,
+            QueryType::NULL => 10,
+            QueryType::OPT => 41,
+            QueryType::SVCB => 64,
+            QueryType::ANY => 255,
+            QueryType::UNKNOWN(num) => num,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QueryType::A => "A",
+            QueryType::NS => "NS",
+            QueryType::CNAME => "CNAME",
+            QueryType::SOA => "SOA",
+            QueryType::PTR => "PTR",
+            QueryType::HINFO => "HINFO",
+            QueryType::MX => "MX",
+            QueryType::TXT => "TXT",
+            QueryType::AAAA => "AAAA",
+            QueryType::NULL => "NULL",
+            QueryType::OPT => "OPT",
+            QueryType::SVCB => "SVCB",
+            QueryType::ANY => "ANY",
+            QueryType::UNKNOWN(_) => "UNKNOWN",
+        }
+    }
+
+    // Mnemonics, or TYPE<n> (RFC 3597) for any type by number.
+    pub fn from_name(name: &str) -> QueryType {
+        let name = name.to_uppercase();
+        if let Some(Ok(num)) = name.strip_prefix("TYPE").map(str::parse::<u16>) {
+            return QueryType::from_num(num);
+        }
+        match name.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "PTR" => QueryType::PTR,
+            "HINFO" => QueryType::HINFO,
+            "MX" => QueryType::MX,
+            "TXT" => QueryType::TXT,
+            "AAAA" => QueryType::AAAA,
+            "NULL" => QueryType::NULL,
+            "OPT" => QueryType::OPT,
+            "SVCB" => QueryType::SVCB,
+            "ANY" => QueryType::ANY,
+            _ => QueryType::UNKNOWN(0),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum QueryClass {
+    IN,
+    // CHAOS, only used for server identification (version.bind and friends)
+    CH,
+    HS,
+    NONE,
+    ANY,
+    // kept as is so it can be echoed back
+    UNKNOWN(u16),
+}
+
+impl QueryClass {
+    pub fn from_num(num: u16) -> QueryClass {
+        match num {
+            1 => QueryClass::IN,
+            3 => QueryClass::CH,
+            4 => QueryClass::HS,
+            254 => QueryClass::NONE,
+            255 => QueryClass::ANY,
+            _ => QueryClass::UNKNOWN(num),
+        }
+    }
+
+    pub fn to_num(self) -> u16 {
+        match self {
+            QueryClass::IN => 1,
+            QueryClass::CH => 3,
+            QueryClass::HS => 4,
+            QueryClass::NONE => 254,
+            QueryClass::ANY => 255,
+            QueryClass::UNKNOWN(num) => num,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DnsQuestion {
+    pub name: Name,
+    pub qtype: QueryType,
+    pub qclass: QueryClass,
+}
+
+impl DnsQuestion {
+    pub fn new() -> DnsQuestion {
+        DnsQuestion {
+            name: Name::root(),
+            qtype: QueryType::A,
+            qclass: QueryClass::IN,
+        }
+    }
+
+    pub fn read(&mut self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        self.name = buf_handler.read_name()?;
+        self.qtype = QueryType::from_num(buf_handler.read_u16()?);
+        self.qclass = QueryClass::from_num(buf_handler.read_u16()?);
+        Ok(())
+    }
+
+    pub fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        buf_handler.write_name(&self.name)?;
+        buf_handler.write_u16(self.qtype.to_num())?;
+        buf_handler.write_u16(self.qclass.to_num())?;
+        Ok(())
+    }
+}
+
+// edns-tcp-keepalive (RFC 7828), data is the idle timeout in units of 100ms
+pub const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+// The DO bit of the OPT record's flags (RFC 3225): the client wants DNSSEC
+// records.
+pub const EDNS_DO: u16 = 0x8000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+// the owner of every OPT record
+static ROOT: Name = Name::root();
+
+// A character-string as zone files write it: quoted, printable ASCII as is,
+// quotes and backslashes escaped, any other byte as \DDD.
+fn character_string(data: &[u8]) -> String {
+    let mut text = String::from('"');
+    for &byte in data {
+        match byte {
+            b'"' | b'\\' => {
+                text.push('\\');
+                text.push(byte as char);
+            }
+            0x20..=0x7e => text.push(byte as char),
+            _ => text.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    text.push('"');
+    text
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DnsRecord {
+    // A type not modeled below, with its rdata kept as it came so it goes out
+    // again unchanged (RFC 3597).
+    UNKNOWN {
+        domain: Name,
+        qtype: u16,
+        class: u16,
+        ttl: u32,
+        data: Vec<u8>,
+    },
+    A {
+        domain: Name,
+        addr: Ipv4Addr,
+        ttl: u32,
+    },
+    NS {
+        domain: Name,
+        ttl: u32,
+        host: Name,
+    },
+    CNAME {
+        domain: Name,
+        ttl: u32,
+        host: Name,
+    },
+    // mname is the primary server, rname the contact's mailbox with the @
+    // written as a dot
+    SOA {
+        domain: Name,
+        ttl: u32,
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR {
+        domain: Name,
+        ttl: u32,
+        host: Name,
+    },
+    HINFO {
+        domain: Name,
+        ttl: u32,
+        cpu: Vec<u8>,
+        os: Vec<u8>,
+    },
+    MX {
+        domain: Name,
+        ttl: u32,
+        priority: u16,
+        host: Name,
+    },
+    AAAA {
+        domain: Name,
+        ttl: u32,
+        addr: Ipv6Addr,
+    },
+    // The only type kept outside class IN, so CHAOS queries can be answered.
+    TXT {
+        domain: Name,
+        ttl: u32,
+        class: QueryClass,
+        data: Vec<Vec<u8>>,
+    },
+    // Service binding (RFC 9460): priority 0 is an alias for target, others
+    // offer the service at target with the given parameters, which are kept
+    // in key order with their values in wire form.
+    SVCB {
+        domain: Name,
+        ttl: u32,
+        priority: u16,
+        target: Name,
+        params: Vec<(u16, Vec<u8>)>,
+    },
+    // EDNS pseudo record (RFC 6891), always owned by the root
+    OPT {
+        payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        flags: u16,
+        options: Vec<EdnsOption>,
+    },
+}
+
+impl DnsRecord {
+    pub fn domain(&self) -> &Name {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::HINFO { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::SVCB { domain, .. } => domain,
+            DnsRecord::OPT { .. } => &ROOT,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::OPT { .. } => 0,
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. } => ttl,
+        }
+    }
+
+    // The rdata in zone file presentation form, e.g. "10 mail.example.com".
+    pub fn rdata_string(&self) -> String {
+        match self {
+            // the generic form of RFC 3597 section 5
+            DnsRecord::UNKNOWN { data, .. } => {
+                let mut text = format!("\\# {}", data.len());
+                if !data.is_empty() {
+                    text.push(' ');
+                    for byte in data.iter() {
+                        text.push_str(&format!("{:02x}", byte));
+                    }
+                }
+                text
+            }
+            DnsRecord::A { addr, .. } => addr.to_string(),
+            DnsRecord::AAAA { addr, .. } => addr.to_string(),
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => host.to_ascii(),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                mname.to_ascii(),
+                rname.to_ascii(),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::HINFO { cpu, os, .. } => {
+                format!("{} {}", character_string(cpu), character_string(os))
+            }
+            DnsRecord::MX { priority, host, .. } => format!("{} {}", priority, host.to_ascii()),
+            DnsRecord::TXT { data, .. } => data
+                .iter()
+                .map(|text| character_string(text))
+                .collect::<Vec<String>>()
+                .join(" "),
+            DnsRecord::SVCB {
+                priority,
+                target,
+                params,
+                ..
+            } => {
+                // the root target means the owner name, so it keeps its dot
+                let target = if target.is_root() {
+                    ".".to_string()
+                } else {
+                    target.to_ascii()
+                };
+                let mut text = format!("{} {}", priority, target);
+                for (key, value) in params.iter() {
+                    text.push(' ');
+                    text.push_str(&svcb::param_string(*key, value));
+                }
+                text
+            }
+            DnsRecord::OPT { options, .. } => options
+                .iter()
+                .map(|option| format!("{}:{}", option.code, option.data.len()))
+                .collect::<Vec<String>>()
+                .join(" "),
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::OPT { .. } => {}
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. } => *ttl = new_ttl,
+        }
+    }
+
+    pub fn set_domain(&mut self, name: &Name) {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::HINFO { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::SVCB { domain, .. } => *domain = name.clone(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::HINFO { .. } => QueryType::HINFO,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    pub fn read(buf_handler: &mut BufHandler) -> Result<DnsRecord, String> {
+        let qname = buf_handler.read_name()?;
+
+        let qtype_num = buf_handler.read_u16()?;
+        let qtype = QueryType::from_num(qtype_num);
+
+        let qclass = buf_handler.read_u16()?;
+        let ttl = buf_handler.read_u32()?;
+        let len = buf_handler.read_u16()?;
+
+        match qtype {
+            QueryType::A => Ok(DnsRecord::A {
+                domain: qname,
+                addr: Ipv4Addr::new(
+                    buf_handler.read()?,
+                    buf_handler.read()?,
+                    buf_handler.read()?,
+                    buf_handler.read()?,
+                ),
+                ttl,
+            }),
+            QueryType::NS => {
+                let ns = buf_handler.read_name()?;
+                Ok(DnsRecord::NS {
+                    domain: qname,
+                    ttl,
+                    host: ns,
+                })
+            }
+            QueryType::CNAME => {
+                let cname = buf_handler.read_name()?;
+                Ok(DnsRecord::CNAME {
+                    domain: qname,
+                    ttl,
+                    host: cname,
+                })
+            }
+            QueryType::SOA => {
+                let mname = buf_handler.read_name()?;
+                let rname = buf_handler.read_name()?;
+
+                Ok(DnsRecord::SOA {
+                    domain: qname,
+                    ttl,
+                    mname,
+                    rname,
+                    serial: buf_handler.read_u32()?,
+                    refresh: buf_handler.read_u32()?,
+                    retry: buf_handler.read_u32()?,
+                    expire: buf_handler.read_u32()?,
+                    minimum: buf_handler.read_u32()?,
+                })
+            }
+            QueryType::PTR => {
+                let ptr = buf_handler.read_name()?;
+                Ok(DnsRecord::PTR {
+                    domain: qname,
+                    ttl,
+                    host: ptr,
+                })
+            }
+            QueryType::HINFO => {
+                let cpu = buf_handler.read_character_string()?;
+                let os = buf_handler.read_character_string()?;
+
+                Ok(DnsRecord::HINFO {
+                    domain: qname,
+                    ttl,
+                    cpu,
+                    os,
+                })
+            }
+            QueryType::MX => {
+                let priority = buf_handler.read_u16()?;
+                let mx = buf_handler.read_name()?;
+
+                Ok(DnsRecord::MX {
+                    domain: qname,
+                    ttl,
+                    priority,
+                    host: mx,
+                })
+            }
+            QueryType::AAAA => Ok(DnsRecord::AAAA {
+                domain: qname,
+                ttl,
+                addr: Ipv6Addr::new(
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                    buf_handler.read_u16()?,
+                ),
+            }),
+            QueryType::TXT => {
+                let end = buf_handler.get_pos() + len as usize;
+                let mut data = Vec::new();
+                while buf_handler.get_pos() < end {
+                    data.push(buf_handler.read_character_string()?);
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain: qname,
+                    ttl,
+                    class: QueryClass::from_num(qclass),
+                    data,
+                })
+            }
+            QueryType::SVCB => {
+                let end = buf_handler.get_pos() + len as usize;
+                let priority = buf_handler.read_u16()?;
+                let target = buf_handler.read_name()?;
+                let mut params = Vec::new();
+                while buf_handler.get_pos() < end {
+                    let key = buf_handler.read_u16()?;
+                    let value_len = buf_handler.read_u16()?;
+                    let mut value = Vec::with_capacity(value_len as usize);
+                    for _ in 0..value_len {
+                        value.push(buf_handler.read()?);
+                    }
+                    params.push((key, value));
+                }
+
+                Ok(DnsRecord::SVCB {
+                    domain: qname,
+                    ttl,
+                    priority,
+                    target,
+                    params,
+                })
+            }
+            // class and ttl are reused for the EDNS header fields
+            QueryType::OPT => {
+                let end = buf_handler.get_pos() + len as usize;
+                let mut options = Vec::new();
+                while buf_handler.get_pos() < end {
+                    let code = buf_handler.read_u16()?;
+                    let option_len = buf_handler.read_u16()?;
+                    let mut data = Vec::with_capacity(option_len as usize);
+                    for _ in 0..option_len {
+                        data.push(buf_handler.read()?);
+                    }
+                    options.push(EdnsOption { code, data });
+                }
+
+                Ok(DnsRecord::OPT {
+                    payload_size: qclass,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: (ttl >> 16) as u8,
+                    flags: ttl as u16,
+                    options,
+                })
+            }
+
+            _ => {
+                let end = buf_handler.get_pos() + len as usize;
+                let mut data = Vec::with_capacity(len as usize);
+                // the obsolete types that may compress their names (RFC 3597
+                // section 4) get them spelled out, as they are copied to
+                // messages where the pointers would go elsewhere
+                if let 3 | 4 | 7 | 8 | 9 | 14 = qtype_num {
+                    while buf_handler.get_pos() < end {
+                        let name = buf_handler.read_name()?;
+                        data.extend_from_slice(name.wire());
+                        data.push(0);
+                    }
+                } else {
+                    for _ in 0..len {
+                        data.push(buf_handler.read()?);
+                    }
+                }
+                Ok(DnsRecord::UNKNOWN {
+                    domain: qname,
+                    qtype: qtype_num,
+                    class: qclass,
+                    ttl,
+                    data,
+                })
+            }
+        }
+    }
+
+    pub fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        match *self {
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                class,
+                ttl,
+                ref data,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(qtype)?;
+                buf_handler.write_u16(class)?;
+                buf_handler.write_u32(ttl)?;
+
+                buf_handler.write_u16(data.len() as u16)?;
+                for byte in data.iter() {
+                    buf_handler.write(*byte)?;
+                }
+            }
+            DnsRecord::A {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::A.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+                buf_handler.write_u16(4)?;
+
+                for octet in addr.octets() {
+                    buf_handler.write(octet)?;
+                }
+            }
+            DnsRecord::AAAA {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::AAAA.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+                buf_handler.write_u16(16)?;
+
+                for segment in addr.segments() {
+                    buf_handler.write_u16(segment)?;
+                }
+            }
+            DnsRecord::NS {
+                ref domain,
+                ttl,
+                ref host,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::NS.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                buf_handler.write_u16(host.wire_len() as u16)?;
+                buf_handler.write_name(host)?;
+            }
+            DnsRecord::CNAME {
+                ref domain,
+                ttl,
+                ref host,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::CNAME.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                buf_handler.write_u16(host.wire_len() as u16)?;
+                buf_handler.write_name(host)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ttl,
+                ref host,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::PTR.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                buf_handler.write_u16(host.wire_len() as u16)?;
+                buf_handler.write_name(host)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ttl,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::SOA.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                // the length goes in once the names are written
+                let len_pos = buf_handler.get_pos();
+                buf_handler.write_u16(0)?;
+                buf_handler.write_name(mname)?;
+                buf_handler.write_name(rname)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buf_handler.write_u32(value)?;
+                }
+                let end = buf_handler.get_pos();
+                buf_handler.seek(len_pos);
+                buf_handler.write_u16((end - len_pos - 2) as u16)?;
+                buf_handler.seek(end);
+            }
+            DnsRecord::HINFO {
+                ref domain,
+                ttl,
+                ref cpu,
+                ref os,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::HINFO.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                buf_handler.write_u16((cpu.len() + os.len() + 2) as u16)?;
+                buf_handler.write_character_string(cpu)?;
+                buf_handler.write_character_string(os)?;
+            }
+            DnsRecord::MX {
+                ref domain,
+                ttl,
+                ref host,
+                priority,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::MX.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                buf_handler.write_u16((host.wire_len() + 2) as u16)?;
+                buf_handler.write_u16(priority)?;
+                buf_handler.write_name(host)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ttl,
+                class,
+                ref data,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::TXT.to_num())?;
+                buf_handler.write_u16(class.to_num())?;
+                buf_handler.write_u32(ttl)?;
+
+                let len: usize = data.iter().map(|text| text.len() + 1).sum();
+                buf_handler.write_u16(len as u16)?;
+                for text in data.iter() {
+                    buf_handler.write_character_string(text)?;
+                }
+            }
+            DnsRecord::SVCB {
+                ref domain,
+                ttl,
+                priority,
+                ref target,
+                ref params,
+            } => {
+                buf_handler.write_name(domain)?;
+                buf_handler.write_u16(QueryType::SVCB.to_num())?;
+                buf_handler.write_u16(1)?;
+                buf_handler.write_u32(ttl)?;
+
+                let len: usize = params.iter().map(|(_, value)| 4 + value.len()).sum();
+                buf_handler.write_u16((2 + target.wire_len() + len) as u16)?;
+                buf_handler.write_u16(priority)?;
+                buf_handler.write_name(target)?;
+                for (key, value) in params.iter() {
+                    buf_handler.write_u16(*key)?;
+                    buf_handler.write_u16(value.len() as u16)?;
+                    for byte in value.iter() {
+                        buf_handler.write(*byte)?;
+                    }
+                }
+            }
+            DnsRecord::OPT {
+                payload_size,
+                extended_rcode,
+                version,
+                flags,
+                ref options,
+            } => {
+                buf_handler.write_name(&ROOT)?;
+                buf_handler.write_u16(QueryType::OPT.to_num())?;
+                buf_handler.write_u16(payload_size)?;
+                buf_handler.write_u32(
+                    (extended_rcode as u32) << 24 | (version as u32) << 16 | flags as u32,
+                )?;
+
+                let len: usize = options.iter().map(|option| 4 + option.data.len()).sum();
+                buf_handler.write_u16(len as u16)?;
+                for option in options.iter() {
+                    buf_handler.write_u16(option.code)?;
+                    buf_handler.write_u16(option.data.len() as u16)?;
+                    for byte in option.data.iter() {
+                        buf_handler.write(*byte)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub nameservers: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,
+}
+
+impl DnsPacket {
+    pub fn new() -> DnsPacket {
+        DnsPacket {
+            header: DnsHeader::new(),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            nameservers: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    // The OPT record, if the packet carries one.
+    pub fn edns(&self) -> Option<&DnsRecord> {
+        self.additionals
+            .iter()
+            .find(|record| record.query_type() == QueryType::OPT)
+    }
+
+    // Whether the OPT record has the DO bit set.
+    pub fn dnssec_ok(&self) -> bool {
+        matches!(self.edns(), Some(DnsRecord::OPT { flags, .. }) if flags & EDNS_DO != 0)
+    }
+
+    pub fn from_buffer(buf_handler: &mut BufHandler) -> Result<Self, String> {
+        let mut packet = Self::new();
+        packet.read(buf_handler)?;
+        Ok(packet)
+    }
+
+    pub fn read(&mut self, buf_reader: &mut BufHandler) -> Result<(), String> {
+        self.header.read(buf_reader)?;
+
+        for _ in 0..self.header.questions {
+            let mut question = DnsQuestion::new();
+            question.read(buf_reader)?;
+            self.questions.push(question);
+        }
+
+        for _ in 0..self.header.answers {
+            self.answers.push(DnsRecord::read(buf_reader)?);
+        }
+
+        for _ in 0..self.header.nameservers {
+            self.nameservers.push(DnsRecord::read(buf_reader)?);
+        }
+
+        for _ in 0..self.header.additionals {
+            self.additionals.push(DnsRecord::read(buf_reader)?);
+        }
+
+        if let Some(DnsRecord::OPT { extended_rcode, .. }) = self.edns() {
+            let low = self.header.response_code.to_num();
+            self.header.response_code = ResponseCode::from_num((*extended_rcode as u16) << 4 | low);
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, buf_handler: &mut BufHandler) -> Result<(), String> {
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.nameservers = self.nameservers.len() as u16;
+        self.header.additionals = self.additionals.len() as u16;
+
+        // The upper bits of the response code go into the OPT record. Without
+        // one an extended code can't be sent, so it is reported as SERVFAIL.
+        let upper = (self.header.response_code.to_num() >> 4) as u8;
+        let mut has_opt = false;
+        for additional in self.additionals.iter_mut() {
+            if let DnsRecord::OPT { extended_rcode, .. } = additional {
+                *extended_rcode = upper;
+                has_opt = true;
+            }
+        }
+        if self.header.response_code.is_extended() && !has_opt {
+            self.header.response_code = ResponseCode::SERVFAIL;
+        }
+
+        self.header.write(buf_handler)?;
+
+        for question in self.questions.iter() {
+            question.write(buf_handler)?;
+        }
+
+        for answer in self.answers.iter() {
+            answer.write(buf_handler)?;
+        }
+
+        for nameserver in self.nameservers.iter() {
+            nameserver.write(buf_handler)?;
+        }
+
+        for additional in self.additionals.iter() {
+            additional.write(buf_handler)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(text: &str) -> Name {
+        Name::from_ascii(text).unwrap()
+    }
+
+    // Writes packet and reads what was written.
+    fn round_trip(packet: &mut DnsPacket) -> DnsPacket {
+        let mut buf_handler = BufHandler::with_size(TCP_MESSAGE_SIZE);
+        packet.write(&mut buf_handler).unwrap();
+        DnsPacket::from_buffer(&mut BufHandler::from_bytes(buf_handler.written())).unwrap()
+    }
+
+    // A response to "WwW.Example.ORG A" with flags and the answers given in
+    // wire form; the question name is at offset 12, so c0 0c points to it.
+    fn message(flags: u16, answers: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0x12, 0x34];
+        data.extend_from_slice(&flags.to_be_bytes());
+        data.extend_from_slice(&[0, 1, 0, answers.len() as u8, 0, 0, 0, 0]);
+        data.extend_from_slice(b"\x03WwW\x07Example\x03ORG\x00\x00\x01\x00\x01");
+        for answer in answers {
+            data.extend_from_slice(answer);
+        }
+        data
+    }
+
+    fn response() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.query = false;
+        packet
+    }
+
+    #[test]
+    fn unknown_rdata_round_trips() {
+        let mut packet = response();
+        packet.answers.push(DnsRecord::UNKNOWN {
+            domain: name("example.org"),
+            qtype: 257,
+            class: 1,
+            ttl: 300,
+            data: b"\x00\x05issueca.example".to_vec(),
+        });
+
+        let read = round_trip(&mut packet);
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(
+            read.answers[0].rdata_string(),
+            "\\# 17 0005697373756563612e6578616d706c65"
+        );
+    }
+
+    #[test]
+    fn compressed_names_in_obsolete_types_are_spelled_out() {
+        // an MB record whose owner and rdata both point at the question name
+        let data = message(
+            0x8180,
+            &[b"\xc0\x0c\x00\x07\x00\x01\x00\x00\x01\x2c\x00\x02\xc0\x0c"],
+        );
+        let mut packet = DnsPacket::from_buffer(&mut BufHandler::from_bytes(&data)).unwrap();
+        let mut spelled_out = name("www.example.org").wire().to_vec();
+        spelled_out.push(0);
+        let DnsRecord::UNKNOWN { qtype, data, .. } = &packet.answers[0] else {
+            panic!("not kept as UNKNOWN: {:?}", packet.answers[0]);
+        };
+        assert_eq!(*qtype, 7);
+        assert!(data.eq_ignore_ascii_case(&spelled_out));
+
+        let read = round_trip(&mut packet);
+        assert_eq!(read.answers, packet.answers);
+    }
+
+    #[test]
+    fn txt_keeps_bytes_that_are_not_utf8() {
+        let mut packet = response();
+        packet.answers.push(DnsRecord::TXT {
+            domain: name("example.org"),
+            ttl: 300,
+            class: QueryClass::IN,
+            data: vec![vec![0xff; 255], "héé".as_bytes().to_vec(), Vec::new()],
+        });
+
+        let read = round_trip(&mut packet);
+        assert_eq!(read.answers, packet.answers);
+    }
+
+    #[test]
+    fn txt_presentation_escapes() {
+        let record = DnsRecord::TXT {
+            domain: name("example.org"),
+            ttl: 300,
+            class: QueryClass::IN,
+            data: vec![b"a\"b\\\x01".to_vec(), b"v=spf1 -all".to_vec()],
+        };
+        assert_eq!(record.rdata_string(), r#""a\"b\\\001" "v=spf1 -all""#);
+    }
+
+    #[test]
+    fn extended_rcode_travels_in_the_opt_record() {
+        for rcode in [ResponseCode::BADCOOKIE, ResponseCode::UNKNOWN(3000)] {
+            let mut packet = response();
+            packet.header.response_code = rcode;
+            packet.additionals.push(DnsRecord::OPT {
+                payload_size: 1232,
+                extended_rcode: 0,
+                version: 0,
+                flags: 0,
+                options: Vec::new(),
+            });
+            assert_eq!(round_trip(&mut packet).header.response_code, rcode);
+        }
+    }
+
+    #[test]
+    fn extended_rcode_without_opt_is_servfail() {
+        let mut packet = response();
+        packet.header.response_code = ResponseCode::BADCOOKIE;
+        assert_eq!(
+            round_trip(&mut packet).header.response_code,
+            ResponseCode::SERVFAIL
+        );
+    }
+
+    #[test]
+    fn unassigned_rcode_is_kept() {
+        let data = message(0x818c, &[]);
+        let mut packet = DnsPacket::from_buffer(&mut BufHandler::from_bytes(&data)).unwrap();
+        assert_eq!(packet.header.response_code, ResponseCode::UNKNOWN(12));
+        assert_eq!(
+            round_trip(&mut packet).header.response_code,
+            ResponseCode::UNKNOWN(12)
+        );
+    }
+
+    #[test]
+    fn compressed_owner_keeps_its_case() {
+        let data = message(
+            0x8180,
+            &[b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\x0a\x00\x00\x01"],
+        );
+        let mut packet = DnsPacket::from_buffer(&mut BufHandler::from_bytes(&data)).unwrap();
+        let owner = packet.answers[0].domain();
+        assert_eq!(owner.to_ascii(), "WwW.Example.ORG");
+        assert_eq!(*owner, name("www.example.org"));
+
+        let read = round_trip(&mut packet);
+        assert_eq!(read.answers[0].domain().to_ascii(), "WwW.Example.ORG");
+        assert_eq!(read.questions, packet.questions);
+    }
+
+    #[test]
+    fn compression_pointer_to_itself_is_rejected() {
+        let mut data = message(0x0100, &[]);
+        data.truncate(12);
+        data.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01");
+        assert!(DnsPacket::from_buffer(&mut BufHandler::from_bytes(&data)).is_err());
+    }
+}
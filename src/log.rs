@@ -0,0 +1,34 @@
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+
+// How much the server writes to stderr besides errors, which are always
+// written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Info,
+    Debug,
+    // every packet in and out, as an annotated hexdump
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(text: &str) -> Result<LogLevel, String> {
+        match text {
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("unknown log level {}", text)),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// Whether messages of level are written.
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
@@ -1,643 +1,309 @@
-use std::net::Ipv4Addr;
-use std::net::Ipv6Addr;
+use std::net::IpAddr;
 use std::net::SocketAddr;
-use std::net::UdpSocket;
-
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum OpCode {
-    QUERY = 0,
-    IQUERY = 1,
-    STATUS = 2,
-}
-
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum ResponseCode {
-    NOERR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NAMERR = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use dns_server::QueryType;
+use dns_server::compare;
+use dns_server::config::Config;
+#[cfg(unix)]
+use dns_server::daemon;
+use dns_server::latency::Latencies;
+use dns_server::name::Name;
+use dns_server::replay;
+use dns_server::resolvconf;
+use dns_server::resolvconf::ResolvConf;
+use dns_server::resolver::Resolver;
+use dns_server::server;
+#[cfg(windows)]
+use dns_server::service;
+use dns_server::zonefile;
+
+const USAGE: &str = "usage: dns-server [--config <path>] [--daemon [--pidfile <path>] [--log-file <path>]] [resolve <host>]
+       dns-server --config <path> check-config
+       dns-server check-zone <origin> <file>
+       dns-server replay <capture> [<server>[:<port>]]
+       dns-server diff <name> <type> <server>[:<port>] <server>[:<port>]
+       dns-server [--config <path>] service install|uninstall|run (Windows)";
+
+const DEFAULT_PIDFILE: &str = "/run/dns-server.pid";
+
+// What --daemon and the options that go with it asked for.
+struct DaemonOptions {
+    pidfile: PathBuf,
+    log_file: Option<PathBuf>,
 }
 
-impl ResponseCode {
-    fn from_num(num: u8) -> ResponseCode {
-        match num {
-            1 => ResponseCode::FORMERR,
-            2 => ResponseCode::SERVFAIL,
-            3 => ResponseCode::NAMERR,
-            4 => ResponseCode::NOTIMP,
-            5 => ResponseCode::REFUSED,
-            0 | _ => ResponseCode::NOERR,
-        }
-    }
-}
-
-impl OpCode {
-    fn from_num(num: u8) -> OpCode {
-        match num {
-            1 => OpCode::IQUERY,
-            2 => OpCode::STATUS,
-            0 | _ => OpCode::QUERY,
-        }
-    }
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
 }
 
-struct BufHandler {
-    buf: [u8; 512],
-    pos: usize,
+struct Args {
+    config_path: Option<String>,
+    // set if --daemon was given
+    daemon: Option<DaemonOptions>,
+    // the remaining subcommand words
+    command: Vec<String>,
 }
 
-impl BufHandler {
-    fn new() -> BufHandler {
-        BufHandler {
-            buf: [0; 512],
-            pos: 0,
-        }
-    }
-
-    fn read(&mut self) -> Result<u8, String> {
-        if self.pos >= 512 {
-            return Err("End of buffer".to_string());
-        }
-        let value = self.buf[self.pos];
-        self.pos += 1;
-        Ok(value)
-    }
-
-    fn read_u16(&mut self) -> Result<u16, String> {
-        Ok((self.read()? as u16) << 8 | (self.read()? as u16))
-    }
-
-    fn read_u32(&mut self) -> Result<u32, String> {
-        Ok((self.read_u16()? as u32) << 16 | (self.read_u16()? as u32))
-    }
-
-    fn get_pos(&self) -> usize {
-        return self.pos;
-    }
-
-    fn read_qname(&mut self, out: &mut String) -> Result<(), String> {
-        let mut delim = "";
-        let mut jumped = false;
-        let mut offset = self.pos;
-
-        loop {
-            let len = self.buf[offset];
-
-            // end of name
-            if len == 0 {
-                if !jumped {
-                    self.pos = offset + 1;
-                }
-                break;
-            }
-
-            // pointer (compression)
-            if len & 0xC0 == 0xC0 {
-                let b2 = self.buf[offset + 1] as u16;
-                let pointer = (((len as u16) ^ 0xC0) << 8) | b2;
-
-                if !jumped {
-                    self.pos = offset + 2;
-                }
-                offset = pointer as usize;
-                jumped = true;
-            } else {
-                offset += 1;
-                let label = &self.buf[offset..offset + (len as usize)];
-                out.push_str(delim);
-                out.push_str(&String::from_utf8_lossy(label).to_lowercase());
-                delim = ".";
-                offset += len as usize;
+fn parse_args() -> Args {
+    let mut args = std::env::args().skip(1);
+    let mut config_path = None;
+    let mut daemon = false;
+    let mut pidfile = None;
+    let mut log_file = None;
+    let mut command = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--daemon" => daemon = true,
+            "--pidfile" => pidfile = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--log-file" => log_file = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
             }
+            _ => command.push(arg),
         }
-        Ok(())
     }
 
-    fn seek(&mut self, pos: usize) {
-        self.pos = pos;
+    if !daemon && (pidfile.is_some() || log_file.is_some()) {
+        usage();
     }
+    let daemon = daemon.then(|| DaemonOptions {
+        pidfile: pidfile.unwrap_or_else(|| PathBuf::from(DEFAULT_PIDFILE)),
+        log_file,
+    });
 
-    fn write(&mut self, data: u8) -> Result<(), String> {
-        if self.pos >= 512 {
-            return Err("End of buffer".to_string());
-        }
-        self.buf[self.pos] = data;
-        self.pos += 1;
-        Ok(())
+    Args {
+        config_path,
+        daemon,
+        command,
     }
+}
 
-    fn write_u16(&mut self, data: u16) -> Result<(), String> {
-        self.write((data >> 8) as u8)?;
-        self.write(data as u8)?;
-        Ok(())
+fn load_config(path: Option<&str>) -> Config {
+    match path {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => Config::new(),
     }
+}
 
-    fn write_u32(&mut self, data: u32) -> Result<(), String> {
-        self.write_u16((data >> 16) as u16)?;
-        self.write_u16(data as u16)?;
-
-        Ok(())
+// Reports every problem in the config file; the exit status tells a deploy
+// script whether it is safe to restart with it.
+fn check_config_command(path: &str) {
+    let errors = Config::check(path);
+    if errors.is_empty() {
+        println!("{}: ok", path);
+        return;
     }
-
-    fn write_qname(&mut self, qname: &String) -> Result<(), String> {
-        for split in qname.split(".") {
-            self.write(split.len() as u8)?;
-            for byte in split.bytes() {
-                self.write(byte)?;
-            }
-        }
-        self.write(0)?;
-        Ok(())
+    for e in errors.iter() {
+        eprintln!("{}", e);
     }
+    std::process::exit(1);
 }
 
-#[derive(Debug, PartialEq)]
-struct DnsHeader {
-    id: u16,                     // 16 Byte
-    query: bool,                 // 1 Bit
-    opcode: OpCode,              // 4 Bit
-    authoritative_answer: bool,  // 1 Bit
-    truncation: bool,            // 1 Bit,
-    recursion_desired: bool,     // 1 Bit
-    recursion_available: bool,   // 1 Bit
-    z: u8,                       // 3 Bit
-    response_code: ResponseCode, // 4 Bit
-
-    questions: u16,   // 16 Byte
-    answers: u16,     // 16 Byte
-    nameservers: u16, // 16 Byte
-    additionals: u16, // 16 Byte
-}
-
-impl DnsHeader {
-    fn new() -> DnsHeader {
-        DnsHeader {
-            id: 0,
-            query: false,
-            opcode: OpCode::QUERY,
-            authoritative_answer: false,
-            truncation: false,
-            recursion_desired: false,
-            recursion_available: false,
-            z: 0,
-            response_code: ResponseCode::NOERR,
+// Parses and lints a zone file the way named-checkzone does.
+fn check_zone_command(origin: &str, path: &str) {
+    let origin = Name::from_unicode(origin).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
 
-            questions: 0,
-            answers: 0,
-            nameservers: 0,
-            additionals: 0,
-        }
+    let problems = zonefile::check(&origin, path);
+    if problems.is_empty() {
+        println!("{}: zone {} ok", path, origin);
+        return;
     }
-
-    fn read(&mut self, buf_handler: &mut BufHandler) -> Result<(), String> {
-        self.id = buf_handler.read_u16()?;
-        let flags = buf_handler.read_u16()?;
-        let a = (flags >> 8) as u8;
-        let b = (flags & 0xFF) as u8;
-
-        self.query = ((a >> 7) & 0x1) == 1;
-        self.opcode = OpCode::from_num((a >> 3) & 0xF);
-        self.authoritative_answer = (a >> 2 & 0x1) == 1;
-        self.truncation = (a >> 1 & 0x1) == 1;
-        self.recursion_desired = (a & 0x1) == 1;
-
-        self.recursion_available = ((b >> 7) & 0x1) == 1;
-        self.z = (b >> 4) & 0xF;
-        self.response_code = ResponseCode::from_num(b & 0xF);
-
-        self.questions = buf_handler.read_u16()?;
-        self.answers = buf_handler.read_u16()?;
-        self.nameservers = buf_handler.read_u16()?;
-        self.additionals = buf_handler.read_u16()?;
-
-        Ok(())
-    }
-
-    fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
-        buf_handler.write_u16(self.id)?;
-
-        buf_handler.write(
-            (self.query as u8) << 7
-                | (self.opcode as u8) << 3
-                | (self.authoritative_answer as u8) << 2
-                | (self.truncation as u8) << 1
-                | (self.recursion_desired as u8),
-        )?;
-
-        buf_handler.write(
-            (self.recursion_available as u8) << 7 | self.z << 4 | (self.response_code as u8),
-        )?;
-
-        buf_handler.write_u16(self.questions)?;
-        buf_handler.write_u16(self.answers)?;
-        buf_handler.write_u16(self.nameservers)?;
-        buf_handler.write_u16(self.additionals)?;
-
-        Ok(())
+    for problem in problems.iter() {
+        eprintln!("{}", problem);
     }
+    std::process::exit(1);
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum QueryType {
-    A,
-    NS,
-    CNAME,
-    MX,
-    AAAA,
-    UNKNOWN,
+// A server address given on the command line, port 53 if it has none.
+fn parse_server(server: &str) -> SocketAddr {
+    server
+        .parse::<SocketAddr>()
+        .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .unwrap_or_else(|_| {
+            eprintln!("bad server address {:?}", server);
+            std::process::exit(2);
+        })
 }
 
-impl QueryType {
-    fn from_num(num: u16) -> QueryType {
-        match num {
-            1 => QueryType::A,
-            2 => QueryType::NS,
-            5 => QueryType::CNAME,
-            15 => QueryType::MX,
-            28 => QueryType::AAAA,
-            _ => QueryType::UNKNOWN,
-        }
-    }
+// Checks the codec against every message in a capture and, given a server,
+// replays the queries in it against the server and compares the responses.
+fn replay_command(path: &str, server: Option<&str>) {
+    let server = server.map(parse_server);
 
-    fn to_num(&self) -> u16 {
-        match *self {
-            QueryType::A => 1,
-            QueryType::NS => 2,
-            QueryType::CNAME => 5,
-            QueryType::MX => 15,
-            QueryType::AAAA => 28,
-            QueryType::UNKNOWN => 0,
-        }
+    let report = replay::replay(Path::new(path), server).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    let summary = &report.summary;
+    println!(
+        "{}: {} frames, {} DNS messages, {} queries replayed, {} problems",
+        path,
+        summary.frames,
+        report.messages,
+        report.replayed,
+        report.problems.len()
+    );
+    if summary.unsupported + summary.fragments + summary.truncated_streams > 0 {
+        println!(
+            "skipped {} frames of unsupported types, {} IP fragments and {} incomplete TCP streams",
+            summary.unsupported, summary.fragments, summary.truncated_streams
+        );
+    }
+    if !report.problems.is_empty() {
+        std::process::exit(1);
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct DnsQuestion {
-    name: String,
-    qtype: QueryType,
-}
-
-impl DnsQuestion {
-    fn new() -> DnsQuestion {
-        DnsQuestion {
-            name: String::from(""),
-            qtype: QueryType::A,
+// Asks two servers the same question and prints how their responses differ.
+fn diff_command(name: &str, qtype: &str, first: &str, second: &str) {
+    let name = Name::from_unicode(name.strip_suffix('.').unwrap_or(name)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let qtype = match QueryType::from_name(qtype) {
+        QueryType::UNKNOWN(0) => {
+            eprintln!("unknown record type {:?}", qtype);
+            std::process::exit(2);
         }
+        qtype => qtype,
+    };
+
+    let answers = compare::ask_both(&name, qtype, [parse_server(first), parse_server(second)]);
+    println!("{} {}", name, qtype.name());
+    for (mark, answer) in ["---", "+++"].iter().zip(answers.iter()) {
+        println!(
+            "{} {} ({} ms)",
+            mark,
+            answer.server,
+            answer.elapsed.as_millis()
+        );
     }
-
-    fn read(&mut self, buf_handler: &mut BufHandler) -> Result<(), String> {
-        buf_handler.read_qname(&mut self.name)?;
-        self.qtype = QueryType::from_num(buf_handler.read_u16()?);
-        let _qclass = buf_handler.read_u16()?;
-        Ok(())
+    let lines = compare::diff_answers(&answers[0], &answers[1]);
+    for line in lines.iter() {
+        println!("{}", line);
     }
-
-    fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
-        buf_handler.write_qname(&self.name)?;
-        buf_handler.write_u16(self.qtype.to_num())?;
-        buf_handler.write_u16(1)?;
-        Ok(())
+    if lines.iter().any(|line| !line.starts_with(' ')) {
+        std::process::exit(1);
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-enum DnsRecord {
-    UNKNOWN {
-        domain: String,
-        qtype: u16,
-    },
-    A {
-        domain: String,
-        addr: Ipv4Addr,
-        ttl: u32,
-    },
-    NS {
-        domain: String,
-        ttl: u32,
-        host: String,
-    },
-    CNAME {
-        domain: String,
-        ttl: u32,
-        host: String,
-    },
-    MX {
-        domain: String,
-        ttl: u32,
-        priority: u16,
-        host: String,
-    },
-    AAAA {
-        domain: String,
-        ttl: u32,
-        addr: Ipv6Addr,
-    },
+#[cfg(windows)]
+fn service_command(action: &str, config_path: Option<&str>) {
+    let result = match action {
+        "install" => service::install(config_path),
+        "uninstall" => service::uninstall(),
+        "run" => service::run(load_config(config_path)),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
 
-impl DnsRecord {
-    fn read(buf_handler: &mut BufHandler) -> Result<DnsRecord, String> {
-        let mut qname = String::new();
-        buf_handler.read_qname(&mut qname)?;
-
-        let qtype = QueryType::from_num(buf_handler.read_u16()?);
-
-        let _qclass = buf_handler.read_u16()?;
-        let ttl = buf_handler.read_u32()?;
-        let _len = buf_handler.read_u16()?;
-
-        match qtype {
-            QueryType::A => Ok(DnsRecord::A {
-                domain: qname,
-                addr: Ipv4Addr::new(
-                    buf_handler.read()?,
-                    buf_handler.read()?,
-                    buf_handler.read()?,
-                    buf_handler.read()?,
-                ),
-                ttl: ttl,
-            }),
-            QueryType::NS => {
-                let mut ns = String::new();
-                buf_handler.read_qname(&mut ns)?;
-                Ok(DnsRecord::NS {
-                    domain: qname,
-                    ttl: ttl,
-                    host: ns,
-                })
-            }
-            QueryType::CNAME => {
-                let mut cname = String::new();
-                buf_handler.read_qname(&mut cname)?;
-                Ok(DnsRecord::CNAME {
-                    domain: qname,
-                    ttl: ttl,
-                    host: cname,
-                })
-            }
-            QueryType::MX => {
-                let priority = buf_handler.read_u16()?;
-                let mut mx = String::new();
-                buf_handler.read_qname(&mut mx)?;
-
-                Ok(DnsRecord::MX {
-                    domain: qname,
-                    ttl: ttl,
-                    priority: priority,
-                    host: mx,
-                })
-            }
-            QueryType::AAAA => Ok(DnsRecord::AAAA {
-                domain: qname,
-                ttl: ttl,
-                addr: Ipv6Addr::new(
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                    buf_handler.read_u16()?,
-                ),
-            }),
-
-            _ => Ok(DnsRecord::UNKNOWN {
-                domain: qname,
-                qtype: qtype.to_num(),
-            }),
-        }
+#[cfg(unix)]
+fn serve_command(config: Config, daemon: Option<DaemonOptions>) {
+    if let Some(options) = &daemon
+        && let Err(e) = daemon::daemonize(&options.pidfile, options.log_file.as_deref())
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 
-    fn write(&self, buf_handler: &mut BufHandler) -> Result<(), String> {
-        match *self {
-            DnsRecord::A {
-                ref domain,
-                ref addr,
-                ttl,
-            } => {
-                buf_handler.write_qname(domain)?;
-                buf_handler.write_u16(QueryType::A.to_num())?;
-                buf_handler.write_u16(1)?;
-                buf_handler.write_u32(ttl)?;
-                buf_handler.write_u16(4)?;
-
-                for octet in addr.octets() {
-                    buf_handler.write(octet)?;
-                }
-            }
-            DnsRecord::AAAA {
-                ref domain,
-                ref addr,
-                ttl,
-            } => {
-                buf_handler.write_qname(domain)?;
-                buf_handler.write_u16(QueryType::AAAA.to_num())?;
-                buf_handler.write_u16(1)?;
-                buf_handler.write_u32(ttl)?;
-                buf_handler.write_u16(16)?;
-
-                for segment in addr.segments() {
-                    buf_handler.write_u16(segment)?;
-                }
-            }
-            DnsRecord::NS {
-                ref domain,
-                ttl,
-                ref host,
-            } => {
-                buf_handler.write_qname(domain)?;
-                buf_handler.write_u16(QueryType::NS.to_num())?;
-                buf_handler.write_u16(1)?;
-                buf_handler.write_u32(ttl)?;
-
-                buf_handler.write_u16((host.len() + 2) as u16)?;
-                buf_handler.write_qname(host)?;
-            }
-            DnsRecord::CNAME {
-                ref domain,
-                ttl,
-                ref host,
-            } => {
-                buf_handler.write_qname(domain)?;
-                buf_handler.write_u16(QueryType::CNAME.to_num())?;
-                buf_handler.write_u16(1)?;
-                buf_handler.write_u32(ttl)?;
-
-                buf_handler.write_u16((host.len() + 2) as u16)?;
-                buf_handler.write_qname(host)?;
-            }
-
-            DnsRecord::MX {
-                ref domain,
-                ttl,
-                ref host,
-                priority,
-            } => {
-                buf_handler.write_qname(domain)?;
-                buf_handler.write_u16(QueryType::MX.to_num())?;
-                buf_handler.write_u16(1)?;
-                buf_handler.write_u32(ttl)?;
-
-                buf_handler.write_u16((host.len() + 4) as u16)?;
-                buf_handler.write_u16(priority)?;
-                buf_handler.write_qname(host)?;
-            }
-            _ => {}
-        }
-        Ok(())
+    let result = server::serve(config);
+    daemon::remove_pidfile();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct DnsPacket {
-    header: DnsHeader,
-    questions: Vec<DnsQuestion>,
-    answers: Vec<DnsRecord>,
-    nameservers: Vec<DnsRecord>,
-    additionals: Vec<DnsRecord>,
-}
-
-impl DnsPacket {
-    fn new() -> DnsPacket {
-        DnsPacket {
-            header: DnsHeader::new(),
-            questions: Vec::new(),
-            answers: Vec::new(),
-            nameservers: Vec::new(),
-            additionals: Vec::new(),
-        }
+#[cfg(not(unix))]
+fn serve_command(config: Config, daemon: Option<DaemonOptions>) {
+    if daemon.is_some() {
+        eprintln!("--daemon is not supported on this platform");
+        std::process::exit(2);
     }
 
-    fn from_buffer(buf_handler: &mut BufHandler) -> Result<Self, String> {
-        let mut packet = Self::new();
-        packet.read(buf_handler)?;
-        Ok(packet)
+    if let Err(e) = server::serve(config) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
+}
 
-    fn read(&mut self, buf_reader: &mut BufHandler) -> Result<(), String> {
-        self.header.read(buf_reader)?;
-
-        for _ in 0..self.header.questions {
-            let mut question = DnsQuestion::new();
-            question.read(buf_reader)?;
-            self.questions.push(question);
-        }
-
-        for _ in 0..self.header.answers {
-            self.answers.push(DnsRecord::read(buf_reader)?);
-        }
-
-        for _ in 0..self.header.nameservers {
-            self.nameservers.push(DnsRecord::read(buf_reader)?);
-        }
-
-        for _ in 0..self.header.additionals {
-            self.additionals.push(DnsRecord::read(buf_reader)?);
-        }
-
-        Ok(())
+// Looks host up the way other programs on this machine would, through the
+// search list in resolv.conf.
+fn resolve_command(host: &str) {
+    if let Err(e) = Name::from_unicode(host.strip_suffix('.').unwrap_or(host)) {
+        eprintln!("{}", e);
+        std::process::exit(2);
     }
-
-    fn write(&mut self, buf_handler: &mut BufHandler) -> Result<(), String> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.nameservers = self.nameservers.len() as u16;
-        self.header.additionals = self.additionals.len() as u16;
-
-        self.header.write(buf_handler)?;
-
-        for question in self.questions.iter() {
-            question.write(buf_handler)?;
-        }
-
-        for answer in self.answers.iter() {
-            answer.write(buf_handler)?;
-        }
-
-        for nameserver in self.nameservers.iter() {
-            nameserver.write(buf_handler)?;
-        }
-
-        for additional in self.additionals.iter() {
-            additional.write(buf_handler)?;
+    let conf =
+        ResolvConf::load(Path::new(resolvconf::DEFAULT_PATH)).unwrap_or_else(|_| ResolvConf::new());
+
+    let resolver = Resolver::new(Arc::new(Latencies::new()));
+    let mut error = None;
+    for name in conf.candidates(host) {
+        match resolver.resolve_addresses(&name) {
+            Ok(addrs) if !addrs.is_empty() => {
+                for addr in addrs {
+                    println!("{}", addr);
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => error = Some(format!("{}: {}", name, e)),
         }
-
-        Ok(())
     }
-}
-
-fn lookup(qname: &String, qtype: QueryType, addr: Ipv4Addr) -> Result<DnsPacket, String> {
-    let udp_socket = UdpSocket::bind("0.0.0.0:34354").unwrap();
-    let mut packet = DnsPacket::new();
-    let mut buf_handler = BufHandler::new();
-
-    packet.header.recursion_desired = true;
-    packet.questions.push(DnsQuestion {
-        name: qname.to_string(),
-        qtype: qtype,
-    });
-    packet.write(&mut buf_handler)?;
-
-    udp_socket
-        .send_to(&buf_handler.buf[0..buf_handler.get_pos()], (addr, 53))
-        .unwrap();
-
-    buf_handler = BufHandler::new();
-    packet = DnsPacket::new();
 
-    udp_socket.recv_from(&mut buf_handler.buf).unwrap();
-    packet.read(&mut buf_handler)?;
-
-    Ok(packet)
+    eprintln!(
+        "{}",
+        error.unwrap_or_else(|| format!("{}: no addresses", host))
+    );
+    std::process::exit(1);
 }
 
 fn main() {
-    let udp_socket = UdpSocket::bind("0.0.0.0:6969").unwrap();
-    let mut buf_handler = BufHandler::new();
-
-    loop {
-        let (_, src) = udp_socket.recv_from(&mut buf_handler.buf).unwrap();
-
-        buf_handler.seek(0);
-        let mut request_packet = DnsPacket::from_buffer(&mut buf_handler).unwrap();
-
-        let mut response_packet = DnsPacket::new();
-        response_packet.header.id = request_packet.header.id;
-        response_packet.header.recursion_desired = true;
-        response_packet.header.recursion_available = true;
-        response_packet.header.authoritative_answer = true;
-
-        let mut packet: DnsPacket = DnsPacket::new();
-
-        if let Some(question) = request_packet.questions.pop() {
-            let mut current_address = "202.12.27.33".parse::<Ipv4Addr>().unwrap();
-
-            loop {
-                packet = lookup(&question.name, question.qtype, current_address).unwrap();
-
-                if !packet.answers.is_empty() {
-                    break;
-                }
-
-                for additional in packet.additionals {
-                    if let DnsRecord::A { addr, .. } = additional {
-                        current_address = addr;
-                    }
-                }
-            }
+    let args = parse_args();
+    let config_path = args.config_path.as_deref();
+
+    match args
+        .command
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => serve_command(load_config(config_path), args.daemon),
+        ["resolve", host] if args.daemon.is_none() => resolve_command(host),
+        ["check-config"] if args.daemon.is_none() => match config_path {
+            Some(path) => check_config_command(path),
+            None => usage(),
+        },
+        ["check-zone", origin, path] if args.daemon.is_none() => check_zone_command(origin, path),
+        ["replay", path] if args.daemon.is_none() => replay_command(path, None),
+        ["replay", path, server] if args.daemon.is_none() => replay_command(path, Some(server)),
+        ["diff", name, qtype, first, second] if args.daemon.is_none() => {
+            diff_command(name, qtype, first, second)
         }
-
-        for answer in packet.answers {
-            response_packet.answers.push(answer);
-        }
-
-        buf_handler.seek(0);
-        response_packet.write(&mut buf_handler).unwrap();
-
-        udp_socket
-            .send_to(&buf_handler.buf[0..buf_handler.get_pos()], src)
-            .unwrap();
+        #[cfg(windows)]
+        ["service", action] if args.daemon.is_none() => service_command(action, config_path),
+        _ => usage(),
     }
 }
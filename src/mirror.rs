@@ -0,0 +1,166 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Instant;
+
+use crate::DnsPacket;
+use crate::QueryType;
+use crate::anonymize;
+use crate::client;
+use crate::compare;
+use crate::log;
+use crate::log::LogLevel;
+use crate::name::Name;
+use crate::random::Rng;
+
+// Tee mode: a copy of the questions clients ask, all of them or a sample, also
+// goes to a second server, and its answers are logged next to the ones the
+// clients got, for trying out a new upstream or a staged version of this
+// server on real traffic. Only the clients' answers are ever sent back, and
+// copies are asked in the background, so a slow or broken mirror doesn't
+// hold up a query.
+//
+// Process wide like telemetry, as every listener's queries are copied.
+
+static MIRROR: OnceLock<Arc<Mirror>> = OnceLock::new();
+
+// Copies waiting to be asked; more are dropped rather than queued.
+const MAX_QUEUED: usize = 1000;
+
+// Threads asking the mirror, each waits up to client::QUERY_TIMEOUT for an
+// answer.
+pub const WORKERS: usize = 8;
+
+// "mirror <addr[:port]> [sample=<fraction>]".
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorConfig {
+    pub server: SocketAddr,
+    // the share of the queries copied
+    pub sample: f64,
+}
+
+// A question to ask the mirror, with what the client was told.
+struct Copied {
+    qname: Name,
+    qtype: QueryType,
+    answer: DnsPacket,
+}
+
+pub struct Mirror {
+    config: MirrorConfig,
+    rng: Mutex<Rng>,
+    queue: mpsc::SyncSender<Copied>,
+    copies: Mutex<mpsc::Receiver<Copied>>,
+    dropped: AtomicU64,
+}
+
+impl Mirror {
+    pub fn new(config: MirrorConfig) -> Mirror {
+        let (queue, copies) = mpsc::sync_channel(MAX_QUEUED);
+        Mirror {
+            config,
+            rng: Mutex::new(Rng::new()),
+            queue,
+            copies: Mutex::new(copies),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Makes this where offer sends copies.
+    pub fn install(self: &Arc<Mirror>) {
+        let _ = MIRROR.set(self.clone());
+    }
+
+    // Asks the mirror the copied questions, one at a time; run by WORKERS
+    // threads.
+    pub fn run(&self) {
+        loop {
+            let copy = match self.copies.lock().unwrap().recv() {
+                Ok(copy) => copy,
+                Err(_) => return,
+            };
+            self.ask(copy);
+        }
+    }
+
+    // Asks over UDP, and again over TCP if the reply is truncated, and logs
+    // the reply: a line at debug when it agrees with what the client got,
+    // the differences at info when it doesn't.
+    fn ask(&self, copy: Copied) {
+        let server = self.config.server;
+        let name = anonymize::name(&copy.qname, Name::to_ascii);
+        let started = Instant::now();
+        let reply = client::lookup(&copy.qname, copy.qtype, server).and_then(|reply| {
+            if reply.header.truncation {
+                client::lookup_tcp(&copy.qname, copy.qtype, server, None)
+            } else {
+                Ok(reply)
+            }
+        });
+        let elapsed = started.elapsed().as_millis();
+
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                if log::enabled(LogLevel::Info) {
+                    eprintln!("mirror: {} {:?}: {}", name, copy.qtype, e);
+                }
+                return;
+            }
+        };
+        if compare::answers_agree(&copy.answer, &reply, copy.qtype) {
+            if log::enabled(LogLevel::Debug) {
+                eprintln!(
+                    "mirror: {} {:?}: {} agrees, {:?} in {} ms",
+                    name, copy.qtype, server, reply.header.response_code, elapsed
+                );
+            }
+            return;
+        }
+
+        if log::enabled(LogLevel::Info) {
+            eprintln!(
+                "mirror: {} {:?}: {} disagrees, {:?} in {} ms:",
+                name, copy.qtype, server, reply.header.response_code, elapsed
+            );
+            // the records would give the name away
+            if !anonymize::hides_names() {
+                for line in compare::diff(&copy.answer, &reply) {
+                    eprintln!("  {}", line);
+                }
+            }
+        }
+    }
+}
+
+// Copies a question a client was answered, answer, to the mirror, if there
+// is one and the query is in the sample.
+pub fn offer(qname: &Name, qtype: QueryType, answer: &DnsPacket) {
+    let Some(mirror) = MIRROR.get() else {
+        return;
+    };
+    let sample = mirror.config.sample;
+    if sample < 1.0 && (mirror.rng.lock().unwrap().next_u64() as f64) >= sample * u64::MAX as f64 {
+        return;
+    }
+    let copy = Copied {
+        qname: qname.clone(),
+        qtype,
+        answer: answer.clone(),
+    };
+    if mirror.queue.try_send(copy).is_err() {
+        // reported at the first and then every thousandth, not each time
+        let dropped = mirror.dropped.fetch_add(1, Ordering::Relaxed);
+        if dropped.is_multiple_of(1000) {
+            eprintln!(
+                "mirror: {} is behind, {} copies dropped",
+                mirror.config.server,
+                dropped + 1
+            );
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// recvmmsg for UDP sockets: every system call takes in whatever has queued
+// up, up to a batch. Each reply goes out as soon as it's ready, so a quick
+// answer doesn't wait for a slow one further on in the batch.
+
+use std::io;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::ptr;
+
+use crate::sys;
+use crate::sys::Datagram;
+use crate::sys::MMsgHdr;
+use crate::sys::MsgHdr;
+use crate::udp::RECV_SIZE;
+
+// Datagrams moved per system call.
+const BATCH: usize = 32;
+
+fn header(datagram: &Datagram) -> MMsgHdr {
+    MMsgHdr {
+        hdr: MsgHdr {
+            name: datagram.msg.name,
+            namelen: datagram.msg.namelen,
+            iov: datagram.msg.iov,
+            iovlen: datagram.msg.iovlen,
+            control: ptr::null_mut(),
+            controllen: 0,
+            flags: 0,
+        },
+        len: 0,
+    }
+}
+
+// Reads datagrams until done, calling handle for each; what it returns is
+// sent back to the sender.
+pub fn serve(
+    socket: &UdpSocket,
+    mut handle: impl FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+    done: impl Fn() -> bool,
+) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    let mut recvs: Vec<Box<Datagram>> = (0..BATCH)
+        .map(|_| Datagram::new(vec![0; RECV_SIZE]))
+        .collect();
+
+    loop {
+        for datagram in recvs.iter_mut() {
+            datagram.reset();
+        }
+        let mut headers: Vec<MMsgHdr> = recvs.iter().map(|datagram| header(datagram)).collect();
+
+        let received = unsafe {
+            sys::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                BATCH as u32,
+                sys::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for (datagram, header) in recvs.iter().zip(headers.iter()).take(received as usize) {
+            if header.hdr.flags & sys::MSG_TRUNC != 0 {
+                continue;
+            }
+            let Some(src) = datagram.addr.get() else {
+                continue;
+            };
+            if let Some(reply) = handle(&datagram.buf[..header.len as usize], src) {
+                let _ = socket.send_to(&reply, src);
+            }
+        }
+        if done() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+// RFC 3492 parameters for IDNA
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+const ACE_PREFIX: &str = "xn--";
+
+// RFC 1035 limits, in octets on the wire
+pub const MAX_LABEL_LEN: usize = 63;
+pub const MAX_NAME_LEN: usize = 255;
+
+fn adapt(delta: u32, num_points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        TMIN
+    } else if k >= bias + TMAX {
+        TMAX
+    } else {
+        k - bias
+    }
+}
+
+fn encode_digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+fn decode_digit(c: char) -> Option<u32> {
+    match c {
+        'a'..='z' => Some(c as u32 - 'a' as u32),
+        'A'..='Z' => Some(c as u32 - 'A' as u32),
+        '0'..='9' => Some(c as u32 - '0' as u32 + 26),
+        _ => None,
+    }
+}
+
+pub fn punycode_encode(input: &str) -> Result<String, String> {
+    let chars: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let overflow = || format!("label {:?} is too long to encode", input);
+
+    let mut output: String = input.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+
+    while (handled as usize) < chars.len() {
+        let m = chars.iter().copied().filter(|c| *c >= n).min().unwrap();
+        delta = (m - n)
+            .checked_mul(handled + 1)
+            .and_then(|d| d.checked_add(delta))
+            .ok_or_else(overflow)?;
+        n = m;
+
+        for c in chars.iter().copied() {
+            if c < n {
+                delta = delta.checked_add(1).ok_or_else(overflow)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta += 1;
+        n += 1;
+    }
+
+    Ok(output)
+}
+
+pub fn punycode_decode(input: &str) -> Result<String, String> {
+    let bad = || format!("invalid punycode {:?}", input);
+
+    let (basic, extended) = match input.rfind('-') {
+        Some(pos) => (&input[..pos], &input[pos + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return Err(bad());
+    }
+
+    let mut output: Vec<char> = basic.chars().collect();
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = extended.chars();
+
+    while digits.as_str() != "" {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = digits.next().and_then(decode_digit).ok_or_else(bad)?;
+            i = digit
+                .checked_mul(w)
+                .and_then(|d| d.checked_add(i))
+                .ok_or_else(bad)?;
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t).ok_or_else(bad)?;
+            k += BASE;
+        }
+
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len).ok_or_else(bad)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n).ok_or_else(bad)?);
+        i += 1;
+    }
+
+    Ok(output.into_iter().collect())
+}
+
+// Appends label in presentation form: \. and \\ for dots and backslashes in
+// it, \DDD for spaces and anything unprintable.
+pub fn escape_label(label: &[u8], out: &mut String) {
+    for &byte in label {
+        match byte {
+            b'.' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            0x21..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+}
+
+// Splits a name in presentation form into its labels, undoing the escapes
+// of escape_label.
+fn parse_labels(text: &str) -> Result<Vec<Vec<u8>>, String> {
+    if text.is_empty() || text == "." {
+        return Ok(Vec::new());
+    }
+
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'.' => {
+                if label.is_empty() {
+                    return Err(format!("empty label in {:?}", text));
+                }
+                labels.push(std::mem::take(&mut label));
+            }
+            b'\\' => {
+                let escaped = bytes
+                    .next()
+                    .ok_or_else(|| format!("{:?} ends in a backslash", text))?;
+                if !escaped.is_ascii_digit() {
+                    label.push(escaped);
+                    continue;
+                }
+                let digits = [Some(escaped), bytes.next(), bytes.next()];
+                let value = digits
+                    .iter()
+                    .try_fold(0u32, |value, digit| match digit {
+                        Some(digit) if digit.is_ascii_digit() => {
+                            Some(value * 10 + (digit - b'0') as u32)
+                        }
+                        _ => None,
+                    })
+                    .filter(|&value| value <= 255)
+                    .ok_or_else(|| format!("bad \\DDD escape in {:?}", text))?;
+                label.push(value as u8);
+            }
+            _ => label.push(byte),
+        }
+    }
+    // empty when the name ends in its trailing dot
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+// A domain name, kept as its labels in uncompressed wire form: each label as
+// its length octet and its octets, leaving out the terminating root label.
+// Internationalized labels are stored as punycode "xn--" labels, and a name
+// can be shown in Unicode for people reading configs, command output and
+// logs. Every Name is within the RFC 1035 limits, so it can always be
+// written to a message.
+//
+// Names are always absolute: "example.com" and "example.com." are the same
+// name, and the root is "" (or "."). The case is kept as given, but
+// comparisons and hashing ignore it, so a Name works as a map key. Names
+// order canonically (RFC 4034 section 6.1), the order of NSEC chains.
+#[derive(Clone)]
+pub struct Name {
+    wire: Vec<u8>,
+}
+
+// The labels of a name, see Name::labels.
+pub struct Labels<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let (&len, rest) = self.rest.split_first()?;
+        let (label, rest) = rest.split_at(len as usize);
+        self.rest = rest;
+        Some(label)
+    }
+}
+
+impl<'a> DoubleEndedIterator for Labels<'a> {
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        // length octets only lead forwards, so walk up to the last label
+        let mut pos = 0;
+        while pos < self.rest.len() {
+            let end = pos + 1 + self.rest[pos] as usize;
+            if end == self.rest.len() {
+                let label = &self.rest[pos + 1..];
+                self.rest = &self.rest[..pos];
+                return Some(label);
+            }
+            pos = end;
+        }
+        None
+    }
+}
+
+impl Name {
+    pub const fn root() -> Name {
+        Name { wire: Vec::new() }
+    }
+
+    // Builds a name out of labels, checking the wire limits.
+    pub fn from_labels<'a>(labels: impl IntoIterator<Item = &'a [u8]>) -> Result<Name, String> {
+        let mut wire = Vec::new();
+        for label in labels {
+            if label.is_empty() {
+                return Err("empty label".to_string());
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(format!(
+                    "label {:?} is longer than {} octets",
+                    String::from_utf8_lossy(label),
+                    MAX_LABEL_LEN
+                ));
+            }
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label);
+        }
+        let name = Name { wire };
+        if name.wire_len() > MAX_NAME_LEN {
+            return Err(format!(
+                "{:?} is longer than {} octets",
+                name.to_ascii(),
+                MAX_NAME_LEN
+            ));
+        }
+        Ok(name)
+    }
+
+    // For the wire form of a name that was checked already, as a message is
+    // read.
+    pub(crate) fn from_wire(wire: Vec<u8>) -> Name {
+        Name { wire }
+    }
+
+    // Accepts names in presentation form, e.g. "www.example.com." or
+    // "a\.b.example", but only ASCII.
+    pub fn from_ascii(text: &str) -> Result<Name, String> {
+        if !text.is_ascii() {
+            return Err(format!("{:?} is not ASCII", text));
+        }
+        let labels = parse_labels(text)?;
+        Name::from_labels(labels.iter().map(Vec::as_slice))
+    }
+
+    // Accepts names as people type them, e.g. "bücher.example", and converts
+    // every non-ASCII label to its "xn--" form.
+    pub fn from_unicode(text: &str) -> Result<Name, String> {
+        if text.is_ascii() {
+            return Name::from_ascii(text);
+        }
+
+        let mut labels = Vec::new();
+        for label in text.strip_suffix('.').unwrap_or(text).split('.') {
+            if label.is_empty() {
+                return Err(format!("empty label in {:?}", text));
+            }
+            if label.is_ascii() {
+                labels.extend(parse_labels(label)?);
+            } else {
+                let encoded = punycode_encode(&label.to_lowercase())?;
+                labels.push(format!("{}{}", ACE_PREFIX, encoded).into_bytes());
+            }
+        }
+        Name::from_labels(labels.iter().map(Vec::as_slice))
+    }
+
+    // The uncompressed wire form without the root label.
+    pub fn wire(&self) -> &[u8] {
+        &self.wire
+    }
+
+    // Octets the name takes up uncompressed, root label included.
+    pub fn wire_len(&self) -> usize {
+        self.wire.len() + 1
+    }
+
+    // Presentation form without the trailing dot; "" for the root.
+    pub fn to_ascii(&self) -> String {
+        let mut text = String::with_capacity(self.wire.len());
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                text.push('.');
+            }
+            escape_label(label, &mut text);
+        }
+        text
+    }
+
+    // The absolute form with its trailing dot, e.g. "example.com.".
+    pub fn to_fqdn(&self) -> String {
+        format!("{}.", self.to_ascii())
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.wire.is_empty()
+    }
+
+    // The labels from the leftmost one, none for the root.
+    pub fn labels(&self) -> Labels<'_> {
+        Labels { rest: &self.wire }
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels().count()
+    }
+
+    // How many labels at the end the two names have in common, e.g. 2 for
+    // www.example.com and mail.example.com.
+    pub fn common_labels(&self, other: &Name) -> usize {
+        self.labels()
+            .rev()
+            .zip(other.labels().rev())
+            .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+            .count()
+    }
+
+    // The canonical form of RFC 4034 section 6.2, all in lowercase, as names
+    // are when they are signed.
+    pub fn to_lowercase(&self) -> Name {
+        Name {
+            wire: self.wire.to_ascii_lowercase(),
+        }
+    }
+
+    // RFC 4034 section 6.1: labels compared from the rightmost one, each as
+    // lowercase octets with a shorter label before any it is the start of,
+    // and a name before the names below it. So example < a.example <
+    // yljkjljk.a.example < Z.a.example < zABC.a.EXAMPLE < z.example.
+    pub fn canonical_cmp(&self, other: &Name) -> Ordering {
+        let mut labels = self.labels().rev();
+        let mut other_labels = other.labels().rev();
+        loop {
+            match (labels.next(), other_labels.next()) {
+                (Some(label), Some(other_label)) => match cmp_labels(label, other_label) {
+                    Ordering::Equal => {}
+                    unequal => return unequal,
+                },
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+            }
+        }
+    }
+
+    // The name with its first label removed, None for the root.
+    pub fn parent(&self) -> Option<Name> {
+        let (&len, rest) = self.wire.split_first()?;
+        Some(Name {
+            wire: rest[len as usize..].to_vec(),
+        })
+    }
+
+    // self.suffix, e.g. www and example.com make www.example.com.
+    pub fn append(&self, suffix: &Name) -> Result<Name, String> {
+        Name::from_labels(self.labels().chain(suffix.labels()))
+    }
+
+    // Whether self is parent or somewhere below it. Everything is below the
+    // root.
+    pub fn is_subdomain_of(&self, parent: &Name) -> bool {
+        let Some(skip) = self.wire.len().checked_sub(parent.wire.len()) else {
+            return false;
+        };
+        // the part of self that is left has to start at a label
+        let mut pos = 0;
+        while pos < skip {
+            pos += 1 + self.wire[pos] as usize;
+        }
+        pos == skip && self.wire[skip..].eq_ignore_ascii_case(&parent.wire)
+    }
+
+    // Labels that don't decode cleanly are left in their ASCII form.
+    pub fn to_unicode(&self) -> String {
+        self.labels()
+            .map(|label| {
+                let mut text = String::new();
+                escape_label(label, &mut text);
+                let prefixed = text.len() > ACE_PREFIX.len()
+                    && text[..ACE_PREFIX.len()].eq_ignore_ascii_case(ACE_PREFIX);
+                if !prefixed {
+                    return text;
+                }
+                punycode_decode(&text[ACE_PREFIX.len()..]).unwrap_or(text)
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+// Two labels as lowercase octets.
+fn cmp_labels(a: &[u8], b: &[u8]) -> Ordering {
+    a.iter()
+        .map(|byte| byte.to_ascii_lowercase())
+        .cmp(b.iter().map(|byte| byte.to_ascii_lowercase()))
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        // length octets stay below the letters, so they compare as they are
+        self.wire.eq_ignore_ascii_case(&other.wire)
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for byte in self.wire.iter() {
+            state.write_u8(byte.to_ascii_lowercase());
+        }
+        state.write_u8(0xff);
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Name) -> Ordering {
+        self.canonical_cmp(other)
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_fqdn())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_unicode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unicode_labels_become_punycode() {
+        let name = Name::from_unicode("Bücher.example.").unwrap();
+        assert_eq!(name.to_ascii(), "xn--bcher-kva.example");
+        assert_eq!(name.to_unicode(), "bücher.example");
+    }
+
+    #[test]
+    fn punycode_round_trips() {
+        for label in ["bücher", "münchen", "例え", "ñandú"] {
+            let encoded = punycode_encode(label).unwrap();
+            assert!(encoded.is_ascii());
+            assert_eq!(punycode_decode(&encoded).unwrap(), label);
+        }
+    }
+
+    #[test]
+    fn escaped_labels_round_trip() {
+        let name = Name::from_ascii(r"a\.b\032c.example").unwrap();
+        assert_eq!(name.label_count(), 2);
+        assert_eq!(name.to_ascii(), r"a\.b\032c.example");
+    }
+
+    #[test]
+    fn names_compare_without_case() {
+        let upper = Name::from_ascii("WWW.Example.ORG").unwrap();
+        let lower = Name::from_ascii("www.example.org").unwrap();
+        assert_eq!(upper, lower);
+        assert!(upper.is_subdomain_of(&Name::from_ascii("EXAMPLE.org").unwrap()));
+        assert_eq!(upper.to_ascii(), "WWW.Example.ORG");
+    }
+}
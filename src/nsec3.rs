@@ -0,0 +1,110 @@
+use crate::digest;
+use crate::name::Name;
+
+// NSEC3 (RFC 5155) hashed owner names, as a signer names the records of an
+// NSEC3 chain and a validator looks a name up in one.
+
+// The only NSEC3 hash algorithm defined, SHA-1.
+pub const SHA1: u8 = 1;
+
+// More iterations than this make validators treat a zone as insecure
+// (RFC 9276 section 3.2), so signers shouldn't use them.
+pub const MAX_ITERATIONS: u16 = 150;
+
+const BASE32HEX: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+// The hash of name: SHA-1 over its canonical (lowercase) wire form and the
+// salt, then iterations more times over the previous hash and the salt.
+pub fn hash(name: &Name, salt: &[u8], iterations: u16) -> [u8; 20] {
+    let mut input = name.to_lowercase().wire().to_vec();
+    input.push(0);
+    input.extend_from_slice(salt);
+    let mut hash = digest::sha1(&input);
+    for _ in 0..iterations {
+        let mut input = hash.to_vec();
+        input.extend_from_slice(salt);
+        hash = digest::sha1(&input);
+    }
+    hash
+}
+
+// The owner of the NSEC3 record for name in zone: its hash in base32hex as
+// a label in front of the zone's name.
+pub fn hashed_owner(
+    name: &Name,
+    zone: &Name,
+    salt: &[u8],
+    iterations: u16,
+) -> Result<Name, String> {
+    let label = base32hex_encode(&hash(name, salt, iterations));
+    Name::from_labels([label.as_bytes()])?.append(zone)
+}
+
+// Base32 with the extended hex alphabet (RFC 4648 section 7), in lowercase
+// and without padding, as it is in NSEC3 owner names and next hashed owner
+// fields.
+pub fn base32hex_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut bits: u32 = 0;
+    let mut pending = 0;
+    for &byte in data {
+        bits = bits << 8 | byte as u32;
+        pending += 8;
+        while pending >= 5 {
+            pending -= 5;
+            text.push(BASE32HEX[(bits >> pending & 0x1F) as usize] as char);
+        }
+    }
+    if pending > 0 {
+        text.push(BASE32HEX[(bits << (5 - pending) & 0x1F) as usize] as char);
+    }
+    text
+}
+
+// Undoes base32hex_encode, taking either case and no padding. Bits left
+// over that don't make up a whole byte have to be zero.
+pub fn base32hex_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(text.len() * 5 / 8);
+    let mut bits: u32 = 0;
+    let mut pending = 0;
+    for c in text.bytes() {
+        let value = BASE32HEX
+            .iter()
+            .position(|&digit| digit == c.to_ascii_lowercase())
+            .ok_or_else(|| format!("bad base32hex {:?}", text))?;
+        bits = bits << 5 | value as u32;
+        pending += 5;
+        if pending >= 8 {
+            pending -= 8;
+            data.push((bits >> pending) as u8);
+        }
+    }
+    if pending >= 5 || bits & ((1 << pending) - 1) != 0 {
+        return Err(format!("bad base32hex {:?}", text));
+    }
+    Ok(data)
+}
+
+// A salt as it is written in NSEC3 and NSEC3PARAM records: hex, or "-" for
+// none.
+pub fn parse_salt(text: &str) -> Result<Vec<u8>, String> {
+    if text == "-" {
+        return Ok(Vec::new());
+    }
+    let bad = || format!("bad NSEC3 salt {:?}", text);
+    if !text.is_ascii() || !text.len().is_multiple_of(2) || text.len() > 2 * 255 {
+        return Err(bad());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| bad()))
+        .collect()
+}
+
+// Undoes parse_salt.
+pub fn salt_string(salt: &[u8]) -> String {
+    if salt.is_empty() {
+        return "-".to_string();
+    }
+    salt.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
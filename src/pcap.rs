@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+use crate::pipeline::Transport;
+
+// Reads the DNS messages out of a packet capture: pcap or pcapng files as
+// tcpdump and Wireshark write them, with Ethernet (VLAN tagged or not), Linux
+// cooked, loopback or raw IP frames. UDP and TCP on port 53 are looked at.
+//
+// TCP streams are split into messages by their length prefixes without
+// looking at sequence numbers, which is right for the usual capture of a
+// complete conversation but gets confused by retransmissions and reordering.
+// IP fragments are skipped.
+
+const DNS_PORT: u16 = 53;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+// A DNS message as seen on the wire.
+#[derive(Debug, Clone)]
+pub struct CapturedMessage {
+    // the number of the frame it was (or, over TCP, ended) in, from 1 like
+    // Wireshark counts them
+    pub frame: usize,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub transport: Transport,
+    pub data: Vec<u8>,
+}
+
+// What the capture held apart from the messages.
+#[derive(Debug, Default)]
+pub struct CaptureSummary {
+    pub frames: usize,
+    // frames with a link or network layer this doesn't read
+    pub unsupported: usize,
+    pub fragments: usize,
+    // TCP data left over at the end of a stream, an incomplete message
+    pub truncated_streams: usize,
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+// Reads integers in the byte order the file was written in.
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, data: &[u8], at: usize) -> Option<u16> {
+        let bytes = data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(self, data: &[u8], at: usize) -> Option<u32> {
+        let bytes = data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+// The frames of a capture with their link types.
+fn frames(data: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    let magic = data.get(0..4).ok_or("not a capture file: too short")?;
+    match magic {
+        [0xA1, 0xB2, 0xC3, 0xD4] | [0xA1, 0xB2, 0x3C, 0x4D] => {
+            pcap_frames(data, Endian { big: true })
+        }
+        [0xD4, 0xC3, 0xB2, 0xA1] | [0x4D, 0x3C, 0xB2, 0xA1] => {
+            pcap_frames(data, Endian { big: false })
+        }
+        [0x0A, 0x0D, 0x0D, 0x0A] => pcapng_frames(data),
+        _ => Err("not a pcap or pcapng file".to_string()),
+    }
+}
+
+fn pcap_frames(data: &[u8], endian: Endian) -> Result<Vec<(u32, &[u8])>, String> {
+    let linktype = endian.u32(data, 20).ok_or("truncated pcap header")? & 0x0FFF_FFFF;
+    let mut frames = Vec::new();
+    let mut pos = 24;
+    while pos < data.len() {
+        let captured = endian
+            .u32(data, pos + 8)
+            .ok_or("truncated pcap record header")? as usize;
+        let frame = data
+            .get(pos + 16..pos + 16 + captured)
+            .ok_or("truncated pcap record")?;
+        frames.push((linktype, frame));
+        pos += 16 + captured;
+    }
+    Ok(frames)
+}
+
+// pcapng: a section header gives the byte order, interface descriptions the
+// link types, and enhanced and simple packet blocks the frames.
+fn pcapng_frames(data: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    let mut frames = Vec::new();
+    let mut endian = Endian { big: false };
+    let mut interfaces: Vec<u32> = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if data.get(pos..pos + 4) == Some(&[0x0A, 0x0D, 0x0D, 0x0A]) {
+            let magic = data
+                .get(pos + 8..pos + 12)
+                .ok_or("truncated pcapng section")?;
+            endian = Endian {
+                big: magic == [0x1A, 0x2B, 0x3C, 0x4D],
+            };
+            interfaces.clear();
+        }
+        let kind = endian.u32(data, pos).ok_or("truncated pcapng block")?;
+        let len = endian.u32(data, pos + 4).ok_or("truncated pcapng block")? as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return Err(format!("bad pcapng block length {} at offset {}", len, pos));
+        }
+        let block = data
+            .get(pos + 8..pos + len - 4)
+            .ok_or("truncated pcapng block")?;
+
+        match kind {
+            // interface description
+            1 => interfaces.push(endian.u16(block, 0).ok_or("truncated pcapng interface")? as u32),
+            // enhanced packet
+            6 => {
+                let interface = endian.u32(block, 0).ok_or("truncated pcapng packet")? as usize;
+                let captured = endian.u32(block, 12).ok_or("truncated pcapng packet")? as usize;
+                let linktype = *interfaces
+                    .get(interface)
+                    .ok_or("pcapng packet for an undescribed interface")?;
+                frames.push((
+                    linktype,
+                    block
+                        .get(20..20 + captured)
+                        .ok_or("truncated pcapng packet")?,
+                ));
+            }
+            // simple packet, always on the first interface
+            3 => {
+                let original = endian.u32(block, 0).ok_or("truncated pcapng packet")? as usize;
+                let linktype = *interfaces
+                    .first()
+                    .ok_or("pcapng packet for an undescribed interface")?;
+                let captured = original.min(block.len() - 4);
+                frames.push((linktype, &block[4..4 + captured]));
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+    Ok(frames)
+}
+
+// The IP packet in a frame.
+fn ip_packet(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    let (ethertype, payload) = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            let mut ethertype = u16_at(frame, at)?;
+            while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+                at += 4;
+                ethertype = u16_at(frame, at)?;
+            }
+            (Some(ethertype), frame.get(at + 2..)?)
+        }
+        LINKTYPE_LINUX_SLL => (Some(u16_at(frame, 14)?), frame.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (Some(u16_at(frame, 0)?), frame.get(20..)?),
+        // the address family in the capturing host's byte order, which is
+        // small enough to read either way
+        LINKTYPE_NULL => {
+            let family = frame.get(0..4)?.iter().map(|&b| b as u32).sum::<u32>();
+            let ethertype = match family {
+                2 => ETHERTYPE_IPV4,
+                24 | 28 | 30 => ETHERTYPE_IPV6,
+                _ => return None,
+            };
+            (Some(ethertype), frame.get(4..)?)
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => (None, frame),
+        _ => return None,
+    };
+    match ethertype {
+        None | Some(ETHERTYPE_IPV4) | Some(ETHERTYPE_IPV6) => Some(payload),
+        _ => None,
+    }
+}
+
+enum Transported<'a> {
+    Segment {
+        protocol: u8,
+        src: IpAddr,
+        dst: IpAddr,
+        payload: &'a [u8],
+    },
+    Fragment,
+}
+
+// The transport protocol, addresses and transport layer data of an IP packet.
+fn transport(packet: &[u8]) -> Option<Transported<'_>> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_len = (packet[0] & 0x0F) as usize * 4;
+            let total_len = u16_at(packet, 2)? as usize;
+            let fragment = u16_at(packet, 6)?;
+            // more fragments, or not the first one
+            if fragment & 0x2000 != 0 || fragment & 0x1FFF != 0 {
+                return Some(Transported::Fragment);
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            Some(Transported::Segment {
+                protocol: *packet.get(9)?,
+                src: IpAddr::V4(Ipv4Addr::from(src)),
+                dst: IpAddr::V4(Ipv4Addr::from(dst)),
+                payload: packet.get(header_len..total_len.min(packet.len()))?,
+            })
+        }
+        6 => {
+            let payload_len = u16_at(packet, 4)? as usize;
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            let mut next = *packet.get(6)?;
+            let mut at = 40;
+            // hop-by-hop, routing and destination options come before the data
+            while matches!(next, 0 | 43 | 60) {
+                next = *packet.get(at)?;
+                at += (*packet.get(at + 1)? as usize + 1) * 8;
+            }
+            if next == 44 {
+                return Some(Transported::Fragment);
+            }
+            Some(Transported::Segment {
+                protocol: next,
+                src: IpAddr::V6(Ipv6Addr::from(src)),
+                dst: IpAddr::V6(Ipv6Addr::from(dst)),
+                payload: packet.get(at..(40 + payload_len).min(packet.len()))?,
+            })
+        }
+        _ => None,
+    }
+}
+
+// Reads every DNS message in the capture file contents data.
+pub fn read_messages(data: &[u8]) -> Result<(Vec<CapturedMessage>, CaptureSummary), String> {
+    let mut summary = CaptureSummary::default();
+    let mut messages = Vec::new();
+    // TCP data not yet split into messages, by direction
+    let mut streams: HashMap<(SocketAddr, SocketAddr), Vec<u8>> = HashMap::new();
+
+    for (index, (linktype, frame)) in frames(data)?.into_iter().enumerate() {
+        summary.frames += 1;
+        let Some(segment) = ip_packet(linktype, frame).and_then(transport) else {
+            summary.unsupported += 1;
+            continue;
+        };
+        let (protocol, src, dst, payload) = match segment {
+            Transported::Segment {
+                protocol,
+                src,
+                dst,
+                payload,
+            } => (protocol, src, dst, payload),
+            Transported::Fragment => {
+                summary.fragments += 1;
+                continue;
+            }
+        };
+        let (Some(src_port), Some(dst_port)) = (u16_at(payload, 0), u16_at(payload, 2)) else {
+            continue;
+        };
+        if src_port != DNS_PORT && dst_port != DNS_PORT {
+            continue;
+        }
+        let (src, dst) = (
+            SocketAddr::new(src, src_port),
+            SocketAddr::new(dst, dst_port),
+        );
+
+        match protocol {
+            PROTO_UDP => messages.push(CapturedMessage {
+                frame: index + 1,
+                src,
+                dst,
+                transport: Transport::Udp,
+                data: payload.get(8..).unwrap_or_default().to_vec(),
+            }),
+            PROTO_TCP => {
+                let Some(&offset) = payload.get(12) else {
+                    continue;
+                };
+                let data = payload
+                    .get((offset >> 4) as usize * 4..)
+                    .unwrap_or_default();
+                let flags = payload.get(13).copied().unwrap_or(0);
+                // SYN starts a stream afresh
+                if flags & 0x02 != 0 {
+                    streams.remove(&(src, dst));
+                }
+                let buffer = streams.entry((src, dst)).or_default();
+                buffer.extend_from_slice(data);
+                while let Some(len) = u16_at(buffer, 0).map(|len| len as usize) {
+                    if buffer.len() < 2 + len {
+                        break;
+                    }
+                    messages.push(CapturedMessage {
+                        frame: index + 1,
+                        src,
+                        dst,
+                        transport: Transport::Tcp,
+                        data: buffer[2..2 + len].to_vec(),
+                    });
+                    buffer.drain(..2 + len);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    summary.truncated_streams = streams.values().filter(|buffer| !buffer.is_empty()).count();
+    Ok((messages, summary))
+}
@@ -0,0 +1,103 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::DnsRecord;
+use crate::ResponseCode;
+use crate::client_policy::ClientPolicy;
+use crate::name::Name;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+// What a stage gets to see about the query being answered.
+pub struct Request<'a> {
+    pub packet: &'a DnsPacket,
+    pub question: &'a DnsQuestion,
+    pub src: SocketAddr,
+    pub transport: Transport,
+    // whether src may use the forwarding/recursing stages
+    pub recursion_allowed: bool,
+    // the policy of the client, None when no client-policy matches it
+    pub policy: Option<&'a ClientPolicy>,
+}
+
+// A stage of the query pipeline. A stage either answers the query by filling
+// in the response, or hands it on with `next.run(...)`, optionally looking at
+// (or changing) the response once the rest of the chain is done with it.
+pub trait Handler: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>);
+}
+
+// The remainder of the chain after the current stage. A stage that needs to
+// can run it more than once, e.g. for a related question.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    handlers: &'a [Arc<dyn Handler>],
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request, response: &mut DnsPacket) {
+        match self.handlers.split_first() {
+            Some((handler, rest)) => handler.handle(request, response, Next { handlers: rest }),
+            // nobody wanted the query
+            None => response.header.response_code = ResponseCode::REFUSED,
+        }
+    }
+}
+
+pub struct Pipeline {
+    handlers: Vec<Arc<dyn Handler>>,
+}
+
+impl Pipeline {
+    pub fn new(handlers: Vec<Arc<dyn Handler>>) -> Pipeline {
+        Pipeline { handlers }
+    }
+
+    pub fn push(&mut self, handler: Arc<dyn Handler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.handlers.iter().map(|handler| handler.name()).collect()
+    }
+
+    pub fn run(&self, request: &Request, response: &mut DnsPacket) {
+        Next {
+            handlers: &self.handlers,
+        }
+        .run(request, response);
+    }
+}
+
+// RFC 8482: answer ANY with a single synthesized HINFO instead of everything
+// we know (or can recurse for) about the name.
+pub fn minimal_any(qname: &Name) -> DnsRecord {
+    DnsRecord::HINFO {
+        domain: qname.clone(),
+        ttl: 3600,
+        cpu: b"RFC8482".to_vec(),
+        os: Vec::new(),
+    }
+}
+
+// The SOA to send with an NXDOMAIN or NODATA answer, out of the authority
+// records it came with: with the negative TTL (RFC 2308 section 3), the
+// lower of its own TTL and its minimum field.
+pub fn negative_soa(records: &[DnsRecord]) -> Option<DnsRecord> {
+    records.iter().find_map(|record| match *record {
+        DnsRecord::SOA { ttl, minimum, .. } => {
+            let mut soa = record.clone();
+            soa.set_ttl(ttl.min(minimum));
+            Some(soa)
+        }
+        _ => None,
+    })
+}
@@ -0,0 +1,100 @@
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::Mutex;
+
+use crate::BufHandler;
+use crate::DnsHeader;
+use crate::DnsPacket;
+
+// Buffers and packets are handed back here when a query is done with them
+// and given to the next one, so their memory is reused instead of going back
+// and forth to the allocator on every query.
+
+// Most of each kind kept around; beyond that they are freed as usual.
+const MAX_IDLE: usize = 128;
+
+static BUFFERS: Mutex<Vec<BufHandler>> = Mutex::new(Vec::new());
+static PACKETS: Mutex<Vec<DnsPacket>> = Mutex::new(Vec::new());
+
+pub trait Recycle: Sized + 'static {
+    fn free_list() -> &'static Mutex<Vec<Self>>;
+}
+
+impl Recycle for BufHandler {
+    fn free_list() -> &'static Mutex<Vec<BufHandler>> {
+        &BUFFERS
+    }
+}
+
+impl Recycle for DnsPacket {
+    fn free_list() -> &'static Mutex<Vec<DnsPacket>> {
+        &PACKETS
+    }
+}
+
+// Goes back to the pool when dropped.
+pub struct Pooled<T: Recycle>(Option<T>);
+
+impl<T: Recycle> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl<T: Recycle> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl<T: Recycle> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        let mut free = T::free_list().lock().unwrap();
+        if free.len() < MAX_IDLE
+            && let Some(item) = self.0.take()
+        {
+            free.push(item);
+        }
+    }
+}
+
+fn take<T: Recycle>() -> Option<T> {
+    T::free_list().lock().unwrap().pop()
+}
+
+// A zeroed buffer of size bytes to write a message into.
+pub fn buffer(size: usize) -> Pooled<BufHandler> {
+    let Some(mut buf_handler) = take::<BufHandler>() else {
+        return Pooled(Some(BufHandler::with_size(size)));
+    };
+    buf_handler.buf.clear();
+    buf_handler.buf.resize(size, 0);
+    buf_handler.seek(0);
+    Pooled(Some(buf_handler))
+}
+
+// A buffer holding a copy of a received message, to read it from.
+pub fn buffer_from(data: &[u8]) -> Pooled<BufHandler> {
+    let Some(mut buf_handler) = take::<BufHandler>() else {
+        return Pooled(Some(BufHandler::from_bytes(data)));
+    };
+    buf_handler.buf.clear();
+    buf_handler.buf.extend_from_slice(data);
+    buf_handler.seek(0);
+    Pooled(Some(buf_handler))
+}
+
+// An empty packet, as from DnsPacket::new.
+pub fn packet() -> Pooled<DnsPacket> {
+    let Some(mut packet) = take::<DnsPacket>() else {
+        return Pooled(Some(DnsPacket::new()));
+    };
+    packet.header = DnsHeader::new();
+    packet.questions.clear();
+    packet.answers.clear();
+    packet.nameservers.clear();
+    packet.additionals.clear();
+    Pooled(Some(packet))
+}
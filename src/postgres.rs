@@ -0,0 +1,253 @@
+// The part of libpq the PostgreSQL zone store needs, declared by hand
+// rather than pulling in a bindings crate. Built with the postgres feature,
+// which links the system's libpq.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_ulong;
+use std::ffi::c_void;
+use std::ptr;
+use std::time::Duration;
+
+const CONNECTION_OK: c_int = 0;
+const PGRES_COMMAND_OK: c_int = 1;
+const PGRES_TUPLES_OK: c_int = 2;
+
+const POLLIN: i16 = 0x1;
+
+#[repr(C)]
+struct PgNotify {
+    relname: *mut c_char,
+    _be_pid: c_int,
+    extra: *mut c_char,
+    _next: *mut PgNotify,
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+#[link(name = "pq")]
+unsafe extern "C" {
+    fn PQconnectdb(conninfo: *const c_char) -> *mut c_void;
+    fn PQstatus(conn: *const c_void) -> c_int;
+    fn PQerrorMessage(conn: *const c_void) -> *const c_char;
+    fn PQfinish(conn: *mut c_void);
+    fn PQexec(conn: *mut c_void, command: *const c_char) -> *mut c_void;
+    fn PQexecParams(
+        conn: *mut c_void,
+        command: *const c_char,
+        n_params: c_int,
+        param_types: *const u32,
+        param_values: *const *const c_char,
+        param_lengths: *const c_int,
+        param_formats: *const c_int,
+        result_format: c_int,
+    ) -> *mut c_void;
+    fn PQresultStatus(res: *const c_void) -> c_int;
+    fn PQresultErrorMessage(res: *const c_void) -> *const c_char;
+    fn PQntuples(res: *const c_void) -> c_int;
+    fn PQgetvalue(res: *const c_void, row: c_int, column: c_int) -> *const c_char;
+    fn PQgetisnull(res: *const c_void, row: c_int, column: c_int) -> c_int;
+    fn PQcmdTuples(res: *mut c_void) -> *const c_char;
+    fn PQclear(res: *mut c_void);
+    fn PQsocket(conn: *const c_void) -> c_int;
+    fn PQconsumeInput(conn: *mut c_void) -> c_int;
+    fn PQnotifies(conn: *mut c_void) -> *mut PgNotify;
+    fn PQfreemem(ptr: *mut c_void);
+}
+
+unsafe extern "C" {
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+fn message(text: *const c_char) -> String {
+    if text.is_null() {
+        return "out of memory".to_string();
+    }
+    // libpq's messages may run over several indented lines
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// A NOTIFY received on a channel the connection LISTENs on.
+pub struct Notification {
+    pub channel: String,
+    pub payload: String,
+}
+
+pub struct Connection {
+    conn: *mut c_void,
+}
+
+// libpq connections may move between threads as long as only one uses them
+// at a time, which the borrow rules see to.
+unsafe impl Send for Connection {}
+
+impl Connection {
+    // Connects with a libpq connection string, "host=... dbname=..." or a
+    // postgresql:// URL.
+    pub fn open(conninfo: &str) -> Result<Connection, String> {
+        let conninfo =
+            CString::new(conninfo).map_err(|_| "nul in connection string".to_string())?;
+        let conn = unsafe { PQconnectdb(conninfo.as_ptr()) };
+        // a handle comes back even when connecting fails, for the message
+        let connection = Connection { conn };
+        if conn.is_null() || unsafe { PQstatus(conn) } != CONNECTION_OK {
+            return Err(connection.error());
+        }
+        Ok(connection)
+    }
+
+    fn error(&self) -> String {
+        if self.conn.is_null() {
+            return "out of memory".to_string();
+        }
+        message(unsafe { PQerrorMessage(self.conn) })
+    }
+
+    // Whether the connection still works; it doesn't after the server went
+    // away.
+    pub fn is_ok(&self) -> bool {
+        unsafe { PQstatus(self.conn) == CONNECTION_OK }
+    }
+
+    fn check(&self, result: *mut c_void) -> Result<Rows, String> {
+        if result.is_null() {
+            return Err(self.error());
+        }
+        let rows = Rows { result };
+        match unsafe { PQresultStatus(result) } {
+            PGRES_COMMAND_OK | PGRES_TUPLES_OK => Ok(rows),
+            _ => Err(message(unsafe { PQresultErrorMessage(result) })),
+        }
+    }
+
+    // Runs statements without parameters, e.g. a schema; several may be
+    // separated by semicolons.
+    pub fn execute(&self, sql: &str) -> Result<(), String> {
+        let sql = CString::new(sql).map_err(|_| "nul in SQL".to_string())?;
+        self.check(unsafe { PQexec(self.conn, sql.as_ptr()) })
+            .map(drop)
+    }
+
+    // Runs sql with params for its $1, $2, ... parameters, all as text, None
+    // for NULL.
+    pub fn query(&self, sql: &str, params: &[Option<&str>]) -> Result<Rows, String> {
+        let sql = CString::new(sql).map_err(|_| "nul in SQL".to_string())?;
+        let params = params
+            .iter()
+            .map(|param| param.map(CString::new).transpose())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "nul in a parameter".to_string())?;
+        let values: Vec<*const c_char> = params
+            .iter()
+            .map(|param| param.as_ref().map_or(ptr::null(), |param| param.as_ptr()))
+            .collect();
+        self.check(unsafe {
+            PQexecParams(
+                self.conn,
+                sql.as_ptr(),
+                values.len() as c_int,
+                ptr::null(),
+                values.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                0,
+            )
+        })
+    }
+
+    // Runs a statement that returns no rows, returning how many rows it
+    // changed.
+    pub fn run(&self, sql: &str, params: &[Option<&str>]) -> Result<usize, String> {
+        let rows = self.query(sql, params)?;
+        let count = message(unsafe { PQcmdTuples(rows.result) });
+        Ok(count.parse().unwrap_or(0))
+    }
+
+    // Waits up to timeout for notifications and returns those that came,
+    // none when it timed out. Errors mean the connection is gone.
+    pub fn wait(&self, timeout: Duration) -> Result<Vec<Notification>, String> {
+        let mut fd = PollFd {
+            fd: unsafe { PQsocket(self.conn) },
+            events: POLLIN,
+            revents: 0,
+        };
+        if fd.fd < 0 {
+            return Err(self.error());
+        }
+        let ret = unsafe { poll(&mut fd, 1, timeout.as_millis() as c_int) };
+        if ret < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e.to_string());
+            }
+        }
+        if ret > 0 && unsafe { PQconsumeInput(self.conn) } == 0 {
+            return Err(self.error());
+        }
+
+        let mut notifications = Vec::new();
+        loop {
+            let notify = unsafe { PQnotifies(self.conn) };
+            if notify.is_null() {
+                return Ok(notifications);
+            }
+            unsafe {
+                notifications.push(Notification {
+                    channel: CStr::from_ptr((*notify).relname)
+                        .to_string_lossy()
+                        .into_owned(),
+                    payload: CStr::from_ptr((*notify).extra)
+                        .to_string_lossy()
+                        .into_owned(),
+                });
+                PQfreemem(notify as *mut c_void);
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { PQfinish(self.conn) };
+    }
+}
+
+// The rows a query returned, as text.
+pub struct Rows {
+    result: *mut c_void,
+}
+
+impl Rows {
+    pub fn count(&self) -> usize {
+        unsafe { PQntuples(self.result) as usize }
+    }
+
+    // The column of row, None for NULL.
+    pub fn get(&self, row: usize, column: usize) -> Option<String> {
+        let (row, column) = (row as c_int, column as c_int);
+        unsafe {
+            if PQgetisnull(self.result, row, column) != 0 {
+                return None;
+            }
+            Some(
+                CStr::from_ptr(PQgetvalue(self.result, row, column))
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    }
+}
+
+impl Drop for Rows {
+    fn drop(&mut self) {
+        unsafe { PQclear(self.result) };
+    }
+}
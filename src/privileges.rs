@@ -0,0 +1,134 @@
+// Giving up root once the listening sockets are bound, so nothing that
+// handles packets runs with more rights than it needs.
+
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// Only the leading fields, which are laid out the same on Linux and the BSDs.
+#[repr(C)]
+struct Passwd {
+    name: *const c_char,
+    passwd: *const c_char,
+    uid: u32,
+    gid: u32,
+}
+
+#[repr(C)]
+struct Group {
+    name: *const c_char,
+    passwd: *const c_char,
+    gid: u32,
+}
+
+unsafe extern "C" {
+    fn geteuid() -> u32;
+    fn getuid() -> u32;
+    fn setuid(uid: u32) -> c_int;
+    fn setgid(gid: u32) -> c_int;
+    fn setgroups(size: usize, list: *const u32) -> c_int;
+    fn chroot(path: *const c_char) -> c_int;
+    fn chdir(path: *const c_char) -> c_int;
+    fn getpwnam(name: *const c_char) -> *const Passwd;
+    fn getgrnam(name: *const c_char) -> *const Group;
+}
+
+fn check(ret: c_int, what: &str) -> Result<(), String> {
+    if ret < 0 {
+        return Err(format!("{}: {}", what, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// A user name or uid, with its primary group.
+fn lookup_user(user: &str) -> Result<(u32, Option<u32>), String> {
+    if let Ok(uid) = user.parse() {
+        return Ok((uid, None));
+    }
+    let name = CString::new(user).map_err(|e| e.to_string())?;
+    let passwd = unsafe { getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("unknown user {:?}", user));
+    }
+    let passwd = unsafe { &*passwd };
+    Ok((passwd.uid, Some(passwd.gid)))
+}
+
+fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|e| e.to_string())?;
+    let entry = unsafe { getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("unknown group {:?}", group));
+    }
+    Ok(unsafe { (*entry).gid })
+}
+
+// The uid and gid to run as; group defaults to the user's primary group.
+pub fn lookup_ids(user: &str, group: Option<&str>) -> Result<(u32, u32), String> {
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid.ok_or_else(|| format!("user {} needs a group", user))?,
+    };
+    Ok((uid, gid))
+}
+
+// Changes the root directory to dir. Nothing outside it can be opened
+// afterwards, so everything the server reads has to be loaded by then.
+fn enter_chroot(dir: &Path) -> Result<(), String> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    check(
+        unsafe { chroot(path.as_ptr()) },
+        &format!("chroot {}", dir.display()),
+    )?;
+    check(unsafe { chdir(c"/".as_ptr()) }, "chdir /")
+}
+
+// Locks the process into chroot_dir if given, then switches to user and group
+// (by default the user's primary group), dropping every supplementary group.
+// Without a user to switch to, running as root is refused unless allow_root
+// is set. Must be called before any thread starts handling packets.
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    chroot_dir: Option<&Path>,
+    allow_root: bool,
+) -> Result<(), String> {
+    let Some(user) = user else {
+        if unsafe { geteuid() } == 0 && !allow_root {
+            return Err(
+                "refusing to run as root: set user (and group) or allow-root yes".to_string(),
+            );
+        }
+        if group.is_some() {
+            return Err("group needs a user".to_string());
+        }
+        return match chroot_dir {
+            Some(dir) => enter_chroot(dir),
+            None => Ok(()),
+        };
+    };
+
+    // looked up while /etc is still reachable
+    let (uid, gid) = lookup_ids(user, group)?;
+    if let Some(dir) = chroot_dir {
+        enter_chroot(dir)?;
+    }
+
+    // the group has to go first, setgid isn't allowed any more after setuid
+    check(unsafe { setgroups(1, &gid) }, "setgroups")?;
+    check(unsafe { setgid(gid) }, "setgid")?;
+    check(unsafe { setuid(uid) }, "setuid")?;
+
+    // make sure there is no way back
+    if uid != 0 && (unsafe { setuid(0) } == 0 || unsafe { getuid() } == 0) {
+        return Err("could not drop root privileges".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use std::net::IpAddr;
+
+use crate::QueryType;
+use crate::acl::Acl;
+
+// What happens to a query a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QtypeAction {
+    // answered REFUSED
+    Refuse,
+    // not answered at all
+    Drop,
+    // answered with no records
+    NoData,
+    // answered as if the name didn't exist
+    NxDomain,
+}
+
+impl QtypeAction {
+    pub fn from_name(name: &str) -> Option<QtypeAction> {
+        match name {
+            "refuse" => Some(QtypeAction::Refuse),
+            "drop" => Some(QtypeAction::Drop),
+            "nodata" => Some(QtypeAction::NoData),
+            "nxdomain" => Some(QtypeAction::NxDomain),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QtypeRule {
+    pub types: Vec<QueryType>,
+    pub action: QtypeAction,
+    // None matches every client
+    pub clients: Option<Acl>,
+}
+
+// Rules for query types clients may not ask for, e.g. ANY from everyone or
+// PTR from a guest network. The first rule matching a query decides.
+#[derive(Debug, Clone)]
+pub struct QtypePolicy {
+    rules: Vec<QtypeRule>,
+}
+
+impl QtypePolicy {
+    pub fn new() -> QtypePolicy {
+        QtypePolicy { rules: Vec::new() }
+    }
+
+    pub fn push(&mut self, rule: QtypeRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn action(&self, client: IpAddr, qtype: QueryType) -> Option<QtypeAction> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.types.contains(&qtype)
+                    && rule.clients.as_ref().is_none_or(|acl| acl.allows(client))
+            })
+            .map(|rule| rule.action)
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::acl::Acl;
+
+// Quotas count queries in windows of this length.
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Clients tracked before the ones that went quiet are forgotten.
+const PRUNE_AT: usize = 16384;
+
+// What to do with a query from a client over its quota.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaAction {
+    // answer with TC set and nothing else, so the client has to come back
+    // over TCP
+    Truncate,
+    Drop,
+    // a query that would be dropped, answered over UDP as Truncate does so
+    // that a client whose address is being spoofed can still get through
+    // over TCP; over TCP it is dropped
+    Slip,
+    Refuse,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaRule {
+    // queries per second above which UDP answers are truncated
+    pub soft: u32,
+    // queries per second above which hard_action applies
+    pub hard: u32,
+    // Drop or Refuse
+    pub hard_action: QuotaAction,
+    // with Drop, every slip-th query over the hard quota slips instead; 0
+    // drops them all
+    pub slip: u32,
+    // None matches every client
+    pub clients: Option<Acl>,
+    // clients are counted together per network of these lengths, so a
+    // whole IPv6 subnet can be held to one quota; 32 and 128 count each
+    // address on its own
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+// The rule as the config has it, less the ACL's name.
+impl std::fmt::Display for QuotaRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "client-quota {} {}", self.soft, self.hard)?;
+        if self.hard_action == QuotaAction::Drop {
+            f.write_str(" hard=drop")?;
+        }
+        if self.slip != 0 {
+            write!(f, " slip={}", self.slip)?;
+        }
+        if self.ipv4_prefix != 32 {
+            write!(f, " ipv4-prefix={}", self.ipv4_prefix)?;
+        }
+        if self.ipv6_prefix != 128 {
+            write!(f, " ipv6-prefix={}", self.ipv6_prefix)?;
+        }
+        Ok(())
+    }
+}
+
+impl QuotaRule {
+    // The network client is counted under.
+    fn aggregate(&self, client: IpAddr) -> IpAddr {
+        match client {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix as u32)
+                    .unwrap_or(0);
+                IpAddr::V4((u32::from(addr) & mask).into())
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix as u32)
+                    .unwrap_or(0);
+                IpAddr::V6((u128::from(addr) & mask).into())
+            }
+        }
+    }
+}
+
+struct Window {
+    start: Instant,
+    queries: u32,
+}
+
+// Per client query rates, held to the quota of the first rule matching the
+// client. Meant for containing a device that floods the server with queries,
+// whatever they are; clients no rule matches aren't counted. Windows are kept
+// per network and prefix length, so rules aggregating differently don't
+// share counts.
+pub struct ClientQuotas {
+    rules: Vec<QuotaRule>,
+    windows: Mutex<HashMap<(IpAddr, u8), Window>>,
+}
+
+impl ClientQuotas {
+    pub fn new(rules: Vec<QuotaRule>) -> ClientQuotas {
+        ClientQuotas {
+            rules,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts a query from client, and says what to do with it if the client
+    // is over its quota, and whose quota that is.
+    pub fn check(&self, client: IpAddr) -> Option<(QuotaAction, &QuotaRule)> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.clients.as_ref().is_none_or(|acl| acl.allows(client)))?;
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= PRUNE_AT {
+            windows.retain(|_, window| now.duration_since(window.start) < WINDOW);
+        }
+
+        let prefix = match client {
+            IpAddr::V4(_) => rule.ipv4_prefix,
+            IpAddr::V6(_) => rule.ipv6_prefix,
+        };
+        let window = windows
+            .entry((rule.aggregate(client), prefix))
+            .or_insert(Window {
+                start: now,
+                queries: 0,
+            });
+        if now.duration_since(window.start) >= WINDOW {
+            window.start = now;
+            window.queries = 0;
+        }
+        window.queries = window.queries.saturating_add(1);
+
+        if window.queries > rule.hard {
+            let excess = window.queries - rule.hard;
+            match rule.hard_action {
+                QuotaAction::Drop if rule.slip != 0 && excess.is_multiple_of(rule.slip) => {
+                    Some((QuotaAction::Slip, rule))
+                }
+                action => Some((action, rule)),
+            }
+        } else if window.queries > rule.soft {
+            Some((QuotaAction::Truncate, rule))
+        } else {
+            None
+        }
+    }
+}
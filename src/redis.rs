@@ -0,0 +1,296 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::log;
+use crate::log::LogLevel;
+
+// Redis is on the query path, so it gets little time to answer; a cache
+// that is slow to answer is worse than none.
+const TIMEOUT: Duration = Duration::from_millis(250);
+
+// After a failure Redis is left alone for this long, so queries don't all
+// wait for it to time out while it's down.
+const BACKOFF: Duration = Duration::from_secs(5);
+
+// Connections kept open for reuse.
+const MAX_IDLE: usize = 8;
+
+// Where the shared cache lives: redis://[[<user>]:<password>@]<host>[:<port>][/<db>].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisConfig {
+    // "host:port", resolved when the server starts
+    pub authority: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: u32,
+    // put in front of every key, so several caches (or other data) can
+    // share a database
+    pub prefix: String,
+}
+
+impl RedisConfig {
+    pub fn parse(url: &str) -> Result<RedisConfig, String> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| format!("cache-redis must be a redis:// URL, not {:?}", url))?;
+        let (rest, db) = match rest.split_once('/') {
+            Some((rest, "")) => (rest, 0),
+            Some((rest, db)) => (
+                rest,
+                db.parse::<u32>()
+                    .map_err(|_| format!("bad Redis database {:?}", db))?,
+            ),
+            None => (rest, 0),
+        };
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => {
+                let (user, password) = userinfo
+                    .split_once(':')
+                    .ok_or_else(|| format!("bad Redis credentials in {:?}", url))?;
+                let user = (!user.is_empty()).then(|| user.to_string());
+                (Some((user, password.to_string())), authority)
+            }
+            None => (None, rest),
+        };
+        if authority.is_empty() {
+            return Err(format!("no host in {:?}", url));
+        }
+        let has_port = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed.contains("]:"),
+            None => authority.contains(':'),
+        };
+        let authority = if has_port {
+            authority.to_string()
+        } else {
+            format!("{}:6379", authority)
+        };
+        let (username, password) = match credentials {
+            Some((user, password)) => (user, Some(password)),
+            None => (None, None),
+        };
+        Ok(RedisConfig {
+            authority,
+            username,
+            password,
+            db,
+            prefix: "dns-server:".to_string(),
+        })
+    }
+}
+
+// A reply to a command; error replies are returned as errors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Nil,
+    Status(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line.to_string()),
+        None => Err("connection closed".to_string()),
+    }
+}
+
+fn read_reply(reader: &mut impl BufRead) -> Result<Reply, String> {
+    let line = read_line(reader)?;
+    let (kind, rest) = line.split_at_checked(1).ok_or("empty reply")?;
+    let number = || {
+        rest.parse::<i64>()
+            .map_err(|_| format!("bad reply {:?}", line))
+    };
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Err(rest.to_string()),
+        ":" => Ok(Reply::Integer(number()?)),
+        "$" => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Reply::Nil);
+            }
+            let mut data = vec![0; len as usize + 2];
+            reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(data))
+        }
+        "*" => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Reply::Nil);
+            }
+            (0..len)
+                .map(|_| read_reply(reader))
+                .collect::<Result<_, _>>()
+                .map(Reply::Array)
+        }
+        _ => Err(format!("bad reply {:?}", line)),
+    }
+}
+
+pub struct Redis {
+    addr: SocketAddr,
+    config: RedisConfig,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+    // while set and in the future, commands fail without trying
+    resting_until: Mutex<Option<Instant>>,
+}
+
+impl Redis {
+    // Resolves the server's address, so it has to run while the resolver
+    // configuration is still reachable. Connections are made as needed.
+    pub fn new(config: RedisConfig) -> Result<Redis, String> {
+        let addr = config
+            .authority
+            .to_socket_addrs()
+            .map_err(|e| format!("cache-redis {}: {}", config.authority, e))?
+            .next()
+            .ok_or_else(|| format!("cache-redis {}: no address", config.authority))?;
+        Ok(Redis {
+            addr,
+            config,
+            idle: Mutex::new(Vec::new()),
+            resting_until: Mutex::new(None),
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.config.prefix
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let stream = TcpStream::connect_timeout(&self.addr, TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        stream
+            .set_write_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        let mut connection = BufReader::new(stream);
+
+        if let Some(password) = &self.config.password {
+            let mut args: Vec<&[u8]> = vec![b"AUTH"];
+            if let Some(user) = &self.config.username {
+                args.push(user.as_bytes());
+            }
+            args.push(password.as_bytes());
+            send(&mut connection, &args)?;
+        }
+        if self.config.db != 0 {
+            send(
+                &mut connection,
+                &[b"SELECT", self.config.db.to_string().as_bytes()],
+            )?;
+        }
+        Ok(connection)
+    }
+
+    // Sends a command on an idle connection, or a new one, and waits for the
+    // reply. A connection that failed is dropped, and the server rested.
+    pub fn command(&self, args: &[&[u8]]) -> Result<Reply, String> {
+        if let Some(until) = *self.resting_until.lock().unwrap()
+            && Instant::now() < until
+        {
+            return Err("resting after a failure".to_string());
+        }
+
+        let ask_new = || {
+            let mut connection = self.connect()?;
+            send(&mut connection, args).map(|reply| (connection, reply))
+        };
+        let idle = self.idle.lock().unwrap().pop();
+        let result = match idle {
+            Some(mut connection) => match send(&mut connection, args) {
+                Ok(reply) => Ok((connection, reply)),
+                // the server may have closed it while it was idle
+                Err(_) => ask_new(),
+            },
+            None => ask_new(),
+        };
+
+        match result {
+            Ok((connection, reply)) => {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < MAX_IDLE {
+                    idle.push(connection);
+                }
+                Ok(reply)
+            }
+            Err(e) => {
+                // error replies drop the connection too; they only come
+                // from a failed AUTH or commands the server doesn't take
+                *self.resting_until.lock().unwrap() = Some(Instant::now() + BACKOFF);
+                if log::enabled(LogLevel::Info) {
+                    eprintln!("cache-redis {}: {}", self.addr, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Every key matching pattern, SCANned in batches so the server isn't
+    // held up.
+    pub fn scan(&self, pattern: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let mut keys = Vec::new();
+        let mut cursor = b"0".to_vec();
+        loop {
+            let reply = self.command(&[b"SCAN", &cursor, b"MATCH", pattern, b"COUNT", b"1000"])?;
+            let Reply::Array(parts) = reply else {
+                return Err("bad SCAN reply".to_string());
+            };
+            match parts.as_slice() {
+                [Reply::Bulk(next), Reply::Array(batch)] => {
+                    for key in batch {
+                        if let Reply::Bulk(key) = key {
+                            keys.push(key.clone());
+                        }
+                    }
+                    if next.as_slice() == b"0" {
+                        return Ok(keys);
+                    }
+                    cursor = next.clone();
+                }
+                _ => return Err("bad SCAN reply".to_string()),
+            }
+        }
+    }
+}
+
+// Writes a command as an array of bulk strings and reads the reply.
+fn send(connection: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<Reply, String> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    connection
+        .get_mut()
+        .write_all(&out)
+        .map_err(|e| e.to_string())?;
+    read_reply(connection)
+}
+
+// Escapes the glob characters of a SCAN MATCH pattern.
+pub fn glob_escape(text: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(text.len());
+    for &byte in text {
+        if matches!(byte, b'*' | b'?' | b'[' | b']' | b'\\') {
+            escaped.push(b'\\');
+        }
+        escaped.push(byte);
+    }
+    escaped
+}
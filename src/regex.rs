@@ -0,0 +1,460 @@
+// A small regular expression engine for matching domain names: literals, `.`,
+// classes (`[a-z0-9-]`, `[^.]`, `\d`, `\w`, `\s`), groups, `|`, the repeats
+// `*`, `+`, `?`, `{m}`, `{m,}` and `{m,n}`, and the anchors `^` and `$`.
+//
+// Expressions are compiled to a program that is run as a Thompson NFA, so
+// matching takes time linear in the length of the name whatever the pattern,
+// and the size of a program is capped so a rule can't get expensive. Matching
+// ignores case and, like grep, finds the expression anywhere in the name
+// unless it is anchored.
+
+// what a pattern may compile to at most
+const MAX_PROGRAM: usize = 2000;
+const MAX_REPEAT: u32 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Class {
+    ranges: Vec<(u8, u8)>,
+    negated: bool,
+}
+
+impl Class {
+    fn new(ranges: Vec<(u8, u8)>, negated: bool) -> Class {
+        Class { ranges, negated }
+    }
+
+    fn matches(&self, c: u8) -> bool {
+        // the input is lowercase, so [A-Z] is checked with its uppercase
+        let found = self.ranges.iter().any(|&(low, high)| {
+            (low..=high).contains(&c) || (low..=high).contains(&c.to_ascii_uppercase())
+        });
+        found != self.negated
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Empty,
+    Byte(u8),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, String> {
+        let c = self.peek().ok_or("unexpected end of regex")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.eat(b'|') {
+            branches.push(self.concatenation()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == b'|' || c == b')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repeat(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.next()? {
+            b'(' => {
+                // (?:...) is the same group without captures to skip
+                if self.eat(b'?') && !self.eat(b':') {
+                    return Err("unsupported group (?".to_string());
+                }
+                let node = self.alternation()?;
+                if !self.eat(b')') {
+                    return Err("missing )".to_string());
+                }
+                node
+            }
+            b'.' => Node::Any,
+            b'^' => Node::Start,
+            b'$' => Node::End,
+            b'[' => Node::Class(self.class()?),
+            b'\\' => self.escape()?,
+            c @ (b'*' | b'+' | b'?' | b'{') => {
+                return Err(format!("nothing to repeat before {:?}", c as char));
+            }
+            c => Node::Byte(c.to_ascii_lowercase()),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.next()?;
+        Ok(match c {
+            b'd' | b'w' | b's' | b'D' | b'W' | b'S' => Node::Class(named_class(c)),
+            c if c.is_ascii_alphanumeric() => {
+                return Err(format!("unsupported escape \\{}", c as char));
+            }
+            c => Node::Byte(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.eat(b'^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().map_err(|_| "missing ]".to_string())?;
+            if c == b']' && !first {
+                break;
+            }
+            first = false;
+            let low = match c {
+                b'\\' => {
+                    let c = self.next()?;
+                    if matches!(c, b'd' | b'w' | b's') {
+                        ranges.extend(named_class(c).ranges);
+                        continue;
+                    }
+                    c
+                }
+                c => c,
+            };
+            // a - at the end of the class is itself
+            if self.peek() == Some(b'-') && self.text.get(self.pos + 1) != Some(&b']') {
+                self.pos += 1;
+                let high = match self.next()? {
+                    b'\\' => self.next()?,
+                    c => c,
+                };
+                if high < low {
+                    return Err(format!("bad class range {}-{}", low as char, high as char));
+                }
+                ranges.push((low, high));
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Class::new(ranges, negated))
+    }
+
+    fn repeat(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            Some(b'{') => {
+                self.pos += 1;
+                return self.counted(atom);
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    // {m}, {m,} or {m,n}, after the {
+    fn counted(&mut self, atom: Node) -> Result<Node, String> {
+        let min = self.number()?;
+        let max = if self.eat(b',') {
+            match self.peek() {
+                Some(b'}') => None,
+                _ => Some(self.number()?),
+            }
+        } else {
+            Some(min)
+        };
+        if !self.eat(b'}') {
+            return Err("missing }".to_string());
+        }
+        if max.is_some_and(|max| max < min) || min > MAX_REPEAT || max.unwrap_or(0) > MAX_REPEAT {
+            return Err(format!("bad repeat count, at most {}", MAX_REPEAT));
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn number(&mut self) -> Result<u32, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| "bad repeat count".to_string())
+    }
+}
+
+fn named_class(c: u8) -> Class {
+    let ranges = match c.to_ascii_lowercase() {
+        b'd' => vec![(b'0', b'9')],
+        b'w' => vec![(b'a', b'z'), (b'A', b'Z'), (b'0', b'9'), (b'_', b'_')],
+        _ => vec![(b' ', b' '), (b'\t', b'\r')],
+    };
+    Class::new(ranges, c.is_ascii_uppercase())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Inst {
+    Byte(u8),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    // try both, the first one first
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() >= MAX_PROGRAM {
+            return Err("regex too large".to_string());
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Empty => {}
+            Node::Byte(c) => {
+                self.push(Inst::Byte(*c))?;
+            }
+            Node::Any => {
+                self.push(Inst::Any)?;
+            }
+            Node::Class(class) => {
+                self.push(Inst::Class(class.clone()))?;
+            }
+            Node::Start => {
+                self.push(Inst::Start)?;
+            }
+            Node::End => {
+                self.push(Inst::End)?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alternate(branches) => {
+                let mut jumps = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(branch)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        self.program[split] = Inst::Split(split + 1, self.program.len());
+                    } else {
+                        self.compile(branch)?;
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat(node, min, max) => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node)?;
+                        self.push(Inst::Jump(split))?;
+                        self.program[split] = Inst::Split(split + 1, self.program.len());
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node)?;
+                        }
+                        let end = self.program.len();
+                        for split in splits {
+                            self.program[split] = Inst::Split(split + 1, end);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The threads of the NFA at one position, each program counter once.
+struct Threads {
+    pcs: Vec<usize>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Threads {
+        Threads {
+            pcs: Vec::with_capacity(size),
+            seen: vec![false; size],
+        }
+    }
+
+    fn clear(&mut self) {
+        for &pc in self.pcs.iter() {
+            self.seen[pc] = false;
+        }
+        self.pcs.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    source: String,
+    // the parsed expression, for looking at what it requires
+    node: Node,
+    program: Vec<Inst>,
+}
+
+impl Regex {
+    pub fn new(source: &str) -> Result<Regex, String> {
+        let bad = |e: String| format!("bad regex {:?}: {}", source, e);
+        let mut parser = Parser {
+            text: source.as_bytes(),
+            pos: 0,
+        };
+        let node = parser.alternation().map_err(bad)?;
+        if parser.pos < source.len() {
+            return Err(bad("unmatched )".to_string()));
+        }
+        let mut compiler = Compiler {
+            program: Vec::new(),
+        };
+        compiler.compile(&node).map_err(bad)?;
+        compiler.push(Inst::Match).map_err(bad)?;
+        Ok(Regex {
+            source: source.to_string(),
+            node,
+            program: compiler.program,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    // The literal text a match must end the input with, as in
+    // "\.example\.com$", and whether it must also start there.
+    pub fn literal_suffix(&self) -> (String, bool) {
+        let nodes = match &self.node {
+            Node::Concat(nodes) => nodes.as_slice(),
+            node => std::slice::from_ref(node),
+        };
+        let Some((Node::End, rest)) = nodes.split_last() else {
+            return (String::new(), false);
+        };
+        let mut suffix = Vec::new();
+        let mut whole = false;
+        for node in rest.iter().rev() {
+            match node {
+                Node::Byte(c) => suffix.push(*c),
+                Node::Start => {
+                    whole = true;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        suffix.reverse();
+        (String::from_utf8_lossy(&suffix).into_owned(), whole)
+    }
+
+    // Follows the jumps from pc, adding the threads that wait on input.
+    fn add(&self, threads: &mut Threads, pc: usize, pos: usize, len: usize) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if threads.seen[pc] {
+                continue;
+            }
+            threads.seen[pc] = true;
+            threads.pcs.push(pc);
+            match self.program[pc] {
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == len => stack.push(pc + 1),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let input = text.as_bytes();
+        let size = self.program.len();
+        let mut current = Threads::new(size);
+        let mut next = Threads::new(size);
+
+        for pos in 0..=input.len() {
+            // unanchored: a match may start anywhere
+            self.add(&mut current, 0, pos, input.len());
+            let c = input.get(pos).map(|c| c.to_ascii_lowercase());
+            for i in 0..current.pcs.len() {
+                let pc = current.pcs[i];
+                let advance = match (&self.program[pc], c) {
+                    (Inst::Match, _) => return true,
+                    (Inst::Byte(want), Some(c)) => *want == c,
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some(c)) => class.matches(c),
+                    _ => false,
+                };
+                if advance {
+                    self.add(&mut next, pc + 1, pos + 1, input.len());
+                }
+            }
+            current.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+}
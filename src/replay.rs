@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::BufHandler;
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::TCP_MESSAGE_SIZE;
+use crate::pcap;
+use crate::pcap::CapturedMessage;
+use crate::pipeline::Transport;
+
+// Replays the client queries of a packet capture, for checking changes to the
+// codec or the server against real traffic. Every message in the capture is
+// parsed, written back out and parsed again, which has to give the same
+// packet. With a server to replay against, every query is sent to it as it
+// was captured and its response compared with the one in the capture.
+
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Report {
+    pub messages: usize,
+    pub replayed: usize,
+    // one line per problem, with the frame it is about
+    pub problems: Vec<String>,
+    pub summary: pcap::CaptureSummary,
+}
+
+// Parses data, writes it and parses the result again.
+pub fn check_codec(data: &[u8]) -> Result<DnsPacket, String> {
+    let packet = DnsPacket::from_buffer(&mut BufHandler::from_bytes(data))
+        .map_err(|e| format!("parse failed: {}", e))?;
+
+    // write fills in the header counts, so it gets a copy to work on
+    let mut written = BufHandler::with_size(TCP_MESSAGE_SIZE);
+    let mut copy = DnsPacket::from_buffer(&mut BufHandler::from_bytes(data))
+        .map_err(|e| format!("parse failed: {}", e))?;
+    copy.write(&mut written)
+        .map_err(|e| format!("write failed: {}", e))?;
+    let reparsed = DnsPacket::from_buffer(&mut BufHandler::from_bytes(written.written()))
+        .map_err(|e| format!("parsing the written packet failed: {}", e))?;
+    if reparsed != packet {
+        return Err(format!(
+            "written packet reads back differently: {:?} became {:?}",
+            packet, reparsed
+        ));
+    }
+    Ok(packet)
+}
+
+// A record as compared between responses: everything but the TTL, which is
+// expected to differ.
+pub fn record_key(record: &DnsRecord) -> String {
+    format!(
+        "{} {} {}",
+        record.domain().to_ascii().to_ascii_lowercase(),
+        record.query_type().name(),
+        record.rdata_string()
+    )
+}
+
+fn diff_section(section: &str, recorded: &[DnsRecord], replayed: &[DnsRecord]) -> Vec<String> {
+    let keys = |records: &[DnsRecord]| {
+        let mut keys: Vec<String> = records
+            .iter()
+            .filter(|record| record.query_type() != QueryType::OPT)
+            .map(record_key)
+            .collect();
+        keys.sort();
+        keys
+    };
+    let (recorded, mut replayed) = (keys(recorded), keys(replayed));
+
+    let mut differences = Vec::new();
+    for key in recorded.iter() {
+        if let Some(i) = replayed.iter().position(|other| other == key) {
+            replayed.remove(i);
+        } else {
+            differences.push(format!("{}: missing {}", section, key));
+        }
+    }
+    for key in replayed {
+        differences.push(format!("{}: extra {}", section, key));
+    }
+    differences
+}
+
+// How the replayed response differs from the recorded one. Record order and
+// TTLs don't count.
+pub fn diff(recorded: &DnsPacket, replayed: &DnsPacket) -> Vec<String> {
+    let mut differences = Vec::new();
+    let (a, b) = (&recorded.header, &replayed.header);
+    if a.response_code != b.response_code {
+        differences.push(format!(
+            "rcode {:?} became {:?}",
+            a.response_code, b.response_code
+        ));
+    }
+    for (flag, was, now) in [
+        ("aa", a.authoritative_answer, b.authoritative_answer),
+        ("tc", a.truncation, b.truncation),
+        ("ra", a.recursion_available, b.recursion_available),
+    ] {
+        if was != now {
+            differences.push(format!("{} flag {} became {}", flag, was, now));
+        }
+    }
+    // a truncated response only says to ask again over TCP
+    if a.truncation || b.truncation {
+        return differences;
+    }
+    differences.extend(diff_section("answer", &recorded.answers, &replayed.answers));
+    differences.extend(diff_section(
+        "authority",
+        &recorded.nameservers,
+        &replayed.nameservers,
+    ));
+    differences.extend(diff_section(
+        "additional",
+        &recorded.additionals,
+        &replayed.additionals,
+    ));
+    differences
+}
+
+// Sends query to server as it is and returns the response, matched by id.
+fn exchange(query: &[u8], transport: Transport, server: SocketAddr) -> Result<Vec<u8>, String> {
+    let fail = |e: std::io::Error| format!("{}: {}", server, e);
+    let id = query.get(0..2).ok_or("query too short")?;
+    match transport {
+        Transport::Udp => {
+            let local = match server {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let socket = UdpSocket::bind(SocketAddr::new(local, 0)).map_err(fail)?;
+            socket.connect(server).map_err(fail)?;
+            socket
+                .set_read_timeout(Some(REPLAY_TIMEOUT))
+                .map_err(fail)?;
+            socket.send(query).map_err(fail)?;
+            let mut buf = vec![0; TCP_MESSAGE_SIZE];
+            loop {
+                let len = socket
+                    .recv(&mut buf)
+                    .map_err(|_| "no response".to_string())?;
+                if buf[..len].get(0..2) == Some(id) {
+                    buf.truncate(len);
+                    return Ok(buf);
+                }
+            }
+        }
+        Transport::Tcp => {
+            let mut stream = TcpStream::connect_timeout(&server, REPLAY_TIMEOUT).map_err(fail)?;
+            stream
+                .set_read_timeout(Some(REPLAY_TIMEOUT))
+                .map_err(fail)?;
+            let mut message = (query.len() as u16).to_be_bytes().to_vec();
+            message.extend_from_slice(query);
+            stream.write_all(&message).map_err(fail)?;
+            let mut len = [0; 2];
+            stream
+                .read_exact(&mut len)
+                .map_err(|_| "no response".to_string())?;
+            let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+            stream
+                .read_exact(&mut buf)
+                .map_err(|_| "response cut short".to_string())?;
+            Ok(buf)
+        }
+    }
+}
+
+fn is_query(message: &CapturedMessage) -> bool {
+    // the QR bit clear
+    message.data.get(2).is_some_and(|flags| flags & 0x80 == 0)
+}
+
+// (client, server, id, transport) of a query, or of the response to it.
+type Exchange = (SocketAddr, SocketAddr, u16, bool);
+
+fn exchange_key(message: &CapturedMessage) -> Option<Exchange> {
+    let id = u16::from_be_bytes(message.data.get(0..2)?.try_into().ok()?);
+    let tcp = message.transport == Transport::Tcp;
+    Some(if is_query(message) {
+        (message.src, message.dst, id, tcp)
+    } else {
+        (message.dst, message.src, id, tcp)
+    })
+}
+
+pub fn replay(path: &Path, server: Option<SocketAddr>) -> Result<Report, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let (messages, summary) = pcap::read_messages(&data)?;
+
+    let mut report = Report {
+        messages: messages.len(),
+        replayed: 0,
+        problems: Vec::new(),
+        summary,
+    };
+    let problem = |message: &CapturedMessage, text: String| {
+        format!(
+            "frame {} {} -> {}: {}",
+            message.frame, message.src, message.dst, text
+        )
+    };
+
+    let mut responses: HashMap<Exchange, &CapturedMessage> = HashMap::new();
+    for message in messages.iter() {
+        if let Err(e) = check_codec(&message.data) {
+            report.problems.push(problem(message, e));
+        }
+        if !is_query(message)
+            && let Some(key) = exchange_key(message)
+        {
+            // the first response counts, like it would for the client
+            responses.entry(key).or_insert(message);
+        }
+    }
+
+    let Some(server) = server else {
+        return Ok(report);
+    };
+    for query in messages.iter().filter(|message| is_query(message)) {
+        report.replayed += 1;
+        let recorded = exchange_key(query).and_then(|key| responses.get(&key));
+        let replayed = match (exchange(&query.data, query.transport, server), recorded) {
+            (Ok(replayed), Some(recorded)) => (replayed, recorded),
+            // nothing to compare with
+            (Ok(_), None) => continue,
+            // it wasn't answered when captured either
+            (Err(_), None) => continue,
+            (Err(e), Some(_)) => {
+                report
+                    .problems
+                    .push(problem(query, format!("replay: {}", e)));
+                continue;
+            }
+        };
+        let (replayed, recorded) = replayed;
+
+        let parse = |data: &[u8]| DnsPacket::from_buffer(&mut BufHandler::from_bytes(data));
+        match (parse(&recorded.data), parse(&replayed)) {
+            (Ok(recorded), Ok(replayed)) => {
+                let differences = diff(&recorded, &replayed);
+                if !differences.is_empty() {
+                    let question = replayed
+                        .questions
+                        .first()
+                        .map(|question| format!("{} {:?}", question.name, question.qtype))
+                        .unwrap_or_default();
+                    report.problems.push(problem(
+                        query,
+                        format!("{}: {}", question, differences.join("; ")),
+                    ));
+                }
+            }
+            // the recorded one was reported already
+            (Err(_), _) => {}
+            (Ok(_), Err(e)) => {
+                report
+                    .problems
+                    .push(problem(query, format!("replayed response: {}", e)));
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    // dig asking a test server for A, AAAA, MX, TXT, SRV, CAA, HTTPS and other
+    // types under example.org, over UDP and TCP
+    const CAPTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/replay.pcap");
+
+    // A server on a free port, UDP and TCP, answering each query in the
+    // capture with the response captured for it.
+    fn recorded_server() -> SocketAddr {
+        let data = std::fs::read(CAPTURE).unwrap();
+        let (messages, _) = pcap::read_messages(&data).unwrap();
+        let mut answers: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        for query in messages.iter().filter(|message| is_query(message)) {
+            let key = exchange_key(query);
+            let response = messages
+                .iter()
+                .find(|message| !is_query(message) && exchange_key(message) == key);
+            if let Some(response) = response {
+                answers.insert(query.data.clone(), response.data.clone());
+            }
+        }
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(server).unwrap();
+        let udp_answers = answers.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0; TCP_MESSAGE_SIZE];
+            while let Ok((len, src)) = udp.recv_from(&mut buf) {
+                if let Some(answer) = udp_answers.get(&buf[..len]) {
+                    udp.send_to(answer, src).unwrap();
+                }
+            }
+        });
+        thread::spawn(move || {
+            for mut stream in tcp.incoming().flatten() {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut query = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut query).unwrap();
+                if let Some(answer) = answers.get(&query) {
+                    let mut message = (answer.len() as u16).to_be_bytes().to_vec();
+                    message.extend_from_slice(answer);
+                    stream.write_all(&message).unwrap();
+                }
+            }
+        });
+        server
+    }
+
+    #[test]
+    fn capture_survives_the_codec() {
+        let report = replay(Path::new(CAPTURE), None).unwrap();
+        assert!(report.messages > 0);
+        assert_eq!(report.problems, Vec::<String>::new());
+
+        // SRV, CAA and HTTPS aren't modeled, so their rdata is carried as is
+        let data = std::fs::read(CAPTURE).unwrap();
+        let (messages, _) = pcap::read_messages(&data).unwrap();
+        let unknown = messages
+            .iter()
+            .flat_map(|message| check_codec(&message.data).unwrap().answers)
+            .filter(|record| matches!(record, DnsRecord::UNKNOWN { .. }))
+            .count();
+        assert!(unknown > 0);
+    }
+
+    #[test]
+    fn replayed_answers_match_the_capture() {
+        let server = recorded_server();
+        let report = replay(Path::new(CAPTURE), Some(server)).unwrap();
+        assert!(report.replayed > 0);
+        assert_eq!(report.problems, Vec::<String>::new());
+    }
+
+    #[test]
+    fn diff_reports_changed_rdata() {
+        let data = std::fs::read(CAPTURE).unwrap();
+        let (messages, _) = pcap::read_messages(&data).unwrap();
+        let response = messages
+            .iter()
+            .filter(|message| !is_query(message))
+            .map(|message| check_codec(&message.data).unwrap())
+            .find(|packet| !packet.answers.is_empty())
+            .unwrap();
+
+        let mut changed = response.clone();
+        changed.answers.pop();
+        assert!(diff(&response, &response).is_empty());
+        assert!(!diff(&response, &changed).is_empty());
+    }
+}
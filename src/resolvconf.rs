@@ -0,0 +1,101 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::name::Name;
+
+pub const DEFAULT_PATH: &str = "/etc/resolv.conf";
+
+// resolv.conf(5) caps ndots at 15
+const MAX_NDOTS: usize = 15;
+
+// The parts of the system resolver configuration the server uses: the name
+// servers to forward to when none are configured, and the search list and
+// ndots for names looked up from the command line.
+#[derive(Debug, Clone)]
+pub struct ResolvConf {
+    pub nameservers: Vec<IpAddr>,
+    pub search: Vec<Name>,
+    pub ndots: usize,
+}
+
+impl ResolvConf {
+    pub fn new() -> ResolvConf {
+        ResolvConf {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<ResolvConf, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(ResolvConf::parse(&text))
+    }
+
+    // Like the C library, skips what it doesn't understand rather than
+    // failing, and lets the last of domain and search win.
+    pub fn parse(text: &str) -> ResolvConf {
+        let mut conf = ResolvConf::new();
+
+        for line in text.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or("");
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let Some((&keyword, args)) = tokens.split_first() else {
+                continue;
+            };
+
+            match keyword {
+                "nameserver" => {
+                    // link-local servers carry a scope (fe80::1%eth0) that
+                    // IpAddr can't hold
+                    if let Some(Ok(addr)) = args.first().map(|arg| arg.parse()) {
+                        conf.nameservers.push(addr);
+                    }
+                }
+                "domain" | "search" => {
+                    conf.search = args
+                        .iter()
+                        .filter_map(|domain| Name::from_unicode(domain).ok())
+                        .filter(|domain| !domain.is_root())
+                        .collect();
+                }
+                "options" => {
+                    for option in args {
+                        if let Some(Ok(ndots)) =
+                            option.strip_prefix("ndots:").map(|n| n.parse::<usize>())
+                        {
+                            conf.ndots = ndots.min(MAX_NDOTS);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        conf
+    }
+
+    // The names to try for name, in order. A name ending in a dot is only
+    // tried as it is; one with at least ndots dots is tried as it is before
+    // the search domains, any other after them.
+    pub fn candidates(&self, name: &str) -> Vec<Name> {
+        if let Some(name) = name.strip_suffix('.') {
+            return Name::from_unicode(name).into_iter().collect();
+        }
+        let Ok(absolute) = Name::from_unicode(name) else {
+            return Vec::new();
+        };
+
+        let searched = self
+            .search
+            .iter()
+            .filter_map(|domain| Name::from_unicode(&format!("{}.{}", name, domain)).ok());
+
+        if name.matches('.').count() >= self.ndots {
+            std::iter::once(absolute).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(absolute)).collect()
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::anonymize;
+use crate::client::lookup;
+use crate::latency::Latencies;
+use crate::log;
+use crate::log::LogLevel;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::pipeline::minimal_any;
+use crate::pipeline::negative_soa;
+use crate::telemetry;
+use crate::telemetry::SpanKind;
+
+const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+// How long a server stays out of rotation for a zone once found lame.
+const LAME_TIME: Duration = Duration::from_secs(15 * 60);
+
+// How long a server that didn't answer is left alone at first and at most;
+// the time doubles with every probe it doesn't answer either.
+const BACKOFF_MIN: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(10 * 60);
+
+const MAX_REFERRALS: usize = 16;
+
+// Nesting limit when resolving the addresses of glueless nameservers.
+const MAX_DEPTH: usize = 4;
+
+// How long one resolution may take, nameserver lookups included, before the
+// client gets SERVFAIL.
+const RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct NameServer {
+    host: Name,
+    addr: Option<Ipv4Addr>,
+}
+
+enum Reply {
+    Answer(DnsPacket),
+    Referral(Name, Vec<NameServer>),
+    Lame,
+}
+
+// A server that stopped answering, for all of its zones.
+struct Backoff {
+    failures: u32,
+    // not asked again before this
+    until: Instant,
+}
+
+fn backoff_time(failures: u32) -> Duration {
+    BACKOFF_MIN
+        .saturating_mul(1 << (failures - 1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+enum Step {
+    Done(DnsPacket),
+    Referral(Name, Vec<NameServer>),
+}
+
+// Decides what a server delegated `zone` told us about qname. Anything that
+// is neither an authoritative answer nor a referral further down the tree
+// means the server doesn't actually serve the zone.
+fn classify(packet: DnsPacket, qname: &Name, zone: &Name) -> Reply {
+    match packet.header.response_code {
+        ResponseCode::NOERR | ResponseCode::NAMERR => {}
+        _ => return Reply::Lame,
+    }
+
+    if packet.header.authoritative_answer {
+        return Reply::Answer(packet);
+    }
+
+    let cut = packet.nameservers.iter().find_map(|record| match record {
+        DnsRecord::NS { domain, .. } => Some(domain.clone()),
+        _ => None,
+    });
+
+    let Some(cut) = cut else {
+        return Reply::Lame;
+    };
+
+    if !packet.answers.is_empty() || cut == *zone || !cut.is_subdomain_of(zone) {
+        return Reply::Lame;
+    }
+    if !qname.is_subdomain_of(&cut) {
+        return Reply::Lame;
+    }
+
+    let mut servers: Vec<NameServer> = Vec::new();
+    for record in packet.nameservers.iter() {
+        if let DnsRecord::NS { domain, host, .. } = record {
+            if *domain != cut {
+                continue;
+            }
+            let glue = packet
+                .additionals
+                .iter()
+                .filter_map(|additional| match additional {
+                    DnsRecord::A { domain, addr, .. } if domain == host => Some(*addr),
+                    _ => None,
+                });
+
+            let before = servers.len();
+            for addr in glue {
+                servers.push(NameServer {
+                    host: host.clone(),
+                    addr: Some(addr),
+                });
+            }
+            // without glue a nameserver inside the zone can only be found
+            // by asking the zone, which is what needs it
+            if servers.len() == before && !host.is_subdomain_of(&cut) {
+                servers.push(NameServer {
+                    host: host.clone(),
+                    addr: None,
+                });
+            }
+        }
+    }
+
+    // glued servers first, they don't cost an extra resolution
+    servers.sort_by_key(|ns| ns.addr.is_none());
+
+    Reply::Referral(cut, servers)
+}
+
+// Iterative resolver starting at the root servers. Nameservers that fail or
+// answer non-authoritatively for a zone delegated to them are remembered as
+// lame for that zone and skipped for a while. Those that don't answer at all
+// are backed off from for every zone, for longer each time they still don't
+// when probed again. Of the rest, those with the lowest round trip times are
+// asked first.
+pub struct Resolver {
+    lame: Mutex<HashMap<(Ipv4Addr, Name), Instant>>,
+    backoff: Mutex<HashMap<Ipv4Addr, Backoff>>,
+    latencies: Arc<Latencies>,
+}
+
+impl Resolver {
+    pub fn new(latencies: Arc<Latencies>) -> Resolver {
+        Resolver {
+            lame: Mutex::new(HashMap::new()),
+            backoff: Mutex::new(HashMap::new()),
+            latencies,
+        }
+    }
+
+    // Errors go to the logs and traces, so they give names the way
+    // anonymize::name shows them.
+    pub fn resolve(&self, qname: &Name, qtype: QueryType) -> Result<DnsPacket, String> {
+        self.resolve_at_depth(qname, qtype, 0, Instant::now() + RESOLUTION_TIMEOUT)
+    }
+
+    // Looks up A and AAAA for host at the same time and merges the results
+    // the way RFC 8305 (Happy Eyeballs) wants them: alternating families,
+    // IPv6 first. Fails only if both lookups fail.
+    pub fn resolve_addresses(&self, host: &Name) -> Result<Vec<IpAddr>, String> {
+        let (v6, v4) = thread::scope(|scope| {
+            let v6 = scope.spawn(|| self.resolve(host, QueryType::AAAA));
+            let v4 = self.resolve(host, QueryType::A);
+            (v6.join().unwrap(), v4)
+        });
+
+        if let (Err(e), Err(_)) = (&v6, &v4) {
+            return Err(e.clone());
+        }
+
+        let v6: Vec<IpAddr> = v6
+            .map(|packet| {
+                packet
+                    .answers
+                    .iter()
+                    .filter_map(|record| match record {
+                        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let v4: Vec<IpAddr> = v4
+            .map(|packet| {
+                packet
+                    .answers
+                    .iter()
+                    .filter_map(|record| match record {
+                        DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut merged = Vec::with_capacity(v6.len() + v4.len());
+        let mut v6 = v6.into_iter();
+        let mut v4 = v4.into_iter();
+        loop {
+            match (v6.next(), v4.next()) {
+                (None, None) => break,
+                (a, b) => merged.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        Ok(merged)
+    }
+
+    fn resolve_at_depth(
+        &self,
+        qname: &Name,
+        qtype: QueryType,
+        depth: usize,
+        deadline: Instant,
+    ) -> Result<DnsPacket, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!(
+                "nameserver lookups nested too deep at {}",
+                anonymize::name(qname, Name::to_unicode)
+            ));
+        }
+
+        let mut zone = Name::root();
+        let mut servers: Vec<NameServer> = ROOT_SERVERS
+            .iter()
+            .map(|addr| NameServer {
+                host: Name::root(),
+                addr: Some(*addr),
+            })
+            .collect();
+
+        for _ in 0..MAX_REFERRALS {
+            match self.query_zone(qname, qtype, &zone, &servers, depth, deadline)? {
+                Step::Done(packet) => return Ok(packet),
+                Step::Referral(cut, next) => {
+                    zone = cut;
+                    servers = next;
+                }
+            }
+        }
+
+        Err(format!(
+            "too many referrals resolving {}",
+            anonymize::name(qname, Name::to_unicode)
+        ))
+    }
+
+    fn query_zone(
+        &self,
+        qname: &Name,
+        qtype: QueryType,
+        zone: &Name,
+        servers: &[NameServer],
+        depth: usize,
+        deadline: Instant,
+    ) -> Result<Step, String> {
+        // those never asked before first, so they get measured too
+        let mut servers: Vec<&NameServer> = servers.iter().collect();
+        servers.sort_by_key(|ns| {
+            let rank = ns
+                .addr
+                .map(|addr| self.latencies.rank(SocketAddr::from((addr, 53))));
+            (ns.addr.is_none(), rank)
+        });
+
+        for ns in servers {
+            let addrs = match ns.addr {
+                Some(addr) => vec![addr],
+                None => self.resolve_host(&ns.host, depth, deadline),
+            };
+
+            for addr in addrs {
+                if self.is_lame(addr, zone) || !self.may_ask(addr) {
+                    continue;
+                }
+                if Instant::now() >= deadline {
+                    return Err(format!(
+                        "gave up on {} after {}s",
+                        anonymize::name(qname, Name::to_unicode),
+                        RESOLUTION_TIMEOUT.as_secs()
+                    ));
+                }
+
+                let server = SocketAddr::from((addr, 53));
+                let started = Instant::now();
+                let reply = match lookup(qname, qtype, server) {
+                    Ok(packet) => {
+                        self.latencies.record(server, zone, started.elapsed());
+                        self.backoff.lock().unwrap().remove(&addr);
+                        classify(packet, qname, zone)
+                    }
+                    Err(_) => {
+                        self.back_off(addr);
+                        continue;
+                    }
+                };
+
+                match reply {
+                    Reply::Answer(packet) => return Ok(Step::Done(packet)),
+                    Reply::Referral(cut, next) if next.is_empty() => {
+                        return Err(format!(
+                            "no glue for the nameservers of \"{}\", all inside it",
+                            anonymize::name(&cut, Name::to_fqdn)
+                        ));
+                    }
+                    Reply::Referral(cut, next) => return Ok(Step::Referral(cut, next)),
+                    Reply::Lame => self.mark_lame(addr, zone),
+                }
+            }
+        }
+
+        Err(format!(
+            "no usable nameserver for zone \"{}\"",
+            anonymize::name(zone, Name::to_fqdn)
+        ))
+    }
+
+    fn resolve_host(&self, host: &Name, depth: usize, deadline: Instant) -> Vec<Ipv4Addr> {
+        let Ok(packet) = self.resolve_at_depth(host, QueryType::A, depth + 1, deadline) else {
+            return Vec::new();
+        };
+
+        packet
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_lame(&self, addr: Ipv4Addr, zone: &Name) -> bool {
+        let key = (addr, zone.clone());
+        let mut lame = self.lame.lock().unwrap();
+        match lame.get(&key) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                lame.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Whether addr may be asked now. The first query after its backoff runs
+    // out is the probe; the ones after it wait as if that had failed already
+    // until it is done.
+    fn may_ask(&self, addr: Ipv4Addr) -> bool {
+        let mut backoff = self.backoff.lock().unwrap();
+        let Some(state) = backoff.get_mut(&addr) else {
+            return true;
+        };
+        let now = Instant::now();
+        if now < state.until {
+            return false;
+        }
+        state.until = now + backoff_time(state.failures);
+        true
+    }
+
+    fn back_off(&self, addr: Ipv4Addr) {
+        let mut backoff = self.backoff.lock().unwrap();
+        let state = backoff.entry(addr).or_insert(Backoff {
+            failures: 0,
+            until: Instant::now(),
+        });
+        state.failures += 1;
+        state.until = Instant::now() + backoff_time(state.failures);
+    }
+
+    fn mark_lame(&self, addr: Ipv4Addr, zone: &Name) {
+        self.lame
+            .lock()
+            .unwrap()
+            .insert((addr, zone.clone()), Instant::now() + LAME_TIME);
+    }
+}
+
+impl Handler for Resolver {
+    fn name(&self) -> &str {
+        "recursor"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, _next: Next<'_>) {
+        if !request.recursion_allowed {
+            response.header.response_code = ResponseCode::REFUSED;
+            return;
+        }
+
+        let question = request.question;
+        if question.qtype == QueryType::ANY {
+            response.answers.push(minimal_any(&question.name));
+            return;
+        }
+
+        let span = telemetry::span("recursion", SpanKind::Internal);
+        match self.resolve(&question.name, question.qtype) {
+            // the zone's SOA tells caches how long the name or type is missing
+            Ok(packet) if packet.answers.is_empty() => {
+                response.header.response_code = packet.header.response_code;
+                response
+                    .nameservers
+                    .extend(negative_soa(&packet.nameservers));
+            }
+            Ok(packet) => response.answers = packet.answers,
+            Err(e) => {
+                if log::enabled(LogLevel::Debug) {
+                    eprintln!(
+                        "recursor: {} {:?}: {}",
+                        anonymize::name(&question.name, Name::to_ascii),
+                        question.qtype,
+                        e
+                    );
+                }
+                span.fail(&e);
+                response.header.response_code = ResponseCode::SERVFAIL;
+            }
+        }
+    }
+}
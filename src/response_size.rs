@@ -0,0 +1,130 @@
+use crate::BufHandler;
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::TCP_MESSAGE_SIZE;
+use crate::UDP_MESSAGE_SIZE;
+use crate::pipeline::Transport;
+use crate::pool;
+use crate::pool::Pooled;
+
+// What the server advertises and holds EDNS clients to over UDP when not
+// configured: small enough not to be fragmented on any path (DNS Flag Day
+// 2020).
+pub const DEFAULT_UDP_SIZE: u16 = 1232;
+
+// What happens to a response that is larger than the client can take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oversize {
+    // sent with TC set and nothing but the question, so the client retries
+    // over TCP
+    Truncate,
+    // the additional and authority sections go first; only if the answer
+    // still doesn't fit are RRsets dropped from its end and TC set
+    Trim,
+}
+
+impl Oversize {
+    pub fn from_name(name: &str) -> Option<Oversize> {
+        match name {
+            "truncate" => Some(Oversize::Truncate),
+            "trim" => Some(Oversize::Trim),
+            _ => None,
+        }
+    }
+}
+
+// How large responses may be, applied when they are written.
+#[derive(Debug, Clone, Copy)]
+pub struct SizePolicy {
+    // the most sent to EDNS clients over UDP, also advertised in OPT records
+    pub udp_max: u16,
+    pub tcp_max: u16,
+    // give EDNS clients asking for more than udp_max what they ask for
+    pub honor_client: bool,
+    pub oversize: Oversize,
+}
+
+impl SizePolicy {
+    pub fn new() -> SizePolicy {
+        SizePolicy {
+            udp_max: DEFAULT_UDP_SIZE,
+            tcp_max: TCP_MESSAGE_SIZE as u16,
+            honor_client: false,
+            oversize: Oversize::Truncate,
+        }
+    }
+
+    // The largest response to request the client can take: over UDP 512
+    // bytes without EDNS, otherwise the size it asks for (never less than
+    // 512), capped at udp_max unless honored.
+    pub fn limit(&self, request: &DnsPacket, transport: Transport) -> usize {
+        if transport == Transport::Tcp {
+            return self.tcp_max as usize;
+        }
+        match request.edns() {
+            Some(DnsRecord::OPT { payload_size, .. }) => {
+                let asked = (*payload_size as usize).max(UDP_MESSAGE_SIZE);
+                if self.honor_client {
+                    asked
+                } else {
+                    asked.min(self.udp_max as usize)
+                }
+            }
+            _ => UDP_MESSAGE_SIZE,
+        }
+    }
+
+    // Writes response in at most limit bytes, making it smaller as oversize
+    // says if it doesn't fit as it is.
+    pub fn write(
+        &self,
+        response: &mut DnsPacket,
+        limit: usize,
+    ) -> Result<Pooled<BufHandler>, String> {
+        if let Some(out) = write_within(response, limit) {
+            return Ok(out);
+        }
+
+        if self.oversize == Oversize::Trim {
+            response
+                .additionals
+                .retain(|record| record.query_type() == QueryType::OPT);
+            if let Some(out) = write_within(response, limit) {
+                return Ok(out);
+            }
+            response.nameservers.clear();
+            if let Some(out) = write_within(response, limit) {
+                return Ok(out);
+            }
+        }
+
+        // whole RRsets off the end of the answer, all of it for truncate
+        response.header.truncation = true;
+        response.nameservers.clear();
+        response
+            .additionals
+            .retain(|record| record.query_type() == QueryType::OPT);
+        if self.oversize == Oversize::Truncate {
+            response.answers.clear();
+        }
+        loop {
+            if let Some(out) = write_within(response, limit) {
+                return Ok(out);
+            }
+            let Some(last) = response.answers.pop() else {
+                return Err(format!("response doesn't fit in {} bytes", limit));
+            };
+            while response.answers.last().is_some_and(|record| {
+                record.domain() == last.domain() && record.query_type() == last.query_type()
+            }) {
+                response.answers.pop();
+            }
+        }
+    }
+}
+
+fn write_within(response: &mut DnsPacket, limit: usize) -> Option<Pooled<BufHandler>> {
+    let mut out = pool::buffer(limit);
+    response.write(&mut out).ok().map(|_| out)
+}
@@ -0,0 +1,251 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::calendar::civil_from_days;
+use crate::calendar::unix_now;
+use crate::log;
+use crate::log::LogLevel;
+use crate::sinkhole::Sinkhole;
+
+// How often the retention task looks at the logs.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// "log-retention [rotate=<time>] [max-size=<size>] [keep=<time>]".
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionConfig {
+    // a log is rotated when the period it was started in, counted from the
+    // epoch so that days start at midnight UTC, is over
+    pub rotate: Duration,
+    // and when it grows past this many bytes
+    pub max_size: Option<u64>,
+    // rotated logs, and what the stats keep per client, older than this
+    // are deleted
+    pub keep: Duration,
+}
+
+impl RetentionConfig {
+    pub fn new() -> RetentionConfig {
+        RetentionConfig {
+            rotate: Duration::from_secs(86400),
+            max_size: None,
+            keep: Duration::from_secs(30 * 86400),
+        }
+    }
+}
+
+struct Output {
+    writer: LineWriter<File>,
+    // when what's in the file started
+    started: SystemTime,
+}
+
+// A log file written a line at a time from any thread, which the retention
+// task can move aside for a new one under the writers.
+pub struct LogFile {
+    // what it is in errors, e.g. "audit-log"
+    directive: &'static str,
+    path: PathBuf,
+    out: Mutex<Output>,
+}
+
+fn open_append(directive: &str, path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("{} {}: {}", directive, path.display(), e))
+}
+
+// Suffix of a rotated log, its rotation time in UTC, e.g. "20261017-000000".
+fn rotation_suffix(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn is_rotation_suffix(suffix: &str) -> bool {
+    suffix.len() == 15
+        && suffix.bytes().enumerate().all(|(i, c)| {
+            if i == 8 {
+                c == b'-'
+            } else {
+                c.is_ascii_digit()
+            }
+        })
+}
+
+fn period(time: SystemTime, rotate: Duration) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / rotate.as_secs().max(1)
+}
+
+impl LogFile {
+    // Opens path for appending, so it has to run while the file is still
+    // reachable, before the chroot. A file that is already there started
+    // when it was last written, as far as rotation goes.
+    pub fn open(directive: &'static str, path: &Path) -> Result<LogFile, String> {
+        let file = open_append(directive, path)?;
+        let started = file
+            .metadata()
+            .ok()
+            .filter(|metadata| metadata.len() > 0)
+            .and_then(|metadata| metadata.modified().ok())
+            .unwrap_or_else(SystemTime::now);
+        Ok(LogFile {
+            directive,
+            path: path.to_path_buf(),
+            out: Mutex::new(Output {
+                writer: LineWriter::new(file),
+                started,
+            }),
+        })
+    }
+
+    pub fn write_line(&self, line: &dyn std::fmt::Display) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out.writer, "{}", line) {
+            eprintln!("{}: {}", self.directive, e);
+        }
+    }
+
+    // Moves the file aside as path.<suffix> and carries on in a new one, if
+    // its period is over or it has grown too large.
+    fn rotate_if_due(&self, config: &RetentionConfig) -> Result<(), String> {
+        let now = SystemTime::now();
+        let mut out = self.out.lock().unwrap();
+        let len = out
+            .writer
+            .get_ref()
+            .metadata()
+            .map_or(0, |metadata| metadata.len());
+        let due = period(out.started, config.rotate) != period(now, config.rotate)
+            || config.max_size.is_some_and(|max| len >= max);
+        if !due || len == 0 {
+            return Ok(());
+        }
+
+        let _ = out.writer.flush();
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".");
+        rotated.push(rotation_suffix(now));
+        fs::rename(&self.path, &rotated)
+            .map_err(|e| format!("{} {}: {}", self.directive, self.path.display(), e))?;
+        out.writer = LineWriter::new(open_append(self.directive, &self.path)?);
+        out.started = now;
+        if log::enabled(LogLevel::Info) {
+            eprintln!(
+                "{}: rotated {} to {}",
+                self.directive,
+                self.path.display(),
+                Path::new(&rotated).display()
+            );
+        }
+        Ok(())
+    }
+
+    // Deletes the rotated files last written more than keep ago.
+    fn delete_expired(&self, keep: Duration) -> Result<(), String> {
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("{} {}: {}", self.directive, dir.display(), e))?;
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            let rotated = file_name
+                .strip_prefix(&prefix)
+                .is_some_and(is_rotation_suffix);
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > keep);
+            if !rotated || !expired {
+                continue;
+            }
+            match fs::remove_file(entry.path()) {
+                Ok(()) if log::enabled(LogLevel::Info) => {
+                    eprintln!("{}: deleted {}", self.directive, entry.path().display())
+                }
+                Ok(()) => {}
+                Err(e) => eprintln!("{}: {}: {}", self.directive, entry.path().display(), e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Keeps long-running servers from filling their disks: rotates the audit
+// and access logs, deletes the rotated ones once they are older than keep,
+// and forgets the sinkhole's clients not seen for as long (their connections
+// stay in its totals).
+pub struct Retention {
+    config: RetentionConfig,
+    files: Vec<&'static LogFile>,
+    sinkhole: Option<Arc<Sinkhole>>,
+}
+
+impl Retention {
+    pub fn new(
+        config: RetentionConfig,
+        files: Vec<&'static LogFile>,
+        sinkhole: Option<Arc<Sinkhole>>,
+    ) -> Retention {
+        Retention {
+            config,
+            files,
+            sinkhole,
+        }
+    }
+
+    pub fn run(&self) {
+        loop {
+            for file in self.files.iter() {
+                let result = file
+                    .rotate_if_due(&self.config)
+                    .and_then(|_| file.delete_expired(self.config.keep));
+                if let Err(e) = result {
+                    eprintln!("log-retention: {}", e);
+                }
+            }
+            if let Some(sinkhole) = &self.sinkhole {
+                let before = (unix_now() as u64).saturating_sub(self.config.keep.as_secs());
+                sinkhole.forget_clients(before);
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::name::Name;
+use crate::regex::Regex;
+
+// What a filtering rule matches:
+//   example.com         the name and everything below it
+//   ads*.example.com    a wildcard, * standing for any run of characters (dots too)
+//   /^ad[0-9]+\./       a regular expression, see the regex module
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Domain(Name),
+    Wildcard(String),
+    Regex(Regex),
+}
+
+// Whether text matches the glob, * matching any run of characters.
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    let (mut g, mut t) = (0, 0);
+    // where the last * was, and how much of text it has taken so far
+    let mut star = None;
+    while t < text.len() {
+        if g < glob.len() && glob[g] == b'*' {
+            star = Some((g, t));
+            g += 1;
+        } else if g < glob.len() && glob[g] == text[t].to_ascii_lowercase() {
+            g += 1;
+            t += 1;
+        } else if let Some((star_g, star_t)) = star {
+            // let the * take one more character
+            star = Some((star_g, star_t + 1));
+            g = star_g + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+// The labels at the end of every name matching a pattern that ends in the
+// text suffix: those after the first dot, or all of them when the suffix is
+// known to start at a label.
+fn suffix_key(suffix: &str, at_label: bool) -> Option<Name> {
+    let labels = if at_label {
+        suffix
+    } else {
+        suffix.split_once('.')?.1
+    };
+    let labels = labels.trim_start_matches('.');
+    Name::from_ascii(labels).ok().filter(|name| !name.is_root())
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Pattern, String> {
+        if let Some(source) = text
+            .strip_prefix('/')
+            .and_then(|rest| rest.strip_suffix('/'))
+        {
+            return Ok(Pattern::Regex(Regex::new(source)?));
+        }
+        if text.contains('*') {
+            let glob = text.trim_end_matches('.').to_ascii_lowercase();
+            // the name check, with the *s standing in for a letter
+            Name::from_ascii(&glob.replace('*', "x"))?;
+            return Ok(Pattern::Wildcard(glob));
+        }
+        Ok(Pattern::Domain(Name::from_unicode(text)?))
+    }
+
+    // A domain every name the pattern matches is at or below, if there is
+    // one; rules are looked up by it.
+    fn key(&self) -> Option<Name> {
+        match self {
+            Pattern::Domain(domain) => Some(domain.clone()),
+            Pattern::Wildcard(glob) => {
+                let suffix = &glob[glob.rfind('*')? + 1..];
+                suffix_key(suffix, suffix.starts_with('.'))
+            }
+            Pattern::Regex(regex) => {
+                let (suffix, whole) = regex.literal_suffix();
+                suffix_key(&suffix, whole || suffix.starts_with('.'))
+            }
+        }
+    }
+
+    // text is name in its ASCII form, without the trailing dot, which
+    // wildcards and expressions are matched against
+    fn matches_text(&self, name: &Name, text: &str) -> bool {
+        match self {
+            Pattern::Domain(domain) => name.is_subdomain_of(domain),
+            Pattern::Wildcard(glob) => glob_matches(glob.as_bytes(), text.as_bytes()),
+            Pattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+
+    pub fn matches(&self, name: &Name) -> bool {
+        self.matches_text(name, &name.to_ascii())
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Pattern::Domain(domain) => write!(f, "{}", domain.to_unicode()),
+            Pattern::Wildcard(glob) => write!(f, "{}", glob),
+            Pattern::Regex(regex) => write!(f, "/{}/", regex.as_str()),
+        }
+    }
+}
+
+// Patterns with a value each, looked up by name. Rules are filed under the
+// domain all their matches are below, so a query only tries the rules filed
+// under one of its own suffixes, plus those without such a domain (like
+// /^ads?\./), which are tried for every name. The earliest added matching
+// rule wins.
+pub struct RuleSet<T> {
+    by_domain: HashMap<Name, Vec<(usize, Pattern, T)>>,
+    unfiled: Vec<(usize, Pattern, T)>,
+    len: usize,
+}
+
+// The first of rules matching name (text in ASCII), if it was added before
+// found.
+fn earliest<'a, T>(
+    rules: &'a [(usize, Pattern, T)],
+    name: &Name,
+    text: &str,
+    found: Option<&'a (usize, Pattern, T)>,
+) -> Option<&'a (usize, Pattern, T)> {
+    rules
+        .iter()
+        .take_while(|rule| found.is_none_or(|found| rule.0 < found.0))
+        .find(|rule| rule.1.matches_text(name, text))
+        .or(found)
+}
+
+impl<T> RuleSet<T> {
+    pub fn new() -> RuleSet<T> {
+        RuleSet {
+            by_domain: HashMap::new(),
+            unfiled: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn add(&mut self, pattern: Pattern, value: T) {
+        let rule = (self.len, pattern, value);
+        self.len += 1;
+        match rule.1.key() {
+            Some(domain) => self.by_domain.entry(domain).or_default().push(rule),
+            None => self.unfiled.push(rule),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn find(&self, name: &Name) -> Option<&T> {
+        self.find_rule(name).map(|(_, value)| value)
+    }
+
+    // The rule that find takes the value of, and the value.
+    pub fn find_rule(&self, name: &Name) -> Option<(&Pattern, &T)> {
+        let text = name.to_ascii();
+        let mut found = earliest(&self.unfiled, name, &text, None);
+        let mut suffix = Some(name.clone());
+        while let Some(domain) = suffix {
+            if let Some(rules) = self.by_domain.get(&domain) {
+                found = earliest(rules, name, &text, found);
+            }
+            suffix = domain.parent();
+        }
+        found.map(|rule| (&rule.1, &rule.2))
+    }
+
+    pub fn matches(&self, name: &Name) -> bool {
+        self.find(name).is_some()
+    }
+}
@@ -0,0 +1,112 @@
+use crate::calendar::TimeZone;
+use crate::calendar::weekday;
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// A daily stretch of time on some days of the week. One that ends before it
+// starts runs past midnight into the next day: "sun-thu 21:00-07:00" covers
+// Sunday night through Friday morning.
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    // indexed by weekday, Sunday first
+    days: [bool; 7],
+    // seconds into the day
+    start: i64,
+    end: i64,
+}
+
+impl Window {
+    fn covers(&self, day: usize, seconds: i64) -> bool {
+        if self.start <= self.end {
+            self.days[day] && self.start <= seconds && seconds < self.end
+        } else {
+            let yesterday = (day + 6) % 7;
+            (self.days[day] && seconds >= self.start)
+                || (self.days[yesterday] && seconds < self.end)
+        }
+    }
+}
+
+fn parse_day(text: &str) -> Result<usize, String> {
+    DAY_NAMES
+        .iter()
+        .position(|name| text.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown day {:?}", text))
+}
+
+// "mon-fri", "sat,sun", "fri-mon" or "daily".
+fn parse_days(text: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    if text == "daily" {
+        return Ok([true; 7]);
+    }
+    for part in text.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_day(first)?, parse_day(last)?);
+                let mut day = first;
+                loop {
+                    days[day] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days[parse_day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+// "07:30" in seconds into the day; "24:00" is the end of it.
+fn parse_clock(text: &str) -> Result<i64, String> {
+    let bad = || format!("bad time of day {:?}", text);
+    let (hours, minutes) = text.split_once(':').ok_or_else(bad)?;
+    let hours: i64 = hours.parse().map_err(|_| bad())?;
+    let minutes: i64 = minutes.parse().map_err(|_| bad())?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(bad());
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+// When something is in effect, in local time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule {
+            windows: Vec::new(),
+        }
+    }
+
+    // Adds the windows "<days> <HH:MM-HH:MM>..." describes.
+    pub fn add(&mut self, days: &str, times: &[&str]) -> Result<(), String> {
+        let days = parse_days(days)?;
+        for time in times {
+            let (start, end) = time
+                .split_once('-')
+                .ok_or_else(|| format!("bad time range {:?}", time))?;
+            let (start, end) = (parse_clock(start)?, parse_clock(end)?);
+            if start == end {
+                return Err(format!("empty time range {:?}", time));
+            }
+            self.windows.push(Window { days, start, end });
+        }
+        Ok(())
+    }
+
+    // Whether the schedule is in effect at unix (seconds since the epoch) in
+    // time zone zone.
+    pub fn is_active(&self, zone: &TimeZone, unix: i64) -> bool {
+        let (days, seconds) = zone.local_time(unix);
+        let day = weekday(days) as usize;
+        self.windows
+            .iter()
+            .any(|window| window.covers(day, seconds))
+    }
+}
@@ -0,0 +1,251 @@
+// A seccomp filter limiting every thread to the system calls the server
+// makes while answering queries: sockets, memory, threads, time. Anything
+// else, opening files and executing programs included, fails with EPERM, so
+// a compromised parser has little left to work with.
+
+use std::ffi::c_int;
+use std::ffi::c_ulong;
+use std::io;
+
+use crate::sys;
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    pub const AUDIT_ARCH: u32 = 0xC000_003E;
+    pub const SYS_SECCOMP: std::ffi::c_long = 317;
+
+    pub const ALLOWED: &[u32] = &[
+        0,   // read
+        1,   // write
+        3,   // close
+        5,   // fstat
+        7,   // poll
+        8,   // lseek
+        9,   // mmap
+        10,  // mprotect
+        11,  // munmap
+        12,  // brk
+        13,  // rt_sigaction
+        14,  // rt_sigprocmask
+        15,  // rt_sigreturn
+        16,  // ioctl
+        19,  // readv
+        20,  // writev
+        24,  // sched_yield
+        25,  // mremap
+        28,  // madvise
+        35,  // nanosleep
+        39,  // getpid
+        41,  // socket
+        42,  // connect
+        43,  // accept
+        44,  // sendto
+        45,  // recvfrom
+        46,  // sendmsg
+        47,  // recvmsg
+        48,  // shutdown
+        49,  // bind
+        51,  // getsockname
+        52,  // getpeername
+        54,  // setsockopt
+        55,  // getsockopt
+        56,  // clone
+        60,  // exit
+        72,  // fcntl
+        131, // sigaltstack
+        186, // gettid
+        202, // futex
+        204, // sched_getaffinity
+        219, // restart_syscall
+        228, // clock_gettime
+        230, // clock_nanosleep
+        231, // exit_group
+        234, // tgkill
+        262, // newfstatat
+        271, // ppoll
+        273, // set_robust_list
+        288, // accept4
+        299, // recvmmsg
+        307, // sendmmsg
+        318, // getrandom
+        332, // statx
+        334, // rseq
+        425, // io_uring_setup
+        426, // io_uring_enter
+        435, // clone3
+    ];
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    pub const AUDIT_ARCH: u32 = 0xC000_00B7;
+    pub const SYS_SECCOMP: std::ffi::c_long = 277;
+
+    pub const ALLOWED: &[u32] = &[
+        25,  // fcntl
+        29,  // ioctl
+        57,  // close
+        62,  // lseek
+        63,  // read
+        64,  // write
+        65,  // readv
+        66,  // writev
+        73,  // ppoll
+        79,  // newfstatat
+        80,  // fstat
+        93,  // exit
+        94,  // exit_group
+        98,  // futex
+        99,  // set_robust_list
+        101, // nanosleep
+        113, // clock_gettime
+        115, // clock_nanosleep
+        123, // sched_getaffinity
+        124, // sched_yield
+        128, // restart_syscall
+        131, // tgkill
+        132, // sigaltstack
+        134, // rt_sigaction
+        135, // rt_sigprocmask
+        139, // rt_sigreturn
+        172, // getpid
+        178, // gettid
+        198, // socket
+        200, // bind
+        202, // accept
+        203, // connect
+        204, // getsockname
+        205, // getpeername
+        206, // sendto
+        207, // recvfrom
+        208, // setsockopt
+        209, // getsockopt
+        210, // shutdown
+        211, // sendmsg
+        212, // recvmsg
+        214, // brk
+        215, // munmap
+        216, // mremap
+        220, // clone
+        222, // mmap
+        226, // mprotect
+        233, // madvise
+        242, // accept4
+        243, // recvmmsg
+        269, // sendmmsg
+        278, // getrandom
+        291, // statx
+        293, // rseq
+        425, // io_uring_setup
+        426, // io_uring_enter
+        435, // clone3
+    ];
+}
+
+const PR_SET_NO_NEW_PRIVS: c_int = 38;
+const SECCOMP_SET_MODE_FILTER: c_ulong = 1;
+// apply to every thread of the process, not just the calling one
+const SECCOMP_FILTER_FLAG_TSYNC: c_ulong = 1;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7FFF_0000;
+const EPERM: u32 = 1;
+
+// classic BPF opcodes
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+// offsets into struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+unsafe extern "C" {
+    fn prctl(option: c_int, ...) -> c_int;
+}
+
+fn statement(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn filter() -> Vec<SockFilter> {
+    let mut program = vec![
+        // system calls of another ABI have other numbers, never allow them
+        statement(BPF_LD_W_ABS, ARCH_OFFSET),
+        SockFilter {
+            code: BPF_JEQ_K,
+            jt: 1,
+            jf: 0,
+            k: arch::AUDIT_ARCH,
+        },
+        statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD_W_ABS, NR_OFFSET),
+    ];
+    for nr in arch::ALLOWED {
+        program.push(SockFilter {
+            code: BPF_JEQ_K,
+            jt: 0,
+            jf: 1,
+            k: *nr,
+        });
+        program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    program.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | EPERM));
+    program
+}
+
+// Installs the filter on every thread. There is no way to remove it again.
+pub fn install() -> Result<(), String> {
+    let fail = |what: &str| format!("seccomp: {}: {}", what, io::Error::last_os_error());
+
+    if unsafe {
+        prctl(
+            PR_SET_NO_NEW_PRIVS,
+            1 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+        )
+    } < 0
+    {
+        return Err(fail("no_new_privs"));
+    }
+
+    let program = filter();
+    let prog = SockFprog {
+        len: program.len() as u16,
+        filter: program.as_ptr(),
+    };
+    let ret = unsafe {
+        sys::syscall(
+            arch::SYS_SECCOMP,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const SockFprog,
+        )
+    };
+    if ret != 0 {
+        return Err(fail("installing the filter"));
+    }
+    Ok(())
+}
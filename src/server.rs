@@ -0,0 +1,1035 @@
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use crate::BufHandler;
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::EDNS_DO;
+use crate::EDNS_TCP_KEEPALIVE;
+use crate::EdnsOption;
+use crate::OpCode;
+use crate::QueryClass;
+use crate::ResponseCode;
+use crate::TCP_MESSAGE_SIZE;
+use crate::aaaa_filter::AaaaFilter;
+use crate::access_log;
+use crate::access_log::AccessLog;
+use crate::acl::Acl;
+use crate::anonymize;
+use crate::anonymize::Anonymizer;
+use crate::anonymize::NameHasher;
+use crate::api::Api;
+use crate::audit;
+use crate::audit::Action;
+use crate::audit::AuditLog;
+use crate::cache::Cache;
+use crate::cache::SharedCache;
+use crate::calendar::TimeZone;
+use crate::chaos::Chaos;
+use crate::client;
+use crate::client_policy::ClientPolicies;
+use crate::config::Config;
+use crate::config::Listener;
+use crate::connections::Connections;
+use crate::connections::Deadline;
+use crate::dhcp::LeaseWatcher;
+use crate::dns_json;
+use crate::doh;
+use crate::doh::Endpoint;
+use crate::doh::HttpRoute;
+use crate::dso;
+use crate::dso::DsoSession;
+use crate::dso::Outcome;
+use crate::forwarder::ForwardRule;
+use crate::forwarder::Forwarder;
+use crate::forwarder::Strategy;
+use crate::forwarder::Upstream;
+use crate::h2;
+use crate::ha::Standby;
+use crate::health::UpstreamHealth;
+use crate::hexdump;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+use crate::latency::Latencies;
+use crate::log;
+use crate::mirror;
+use crate::mirror::Mirror;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Pipeline;
+use crate::pipeline::Request;
+use crate::pipeline::Transport;
+use crate::pool;
+use crate::pool::Pooled;
+#[cfg(unix)]
+use crate::privileges;
+use crate::qtype_policy::QtypeAction;
+use crate::qtype_policy::QtypePolicy;
+use crate::quota::ClientQuotas;
+use crate::quota::QuotaAction;
+use crate::redis::Redis;
+use crate::resolvconf::ResolvConf;
+use crate::resolver::Resolver;
+use crate::response_size::SizePolicy;
+use crate::retention::Retention;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use crate::seccomp;
+use crate::sinkhole::Sinkhole;
+use crate::sortlist::SortList;
+use crate::special::SpecialUse;
+use crate::stats::Stats;
+use crate::telemetry;
+use crate::telemetry::SpanKind;
+use crate::telemetry::Telemetry;
+use crate::threat::ThreatFeeds;
+use crate::udp;
+use crate::udp::UdpIo;
+use crate::zone::LocalZones;
+use crate::zone_store;
+use crate::zone_watch::ZoneWatcher;
+
+// Queries from one TCP connection that are answered at the same time; reading
+// further queries waits until one of them is done.
+const MAX_TCP_IN_FLIGHT: usize = 32;
+
+// Threads answering a listener's UDP queries, so a query waiting on a slow
+// upstream or recursion holds up only itself. Queries received while all of
+// them are busy wait in a queue of up to MAX_UDP_QUEUED, and past that are
+// dropped, as a full socket buffer would drop them.
+const UDP_WORKERS: usize = 64;
+const MAX_UDP_QUEUED: usize = 1024;
+
+// Per-listener front end: does the protocol level checks every query needs
+// and hands the rest to the listener's pipeline.
+pub struct Frontend {
+    zones: Arc<LocalZones>,
+    chaos: Arc<Chaos>,
+    allow_recursion: Option<Acl>,
+    qtype_policy: Arc<QtypePolicy>,
+    quotas: Arc<ClientQuotas>,
+    policies: Arc<ClientPolicies>,
+    // the client policy of every query to the listener, identified per
+    // client when None
+    profile: Option<String>,
+    // the paths an HTTP listener answers on besides the default ones
+    routes: Vec<HttpRoute>,
+    pipeline: Pipeline,
+    stats: Arc<Stats>,
+    size_policy: SizePolicy,
+    // shared by every listener
+    connections: Arc<Connections>,
+    // set on a secondary, which refuses queries while its primary is up
+    standby: Option<Arc<Standby>>,
+}
+
+impl Frontend {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        zones: Arc<LocalZones>,
+        chaos: Arc<Chaos>,
+        allow_recursion: Option<Acl>,
+        qtype_policy: Arc<QtypePolicy>,
+        quotas: Arc<ClientQuotas>,
+        policies: Arc<ClientPolicies>,
+        profile: Option<String>,
+        routes: Vec<HttpRoute>,
+        pipeline: Pipeline,
+        stats: Arc<Stats>,
+        size_policy: SizePolicy,
+        connections: Arc<Connections>,
+        standby: Option<Arc<Standby>>,
+    ) -> Frontend {
+        Frontend {
+            zones,
+            chaos,
+            allow_recursion,
+            qtype_policy,
+            quotas,
+            policies,
+            profile,
+            routes,
+            pipeline,
+            stats,
+            size_policy,
+            connections,
+            standby,
+        }
+    }
+
+    fn recursion_allowed(&self, ip: IpAddr) -> bool {
+        match self.allow_recursion {
+            Some(ref acl) => acl.allows(ip),
+            None => true,
+        }
+    }
+
+    // Adds the OPT record to answer a query carrying one with, even when the
+    // query's EDNS is rejected (RFC 6891). Only version 0 exists so far; later
+    // versions get BADVERS. Over TCP a client asking for edns-tcp-keepalive
+    // is told how long the connection may stay idle; the client itself must
+    // not send a timeout (RFC 7828).
+    fn add_edns(
+        &self,
+        request: &DnsPacket,
+        transport: Transport,
+        response: &mut DnsPacket,
+    ) -> Result<(), ResponseCode> {
+        let Some(DnsRecord::OPT {
+            version, options, ..
+        }) = request.edns()
+        else {
+            return Ok(());
+        };
+
+        let mut result = Ok(());
+        let mut response_options = Vec::new();
+        if *version > 0 {
+            result = Err(ResponseCode::BADVERS);
+        } else if transport == Transport::Tcp
+            && let Some(keepalive) = options
+                .iter()
+                .find(|option| option.code == EDNS_TCP_KEEPALIVE)
+        {
+            if keepalive.data.is_empty() {
+                let idle = self.connections.policy().idle;
+                let timeout = (idle.as_millis() / 100).min(u16::MAX as u128) as u16;
+                response_options.push(EdnsOption {
+                    code: EDNS_TCP_KEEPALIVE,
+                    data: timeout.to_be_bytes().to_vec(),
+                });
+            } else {
+                result = Err(ResponseCode::FORMERR);
+            }
+        }
+
+        // DO is echoed (RFC 3225 section 3)
+        let flags = match request.dnssec_ok() {
+            true => EDNS_DO,
+            false => 0,
+        };
+        response.additionals.push(DnsRecord::OPT {
+            payload_size: self.size_policy.udp_max,
+            extended_rcode: 0,
+            version: 0,
+            flags,
+            options: response_options,
+        });
+        result
+    }
+
+    // profile is the client policy the query gets, if not the client's.
+    pub fn handle_query(
+        &self,
+        request: &DnsPacket,
+        src: SocketAddr,
+        transport: Transport,
+        profile: Option<&str>,
+    ) -> Option<Pooled<DnsPacket>> {
+        let recursion_allowed = self.recursion_allowed(src.ip());
+        let policy = match profile {
+            Some(name) => self.policies.get(name),
+            None => self.policies.identify(request, src.ip()),
+        };
+
+        let mut response = pool::packet();
+        response.header.id = request.header.id;
+        response.header.query = true;
+        response.header.opcode = request.header.opcode;
+        response.header.recursion_desired = request.header.recursion_desired;
+        response.header.recursion_available = recursion_allowed;
+        // CD is echoed (RFC 4035 section 3.1.6); with no validation here,
+        // there is nothing for it to turn off. AD stays clear, whatever the
+        // query or the upstream says, as nothing here is validated.
+        response.header.checking_disabled = request.header.checking_disabled;
+        response.header.authed_data = false;
+
+        // Every response echoes the question section. Nobody agrees on what
+        // more than one question would mean, so such packets are rejected.
+        response.questions.extend(request.questions.iter().cloned());
+
+        // a client over its quota costs as little as possible
+        let limited = match self.quotas.check(src.ip()) {
+            Some((QuotaAction::Truncate, _)) if transport == Transport::Tcp => None,
+            Some((QuotaAction::Slip, rule)) if transport == Transport::Tcp => {
+                Some((QuotaAction::Drop, rule))
+            }
+            limited => limited,
+        };
+        if let Some((action, rule)) = limited {
+            if let Some(question) = request.questions.first() {
+                let outcome = match action {
+                    QuotaAction::Truncate => "truncated",
+                    QuotaAction::Drop => "dropped",
+                    QuotaAction::Slip => "slipped",
+                    QuotaAction::Refuse => "REFUSED",
+                };
+                audit::record(
+                    src.ip(),
+                    policy,
+                    question,
+                    Action::RateLimited,
+                    rule,
+                    &outcome,
+                );
+            }
+            match action {
+                QuotaAction::Drop => return None,
+                QuotaAction::Refuse => response.header.response_code = ResponseCode::REFUSED,
+                QuotaAction::Truncate | QuotaAction::Slip => response.header.truncation = true,
+            }
+            return Some(response);
+        }
+
+        if let Err(response_code) = self.add_edns(request, transport, &mut response) {
+            response.header.response_code = response_code;
+            return Some(response);
+        }
+
+        // REFUSED sends clients on to the next server they know, the primary
+        if self
+            .standby
+            .as_ref()
+            .is_some_and(|standby| !standby.is_active())
+        {
+            response.header.response_code = ResponseCode::REFUSED;
+            return Some(response);
+        }
+
+        // Inverse queries are obsolete and server status was never defined.
+        // DSO only gets here over UDP, where it isn't allowed.
+        if let OpCode::IQUERY | OpCode::STATUS | OpCode::DSO | OpCode::UNKNOWN(_) =
+            request.header.opcode
+        {
+            response.header.response_code = ResponseCode::NOTIMP;
+            return Some(response);
+        }
+
+        let [ref question] = request.questions[..] else {
+            response.header.response_code = ResponseCode::FORMERR;
+            return Some(response);
+        };
+
+        // All data lives in class IN; CHAOS only identifies the server. NONE
+        // only has a meaning in updates.
+        match question.qclass {
+            QueryClass::IN => {}
+            QueryClass::CH => {
+                self.chaos.answer(question, &mut response);
+                return Some(response);
+            }
+            QueryClass::NONE => {
+                response.header.response_code = ResponseCode::FORMERR;
+                return Some(response);
+            }
+            QueryClass::HS | QueryClass::ANY | QueryClass::UNKNOWN(_) => {
+                response.header.response_code = ResponseCode::REFUSED;
+                return Some(response);
+            }
+        }
+
+        if let OpCode::NOTIFY | OpCode::UPDATE = request.header.opcode {
+            // Only zones we serve can be notified or updated, and we have no
+            // handler for either yet.
+            response.header.response_code = match self.zones.find_zone(&question.name) {
+                Some(_) => ResponseCode::NOTIMP,
+                None => ResponseCode::NOTAUTH,
+            };
+            return Some(response);
+        }
+
+        match self.qtype_policy.action(src.ip(), question.qtype) {
+            Some(QtypeAction::Drop) => return None,
+            Some(QtypeAction::Refuse) => {
+                response.header.response_code = ResponseCode::REFUSED;
+                return Some(response);
+            }
+            Some(QtypeAction::NoData) => return Some(response),
+            Some(QtypeAction::NxDomain) => {
+                response.header.response_code = ResponseCode::NAMERR;
+                return Some(response);
+            }
+            None => {}
+        }
+
+        let query = Request {
+            packet: request,
+            question,
+            src,
+            transport,
+            recursion_allowed,
+            policy,
+        };
+        self.pipeline.run(&query, &mut response);
+        mirror::offer(&question.name, question.qtype, &response);
+
+        Some(response)
+    }
+
+    // Parses the query in buf_handler and encodes the response, noting what
+    // it was about on the query's trace.
+    fn process(
+        &self,
+        buf_handler: &mut BufHandler,
+        src: SocketAddr,
+        transport: Transport,
+        trace: &telemetry::Span,
+    ) -> Result<Pooled<BufHandler>, String> {
+        let transport_name = match transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        };
+        hexdump::trace(
+            "query from",
+            anonymize::peer(src),
+            transport_name,
+            &buf_handler.buf,
+        );
+        trace.set("client.address", anonymize::client(src.ip()));
+        trace.set("network.transport", transport_name);
+
+        let mut request_packet = pool::packet();
+        if let Err(e) = request_packet.read(buf_handler) {
+            trace.fail(&e);
+            return Err(e);
+        }
+        if let [question] = &request_packet.questions[..] {
+            trace.set(
+                "dns.question.name",
+                anonymize::name(&question.name, Name::to_ascii),
+            );
+            trace.set("dns.question.type", format!("{:?}", question.qtype));
+        }
+
+        let Some(mut response_packet) =
+            self.handle_query(&request_packet, src, transport, self.profile.as_deref())
+        else {
+            trace.set("dns.dropped", true);
+            return Err("dropped".to_string());
+        };
+        self.stats.record(response_packet.header.response_code);
+        trace.set(
+            "dns.response.code",
+            format!("{:?}", response_packet.header.response_code),
+        );
+
+        // for UDP this is all of the writing that happens per query, the
+        // datagrams are sent in batches
+        let _span = telemetry::span("write response", SpanKind::Internal);
+        let limit = self.size_policy.limit(&request_packet, transport);
+        let out = self.size_policy.write(&mut response_packet, limit)?;
+        hexdump::trace(
+            "response to",
+            anonymize::peer(src),
+            transport_name,
+            out.written(),
+        );
+        Ok(out)
+    }
+
+    // Reads queries on this thread and answers them on UDP_WORKERS others,
+    // each sending its reply as soon as it has it.
+    pub fn run_udp(&self, udp_socket: &UdpSocket, io: UdpIo) {
+        let (queue, queries) = mpsc::sync_channel::<(Vec<u8>, SocketAddr, Instant)>(MAX_UDP_QUEUED);
+        let queries = Mutex::new(queries);
+
+        thread::scope(|scope| {
+            for _ in 0..UDP_WORKERS {
+                scope.spawn(|| {
+                    loop {
+                        let Ok((query, src, started)) = queries.lock().unwrap().recv() else {
+                            return;
+                        };
+                        if let Some(reply) = self.answer_udp(&query, src, started) {
+                            let _ = udp_socket.send_to(&reply, src);
+                        }
+                    }
+                });
+            }
+
+            udp::serve(udp_socket, io, |query, src| {
+                let _ = queue.try_send((query.to_vec(), src, Instant::now()));
+                None
+            });
+        });
+    }
+
+    fn answer_udp(&self, query: &[u8], src: SocketAddr, started: Instant) -> Option<Vec<u8>> {
+        let trace = telemetry::trace("dns query");
+        let mut buf_handler = pool::buffer_from(query);
+        let out = self
+            .process(&mut buf_handler, src, Transport::Udp, &trace)
+            .ok()?;
+        telemetry::record_query(started.elapsed());
+        Some(out.written().to_vec())
+    }
+
+    pub fn run_tcp(&self, tcp_listener: &TcpListener) {
+        thread::scope(|scope| {
+            for stream in tcp_listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let Some(slot) = stream
+                    .peer_addr()
+                    .ok()
+                    .and_then(|src| self.connections.admit(src.ip()))
+                else {
+                    continue;
+                };
+                scope.spawn(move || {
+                    self.serve_connection(stream);
+                    drop(slot);
+                });
+            }
+        });
+    }
+
+    // Reads queries off the connection one after the other and answers each
+    // on its own thread, so a slow query doesn't hold up the ones behind it.
+    // Responses go out in whatever order they complete (RFC 7766 section 6.2.1.1).
+    // DSO messages are connection state, so they are dealt with in line.
+    fn serve_connection(&self, stream: TcpStream) {
+        let Ok(src) = stream.peer_addr() else {
+            return;
+        };
+        let policy = *self.connections.policy();
+        // a client that doesn't take its responses doesn't hold a thread
+        if stream.set_write_timeout(Some(policy.io)).is_err() {
+            return;
+        }
+        let Ok(writer) = stream.try_clone() else {
+            return;
+        };
+        let writer = Mutex::new(writer);
+        let in_flight = (Mutex::new(0usize), Condvar::new());
+        let mut dso = DsoSession::new();
+        let mut idle = policy.idle;
+        let mut reader = stream;
+
+        thread::scope(|scope| {
+            loop {
+                let mut len = [0u8; 2];
+                if reader.set_read_timeout(Some(idle)).is_err()
+                    || reader.read_exact(&mut len).is_err()
+                {
+                    break;
+                }
+                // the rest of the message has io to arrive, however slowly
+                let mut buf_handler = pool::buffer(u16::from_be_bytes(len) as usize);
+                if Deadline::new(&reader, policy.io)
+                    .read_exact(&mut buf_handler.buf)
+                    .is_err()
+                {
+                    break;
+                }
+
+                let opcode = buf_handler.buf.get(2).map(|flags| (flags >> 3) & 0xF);
+                if opcode == Some(OpCode::DSO.to_num()) {
+                    let was_established = dso.is_established();
+                    match dso.handle(&mut buf_handler) {
+                        Outcome::Reply(message) => {
+                            let mut out = pool::buffer(TCP_MESSAGE_SIZE);
+                            if message.write(&mut out).is_err() || !write_framed(&writer, &out) {
+                                break;
+                            }
+                        }
+                        Outcome::Abort => break,
+                    }
+                    // the client now promises traffic every keepalive interval
+                    if !was_established && dso.is_established() {
+                        idle = dso::KEEPALIVE_INTERVAL * 2;
+                    }
+                    continue;
+                }
+
+                {
+                    let (count, done) = &in_flight;
+                    let mut count = count.lock().unwrap();
+                    while *count >= MAX_TCP_IN_FLIGHT {
+                        count = done.wait(count).unwrap();
+                    }
+                    *count += 1;
+                }
+
+                let writer = &writer;
+                let in_flight = &in_flight;
+                scope.spawn(move || {
+                    let started = Instant::now();
+                    let trace = telemetry::trace("dns query");
+                    if let Ok(out) = self.process(&mut buf_handler, src, Transport::Tcp, &trace) {
+                        let span = telemetry::span("write response", SpanKind::Internal);
+                        if !write_framed(writer, &out) {
+                            span.fail("connection closed");
+                        }
+                        drop(span);
+                        telemetry::record_query(started.elapsed());
+                    }
+                    drop(trace);
+
+                    let (count, done) = in_flight;
+                    *count.lock().unwrap() -= 1;
+                    done.notify_one();
+                });
+            }
+        });
+
+        let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    }
+
+    // Answers DoH and DNS JSON API requests, one per connection over HTTP/1.1
+    // or as many as the client likes over HTTP/2. Connections beyond the
+    // connection limits are closed right away.
+    pub fn run_http(&self, tcp_listener: &TcpListener) {
+        thread::scope(|scope| {
+            for stream in tcp_listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let Some(slot) = stream
+                    .peer_addr()
+                    .ok()
+                    .and_then(|src| self.connections.admit(src.ip()))
+                else {
+                    continue;
+                };
+                scope.spawn(move || {
+                    self.serve_http(stream);
+                    drop(slot);
+                });
+            }
+        });
+    }
+
+    fn serve_http(&self, stream: TcpStream) {
+        let Ok(src) = stream.peer_addr() else {
+            return;
+        };
+        let io = self.connections.policy().io;
+        let _ = stream.set_write_timeout(Some(io));
+
+        // the request, or the HTTP/2 preface, has io to arrive in full
+        let mut request = Deadline::new(&stream, io);
+        let Ok(start) = h2::read_preface(&mut request) else {
+            return;
+        };
+        if start == h2::PREFACE {
+            h2::serve(stream, &|request| {
+                let started = Instant::now();
+                let response = self.answer_http(request, src);
+                access_log::record(
+                    src.ip(),
+                    Some(request),
+                    "HTTP/2.0",
+                    response.status,
+                    response.body.len(),
+                    started.elapsed(),
+                );
+                response
+            });
+            return;
+        }
+        let started = Instant::now();
+        let request = HttpRequest::read(start.as_slice().chain(request));
+        let response = match &request {
+            Ok(request) => self.answer_http(request, src),
+            Err(e) => HttpResponse::error(400, e),
+        };
+        let _ = response.write_to(&stream);
+        access_log::record(
+            src.ip(),
+            request.as_ref().ok(),
+            "HTTP/1.1",
+            response.status,
+            response.body.len(),
+            started.elapsed(),
+        );
+    }
+
+    // Answers a DNS message (RFC 8484) or JSON API request, with the
+    // profile of the route it came by.
+    fn answer_http(&self, request: &HttpRequest, src: SocketAddr) -> HttpResponse {
+        let profile = match doh::endpoint(&self.routes, request) {
+            Endpoint::Route(route) => route.profile.as_deref().or(self.profile.as_deref()),
+            Endpoint::Default => self.profile.as_deref(),
+            Endpoint::Unknown => return HttpResponse::error(404, "no such endpoint"),
+        };
+        let message = doh::is_message(request);
+        if !message && request.method != "GET" {
+            return HttpResponse::error(405, "only GET, or POST of a DNS message, is supported");
+        }
+        let query = match message {
+            true => doh::query(request),
+            false => dns_json::query(request),
+        };
+        let query = match query {
+            Ok(query) => query,
+            Err(e) => return dns_json::with_cors(HttpResponse::error(400, &e)),
+        };
+
+        let started = Instant::now();
+        let trace = telemetry::trace("dns query");
+        trace.set("client.address", anonymize::client(src.ip()));
+        trace.set("network.transport", "http");
+        if let [question] = &query.questions[..] {
+            trace.set(
+                "dns.question.name",
+                anonymize::name(&question.name, Name::to_ascii),
+            );
+            trace.set("dns.question.type", format!("{:?}", question.qtype));
+        }
+
+        // HTTP runs over TCP, so nothing is truncated for size or quota
+        let Some(mut response) = self.handle_query(&query, src, Transport::Tcp, profile) else {
+            trace.set("dns.dropped", true);
+            return dns_json::with_cors(HttpResponse::error(503, "query dropped"));
+        };
+        self.stats.record(response.header.response_code);
+        trace.set(
+            "dns.response.code",
+            format!("{:?}", response.header.response_code),
+        );
+        telemetry::record_query(started.elapsed());
+        if !message {
+            return dns_json::response(&response);
+        }
+        let limit = self.size_policy.limit(&query, Transport::Tcp);
+        match self.size_policy.write(&mut response, limit) {
+            Ok(out) => doh::response(&response, out.written()),
+            Err(e) => HttpResponse::error(500, &e),
+        }
+    }
+}
+
+// Writes the message in out with its two byte length prefix. Returns false
+// if the connection is gone.
+fn write_framed(writer: &Mutex<TcpStream>, out: &BufHandler) -> bool {
+    let len = out.get_pos();
+    let mut message = Vec::with_capacity(2 + len);
+    message.extend_from_slice(&(len as u16).to_be_bytes());
+    message.extend_from_slice(&out.buf[0..len]);
+    writer.lock().unwrap().write_all(&message).is_ok()
+}
+
+// The upstreams for a server that forwards (some listener runs the forward
+// stage without the recursor behind it) but has none configured: the name
+// servers in the system's resolv.conf, minus the ones that are this server.
+fn system_forwarders(path: &Path, listeners: &[Listener]) -> Result<Option<ForwardRule>, String> {
+    let forwarding_only = listeners.iter().any(|listener| {
+        listener.stages.iter().any(|stage| stage == "forward")
+            && !listener.stages.iter().any(|stage| stage == "recursor")
+    });
+    if !forwarding_only {
+        return Ok(None);
+    }
+
+    let conf =
+        ResolvConf::load(path).map_err(|e| format!("no forward servers configured, and {}", e))?;
+    let is_self = |addr: SocketAddr| {
+        listeners.iter().any(|listener| {
+            listener.addr == addr
+                || (listener.addr.ip().is_unspecified()
+                    && listener.addr.port() == addr.port()
+                    && addr.ip().is_loopback())
+        })
+    };
+    let servers: Vec<Upstream> = conf
+        .nameservers
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, 53))
+        .filter(|addr| !is_self(*addr))
+        .map(|addr| Upstream {
+            addr,
+            proxy: None,
+            source: None,
+        })
+        .collect();
+    if servers.is_empty() {
+        return Err(format!(
+            "no forward servers configured, and none usable in {}",
+            path.display()
+        ));
+    }
+
+    Ok(Some(ForwardRule {
+        domain: Name::root(),
+        servers,
+        strategy: Strategy::Failover,
+        retries: None,
+        policy: None,
+        cross_check: None,
+    }))
+}
+
+// Builds every listener's pipeline out of the shared stage instances and
+// serves them over UDP and TCP.
+pub fn serve(config: Config) -> Result<(), String> {
+    log::set_level(config.log_level);
+    client::set_default_sources(config.query_sources);
+    let udp_io = config.udp_io;
+    client::set_udp_io(udp_io);
+    client::set_payload_size(config.size_policy.udp_max);
+
+    let listeners = if config.listeners.is_empty() {
+        vec![Listener::fallback()]
+    } else {
+        config.listeners
+    };
+
+    let mut forwarders = config.forwarders;
+    if forwarders.is_empty()
+        && let Some(path) = config.resolv_conf.as_deref()
+        && let Some(rule) = system_forwarders(path, &listeners)?
+    {
+        forwarders.push(rule);
+    }
+
+    let mut zones = config.zones;
+    // opened while the path is still reachable, before any chroot
+    if let Some(store) = zone_store::open(&config.zone_store)? {
+        zones.set_store(store)?;
+    }
+    let zones = Arc::new(zones);
+    let leases = (!config.dhcp_leases.is_empty())
+        .then(|| LeaseWatcher::new(config.dhcp_leases, zones.clone()))
+        .transpose()?;
+    // watched while the files are still reachable; seccomp doesn't let them
+    // be read again
+    let mut zone_watcher = (!config.zone_files.is_empty() && !config.seccomp)
+        .then(|| ZoneWatcher::new(config.zone_files, zones.clone()))
+        .transpose()?;
+    // read while /etc is still reachable
+    let mut blocklist = config.blocklist;
+    blocklist.set_time_zone(config.time_zone.unwrap_or_else(TimeZone::local));
+    // the feeds' hosts are resolved while /etc is still reachable
+    let mut threats = ThreatFeeds::new(config.threat_feeds)?;
+    // bound while the ports below 1024 are still allowed
+    let sinkhole = match config.sinkhole {
+        Some(sinkhole) => {
+            let sinkhole = Arc::new(Sinkhole::new(sinkhole)?);
+            blocklist.set_sinkhole(sinkhole.clone());
+            threats.set_sinkhole(sinkhole.clone());
+            Some(sinkhole)
+        }
+        None => None,
+    };
+    let blocklist = Arc::new(blocklist);
+    let threats = Arc::new(threats);
+    let mut cache = Cache::new(config.ttl_policy, config.rrset_order);
+    if let Some(redis) = config.cache_redis {
+        cache.set_shared(SharedCache::new(Redis::new(redis)?));
+    }
+    let cache = Arc::new(cache);
+    let stats = Arc::new(Stats::new());
+    let health = Arc::new(UpstreamHealth::new());
+    let latencies = Arc::new(Latencies::new());
+    let qtype_policy = Arc::new(config.qtype_policy);
+    let quotas = Arc::new(ClientQuotas::new(config.client_quotas));
+    let policies = Arc::new(config.client_policies);
+    let chaos = Arc::new(Chaos::new(config.chaos_version, config.chaos_id));
+    if let Some(anonymize) = config.anonymize_clients {
+        Anonymizer::new(anonymize).install();
+    }
+    if let Some(hashing) = config.hash_query_names {
+        NameHasher::new(hashing).install();
+    }
+    if let Some(path) = &config.audit_log {
+        AuditLog::open(path)?.install();
+    }
+    if let Some(path) = &config.access_log {
+        AccessLog::open(path)?.install();
+    }
+    let retention = config.log_retention.map(|retention| {
+        let files = [audit::log_file(), access_log::log_file()]
+            .into_iter()
+            .flatten()
+            .collect();
+        Retention::new(retention, files, sinkhole.clone())
+    });
+    let telemetry = match config.otlp {
+        Some(otlp) => {
+            let telemetry = Arc::new(Telemetry::new(
+                otlp,
+                stats.clone(),
+                cache.clone(),
+                blocklist.clone(),
+                threats.clone(),
+                sinkhole.clone(),
+                latencies.clone(),
+            )?);
+            telemetry.install();
+            Some(telemetry)
+        }
+        None => None,
+    };
+    let mirror = config.mirror.map(|mirror| {
+        let mirror = Arc::new(Mirror::new(mirror));
+        mirror.install();
+        mirror
+    });
+    let standby = match config.ha_primary {
+        Some(ha) => Some(Arc::new(Standby::new(ha, zones.clone(), cache.clone())?)),
+        None => None,
+    };
+
+    let stages: Vec<Arc<dyn Handler>> = vec![
+        Arc::new(SortList::new(config.sortlist)),
+        threats.clone(),
+        blocklist.clone(),
+        Arc::new(AaaaFilter::new(config.filter_aaaa)),
+        zones.clone(),
+        Arc::new(SpecialUse::new(config.designations)),
+        cache.clone(),
+        Arc::new(Forwarder::new(
+            forwarders,
+            health.clone(),
+            latencies.clone(),
+        )),
+        Arc::new(Resolver::new(latencies.clone())),
+    ];
+
+    let connections = Arc::new(Connections::new(config.connection_policy));
+    let mut frontends = Vec::new();
+    let mut http_frontends = Vec::new();
+    for listener in listeners {
+        let mut pipeline = Pipeline::new(Vec::new());
+        for name in listener.stages.iter() {
+            let stage = stages
+                .iter()
+                .find(|stage| stage.name() == name)
+                .ok_or_else(|| format!("unknown stage {:?}", name))?;
+            pipeline.push(stage.clone());
+        }
+
+        let frontend = Frontend::new(
+            zones.clone(),
+            chaos.clone(),
+            config.allow_recursion.clone(),
+            qtype_policy.clone(),
+            quotas.clone(),
+            policies.clone(),
+            listener.profile.clone(),
+            listener.routes.clone(),
+            pipeline,
+            stats.clone(),
+            config.size_policy,
+            connections.clone(),
+            standby.clone(),
+        );
+        if listener.http {
+            let tcp_listener = TcpListener::bind(listener.addr)
+                .map_err(|e| format!("http-listen {}: {}", listener.addr, e))?;
+            http_frontends.push((frontend, tcp_listener));
+            continue;
+        }
+        let udp_socket = UdpSocket::bind(listener.addr)
+            .map_err(|e| format!("listen {}: {}", listener.addr, e))?;
+        let tcp_listener = TcpListener::bind(listener.addr)
+            .map_err(|e| format!("listen {}/tcp: {}", listener.addr, e))?;
+        frontends.push((frontend, udp_socket, tcp_listener));
+    }
+
+    let api = match (config.api_listen, config.api_token) {
+        (Some(addr), Some(token)) => {
+            let listener =
+                TcpListener::bind(addr).map_err(|e| format!("api-listen {}: {}", addr, e))?;
+            let api = Api::new(
+                token,
+                zones,
+                blocklist,
+                threats.clone(),
+                sinkhole.clone(),
+                cache,
+                stats,
+                health.clone(),
+                latencies,
+                standby.clone(),
+            );
+            Some((api, listener))
+        }
+        _ => None,
+    };
+
+    // the sockets are bound, root isn't needed any more
+    #[cfg(unix)]
+    privileges::drop_privileges(
+        config.user.as_deref(),
+        config.group.as_deref(),
+        config.chroot.as_deref(),
+        config.allow_root,
+    )?;
+    #[cfg(not(unix))]
+    if config.user.is_some() || config.group.is_some() || config.chroot.is_some() {
+        return Err("user, group and chroot are not supported on this platform".to_string());
+    }
+
+    if config.seccomp {
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        seccomp::install()?;
+        #[cfg(not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )))]
+        return Err("seccomp is not supported on this platform".to_string());
+    }
+
+    thread::scope(|scope| {
+        scope.spawn(|| health.probe_loop());
+        if let Some(leases) = &leases {
+            scope.spawn(move || leases.run());
+        }
+        if let Some(zone_watcher) = &mut zone_watcher {
+            scope.spawn(move || zone_watcher.run());
+        }
+        if !threats.is_empty() {
+            scope.spawn(|| threats.run());
+        }
+        if let Some(sinkhole) = &sinkhole {
+            scope.spawn(move || sinkhole.run());
+        }
+        if let Some(telemetry) = &telemetry {
+            scope.spawn(move || telemetry.run());
+        }
+        if let Some(retention) = &retention {
+            scope.spawn(move || retention.run());
+        }
+        if let Some(mirror) = &mirror {
+            for _ in 0..mirror::WORKERS {
+                scope.spawn(move || mirror.run());
+            }
+        }
+        if let Some(standby) = &standby {
+            scope.spawn(move || standby.run());
+        }
+        if let Some((api, listener)) = api {
+            scope.spawn(move || api.run(listener));
+        }
+        for (frontend, udp_socket, tcp_listener) in frontends.iter() {
+            scope.spawn(move || frontend.run_udp(udp_socket, udp_io));
+            scope.spawn(move || frontend.run_tcp(tcp_listener));
+        }
+        for (frontend, tcp_listener) in http_frontends.iter() {
+            scope.spawn(move || frontend.run_http(tcp_listener));
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,307 @@
+// Running as a Windows service: installing it with the service control
+// manager, answering its start and stop requests, and reporting to the
+// Application event log since a service has no console.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::config::Config;
+use crate::server;
+
+pub const SERVICE_NAME: &str = "dns-server";
+const DISPLAY_NAME: &str = "DNS Server";
+
+const SC_MANAGER_ALL_ACCESS: u32 = 0xF003F;
+const SERVICE_ALL_ACCESS: u32 = 0xF01FF;
+const DELETE: u32 = 0x10000;
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_AUTO_START: u32 = 2;
+const SERVICE_ERROR_NORMAL: u32 = 1;
+
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_START_PENDING: u32 = 2;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+
+const EVENTLOG_ERROR_TYPE: u16 = 1;
+const EVENTLOG_INFORMATION_TYPE: u16 = 4;
+
+type Handle = *mut c_void;
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *const u16,
+    proc_: Option<extern "system" fn(u32, *mut *mut u16)>,
+}
+
+#[link(name = "advapi32")]
+unsafe extern "system" {
+    fn OpenSCManagerW(machine: *const u16, database: *const u16, access: u32) -> Handle;
+    fn CreateServiceW(
+        manager: Handle,
+        name: *const u16,
+        display_name: *const u16,
+        access: u32,
+        service_type: u32,
+        start_type: u32,
+        error_control: u32,
+        binary_path: *const u16,
+        load_order_group: *const u16,
+        tag_id: *mut u32,
+        dependencies: *const u16,
+        start_name: *const u16,
+        password: *const u16,
+    ) -> Handle;
+    fn OpenServiceW(manager: Handle, name: *const u16, access: u32) -> Handle;
+    fn DeleteService(service: Handle) -> i32;
+    fn CloseServiceHandle(handle: Handle) -> i32;
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32,
+        context: *mut c_void,
+    ) -> Handle;
+    fn SetServiceStatus(status_handle: Handle, status: *const ServiceStatus) -> i32;
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> Handle;
+    fn ReportEventW(
+        event_log: Handle,
+        event_type: u16,
+        category: u16,
+        event_id: u32,
+        user_sid: *mut c_void,
+        num_strings: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        data: *mut c_void,
+    ) -> i32;
+    fn DeregisterEventSource(event_log: Handle) -> i32;
+}
+
+// The config loaded on the command line, taken by service_main.
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+// Where service_main reports its state, set once it has registered.
+static STATUS_HANDLE: Mutex<usize> = Mutex::new(0);
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+fn last_error(what: &str) -> String {
+    format!("{}: {}", what, std::io::Error::last_os_error())
+}
+
+// Writes message to the Application event log under the service's name.
+pub fn log_event(message: &str, error: bool) {
+    let source = wide(SERVICE_NAME);
+    let message = wide(message);
+    let strings = [message.as_ptr()];
+    let event_type = if error {
+        EVENTLOG_ERROR_TYPE
+    } else {
+        EVENTLOG_INFORMATION_TYPE
+    };
+    unsafe {
+        let log = RegisterEventSourceW(ptr::null(), source.as_ptr());
+        if log.is_null() {
+            return;
+        }
+        ReportEventW(
+            log,
+            event_type,
+            0,
+            0,
+            ptr::null_mut(),
+            1,
+            0,
+            strings.as_ptr(),
+            ptr::null_mut(),
+        );
+        DeregisterEventSource(log);
+    }
+}
+
+fn set_status(state: u32, exit_code: u32) {
+    let handle = *STATUS_HANDLE.lock().unwrap();
+    if handle == 0 {
+        return;
+    }
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        win32_exit_code: if exit_code == NO_ERROR {
+            NO_ERROR
+        } else {
+            ERROR_SERVICE_SPECIFIC_ERROR
+        },
+        service_specific_exit_code: exit_code,
+        check_point: 0,
+        wait_hint: if state == SERVICE_RUNNING { 0 } else { 3000 },
+    };
+    unsafe { SetServiceStatus(handle as Handle, &status) };
+}
+
+extern "system" fn control_handler(control: u32, _: u32, _: *mut c_void, _: *mut c_void) -> u32 {
+    match control {
+        // serve has no way to wind down, and holds nothing that needs it
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, NO_ERROR);
+            log_event("stopping", false);
+            set_status(SERVICE_STOPPED, NO_ERROR);
+            std::process::exit(0);
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+extern "system" fn service_main(_: u32, _: *mut *mut u16) {
+    let name = wide(SERVICE_NAME);
+    let handle =
+        unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), control_handler, ptr::null_mut()) };
+    if handle.is_null() {
+        log_event(&last_error("registering the control handler"), true);
+        return;
+    }
+    *STATUS_HANDLE.lock().unwrap() = handle as usize;
+    set_status(SERVICE_START_PENDING, NO_ERROR);
+
+    let Some(config) = CONFIG.lock().unwrap().take() else {
+        set_status(SERVICE_STOPPED, 1);
+        return;
+    };
+
+    // serve only returns if it couldn't start
+    let server = thread::spawn(move || server::serve(config));
+    set_status(SERVICE_RUNNING, NO_ERROR);
+    log_event("started", false);
+
+    let result = server
+        .join()
+        .unwrap_or_else(|_| Err("server panicked".to_string()));
+    if let Err(e) = result {
+        log_event(&e, true);
+        set_status(SERVICE_STOPPED, 1);
+    } else {
+        set_status(SERVICE_STOPPED, NO_ERROR);
+    }
+}
+
+// Hands the process over to the service control manager, which calls back
+// into service_main. Only works when started by it.
+pub fn run(config: Config) -> Result<(), String> {
+    *CONFIG.lock().unwrap() = Some(config);
+
+    let name = wide(SERVICE_NAME);
+    let table = [
+        ServiceTableEntry {
+            name: name.as_ptr(),
+            proc_: Some(service_main),
+        },
+        ServiceTableEntry {
+            name: ptr::null(),
+            proc_: None,
+        },
+    ];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(last_error("not started as a service"));
+    }
+    Ok(())
+}
+
+// Registers the service to start automatically, running this executable with
+// config_path (made absolute, as services start in the system directory).
+pub fn install(config_path: Option<&str>) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let mut command = format!("\"{}\"", exe.display());
+    if let Some(path) = config_path {
+        let path = std::path::absolute(path).map_err(|e| format!("{}: {}", path, e))?;
+        command.push_str(&format!(" --config \"{}\"", path.display()));
+    }
+    command.push_str(" service run");
+
+    let name = wide(SERVICE_NAME);
+    let display_name = wide(DISPLAY_NAME);
+    let command = wide(&command);
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_ALL_ACCESS);
+        if manager.is_null() {
+            return Err(last_error("opening the service control manager"));
+        }
+        let service = CreateServiceW(
+            manager,
+            name.as_ptr(),
+            display_name.as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            command.as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        );
+        let result = if service.is_null() {
+            Err(last_error("creating the service"))
+        } else {
+            CloseServiceHandle(service);
+            Ok(())
+        };
+        CloseServiceHandle(manager);
+        result
+    }
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let name = wide(SERVICE_NAME);
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_ALL_ACCESS);
+        if manager.is_null() {
+            return Err(last_error("opening the service control manager"));
+        }
+        let service = OpenServiceW(manager, name.as_ptr(), DELETE);
+        let result = if service.is_null() {
+            Err(last_error("opening the service"))
+        } else {
+            let deleted = DeleteService(service) != 0;
+            let result = if deleted {
+                Ok(())
+            } else {
+                Err(last_error("deleting the service"))
+            };
+            CloseServiceHandle(service);
+            result
+        };
+        CloseServiceHandle(manager);
+        result
+    }
+}
@@ -0,0 +1,388 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DnsPacket;
+use crate::DnsQuestion;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::anonymize;
+use crate::calendar::unix_now;
+use crate::connections::ConnectionPolicy;
+use crate::connections::Connections;
+use crate::connections::Deadline;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+use crate::name::Name;
+
+// TTL of sinkhole answers, short so a name taken off the blocklist resolves
+// again soon.
+const SINKHOLE_TTL: u32 = 60;
+
+// How long a connection gets to say what it came for.
+const GREETING_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A client connecting for the same name again within this long isn't logged
+// again.
+const LOG_INTERVAL: Duration = Duration::from_secs(600);
+
+// Clients whose connections are counted; past this many, new ones are only
+// logged.
+const MAX_CLIENTS: usize = 10000;
+
+// Largest TLS record read looking for the server name.
+const MAX_HELLO: usize = 16384;
+
+// Where blocked names point instead of NXDOMAIN: "sinkhole <address>...
+// [ports=<port>,...]", at most one address per family.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkholeConfig {
+    pub addrs: Vec<IpAddr>,
+    pub ports: Vec<u16>,
+}
+
+// What the sinkhole knows of a client that connected to it.
+pub struct SinkholeClient {
+    pub addr: IpAddr,
+    pub connections: u64,
+    // unix time of the last connection
+    pub last_seen: u64,
+    // the name it last asked for, if it said
+    pub last_name: Option<Name>,
+}
+
+// The server name a TLS ClientHello asks for (RFC 6066 section 3), if the
+// record holds one.
+fn server_name(record: &[u8]) -> Option<Name> {
+    let mut at = Cursor { bytes: record };
+    // record header, then the handshake header of a ClientHello
+    if at.u8()? != 0x16 {
+        return None;
+    }
+    at.skip(4)?;
+    if at.u8()? != 0x01 {
+        return None;
+    }
+    // length, version, random
+    at.skip(3 + 2 + 32)?;
+    let len = at.u8()? as usize;
+    at.skip(len)?;
+    let len = at.u16()? as usize;
+    at.skip(len)?;
+    let len = at.u8()? as usize;
+    at.skip(len)?;
+    let len = at.u16()? as usize;
+    let mut extensions = Cursor {
+        bytes: at.take(len)?,
+    };
+    while !extensions.bytes.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Cursor {
+            bytes: extensions.take(len)?,
+        };
+        if kind != 0 {
+            continue;
+        }
+        // the list's length, then entries of a type and a name
+        data.skip(2)?;
+        while !data.bytes.is_empty() {
+            let kind = data.u8()?;
+            let len = data.u16()? as usize;
+            let name = data.take(len)?;
+            if kind == 0 {
+                return Name::from_unicode(std::str::from_utf8(name).ok()?).ok();
+            }
+        }
+    }
+    None
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+// Answers blocked names with its own addresses and listens on them, so the
+// devices that go on to connect to a blocked name show up: each connection
+// is logged with the client and, from the HTTP Host or the TLS server name,
+// the name it was after (once per client and name every LOG_INTERVAL), and
+// counted per client. HTTP clients get a 403 saying the name is blocked;
+// other connections are closed once read from.
+pub struct Sinkhole {
+    addrs: Vec<IpAddr>,
+    listeners: Vec<TcpListener>,
+    connections: Connections,
+    // queries answered with the sinkhole's addresses
+    answered: AtomicU64,
+    accepted: AtomicU64,
+    clients: Mutex<HashMap<IpAddr, SinkholeClient>>,
+    // when each (client, name) was last logged
+    logged: Mutex<HashMap<(IpAddr, Option<Name>), Instant>>,
+}
+
+impl Sinkhole {
+    // Binds the listeners, so it has to run before privileges are dropped
+    // when the ports are below 1024.
+    pub fn new(config: SinkholeConfig) -> Result<Sinkhole, String> {
+        let mut listeners = Vec::new();
+        for addr in config.addrs.iter() {
+            for port in config.ports.iter() {
+                let addr = SocketAddr::new(*addr, *port);
+                let listener =
+                    TcpListener::bind(addr).map_err(|e| format!("sinkhole {}: {}", addr, e))?;
+                listeners.push(listener);
+            }
+        }
+        // connections are short; a device hammering the sinkhole can't
+        // hold many threads
+        let policy = ConnectionPolicy {
+            max: 256,
+            per_client: 8,
+            idle: GREETING_TIMEOUT,
+            io: GREETING_TIMEOUT,
+        };
+        Ok(Sinkhole {
+            addrs: config.addrs,
+            listeners,
+            connections: Connections::new(policy),
+            answered: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            clients: Mutex::new(HashMap::new()),
+            logged: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Answers question with the sinkhole's address of its family; other
+    // types get an empty answer.
+    pub fn answer(&self, question: &DnsQuestion, response: &mut DnsPacket) {
+        self.answered.fetch_add(1, Ordering::Relaxed);
+        response.header.response_code = ResponseCode::NOERR;
+        for addr in self.addrs.iter() {
+            match (addr, question.qtype) {
+                (IpAddr::V4(addr), QueryType::A) => response.answers.push(DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr: *addr,
+                    ttl: SINKHOLE_TTL,
+                }),
+                (IpAddr::V6(addr), QueryType::AAAA) => response.answers.push(DnsRecord::AAAA {
+                    domain: question.name.clone(),
+                    addr: *addr,
+                    ttl: SINKHOLE_TTL,
+                }),
+                _ => {}
+            }
+        }
+    }
+
+    // For the audit log: what the client got instead of NXDOMAIN.
+    pub fn outcome(&self) -> String {
+        let addrs: Vec<String> = self.addrs.iter().map(|addr| addr.to_string()).collect();
+        format!("sinkhole {}", addrs.join(" "))
+    }
+
+    pub fn answered(&self) -> u64 {
+        self.answered.load(Ordering::Relaxed)
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    // The clients that connected, those with the most connections first.
+    pub fn clients(&self) -> Vec<SinkholeClient> {
+        let mut clients: Vec<SinkholeClient> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|client| SinkholeClient {
+                addr: client.addr,
+                connections: client.connections,
+                last_seen: client.last_seen,
+                last_name: client.last_name.clone(),
+            })
+            .collect();
+        clients.sort_by_key(|client| Reverse(client.connections));
+        clients
+    }
+
+    // Forgets the clients last seen before the unix time before; their
+    // connections stay in accepted.
+    pub fn forget_clients(&self, before: u64) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|_, client| client.last_seen >= before);
+    }
+
+    // Accepts connections on every listener until the server stops.
+    pub fn run(&self) {
+        thread::scope(|scope| {
+            for listener in self.listeners.iter() {
+                scope.spawn(move || {
+                    thread::scope(|scope| {
+                        for stream in listener.incoming() {
+                            let Ok(stream) = stream else {
+                                continue;
+                            };
+                            let Some(slot) = stream
+                                .peer_addr()
+                                .ok()
+                                .and_then(|src| self.connections.admit(src.ip()))
+                            else {
+                                continue;
+                            };
+                            scope.spawn(move || {
+                                self.serve_connection(stream);
+                                drop(slot);
+                            });
+                        }
+                    });
+                });
+            }
+        });
+    }
+
+    fn serve_connection(&self, stream: TcpStream) {
+        let (Ok(src), Ok(local)) = (stream.peer_addr(), stream.local_addr()) else {
+            return;
+        };
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        let _ = stream.set_write_timeout(Some(GREETING_TIMEOUT));
+        let mut first = [0u8; 1];
+        let _ = stream.set_read_timeout(Some(GREETING_TIMEOUT));
+        let is_tls = stream.peek(&mut first).is_ok_and(|len| len == 1) && first[0] == 0x16;
+
+        let name = if is_tls {
+            let mut record = [0u8; 5 + MAX_HELLO];
+            let mut reader = Deadline::new(&stream, GREETING_TIMEOUT);
+            let mut len = 0;
+            // the header tells how much of the record is left to read
+            while len < record.len() {
+                let want = match len {
+                    0..5 => 5,
+                    _ => {
+                        (5 + u16::from_be_bytes([record[3], record[4]]) as usize).min(record.len())
+                    }
+                };
+                if len >= want {
+                    break;
+                }
+                match reader.read(&mut record[len..want]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => len += read,
+                }
+            }
+            server_name(&record[..len])
+        } else {
+            let request = HttpRequest::read(Deadline::new(&stream, GREETING_TIMEOUT)).ok();
+            let name = request
+                .as_ref()
+                .and_then(|request| request.header("host"))
+                .map(|host| match host.rsplit_once(':') {
+                    Some((host, port)) if port.parse::<u16>().is_ok() => host,
+                    _ => host,
+                })
+                .map(|host| host.trim_matches(['[', ']']))
+                // a client that came by address didn't look up a name
+                .filter(|host| host.parse::<IpAddr>().is_err())
+                .and_then(|host| Name::from_unicode(host).ok());
+            if request.is_some() {
+                let message = match &name {
+                    Some(name) => {
+                        format!("{} is blocked by this network's DNS.\n", name.to_unicode())
+                    }
+                    None => "This name is blocked by this network's DNS.\n".to_string(),
+                };
+                let _ =
+                    HttpResponse::new(403, "text/plain", message.into_bytes()).write_to(&stream);
+            }
+            name
+        };
+        self.record(src.ip(), local.port(), name);
+    }
+
+    // Counts a connection from client and logs it.
+    fn record(&self, client: IpAddr, port: u16, name: Option<Name>) {
+        let now = Instant::now();
+        let mut logged = self.logged.lock().unwrap();
+        let key = (client, name.clone());
+        let log = logged
+            .get(&key)
+            .is_none_or(|at| now.duration_since(*at) >= LOG_INTERVAL);
+        if log {
+            if logged.len() >= MAX_CLIENTS {
+                logged.retain(|_, at| now.duration_since(*at) < LOG_INTERVAL);
+            }
+            logged.insert(key, now);
+        }
+        drop(logged);
+
+        if log {
+            match &name {
+                Some(name) => eprintln!(
+                    "sinkhole: {} connected to port {} for {}",
+                    anonymize::client(client),
+                    port,
+                    anonymize::name(name, Name::to_unicode)
+                ),
+                None => eprintln!(
+                    "sinkhole: {} connected to port {}",
+                    anonymize::client(client),
+                    port
+                ),
+            }
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            return;
+        }
+        let entry = clients.entry(client).or_insert(SinkholeClient {
+            addr: client,
+            connections: 0,
+            last_seen: 0,
+            last_name: None,
+        });
+        entry.connections += 1;
+        entry.last_seen = unix_now() as u64;
+        if name.is_some() {
+            entry.last_name = name;
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::net::IpAddr;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::acl::Cidr;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+
+// Reorders the addresses in answers by network preference. Addresses in a
+// listed network that also holds the client come first, then addresses in the
+// listed networks in the order given, then everything else. Other records
+// keep their place.
+pub struct SortList {
+    networks: Vec<Cidr>,
+}
+
+fn address(record: &DnsRecord) -> Option<IpAddr> {
+    match *record {
+        DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+        _ => None,
+    }
+}
+
+impl SortList {
+    pub fn new(networks: Vec<Cidr>) -> SortList {
+        SortList { networks }
+    }
+
+    fn rank(&self, client: IpAddr, addr: IpAddr) -> usize {
+        let shares_network = self
+            .networks
+            .iter()
+            .any(|network| network.contains(client) && network.contains(addr));
+        if shares_network {
+            return 0;
+        }
+        match self
+            .networks
+            .iter()
+            .position(|network| network.contains(addr))
+        {
+            Some(i) => 1 + i,
+            None => 1 + self.networks.len(),
+        }
+    }
+
+    pub fn sort(&self, client: IpAddr, records: &mut [DnsRecord]) {
+        let slots: Vec<usize> = (0..records.len())
+            .filter(|&i| address(&records[i]).is_some())
+            .collect();
+
+        let mut addresses: Vec<DnsRecord> = slots.iter().map(|&i| records[i].clone()).collect();
+        addresses.sort_by_key(|record| self.rank(client, address(record).unwrap()));
+
+        for (slot, record) in slots.into_iter().zip(addresses) {
+            records[slot] = record;
+        }
+    }
+}
+
+impl Handler for SortList {
+    fn name(&self) -> &str {
+        "sortlist"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        next.run(request, response);
+
+        if !self.networks.is_empty() {
+            self.sort(request.src.ip(), &mut response.answers);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::pipeline::minimal_any;
+
+const TTL: u32 = 3600;
+
+// Names that must never be sent to the root or an upstream: reserved top
+// level domains (RFC 6761, RFC 6762, RFC 7686) and the reverse zones of
+// private, loopback, link-local and "this network" IPv4 addresses (RFC 1918,
+// RFC 6303) and of unique local and link-local IPv6 addresses (RFC 4193).
+const NXDOMAIN_ZONES: [&str; 30] = [
+    "invalid",
+    "test",
+    "onion",
+    "local",
+    "10.in-addr.arpa",
+    "16.172.in-addr.arpa",
+    "17.172.in-addr.arpa",
+    "18.172.in-addr.arpa",
+    "19.172.in-addr.arpa",
+    "20.172.in-addr.arpa",
+    "21.172.in-addr.arpa",
+    "22.172.in-addr.arpa",
+    "23.172.in-addr.arpa",
+    "24.172.in-addr.arpa",
+    "25.172.in-addr.arpa",
+    "26.172.in-addr.arpa",
+    "27.172.in-addr.arpa",
+    "28.172.in-addr.arpa",
+    "29.172.in-addr.arpa",
+    "30.172.in-addr.arpa",
+    "31.172.in-addr.arpa",
+    "168.192.in-addr.arpa",
+    "254.169.in-addr.arpa",
+    "127.in-addr.arpa",
+    "0.in-addr.arpa",
+    "d.f.ip6.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+    "b.e.f.ip6.arpa",
+];
+
+// Where DDR clients ask for the resolver's encrypted endpoints (RFC 9462).
+const RESOLVER_ARPA: &str = "resolver.arpa";
+const DDR_NAME: &str = "_dns.resolver.arpa";
+
+const LOOPBACK_V4_REVERSE: &str = "1.0.0.127.in-addr.arpa";
+const LOOPBACK_V6_REVERSE: &str =
+    "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa";
+
+// An encrypted endpoint clients are pointed at by DDR: the server speaks no
+// TLS itself, so this is a proxy terminating it in front of a listener.
+pub struct Designation {
+    pub target: Name,
+    // in key order, with alpn always there
+    pub params: Vec<(u16, Vec<u8>)>,
+}
+
+// Answers special-use names itself: localhost resolves to the loopback
+// addresses (and back), _dns.resolver.arpa to the designated endpoints, the
+// other reserved names don't exist.
+pub struct SpecialUse {
+    localhost: Name,
+    loopback_reverse: [Name; 2],
+    nxdomain_zones: Vec<Name>,
+    resolver_arpa: Name,
+    ddr_name: Name,
+    // SVCB records for _dns.resolver.arpa, in the order they were configured
+    designations: Vec<DnsRecord>,
+}
+
+impl SpecialUse {
+    pub fn new(designations: Vec<Designation>) -> SpecialUse {
+        let name = |text: &str| Name::from_ascii(text).unwrap();
+        let ddr_name = name(DDR_NAME);
+        SpecialUse {
+            localhost: name("localhost"),
+            loopback_reverse: [name(LOOPBACK_V4_REVERSE), name(LOOPBACK_V6_REVERSE)],
+            nxdomain_zones: NXDOMAIN_ZONES.iter().map(|zone| name(zone)).collect(),
+            resolver_arpa: name(RESOLVER_ARPA),
+            designations: designations
+                .into_iter()
+                .enumerate()
+                .map(|(i, designation)| DnsRecord::SVCB {
+                    domain: ddr_name.clone(),
+                    ttl: TTL,
+                    priority: i as u16 + 1,
+                    target: designation.target,
+                    params: designation.params,
+                })
+                .collect(),
+            ddr_name,
+        }
+    }
+}
+
+impl Handler for SpecialUse {
+    fn name(&self) -> &str {
+        "special"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let question = request.question;
+        let name = &question.name;
+
+        if name.is_subdomain_of(&self.localhost) {
+            response.header.authoritative_answer = true;
+            match question.qtype {
+                QueryType::A => response.answers.push(DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr: Ipv4Addr::LOCALHOST,
+                    ttl: TTL,
+                }),
+                QueryType::AAAA => response.answers.push(DnsRecord::AAAA {
+                    domain: question.name.clone(),
+                    ttl: TTL,
+                    addr: Ipv6Addr::LOCALHOST,
+                }),
+                QueryType::ANY => response.answers.push(minimal_any(name)),
+                _ => {}
+            }
+            return;
+        }
+
+        if self.loopback_reverse.contains(name) {
+            response.header.authoritative_answer = true;
+            if question.qtype == QueryType::PTR {
+                response.answers.push(DnsRecord::PTR {
+                    domain: question.name.clone(),
+                    ttl: TTL,
+                    host: self.localhost.clone(),
+                });
+            }
+            return;
+        }
+
+        // never forwarded: another resolver's endpoints are no use to clients
+        // that asked this one
+        if name.is_subdomain_of(&self.resolver_arpa) {
+            response.header.authoritative_answer = true;
+            if *name == self.ddr_name {
+                if let QueryType::SVCB | QueryType::ANY = question.qtype {
+                    response.answers.extend(self.designations.iter().cloned());
+                }
+            } else if *name != self.resolver_arpa {
+                response.header.response_code = ResponseCode::NAMERR;
+            }
+            return;
+        }
+
+        if self
+            .nxdomain_zones
+            .iter()
+            .any(|zone| name.is_subdomain_of(zone))
+        {
+            response.header.authoritative_answer = true;
+            response.header.response_code = ResponseCode::NAMERR;
+            return;
+        }
+
+        next.run(request, response);
+    }
+}
@@ -0,0 +1,232 @@
+// The part of the SQLite C API the SQLite zone store needs, declared by hand
+// rather than pulling in a bindings crate. Built with the sqlite feature,
+// which links the system's libsqlite3.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_void;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_NULL: c_int = 5;
+
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+// the connection is only ever used behind a mutex
+const SQLITE_OPEN_NOMUTEX: c_int = 0x8000;
+
+// the destructor argument telling SQLite to copy what is bound
+const SQLITE_TRANSIENT: isize = -1;
+
+#[link(name = "sqlite3")]
+unsafe extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut c_void,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close_v2(db: *mut c_void) -> c_int;
+    fn sqlite3_errmsg(db: *mut c_void) -> *const c_char;
+    fn sqlite3_exec(
+        db: *mut c_void,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_changes(db: *mut c_void) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut c_void,
+        sql: *const c_char,
+        len: c_int,
+        stmt: *mut *mut c_void,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_null(stmt: *mut c_void, index: c_int) -> c_int;
+    fn sqlite3_bind_int64(stmt: *mut c_void, index: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_blob(
+        stmt: *mut c_void,
+        index: c_int,
+        value: *const c_void,
+        len: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_step(stmt: *mut c_void) -> c_int;
+    fn sqlite3_column_type(stmt: *mut c_void, column: c_int) -> c_int;
+    fn sqlite3_column_int64(stmt: *mut c_void, column: c_int) -> i64;
+    fn sqlite3_column_blob(stmt: *mut c_void, column: c_int) -> *const c_void;
+    fn sqlite3_column_bytes(stmt: *mut c_void, column: c_int) -> c_int;
+    fn sqlite3_finalize(stmt: *mut c_void) -> c_int;
+}
+
+// A value bound to a statement parameter.
+#[derive(Clone, Copy)]
+pub enum Value<'a> {
+    Null,
+    Integer(i64),
+    Blob(&'a [u8]),
+}
+
+pub struct Connection {
+    db: *mut c_void,
+}
+
+// SQLite connections may move between threads as long as only one uses
+// them at a time, which the borrow rules see to.
+unsafe impl Send for Connection {}
+
+impl Connection {
+    // Opens the database at path, creating it if it isn't there.
+    pub fn open(path: &Path) -> Result<Connection, String> {
+        let filename = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| format!("bad path {}", path.display()))?;
+        let mut db = ptr::null_mut();
+        let ret = unsafe {
+            sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX,
+                ptr::null(),
+            )
+        };
+        // a handle comes back even when opening fails, for the message
+        let connection = Connection { db };
+        if ret != SQLITE_OK {
+            return Err(format!("{}: {}", path.display(), connection.error()));
+        }
+        Ok(connection)
+    }
+
+    fn error(&self) -> String {
+        if self.db.is_null() {
+            return "out of memory".to_string();
+        }
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn check(&self, ret: c_int) -> Result<(), String> {
+        if ret == SQLITE_OK {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    // Runs statements without parameters or results, e.g. a schema.
+    pub fn execute(&self, sql: &str) -> Result<(), String> {
+        let sql = CString::new(sql).map_err(|_| "nul in SQL".to_string())?;
+        self.check(unsafe {
+            sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        })
+    }
+
+    // Prepares sql and binds params to its ?1, ?2, ... parameters.
+    pub fn prepare(&self, sql: &str, params: &[Value]) -> Result<Statement<'_>, String> {
+        let mut stmt = ptr::null_mut();
+        self.check(unsafe {
+            sqlite3_prepare_v2(
+                self.db,
+                sql.as_ptr() as *const c_char,
+                sql.len() as c_int,
+                &mut stmt,
+                ptr::null_mut(),
+            )
+        })?;
+        let statement = Statement {
+            connection: self,
+            stmt,
+        };
+        for (i, param) in params.iter().enumerate() {
+            let index = i as c_int + 1;
+            self.check(unsafe {
+                match param {
+                    Value::Null => sqlite3_bind_null(stmt, index),
+                    Value::Integer(value) => sqlite3_bind_int64(stmt, index, *value),
+                    Value::Blob(value) => sqlite3_bind_blob(
+                        stmt,
+                        index,
+                        value.as_ptr() as *const c_void,
+                        value.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    ),
+                }
+            })?;
+        }
+        Ok(statement)
+    }
+
+    // Runs a statement that returns no rows, returning how many rows it
+    // changed.
+    pub fn run(&self, sql: &str, params: &[Value]) -> Result<usize, String> {
+        let mut statement = self.prepare(sql, params)?;
+        while statement.step()? {}
+        Ok(unsafe { sqlite3_changes(self.db) } as usize)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close_v2(self.db) };
+    }
+}
+
+pub struct Statement<'a> {
+    connection: &'a Connection,
+    stmt: *mut c_void,
+}
+
+impl Statement<'_> {
+    // Moves to the next row, false when there are no more.
+    pub fn step(&mut self) -> Result<bool, String> {
+        match unsafe { sqlite3_step(self.stmt) } {
+            SQLITE_ROW => Ok(true),
+            SQLITE_DONE => Ok(false),
+            _ => Err(self.connection.error()),
+        }
+    }
+
+    // The column of the current row, None for NULL.
+    pub fn integer(&self, column: usize) -> Option<i64> {
+        let column = column as c_int;
+        unsafe {
+            if sqlite3_column_type(self.stmt, column) == SQLITE_NULL {
+                None
+            } else {
+                Some(sqlite3_column_int64(self.stmt, column))
+            }
+        }
+    }
+
+    pub fn blob(&self, column: usize) -> Vec<u8> {
+        let column = column as c_int;
+        unsafe {
+            let data = sqlite3_column_blob(self.stmt, column);
+            let len = sqlite3_column_bytes(self.stmt, column) as usize;
+            if data.is_null() {
+                return Vec::new();
+            }
+            std::slice::from_raw_parts(data as *const u8, len).to_vec()
+        }
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.stmt) };
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use crate::ResponseCode;
+
+// Server wide counters.
+pub struct Stats {
+    started: Instant,
+    queries: AtomicU64,
+    // indexed by response code, which are 12 bits but only go this far
+    rcodes: [AtomicU64; 24],
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            queries: AtomicU64::new(0),
+            rcodes: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    pub fn record(&self, response_code: ResponseCode) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = self.rcodes.get(response_code.to_num() as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn responses(&self, response_code: ResponseCode) -> u64 {
+        self.rcodes
+            .get(response_code.to_num() as usize)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,183 @@
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+// SvcParamKeys (RFC 9460 section 14.3.2, dohpath from RFC 9461)
+pub const MANDATORY: u16 = 0;
+pub const ALPN: u16 = 1;
+pub const NO_DEFAULT_ALPN: u16 = 2;
+pub const PORT: u16 = 3;
+pub const IPV4HINT: u16 = 4;
+pub const ECH: u16 = 5;
+pub const IPV6HINT: u16 = 6;
+pub const DOHPATH: u16 = 7;
+
+const KEY_NAMES: [(u16, &str); 8] = [
+    (MANDATORY, "mandatory"),
+    (ALPN, "alpn"),
+    (NO_DEFAULT_ALPN, "no-default-alpn"),
+    (PORT, "port"),
+    (IPV4HINT, "ipv4hint"),
+    (ECH, "ech"),
+    (IPV6HINT, "ipv6hint"),
+    (DOHPATH, "dohpath"),
+];
+
+// The key's name, key<n> for keys without one.
+pub fn key_name(key: u16) -> String {
+    match KEY_NAMES.iter().find(|(known, _)| *known == key) {
+        Some((_, name)) => name.to_string(),
+        None => format!("key{}", key),
+    }
+}
+
+fn key_from_name(name: &str) -> Result<u16, String> {
+    if let Some((key, _)) = KEY_NAMES.iter().find(|(_, known)| *known == name) {
+        return Ok(*key);
+    }
+    name.strip_prefix("key")
+        .and_then(|num| num.parse::<u16>().ok())
+        .ok_or_else(|| format!("unknown SVCB parameter {:?}", name))
+}
+
+// Printable ASCII as is, anything else (and quotes and backslashes) as \DDD.
+fn escaped(value: &[u8]) -> String {
+    let mut text = String::new();
+    for &byte in value {
+        if byte.is_ascii_graphic() && byte != b'"' && byte != b'\\' {
+            text.push(byte as char);
+        } else {
+            text.push_str(&format!("\\{:03}", byte));
+        }
+    }
+    text
+}
+
+// The value as the key defines it, None if it isn't well formed.
+fn value_string(key: u16, value: &[u8]) -> Option<String> {
+    let list = |items: Vec<String>| Some(items.join(","));
+    match key {
+        MANDATORY if value.len().is_multiple_of(2) => list(
+            value
+                .chunks(2)
+                .map(|key| key_name(u16::from_be_bytes([key[0], key[1]])))
+                .collect(),
+        ),
+        ALPN => {
+            let mut ids = Vec::new();
+            let mut rest = value;
+            while let Some((&len, tail)) = rest.split_first() {
+                if len == 0 || tail.len() < len as usize {
+                    return None;
+                }
+                ids.push(escaped(&tail[..len as usize]));
+                rest = &tail[len as usize..];
+            }
+            list(ids)
+        }
+        PORT if value.len() == 2 => Some(u16::from_be_bytes([value[0], value[1]]).to_string()),
+        IPV4HINT if !value.is_empty() && value.len().is_multiple_of(4) => list(
+            value
+                .chunks(4)
+                .map(|addr| Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).to_string())
+                .collect(),
+        ),
+        IPV6HINT if !value.is_empty() && value.len().is_multiple_of(16) => list(
+            value
+                .chunks(16)
+                .map(|addr| Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap()).to_string())
+                .collect(),
+        ),
+        DOHPATH => Some(escaped(value)),
+        _ => None,
+    }
+}
+
+// One parameter in presentation form, e.g. alpn=dot or port=853.
+pub fn param_string(key: u16, value: &[u8]) -> String {
+    if key == NO_DEFAULT_ALPN && value.is_empty() {
+        return key_name(key);
+    }
+    match value_string(key, value) {
+        Some(text) => format!("{}={}", key_name(key), text),
+        None => format!("{}=\"{}\"", key_name(key), escaped(value)),
+    }
+}
+
+fn param_value(key: u16, text: &str) -> Result<Vec<u8>, String> {
+    let bad = |e: String| format!("bad {} {:?}: {}", key_name(key), text, e);
+    let mut value = Vec::new();
+    match key {
+        MANDATORY => {
+            for name in text.split(',') {
+                value.extend_from_slice(&key_from_name(name)?.to_be_bytes());
+            }
+        }
+        ALPN => {
+            for id in text.split(',') {
+                if id.is_empty() || id.len() > 255 {
+                    return Err(bad("protocol ids are 1 to 255 characters".to_string()));
+                }
+                value.push(id.len() as u8);
+                value.extend_from_slice(id.as_bytes());
+            }
+        }
+        NO_DEFAULT_ALPN => {
+            if !text.is_empty() {
+                return Err(bad("takes no value".to_string()));
+            }
+        }
+        PORT => {
+            let port = text.parse::<u16>().map_err(|e| bad(e.to_string()))?;
+            value.extend_from_slice(&port.to_be_bytes());
+        }
+        IPV4HINT => {
+            for addr in text.split(',') {
+                let addr = addr.parse::<Ipv4Addr>().map_err(|e| bad(e.to_string()))?;
+                value.extend_from_slice(&addr.octets());
+            }
+        }
+        IPV6HINT => {
+            for addr in text.split(',') {
+                let addr = addr.parse::<Ipv6Addr>().map_err(|e| bad(e.to_string()))?;
+                value.extend_from_slice(&addr.octets());
+            }
+        }
+        DOHPATH if !text.starts_with('/') || !text.contains("{?dns}") => {
+            return Err(bad("must be a path with {?dns} in it".to_string()));
+        }
+        _ => value.extend_from_slice(text.as_bytes()),
+    }
+    Ok(value)
+}
+
+// Parameters in presentation form (key=value, or just the key for
+// no-default-alpn), put in key order as the wire form wants them. Keys may
+// not repeat, and every key mandatory= lists has to be there.
+pub fn parse_params(args: &[&str]) -> Result<Vec<(u16, Vec<u8>)>, String> {
+    let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+    for arg in args {
+        let (name, text) = arg.split_once('=').unwrap_or((arg, ""));
+        let key = key_from_name(name)?;
+        if params.iter().any(|(known, _)| *known == key) {
+            return Err(format!("SVCB parameter {} given twice", name));
+        }
+        params.push((key, param_value(key, text.trim_matches('"'))?));
+    }
+    params.sort_by_key(|(key, _)| *key);
+
+    if let Some((_, mandatory)) = params.iter().find(|(key, _)| *key == MANDATORY) {
+        for key in mandatory.chunks(2) {
+            let key = u16::from_be_bytes([key[0], key[1]]);
+            if key == MANDATORY {
+                return Err("mandatory can't list itself".to_string());
+            }
+            if !params.iter().any(|(known, _)| *known == key) {
+                return Err(format!(
+                    "mandatory SVCB parameter {} is missing",
+                    key_name(key)
+                ));
+            }
+        }
+    }
+    Ok(params)
+}
@@ -0,0 +1,188 @@
+// The few C library calls and socket structures the Linux UDP backends and
+// the zone file watcher need, declared by hand rather than pulling in a
+// bindings crate. Layouts are the 64-bit Linux ones.
+
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_long;
+use std::ffi::c_uint;
+use std::ffi::c_void;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::SocketAddrV6;
+use std::ptr;
+
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+
+// set in msg_flags when a datagram didn't fit the buffer
+pub const MSG_TRUNC: c_int = 0x20;
+// recvmmsg: block for the first datagram only
+pub const MSG_WAITFORONE: c_int = 0x10000;
+
+pub const PROT_READ: c_int = 0x1;
+pub const PROT_WRITE: c_int = 0x2;
+pub const MAP_SHARED: c_int = 0x01;
+pub const MAP_POPULATE: c_int = 0x8000;
+pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+pub const IN_CLOEXEC: c_int = 0o2000000;
+// a file opened for writing was closed
+pub const IN_CLOSE_WRITE: u32 = 0x8;
+// a file was renamed into the directory, as editors save
+pub const IN_MOVED_TO: u32 = 0x80;
+pub const IN_DELETE: u32 = 0x200;
+// wd, mask, cookie and len of struct inotify_event, before the name
+pub const INOTIFY_EVENT_SIZE: usize = 16;
+
+unsafe extern "C" {
+    pub fn syscall(num: c_long, ...) -> c_long;
+    pub fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    pub fn close(fd: c_int) -> c_int;
+    pub fn recvmmsg(
+        fd: c_int,
+        msgvec: *mut MMsgHdr,
+        vlen: c_uint,
+        flags: c_int,
+        timeout: *mut c_void,
+    ) -> c_int;
+    pub fn inotify_init1(flags: c_int) -> c_int;
+    pub fn inotify_add_watch(fd: c_int, path: *const c_char, mask: u32) -> c_int;
+}
+
+#[repr(C)]
+pub struct IoVec {
+    pub base: *mut c_void,
+    pub len: usize,
+}
+
+#[repr(C)]
+pub struct MsgHdr {
+    pub name: *mut c_void,
+    pub namelen: c_uint,
+    pub iov: *mut IoVec,
+    pub iovlen: usize,
+    pub control: *mut c_void,
+    pub controllen: usize,
+    pub flags: c_int,
+}
+
+#[repr(C)]
+pub struct MMsgHdr {
+    pub hdr: MsgHdr,
+    // bytes received or sent
+    pub len: c_uint,
+}
+
+// Room for any socket address (struct sockaddr_storage).
+#[repr(C, align(8))]
+pub struct SockAddrStorage {
+    pub bytes: [u8; 128],
+}
+
+impl SockAddrStorage {
+    pub fn new() -> SockAddrStorage {
+        SockAddrStorage { bytes: [0; 128] }
+    }
+
+    // Fills in a sockaddr_in or sockaddr_in6 and returns its length.
+    pub fn set(&mut self, addr: SocketAddr) -> c_uint {
+        self.bytes = [0; 128];
+        match addr {
+            SocketAddr::V4(addr) => {
+                self.bytes[0..2].copy_from_slice(&AF_INET.to_ne_bytes());
+                self.bytes[2..4].copy_from_slice(&addr.port().to_be_bytes());
+                self.bytes[4..8].copy_from_slice(&addr.ip().octets());
+                16
+            }
+            SocketAddr::V6(addr) => {
+                self.bytes[0..2].copy_from_slice(&AF_INET6.to_ne_bytes());
+                self.bytes[2..4].copy_from_slice(&addr.port().to_be_bytes());
+                self.bytes[4..8].copy_from_slice(&addr.flowinfo().to_ne_bytes());
+                self.bytes[8..24].copy_from_slice(&addr.ip().octets());
+                self.bytes[24..28].copy_from_slice(&addr.scope_id().to_ne_bytes());
+                28
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<SocketAddr> {
+        let family = u16::from_ne_bytes([self.bytes[0], self.bytes[1]]);
+        let port = u16::from_be_bytes([self.bytes[2], self.bytes[3]]);
+        match family {
+            AF_INET => {
+                let octets: [u8; 4] = self.bytes[4..8].try_into().unwrap();
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(octets),
+                    port,
+                )))
+            }
+            AF_INET6 => {
+                let flowinfo = u32::from_ne_bytes(self.bytes[4..8].try_into().unwrap());
+                let octets: [u8; 16] = self.bytes[8..24].try_into().unwrap();
+                let scope_id = u32::from_ne_bytes(self.bytes[24..28].try_into().unwrap());
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(octets),
+                    port,
+                    flowinfo,
+                    scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+// A datagram buffer and the msghdr describing it. Boxed, as the header
+// points into the rest of it and the kernel may hold on to those pointers
+// until the operation completes.
+pub struct Datagram {
+    pub buf: Vec<u8>,
+    pub iov: IoVec,
+    pub addr: SockAddrStorage,
+    pub msg: MsgHdr,
+}
+
+impl Datagram {
+    pub fn new(buf: Vec<u8>) -> Box<Datagram> {
+        let mut slot = Box::new(Datagram {
+            buf,
+            iov: IoVec {
+                base: ptr::null_mut(),
+                len: 0,
+            },
+            addr: SockAddrStorage::new(),
+            msg: MsgHdr {
+                name: ptr::null_mut(),
+                namelen: 0,
+                iov: ptr::null_mut(),
+                iovlen: 1,
+                control: ptr::null_mut(),
+                controllen: 0,
+                flags: 0,
+            },
+        });
+        slot.iov.base = slot.buf.as_mut_ptr() as *mut c_void;
+        slot.iov.len = slot.buf.len();
+        slot.msg.name = &mut slot.addr as *mut SockAddrStorage as *mut c_void;
+        slot.msg.namelen = slot.addr.bytes.len() as u32;
+        slot.msg.iov = &mut slot.iov;
+        slot
+    }
+
+    // Makes the header ready to receive into again.
+    pub fn reset(&mut self) {
+        self.msg.namelen = self.addr.bytes.len() as u32;
+        self.msg.flags = 0;
+    }
+}
@@ -0,0 +1,754 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::ResponseCode;
+use crate::blocklist::Blocklist;
+use crate::cache::Cache;
+use crate::http;
+use crate::json::Json;
+use crate::latency::Latencies;
+use crate::latency::Summary;
+use crate::random::Rng;
+use crate::sinkhole::Sinkhole;
+use crate::stats::Stats;
+use crate::threat::ThreatFeeds;
+
+// OpenTelemetry export over OTLP/HTTP with the JSON encoding. Sampled queries
+// are traced as a tree of spans: the query itself, with the cache lookup, each
+// upstream round trip and the writing of the response below it. Counters and
+// latency histograms go out as metrics.
+//
+// A query is answered on one thread from start to end, so the trace being
+// recorded lives in a thread local and stages open spans without having it
+// passed to them.
+
+// How often spans and metrics are sent.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Spans waiting for the next export at most; more are dropped.
+const MAX_QUEUED: usize = 8192;
+
+// Bucket bounds of the latency histograms, in milliseconds.
+const DURATION_BOUNDS: [f64; 13] = [
+    0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0,
+];
+
+// Where to send telemetry: "otlp-endpoint http://<host>:<port>[/<path>]".
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    // "host:port", resolved when the server starts
+    pub authority: String,
+    // prefix of /v1/traces and /v1/metrics
+    pub path: String,
+    pub service: String,
+    // the fraction of queries traced
+    pub sample: f64,
+}
+
+impl OtlpConfig {
+    pub fn parse(url: &str) -> Result<OtlpConfig, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("otlp-endpoint must be an http:// URL, not {:?}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let has_port = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed.contains("]:"),
+            None => authority.contains(':'),
+        };
+        // the port OTLP/HTTP listens on by default
+        let authority = if has_port {
+            authority.to_string()
+        } else {
+            format!("{}:4318", authority)
+        };
+        Ok(OtlpConfig {
+            authority,
+            path: path.to_string(),
+            service: "dns-server".to_string(),
+            sample: 1.0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal,
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    fn to_num(self) -> u64 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::Str(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Int(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+// {"key": ..., "value": {"stringValue": ...}}, as OTLP writes attributes;
+// 64 bit integers go as strings.
+fn attribute(key: &str, value: &Value) -> Json {
+    let value = match value {
+        Value::Str(text) => ("stringValue", text.as_str().into()),
+        Value::Int(n) => ("intValue", n.to_string().into()),
+        Value::Bool(b) => ("boolValue", (*b).into()),
+    };
+    Json::object(vec![
+        ("key", key.into()),
+        ("value", Json::object(vec![value])),
+    ])
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+struct SpanData {
+    id: u64,
+    // 0 for the root
+    parent: u64,
+    name: &'static str,
+    kind: SpanKind,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+impl SpanData {
+    fn to_json(&self, trace_id: u128) -> Json {
+        let mut fields = vec![
+            ("traceId", format!("{:032x}", trace_id).into()),
+            ("spanId", format!("{:016x}", self.id).into()),
+            ("name", self.name.into()),
+            ("kind", self.kind.to_num().into()),
+            ("startTimeUnixNano", self.start.to_string().into()),
+            ("endTimeUnixNano", self.end.to_string().into()),
+            (
+                "attributes",
+                Json::Array(
+                    self.attributes
+                        .iter()
+                        .map(|(key, value)| attribute(key, value))
+                        .collect(),
+                ),
+            ),
+        ];
+        if self.parent != 0 {
+            fields.push(("parentSpanId", format!("{:016x}", self.parent).into()));
+        }
+        if let Some(message) = &self.error {
+            // STATUS_CODE_ERROR
+            fields.push((
+                "status",
+                Json::object(vec![
+                    ("code", 2u64.into()),
+                    ("message", message.as_str().into()),
+                ]),
+            ));
+        }
+        Json::object(fields)
+    }
+}
+
+// The trace of the query this thread is answering.
+struct ActiveTrace {
+    id: u128,
+    spans: Vec<SpanData>,
+    // indexes into spans of the ones not ended yet, innermost last
+    open: Vec<usize>,
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Option<ActiveTrace>> = const { RefCell::new(None) };
+}
+
+static TELEMETRY: OnceLock<Arc<Telemetry>> = OnceLock::new();
+
+// A span being recorded, ended when dropped. Spans opened while no trace is
+// being recorded (telemetry off, the query not sampled, or work outside of
+// a query) do nothing.
+pub struct Span {
+    index: Option<usize>,
+}
+
+impl Span {
+    fn inert() -> Span {
+        Span { index: None }
+    }
+
+    fn with<F: FnOnce(&mut SpanData)>(&self, f: F) {
+        let Some(index) = self.index else {
+            return;
+        };
+        ACTIVE.with(|active| {
+            if let Some(trace) = active.borrow_mut().as_mut() {
+                f(&mut trace.spans[index]);
+            }
+        });
+    }
+
+    pub fn set(&self, key: &'static str, value: impl Into<Value>) {
+        if self.index.is_some() {
+            let value = value.into();
+            self.with(|span| span.attributes.push((key, value)));
+        }
+    }
+
+    pub fn fail(&self, message: &str) {
+        self.with(|span| span.error = Some(message.to_string()));
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(index) = self.index else {
+            return;
+        };
+        let finished = ACTIVE.with(|active| {
+            let mut active = active.borrow_mut();
+            let trace = active.as_mut()?;
+            trace.spans[index].end = unix_nanos();
+            trace.open.retain(|&open| open != index);
+            // the root is the last to end and takes the trace with it
+            if index == 0 { active.take() } else { None }
+        });
+        if let (Some(trace), Some(telemetry)) = (finished, TELEMETRY.get()) {
+            telemetry.queue(trace);
+        }
+    }
+}
+
+// Starts the trace of a query on this thread, if telemetry is on and the
+// query is picked by sampling, and returns its root span.
+pub fn trace(name: &'static str) -> Span {
+    let Some(telemetry) = TELEMETRY.get() else {
+        return Span::inert();
+    };
+    let (id, span_id) = {
+        let mut rng = telemetry.rng.lock().unwrap();
+        if (rng.next_u64() as f64) >= telemetry.config.sample * u64::MAX as f64 {
+            return Span::inert();
+        }
+        let id = (rng.next_u64() as u128) << 64 | rng.next_u64() as u128;
+        (id, rng.next_u64() | 1)
+    };
+    ACTIVE.with(|active| {
+        *active.borrow_mut() = Some(ActiveTrace {
+            id,
+            spans: vec![SpanData {
+                id: span_id,
+                parent: 0,
+                name,
+                kind: SpanKind::Server,
+                start: unix_nanos(),
+                end: 0,
+                attributes: Vec::new(),
+                error: None,
+            }],
+            open: vec![0],
+        });
+    });
+    Span { index: Some(0) }
+}
+
+// Opens a span below the innermost open one of this thread's trace.
+pub fn span(name: &'static str, kind: SpanKind) -> Span {
+    let index = ACTIVE.with(|active| {
+        let mut active = active.borrow_mut();
+        let trace = active.as_mut()?;
+        let parent = trace.spans[*trace.open.last()?].id;
+        // ids only need to be unique within the trace
+        let id = trace.spans[0].id.wrapping_add(trace.spans.len() as u64 * 2);
+        trace.spans.push(SpanData {
+            id,
+            parent,
+            name,
+            kind,
+            start: unix_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+            error: None,
+        });
+        let index = trace.spans.len() - 1;
+        trace.open.push(index);
+        Some(index)
+    });
+    Span { index }
+}
+
+// Counts of durations falling in each of DURATION_BOUNDS' buckets.
+struct Histogram {
+    buckets: [AtomicU64; DURATION_BOUNDS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let millis = duration.as_secs_f64() * 1000.0;
+        let bucket = DURATION_BOUNDS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(DURATION_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn to_json(&self, start: u64, now: u64) -> Json {
+        let point = Json::object(vec![
+            ("startTimeUnixNano", start.to_string().into()),
+            ("timeUnixNano", now.to_string().into()),
+            (
+                "count",
+                self.count.load(Ordering::Relaxed).to_string().into(),
+            ),
+            (
+                "sum",
+                Json::Number(self.sum_micros.load(Ordering::Relaxed) as f64 / 1000.0),
+            ),
+            (
+                "bucketCounts",
+                Json::Array(
+                    self.buckets
+                        .iter()
+                        .map(|count| count.load(Ordering::Relaxed).to_string().into())
+                        .collect(),
+                ),
+            ),
+            (
+                "explicitBounds",
+                Json::Array(
+                    DURATION_BOUNDS
+                        .iter()
+                        .map(|&bound| Json::Number(bound))
+                        .collect(),
+                ),
+            ),
+        ]);
+        // AGGREGATION_TEMPORALITY_CUMULATIVE
+        Json::object(vec![
+            ("dataPoints", Json::Array(vec![point])),
+            ("aggregationTemporality", 2u64.into()),
+        ])
+    }
+}
+
+// Records a query's latency from the moment it was read to the moment its
+// response was handed to the socket.
+pub fn record_query(duration: Duration) {
+    if let Some(telemetry) = TELEMETRY.get() {
+        telemetry.query_duration.record(duration);
+    }
+}
+
+// Records the round trip time of a query sent upstream, answered or not.
+pub fn record_upstream(duration: Duration) {
+    if let Some(telemetry) = TELEMETRY.get() {
+        telemetry.upstream_duration.record(duration);
+    }
+}
+
+// Finished traces waiting for the next export.
+struct Queue {
+    traces: Vec<ActiveTrace>,
+    spans: usize,
+}
+
+pub struct Telemetry {
+    addr: SocketAddr,
+    config: OtlpConfig,
+    // when the cumulative metrics started counting
+    started: u64,
+    rng: Mutex<Rng>,
+    queued: Mutex<Queue>,
+    dropped_spans: AtomicU64,
+    query_duration: Histogram,
+    upstream_duration: Histogram,
+    stats: Arc<Stats>,
+    cache: Arc<Cache>,
+    blocklist: Arc<Blocklist>,
+    threats: Arc<ThreatFeeds>,
+    sinkhole: Option<Arc<Sinkhole>>,
+    latencies: Arc<Latencies>,
+}
+
+impl Telemetry {
+    // Resolves the endpoint, so it has to run while the resolver
+    // configuration is still reachable.
+    pub fn new(
+        config: OtlpConfig,
+        stats: Arc<Stats>,
+        cache: Arc<Cache>,
+        blocklist: Arc<Blocklist>,
+        threats: Arc<ThreatFeeds>,
+        sinkhole: Option<Arc<Sinkhole>>,
+        latencies: Arc<Latencies>,
+    ) -> Result<Telemetry, String> {
+        let addr = config
+            .authority
+            .to_socket_addrs()
+            .map_err(|e| format!("otlp-endpoint {}: {}", config.authority, e))?
+            .next()
+            .ok_or_else(|| format!("otlp-endpoint {}: no address", config.authority))?;
+        Ok(Telemetry {
+            addr,
+            config,
+            started: unix_nanos(),
+            rng: Mutex::new(Rng::new()),
+            queued: Mutex::new(Queue {
+                traces: Vec::new(),
+                spans: 0,
+            }),
+            dropped_spans: AtomicU64::new(0),
+            query_duration: Histogram::new(),
+            upstream_duration: Histogram::new(),
+            stats,
+            cache,
+            blocklist,
+            threats,
+            sinkhole,
+            latencies,
+        })
+    }
+
+    // Makes this the process wide exporter the spans go to.
+    pub fn install(self: &Arc<Telemetry>) {
+        let _ = TELEMETRY.set(self.clone());
+    }
+
+    fn queue(&self, trace: ActiveTrace) {
+        let spans = trace.spans.len();
+        let mut queued = self.queued.lock().unwrap();
+        if queued.spans + spans > MAX_QUEUED {
+            self.dropped_spans
+                .fetch_add(spans as u64, Ordering::Relaxed);
+            return;
+        }
+        queued.spans += spans;
+        queued.traces.push(trace);
+    }
+
+    fn resource(&self) -> Json {
+        Json::object(vec![(
+            "attributes",
+            Json::Array(vec![attribute(
+                "service.name",
+                &Value::Str(self.config.service.clone()),
+            )]),
+        )])
+    }
+
+    fn scope() -> Json {
+        Json::object(vec![
+            ("name", "dns-server".into()),
+            ("version", env!("CARGO_PKG_VERSION").into()),
+        ])
+    }
+
+    fn traces_json(&self, traces: &[ActiveTrace]) -> Json {
+        let spans = traces
+            .iter()
+            .flat_map(|trace| trace.spans.iter().map(|span| span.to_json(trace.id)))
+            .collect();
+        Json::object(vec![(
+            "resourceSpans",
+            Json::Array(vec![Json::object(vec![
+                ("resource", self.resource()),
+                (
+                    "scopeSpans",
+                    Json::Array(vec![Json::object(vec![
+                        ("scope", Telemetry::scope()),
+                        ("spans", Json::Array(spans)),
+                    ])]),
+                ),
+            ])]),
+        )])
+    }
+
+    fn metrics_json(&self) -> Json {
+        let now = unix_nanos();
+        let counter = |name: &str, unit: &str, points: Vec<(Option<(&str, Value)>, u64)>| {
+            let points = points
+                .into_iter()
+                .map(|(attr, value)| {
+                    let attributes = attr
+                        .map(|(key, value)| vec![attribute(key, &value)])
+                        .unwrap_or_default();
+                    Json::object(vec![
+                        ("startTimeUnixNano", self.started.to_string().into()),
+                        ("timeUnixNano", now.to_string().into()),
+                        ("asInt", value.to_string().into()),
+                        ("attributes", Json::Array(attributes)),
+                    ])
+                })
+                .collect();
+            Json::object(vec![
+                ("name", name.into()),
+                ("unit", unit.into()),
+                (
+                    "sum",
+                    Json::object(vec![
+                        ("dataPoints", Json::Array(points)),
+                        ("aggregationTemporality", 2u64.into()),
+                        ("isMonotonic", true.into()),
+                    ]),
+                ),
+            ])
+        };
+        let histogram = |name: &str, histogram: &Histogram| {
+            Json::object(vec![
+                ("name", name.into()),
+                ("unit", "ms".into()),
+                ("histogram", histogram.to_json(self.started, now)),
+            ])
+        };
+        // p50, p95 and p99 of the decaying histograms upstreams are picked by
+        let summary = |name: &str, key: &str, points: Vec<(String, Summary)>| {
+            let points = points
+                .into_iter()
+                .map(|(value, summary)| {
+                    let quantiles = [(0.5, summary.p50), (0.95, summary.p95), (0.99, summary.p99)]
+                        .iter()
+                        .map(|&(quantile, rtt)| {
+                            Json::object(vec![
+                                ("quantile", Json::Number(quantile)),
+                                ("value", Json::Number(rtt.as_secs_f64() * 1000.0)),
+                            ])
+                        })
+                        .collect();
+                    Json::object(vec![
+                        ("startTimeUnixNano", self.started.to_string().into()),
+                        ("timeUnixNano", now.to_string().into()),
+                        ("count", summary.count.to_string().into()),
+                        ("sum", Json::Number(summary.sum.as_secs_f64() * 1000.0)),
+                        ("quantileValues", Json::Array(quantiles)),
+                        (
+                            "attributes",
+                            Json::Array(vec![attribute(key, &value.into())]),
+                        ),
+                    ])
+                })
+                .collect();
+            Json::object(vec![
+                ("name", name.into()),
+                ("unit", "ms".into()),
+                (
+                    "summary",
+                    Json::object(vec![("dataPoints", Json::Array(points))]),
+                ),
+            ])
+        };
+
+        let rcodes = [
+            ResponseCode::NOERR,
+            ResponseCode::FORMERR,
+            ResponseCode::SERVFAIL,
+            ResponseCode::NAMERR,
+            ResponseCode::NOTIMP,
+            ResponseCode::REFUSED,
+            ResponseCode::NOTAUTH,
+            ResponseCode::BADVERS,
+        ];
+        let mut metrics = vec![
+            counter("dns.queries", "{query}", vec![(None, self.stats.queries())]),
+            counter(
+                "dns.responses",
+                "{response}",
+                rcodes
+                    .iter()
+                    .map(|rcode| {
+                        let attr = ("dns.response.code", format!("{:?}", rcode).into());
+                        (Some(attr), self.stats.responses(*rcode))
+                    })
+                    .collect(),
+            ),
+            counter(
+                "dns.cache.lookups",
+                "{lookup}",
+                vec![
+                    (Some(("dns.cache.hit", true.into())), self.cache.hits()),
+                    (Some(("dns.cache.hit", false.into())), self.cache.misses()),
+                ],
+            ),
+            counter(
+                "dns.cache.shared_hits",
+                "{lookup}",
+                vec![(None, self.cache.shared_hits())],
+            ),
+            counter(
+                "dns.blocked",
+                "{query}",
+                vec![(None, self.blocklist.blocked())],
+            ),
+            counter(
+                "dns.rewritten",
+                "{query}",
+                vec![(None, self.blocklist.rewritten())],
+            ),
+            counter(
+                "dns.threats",
+                "{query}",
+                self.threats
+                    .stats()
+                    .into_iter()
+                    .map(|feed| (Some(("dns.threat.feed", feed.name.into())), feed.hits))
+                    .collect(),
+            ),
+            counter(
+                "otel.spans.dropped",
+                "{span}",
+                vec![(None, self.dropped_spans.load(Ordering::Relaxed))],
+            ),
+            histogram("dns.query.duration", &self.query_duration),
+            histogram("dns.upstream.duration", &self.upstream_duration),
+            summary(
+                "dns.upstream.rtt",
+                "server.address",
+                self.latencies
+                    .servers()
+                    .into_iter()
+                    .map(|(server, summary)| (server.to_string(), summary))
+                    .collect(),
+            ),
+            summary(
+                "dns.zone.rtt",
+                "dns.zone",
+                self.latencies
+                    .zones()
+                    .into_iter()
+                    .map(|(zone, summary)| (zone.to_fqdn(), summary))
+                    .collect(),
+            ),
+        ];
+        if let Some(sinkhole) = &self.sinkhole {
+            metrics.push(counter(
+                "dns.sinkhole.answers",
+                "{query}",
+                vec![(None, sinkhole.answered())],
+            ));
+            metrics.push(counter(
+                "dns.sinkhole.connections",
+                "{connection}",
+                vec![(None, sinkhole.accepted())],
+            ));
+        }
+
+        Json::object(vec![(
+            "resourceMetrics",
+            Json::Array(vec![Json::object(vec![
+                ("resource", self.resource()),
+                (
+                    "scopeMetrics",
+                    Json::Array(vec![Json::object(vec![
+                        ("scope", Telemetry::scope()),
+                        ("metrics", Json::Array(metrics)),
+                    ])]),
+                ),
+            ])]),
+        )])
+    }
+
+    fn post(&self, signal: &str, body: &Json) -> Result<(), String> {
+        let path = format!("{}/v1/{}", self.config.path, signal);
+        let status = http::post(
+            self.addr,
+            &self.config.authority,
+            &path,
+            "application/json",
+            body.to_string().as_bytes(),
+            POST_TIMEOUT,
+        )?;
+        if !(200..300).contains(&status) {
+            return Err(format!("{} answered {}", path, status));
+        }
+        Ok(())
+    }
+
+    // Sends what was recorded every EXPORT_INTERVAL. A failed export is
+    // reported once until exports work again; its spans are lost.
+    pub fn run(&self) {
+        let mut failing = false;
+        loop {
+            thread::sleep(EXPORT_INTERVAL);
+
+            let traces = {
+                let mut queued = self.queued.lock().unwrap();
+                queued.spans = 0;
+                std::mem::take(&mut queued.traces)
+            };
+            let mut result = self.post("metrics", &self.metrics_json());
+            if !traces.is_empty() && result.is_ok() {
+                result = self.post("traces", &self.traces_json(&traces));
+            }
+
+            match result {
+                Err(e) if !failing => {
+                    eprintln!("otlp export: {}", e);
+                    failing = true;
+                }
+                Err(_) => {}
+                Ok(()) => failing = false,
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::DnsPacket;
+use crate::ResponseCode;
+use crate::anonymize;
+use crate::audit;
+use crate::audit::Action;
+use crate::blocklist::listed;
+use crate::calendar::unix_now;
+use crate::http;
+use crate::log;
+use crate::log::LogLevel;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::sinkhole::Sinkhole;
+
+// How long a feed gets to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How soon a feed that couldn't be fetched or read is tried again, unless
+// its refresh comes sooner.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+// A client asking for the same listed name again within this long isn't
+// alerted on again.
+const ALERT_INTERVAL: Duration = Duration::from_secs(600);
+
+// Alerts remembered for ALERT_INTERVAL; past this many the expired ones go.
+const MAX_ALERTS: usize = 10000;
+
+// What a query for a listed name gets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreatAction {
+    // NXDOMAIN (or the sinkhole's addresses), and an alert
+    Block,
+    // only the alert; the query is answered as usual
+    Alert,
+}
+
+// Where a feed comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeedSource {
+    // "host[:port]" and the path to GET
+    Http { authority: String, path: String },
+    // a file kept up to date by something else, e.g. a cron job fetching it
+    // over HTTPS
+    File(PathBuf),
+}
+
+// A list of malicious domains, one per line or in hosts file format:
+// "threat-feed <name> <http://...|path> [category=<name>] [refresh=<time>]
+// [action=block|alert]".
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedConfig {
+    pub name: String,
+    pub source: FeedSource,
+    // what the domains are, e.g. malware or phishing, for alerts and stats
+    pub category: String,
+    pub refresh: Duration,
+    pub action: ThreatAction,
+}
+
+impl FeedConfig {
+    pub fn parse(name: &str, location: &str) -> Result<FeedConfig, String> {
+        let source = if let Some(url) = location.strip_prefix("http://") {
+            let (authority, path) = match url.find('/') {
+                Some(slash) => (&url[..slash], &url[slash..]),
+                None => (url, "/"),
+            };
+            if authority.is_empty() {
+                return Err(format!("threat-feed URL without a host: {:?}", location));
+            }
+            FeedSource::Http {
+                authority: authority.to_string(),
+                path: path.to_string(),
+            }
+        } else if location.starts_with("https://") {
+            return Err(format!(
+                "threat-feed {}: there's no TLS to fetch https:// with; fetch it into a file and give its path",
+                name
+            ));
+        } else {
+            FeedSource::File(PathBuf::from(location))
+        };
+        Ok(FeedConfig {
+            name: name.to_string(),
+            source,
+            category: "malware".to_string(),
+            refresh: Duration::from_secs(3600),
+            action: ThreatAction::Block,
+        })
+    }
+}
+
+// The domains of a feed's text. Lines hold a domain, or an address and then
+// a domain as in a hosts file; # starts a comment. Names of a single label,
+// like localhost, are left out so a feed can't block a whole TLD.
+pub fn parse_feed(text: &str) -> HashSet<Name> {
+    let mut domains = HashSet::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let Some(first) = words.next() else {
+            continue;
+        };
+        let domain = match first.parse::<IpAddr>() {
+            Ok(_) => words.next(),
+            Err(_) => Some(first),
+        };
+        if let Some(Ok(domain)) = domain.map(Name::from_unicode)
+            && domain.label_count() > 1
+        {
+            domains.insert(domain);
+        }
+    }
+    domains
+}
+
+// A feed with what it listed when last read.
+struct Feed {
+    config: FeedConfig,
+    // resolved at startup for Http feeds
+    addr: Option<SocketAddr>,
+    domains: RwLock<HashSet<Name>>,
+    // queries for its domains
+    hits: AtomicU64,
+    // unix time it was last read successfully, 0 before that
+    updated: AtomicU64,
+}
+
+// A feed's figures for the management API and telemetry.
+pub struct FeedStats {
+    pub name: String,
+    pub category: String,
+    pub action: ThreatAction,
+    pub domains: usize,
+    pub hits: u64,
+    pub updated: Option<u64>,
+}
+
+// What refresh keeps between reads of a feed.
+struct FeedState {
+    etag: Option<String>,
+    modified: Option<SystemTime>,
+    error: Option<String>,
+    next: Instant,
+}
+
+// The threat feeds, kept apart from the blocklist: their domains are
+// answered by their own action, every hit raises an alert (once per client
+// and name every ALERT_INTERVAL) and goes to the audit log, and each feed
+// counts its hits. Feeds are read again every refresh, over HTTP with the
+// ETag of the last response so an unchanged list isn't sent again.
+pub struct ThreatFeeds {
+    feeds: Vec<Feed>,
+    // when each (client, name) was last alerted on
+    alerted: Mutex<HashMap<(IpAddr, Name), Instant>>,
+    // what blocked names are answered with instead of NXDOMAIN
+    sinkhole: Option<Arc<Sinkhole>>,
+}
+
+impl ThreatFeeds {
+    // Resolves the feeds' hosts, so it has to run while the resolver
+    // configuration is still reachable. Nothing is read yet; run does that.
+    pub fn new(configs: Vec<FeedConfig>) -> Result<ThreatFeeds, String> {
+        let mut feeds = Vec::new();
+        for config in configs {
+            let addr = match &config.source {
+                FeedSource::Http { authority, .. } => {
+                    let with_port = if authority
+                        .rsplit_once(':')
+                        .is_some_and(|(host, _)| !host.starts_with('[') || host.ends_with(']'))
+                    {
+                        authority.clone()
+                    } else {
+                        format!("{}:80", authority)
+                    };
+                    let addr = with_port
+                        .to_socket_addrs()
+                        .map_err(|e| format!("threat-feed {}: {}: {}", config.name, authority, e))?
+                        .next()
+                        .ok_or_else(|| {
+                            format!("threat-feed {}: {}: no address", config.name, authority)
+                        })?;
+                    Some(addr)
+                }
+                FeedSource::File(_) => None,
+            };
+            feeds.push(Feed {
+                config,
+                addr,
+                domains: RwLock::new(HashSet::new()),
+                hits: AtomicU64::new(0),
+                updated: AtomicU64::new(0),
+            });
+        }
+        Ok(ThreatFeeds {
+            feeds,
+            alerted: Mutex::new(HashMap::new()),
+            sinkhole: None,
+        })
+    }
+
+    pub fn set_sinkhole(&mut self, sinkhole: Arc<Sinkhole>) {
+        self.sinkhole = Some(sinkhole);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.feeds.is_empty()
+    }
+
+    pub fn stats(&self) -> Vec<FeedStats> {
+        self.feeds
+            .iter()
+            .map(|feed| FeedStats {
+                name: feed.config.name.clone(),
+                category: feed.config.category.clone(),
+                action: feed.config.action,
+                domains: feed.domains.read().unwrap().len(),
+                hits: feed.hits.load(Ordering::Relaxed),
+                updated: Some(feed.updated.load(Ordering::Relaxed)).filter(|time| *time > 0),
+            })
+            .collect()
+    }
+
+    // Reads the feed if it changed since state says, returning how many
+    // domains it now lists, or None if it didn't change.
+    fn read(feed: &Feed, state: &mut FeedState) -> Result<Option<usize>, String> {
+        let text = match (&feed.config.source, feed.addr) {
+            (FeedSource::Http { authority, path }, Some(addr)) => {
+                let mut headers = Vec::new();
+                if let Some(etag) = &state.etag {
+                    headers.push(("If-None-Match", etag.as_str()));
+                }
+                let reply = http::get(addr, authority, path, &headers, FETCH_TIMEOUT)?;
+                match reply.status {
+                    304 => return Ok(None),
+                    200 => {}
+                    status => return Err(format!("{} answered {}", path, status)),
+                }
+                state.etag = reply.header("etag").map(str::to_string);
+                String::from_utf8_lossy(&reply.body).into_owned()
+            }
+            (FeedSource::File(path), _) => {
+                let modified = fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                if state.modified == Some(modified) {
+                    return Ok(None);
+                }
+                let text =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                state.modified = Some(modified);
+                text
+            }
+            (FeedSource::Http { .. }, None) => return Err("no address".to_string()),
+        };
+
+        let domains = parse_feed(&text);
+        let count = domains.len();
+        *feed.domains.write().unwrap() = domains;
+        Ok(Some(count))
+    }
+
+    // Reads every feed now and then again every refresh until the server
+    // stops. Runs after any chroot, so file paths are as seen from inside
+    // it.
+    pub fn run(&self) {
+        let mut states: Vec<FeedState> = self
+            .feeds
+            .iter()
+            .map(|_| FeedState {
+                etag: None,
+                modified: None,
+                error: None,
+                next: Instant::now(),
+            })
+            .collect();
+        loop {
+            for (feed, state) in self.feeds.iter().zip(states.iter_mut()) {
+                if state.next > Instant::now() {
+                    continue;
+                }
+                let result = Self::read(feed, state);
+                state.next = Instant::now()
+                    + match result {
+                        Ok(_) => feed.config.refresh,
+                        Err(_) => feed.config.refresh.min(RETRY_INTERVAL),
+                    };
+                if result.is_ok() {
+                    feed.updated.store(unix_now() as u64, Ordering::Relaxed);
+                }
+
+                match result {
+                    Ok(Some(count)) if log::enabled(LogLevel::Info) => {
+                        eprintln!("threat-feed {}: {} domains", feed.config.name, count);
+                    }
+                    Ok(_) => {}
+                    // a failure is reported once, not on every retry
+                    Err(e) if state.error.as_ref() != Some(&e) => {
+                        eprintln!("threat-feed {}: {}", feed.config.name, e);
+                        state.error = Some(e);
+                        continue;
+                    }
+                    Err(_) => continue,
+                }
+                state.error = None;
+            }
+
+            let next = states.iter().map(|state| state.next).min();
+            match next {
+                Some(next) => thread::sleep(next.saturating_duration_since(Instant::now())),
+                None => return,
+            }
+        }
+    }
+
+    // Logs that client asked for a listed name, unless it was told already
+    // within ALERT_INTERVAL.
+    fn alert(&self, client: IpAddr, name: &Name, feed: &Feed, listed: &Name) {
+        let now = Instant::now();
+        let mut alerted = self.alerted.lock().unwrap();
+        let key = (client, name.clone());
+        if alerted
+            .get(&key)
+            .is_some_and(|at| now.duration_since(*at) < ALERT_INTERVAL)
+        {
+            return;
+        }
+        if alerted.len() >= MAX_ALERTS {
+            alerted.retain(|_, at| now.duration_since(*at) < ALERT_INTERVAL);
+        }
+        alerted.insert(key, now);
+        drop(alerted);
+
+        let action = match feed.config.action {
+            ThreatAction::Block => "blocked",
+            ThreatAction::Alert => "allowed",
+        };
+        eprintln!(
+            "threat: {} asked for {}, {} listed by threat-feed {} as {}; {}",
+            anonymize::client(client),
+            anonymize::name(name, Name::to_unicode),
+            listed.to_unicode(),
+            feed.config.name,
+            feed.config.category,
+            action
+        );
+    }
+}
+
+impl Handler for ThreatFeeds {
+    fn name(&self) -> &str {
+        "threats"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let name = &request.question.name;
+        let client = request.src.ip();
+        let hit = self.feeds.iter().find_map(|feed| {
+            let domain = listed(&feed.domains.read().unwrap(), name)?;
+            Some((feed, domain))
+        });
+        let Some((feed, domain)) = hit else {
+            next.run(request, response);
+            return;
+        };
+
+        feed.hits.fetch_add(1, Ordering::Relaxed);
+        self.alert(client, name, feed, &domain);
+        let rule = format_args!(
+            "threat-feed {} {} ({})",
+            feed.config.name,
+            domain.to_unicode(),
+            feed.config.category
+        );
+        match feed.config.action {
+            ThreatAction::Block => {
+                let outcome = match &self.sinkhole {
+                    Some(sinkhole) => {
+                        sinkhole.answer(request.question, response);
+                        sinkhole.outcome()
+                    }
+                    None => {
+                        response.header.response_code = ResponseCode::NAMERR;
+                        "NXDOMAIN".to_string()
+                    }
+                };
+                audit::record(
+                    client,
+                    request.policy,
+                    request.question,
+                    Action::Threat,
+                    &rule,
+                    &outcome,
+                );
+            }
+            ThreatAction::Alert => {
+                audit::record(
+                    client,
+                    request.policy,
+                    request.question,
+                    Action::Threat,
+                    &rule,
+                    &"allowed",
+                );
+                next.run(request, response);
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+
+// How UDP sockets are read and written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UdpIo {
+    // one recv_from and send_to per packet
+    Plain,
+    // reads batched with recvmmsg, Linux only
+    Mmsg,
+    // batched through an io_uring, Linux only
+    IoUring,
+}
+
+impl UdpIo {
+    pub fn from_name(name: &str) -> Option<UdpIo> {
+        match name {
+            "plain" => Some(UdpIo::Plain),
+            "mmsg" => Some(UdpIo::Mmsg),
+            "io-uring" => Some(UdpIo::IoUring),
+            _ => None,
+        }
+    }
+
+    // Batching where the platform has it.
+    pub fn preferred() -> UdpIo {
+        if cfg!(target_os = "linux") {
+            UdpIo::Mmsg
+        } else {
+            UdpIo::Plain
+        }
+    }
+}
+
+// Largest datagram any of the backends takes in; longer ones are dropped or
+// cut short.
+pub const RECV_SIZE: usize = 4096;
+
+// Reads datagrams off socket forever, calling handle for each; what it
+// returns is sent back to the sender. Falls back to plain reads and writes
+// if the requested backend can't be set up.
+pub fn serve(
+    socket: &UdpSocket,
+    io: UdpIo,
+    handle: impl FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+) {
+    serve_until(socket, io, handle, || false)
+}
+
+// Like serve, but returns once done says so. done is asked after every
+// datagram, or batch of them, so whatever makes it true sends the socket a
+// datagram to wake it up.
+pub fn serve_until(
+    socket: &UdpSocket,
+    io: UdpIo,
+    mut handle: impl FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+    done: impl Fn() -> bool,
+) {
+    #[cfg(target_os = "linux")]
+    match io {
+        UdpIo::Plain => {}
+        UdpIo::Mmsg => match crate::mmsg::serve(socket, &mut handle, &done) {
+            Ok(()) => return,
+            Err(e) => eprintln!("recvmmsg failed, using plain UDP I/O: {}", e),
+        },
+        UdpIo::IoUring => {
+            let result = crate::uring::UdpRing::new(socket)
+                .and_then(|mut ring| ring.serve(socket, &mut handle, &done));
+            match result {
+                Ok(()) => return,
+                Err(e) => eprintln!("io_uring unavailable, using plain UDP I/O: {}", e),
+            }
+        }
+    }
+
+    let mut buf = vec![0; RECV_SIZE];
+    while !done() {
+        let Ok((len, src)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if let Some(reply) = handle(&buf[..len], src) {
+            let _ = socket.send_to(&reply, src);
+        }
+    }
+}
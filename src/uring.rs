@@ -0,0 +1,407 @@
+// io_uring (Linux 5.6+) for UDP sockets: a fixed number of receives stay
+// posted on the ring, and each round trip to the kernel both submits the
+// queued sends and reaps every receive that has completed, instead of one
+// recv_from/send_to pair per packet.
+
+use std::ffi::c_int;
+use std::ffi::c_long;
+use std::ffi::c_void;
+use std::io;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+use crate::sys;
+use crate::sys::Datagram;
+use crate::sys::MsgHdr;
+use crate::udp::RECV_SIZE;
+
+const SYS_IO_URING_SETUP: c_long = 425;
+const SYS_IO_URING_ENTER: c_long = 426;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+const IORING_OP_SENDMSG: u8 = 9;
+const IORING_OP_RECVMSG: u8 = 10;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+
+// Receives kept posted, and the most sends in flight at once.
+const DEPTH: u32 = 64;
+
+// user_data of sends, so their completions aren't taken for receives
+const SEND_TAG: u64 = 1 << 63;
+// and of cancellations
+const CANCEL_TAG: u64 = 1 << 62;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    msg_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: c_int, len: usize, offset: i64) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            sys::mmap(
+                ptr::null_mut(),
+                len,
+                sys::PROT_READ | sys::PROT_WRITE,
+                sys::MAP_SHARED | sys::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { (self.ptr as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            sys::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+pub struct UdpRing {
+    fd: c_int,
+    socket: c_int,
+    sq_ring: Mapping,
+    cq_ring: Mapping,
+    sqes: Mapping,
+    params: Params,
+    // our copy of the submission tail, published to the kernel in push
+    sq_tail: u32,
+    // boxed so the pointers inside each slot stay valid
+    #[allow(clippy::vec_box)]
+    recvs: Vec<Box<Datagram>>,
+    sends: Vec<Option<Box<Datagram>>>,
+}
+
+impl UdpRing {
+    pub fn new(socket: &UdpSocket) -> io::Result<UdpRing> {
+        let mut params = Params::default();
+        let fd = unsafe { sys::syscall(SYS_IO_URING_SETUP, DEPTH * 2, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as c_int;
+
+        let mapped = (|| {
+            let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+            let cq_len =
+                params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+            let sq_ring = Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?;
+            let cq_ring = Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?;
+            let sqes = Mapping::new(
+                fd,
+                params.sq_entries as usize * size_of::<Sqe>(),
+                IORING_OFF_SQES,
+            )?;
+            Ok((sq_ring, cq_ring, sqes))
+        })();
+        let (sq_ring, cq_ring, sqes) = match mapped {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                unsafe { sys::close(fd) };
+                return Err(e);
+            }
+        };
+
+        let sq_tail =
+            unsafe { (*sq_ring.at::<AtomicU32>(params.sq_off.tail)).load(Ordering::Acquire) };
+        Ok(UdpRing {
+            fd,
+            socket: socket.as_raw_fd(),
+            sq_ring,
+            cq_ring,
+            sqes,
+            params,
+            sq_tail,
+            recvs: (0..DEPTH)
+                .map(|_| Datagram::new(vec![0; RECV_SIZE]))
+                .collect(),
+            sends: (0..DEPTH).map(|_| None).collect(),
+        })
+    }
+
+    fn sq_head(&self) -> u32 {
+        unsafe { (*self.sq_ring.at::<AtomicU32>(self.params.sq_off.head)).load(Ordering::Acquire) }
+    }
+
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        if self.sq_tail.wrapping_sub(self.sq_head()) == self.params.sq_entries {
+            self.enter(0)?;
+        }
+
+        let mask = unsafe { *self.sq_ring.at::<u32>(self.params.sq_off.ring_mask) };
+        let index = self.sq_tail & mask;
+        unsafe {
+            *self.sqes.at::<Sqe>(index * size_of::<Sqe>() as u32) = sqe;
+            *self.sq_ring.at::<u32>(self.params.sq_off.array + index * 4) = index;
+        }
+        self.sq_tail = self.sq_tail.wrapping_add(1);
+        unsafe {
+            (*self.sq_ring.at::<AtomicU32>(self.params.sq_off.tail))
+                .store(self.sq_tail, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    // Submits what is queued and waits until min_complete operations are done.
+    fn enter(&mut self, min_complete: u32) -> io::Result<()> {
+        let flags = if min_complete > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+        loop {
+            let to_submit = self.sq_tail.wrapping_sub(self.sq_head());
+            let ret = unsafe {
+                sys::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd,
+                    to_submit,
+                    min_complete,
+                    flags,
+                    ptr::null::<c_void>(),
+                    0usize,
+                )
+            };
+            if ret >= 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    fn reap(&mut self) -> Vec<Cqe> {
+        let off = &self.params.cq_off;
+        let head = unsafe { &*self.cq_ring.at::<AtomicU32>(off.head) };
+        let tail = unsafe { &*self.cq_ring.at::<AtomicU32>(off.tail) };
+        let mask = unsafe { *self.cq_ring.at::<u32>(off.ring_mask) };
+
+        let mut cqes = Vec::new();
+        let mut current = head.load(Ordering::Relaxed);
+        let end = tail.load(Ordering::Acquire);
+        while current != end {
+            let offset = off.cqes + (current & mask) * size_of::<Cqe>() as u32;
+            cqes.push(unsafe { *self.cq_ring.at::<Cqe>(offset) });
+            current = current.wrapping_add(1);
+        }
+        head.store(current, Ordering::Release);
+        cqes
+    }
+
+    fn post_recv(&mut self, index: usize) -> io::Result<()> {
+        let slot = &mut self.recvs[index];
+        slot.reset();
+        let sqe = Sqe {
+            opcode: IORING_OP_RECVMSG,
+            fd: self.socket,
+            addr: &mut slot.msg as *mut MsgHdr as u64,
+            len: 1,
+            user_data: index as u64,
+            ..Sqe::default()
+        };
+        self.push(sqe)
+    }
+
+    // Queues data for dest, or returns it when every send slot is busy.
+    fn post_send(&mut self, data: Vec<u8>, dest: SocketAddr) -> io::Result<Option<Vec<u8>>> {
+        let Some(index) = self.sends.iter().position(Option::is_none) else {
+            return Ok(Some(data));
+        };
+        let mut slot = Datagram::new(data);
+        slot.msg.namelen = slot.addr.set(dest);
+        let sqe = Sqe {
+            opcode: IORING_OP_SENDMSG,
+            fd: self.socket,
+            addr: &mut slot.msg as *mut MsgHdr as u64,
+            len: 1,
+            user_data: SEND_TAG | index as u64,
+            ..Sqe::default()
+        };
+        self.sends[index] = Some(slot);
+        self.push(sqe)?;
+        Ok(None)
+    }
+
+    // Cancels the receives still posted and waits for them, and for the
+    // sends in flight, as the kernel would otherwise write to or read from
+    // their buffers after they are freed.
+    fn drain(&mut self) -> io::Result<()> {
+        for index in 0..self.recvs.len() {
+            let sqe = Sqe {
+                opcode: IORING_OP_ASYNC_CANCEL,
+                addr: index as u64,
+                user_data: CANCEL_TAG,
+                ..Sqe::default()
+            };
+            self.push(sqe)?;
+        }
+
+        let mut posted = self.recvs.len();
+        while posted > 0 || self.sends.iter().any(Option::is_some) {
+            self.enter(1)?;
+            for cqe in self.reap() {
+                if cqe.user_data == CANCEL_TAG {
+                    continue;
+                }
+                if cqe.user_data & SEND_TAG != 0 {
+                    self.sends[(cqe.user_data & !SEND_TAG) as usize] = None;
+                    continue;
+                }
+                posted -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Reads datagrams until done, calling handle for each; what it returns
+    // is sent back to the sender.
+    pub fn serve(
+        &mut self,
+        socket: &UdpSocket,
+        mut handle: impl FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+        done: impl Fn() -> bool,
+    ) -> io::Result<()> {
+        for index in 0..self.recvs.len() {
+            self.post_recv(index)?;
+        }
+
+        loop {
+            self.enter(1)?;
+
+            for cqe in self.reap() {
+                if cqe.user_data & SEND_TAG != 0 {
+                    self.sends[(cqe.user_data & !SEND_TAG) as usize] = None;
+                    continue;
+                }
+
+                let index = cqe.user_data as usize;
+                let slot = &self.recvs[index];
+                let reply = match slot.addr.get() {
+                    Some(src) if cqe.res >= 0 && slot.msg.flags & sys::MSG_TRUNC == 0 => {
+                        handle(&slot.buf[..cqe.res as usize], src).map(|reply| (reply, src))
+                    }
+                    _ => None,
+                };
+                if let Some((reply, dest)) = reply {
+                    match self.post_send(reply, dest)? {
+                        // submitted now rather than with the next wait, so
+                        // it doesn't sit behind the rest of the completions
+                        None => self.enter(0)?,
+                        Some(reply) => {
+                            let _ = socket.send_to(&reply, dest);
+                        }
+                    }
+                }
+                self.post_recv(index)?;
+            }
+            if done() {
+                let drained = self.drain();
+                if drained.is_err() {
+                    // still in the kernel's hands, so they are never freed
+                    std::mem::forget(std::mem::take(&mut self.recvs));
+                    std::mem::forget(std::mem::take(&mut self.sends));
+                }
+                return drained;
+            }
+        }
+    }
+}
+
+impl Drop for UdpRing {
+    fn drop(&mut self) {
+        unsafe {
+            sys::close(self.fd);
+        }
+    }
+}
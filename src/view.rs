@@ -0,0 +1,247 @@
+use std::fmt;
+
+use crate::BufHandler;
+use crate::DnsHeader;
+use crate::DnsRecord;
+use crate::QueryClass;
+use crate::QueryType;
+use crate::name::MAX_NAME_LEN;
+use crate::name::Name;
+use crate::name::escape_label;
+
+// Read-only views of a message in its receive buffer. Nothing is copied:
+// names are walked label by label where they lie, following compression
+// pointers only when they are read, and rdata stays a slice. Meant for the
+// paths that only look at a message (or decide whether it is worth parsing
+// at all); DnsPacket is still the type to build and change messages with.
+
+fn u16_at(buf: &[u8], offset: usize) -> Result<u16, String> {
+    buf.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "End of buffer".to_string())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Result<u32, String> {
+    buf.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "End of buffer".to_string())
+}
+
+// A possibly compressed name at offset in buf.
+#[derive(Clone, Copy)]
+pub struct NameRef<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> NameRef<'a> {
+    // Checks the name at offset and returns it with the offset just past it
+    // (past the pointer, if it is compressed).
+    fn read(buf: &'a [u8], offset: usize) -> Result<(NameRef<'a>, usize), String> {
+        let name = NameRef { buf, offset };
+        let mut end = None;
+        let mut wire_len = 1;
+        let mut pos = offset;
+
+        loop {
+            let len = *buf.get(pos).ok_or("End of buffer")?;
+            if len == 0 {
+                return Ok((name, end.unwrap_or(pos + 1)));
+            }
+            if len & 0xC0 == 0xC0 {
+                let pointer = u16_at(buf, pos)? as usize & 0x3FFF;
+                // pointers may only go backwards, which also rules out loops
+                if pointer >= pos {
+                    return Err("Bad compression pointer".to_string());
+                }
+                end.get_or_insert(pos + 2);
+                pos = pointer;
+            } else if len & 0xC0 != 0 {
+                return Err("Unsupported label type".to_string());
+            } else {
+                wire_len += len as usize + 1;
+                if wire_len > MAX_NAME_LEN || pos + 1 + len as usize > buf.len() {
+                    return Err("Bad name".to_string());
+                }
+                pos += 1 + len as usize;
+            }
+        }
+    }
+
+    // The labels from the leftmost one, decompressing as it goes.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            buf: self.buf,
+            pos: self.offset,
+        }
+    }
+
+    // Compares with name, ignoring case.
+    pub fn eq_name(&self, name: &Name) -> bool {
+        let mut expected = name.labels();
+        for label in self.labels() {
+            match expected.next() {
+                Some(other) if label.eq_ignore_ascii_case(other) => {}
+                _ => return false,
+            }
+        }
+        expected.next().is_none()
+    }
+}
+
+impl fmt::Display for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            let mut text = String::new();
+            escape_label(label, &mut text);
+            f.write_str(&text)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Labels<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    // NameRef::read has checked the name already, so this can't run off the
+    // buffer or loop.
+    fn next(&mut self) -> Option<&'a [u8]> {
+        loop {
+            let len = self.buf[self.pos] as usize;
+            if len == 0 {
+                return None;
+            }
+            if len & 0xC0 == 0xC0 {
+                self.pos = (len & 0x3F) << 8 | self.buf[self.pos + 1] as usize;
+                continue;
+            }
+            let label = &self.buf[self.pos + 1..self.pos + 1 + len];
+            self.pos += 1 + len;
+            return Some(label);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct QuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: QueryType,
+    pub qclass: QueryClass,
+}
+
+#[derive(Clone, Copy)]
+pub struct RecordRef<'a> {
+    pub name: NameRef<'a>,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl RecordRef<'_> {
+    // Parses the record into its owned form, for when it has to be kept.
+    pub fn to_record(&self) -> Result<DnsRecord, String> {
+        let mut buf_handler = BufHandler::from_bytes(self.buf);
+        buf_handler.seek(self.offset);
+        DnsRecord::read(&mut buf_handler)
+    }
+}
+
+// A message whose sections have been checked to be well formed, so walking
+// them again can't fail.
+pub struct PacketView<'a> {
+    buf: &'a [u8],
+    pub header: DnsHeader,
+    // offsets of the question, answer, authority and additional sections
+    sections: [usize; 4],
+}
+
+impl<'a> PacketView<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<PacketView<'a>, String> {
+        let mut header = DnsHeader::new();
+        header.id = u16_at(buf, 0)?;
+        header.set_flags(u16_at(buf, 2)?);
+        header.questions = u16_at(buf, 4)?;
+        header.answers = u16_at(buf, 6)?;
+        header.nameservers = u16_at(buf, 8)?;
+        header.additionals = u16_at(buf, 10)?;
+
+        let mut sections = [12; 4];
+        let mut pos = 12;
+        for _ in 0..header.questions {
+            pos = NameRef::read(buf, pos)?.1 + 4;
+        }
+        let counts = [header.answers, header.nameservers, header.additionals];
+        for (section, count) in counts.into_iter().enumerate() {
+            sections[section + 1] = pos;
+            for _ in 0..count {
+                pos = NameRef::read(buf, pos)?.1;
+                let len = u16_at(buf, pos + 8)? as usize;
+                pos += 10 + len;
+            }
+        }
+        if pos > buf.len() {
+            return Err("End of buffer".to_string());
+        }
+
+        Ok(PacketView {
+            buf,
+            header,
+            sections,
+        })
+    }
+
+    pub fn questions(&self) -> impl Iterator<Item = QuestionRef<'a>> + '_ {
+        let mut pos = self.sections[0];
+        (0..self.header.questions).map(move |_| {
+            let (name, end) = NameRef::read(self.buf, pos).unwrap();
+            pos = end + 4;
+            QuestionRef {
+                name,
+                qtype: QueryType::from_num(u16_at(self.buf, end).unwrap()),
+                qclass: QueryClass::from_num(u16_at(self.buf, end + 2).unwrap()),
+            }
+        })
+    }
+
+    fn records(&self, section: usize, count: u16) -> impl Iterator<Item = RecordRef<'a>> + '_ {
+        let mut pos = self.sections[section];
+        (0..count).map(move |_| {
+            let offset = pos;
+            let (name, end) = NameRef::read(self.buf, pos).unwrap();
+            let len = u16_at(self.buf, end + 8).unwrap() as usize;
+            pos = end + 10 + len;
+            RecordRef {
+                name,
+                rtype: u16_at(self.buf, end).unwrap(),
+                class: u16_at(self.buf, end + 2).unwrap(),
+                ttl: u32_at(self.buf, end + 4).unwrap(),
+                rdata: &self.buf[end + 10..pos],
+                buf: self.buf,
+                offset,
+            }
+        })
+    }
+
+    pub fn answers(&self) -> impl Iterator<Item = RecordRef<'a>> + '_ {
+        self.records(1, self.header.answers)
+    }
+
+    pub fn nameservers(&self) -> impl Iterator<Item = RecordRef<'a>> + '_ {
+        self.records(2, self.header.nameservers)
+    }
+
+    pub fn additionals(&self) -> impl Iterator<Item = RecordRef<'a>> + '_ {
+        self.records(3, self.header.additionals)
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use crate::DnsPacket;
+use crate::DnsRecord;
+use crate::QueryClass;
+use crate::QueryType;
+use crate::ResponseCode;
+use crate::name::Name;
+use crate::pipeline::Handler;
+use crate::pipeline::Next;
+use crate::pipeline::Request;
+use crate::pipeline::minimal_any;
+use crate::pipeline::negative_soa;
+use crate::random::Rng;
+use crate::zone_store::MemoryStore;
+use crate::zone_store::ZoneChange;
+use crate::zone_store::ZoneStore;
+
+// CNAMEs followed through the local data for one answer, so a loop ends.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct ZoneEntry {
+    pub record: DnsRecord,
+    pub weight: Option<u32>,
+    // made up by refresh_reverse_records rather than configured
+    pub generated: bool,
+    // from a DHCP lease file, replaced by set_leases
+    pub leased: bool,
+}
+
+// Records served locally instead of being resolved. Owner names keep the
+// case they were configured with; lookups are case-insensitive.
+// Names under a declared zone apex are answered authoritatively even when
+// there is no data for them. The data can be changed while serving, and is
+// kept in a ZoneStore, in memory unless the config names another.
+pub struct LocalZones {
+    store: Box<dyn ZoneStore>,
+    auto_reverse: AtomicBool,
+    rng: Mutex<Rng>,
+}
+
+impl LocalZones {
+    pub fn new() -> LocalZones {
+        LocalZones {
+            store: Box::new(MemoryStore::new()),
+            auto_reverse: AtomicBool::new(false),
+            rng: Mutex::new(Rng::new()),
+        }
+    }
+
+    // Moves the zones and configured records over to store, which keeps
+    // what it holds already; the records from leases and the generated ones
+    // are made again.
+    pub fn set_store(&mut self, store: Box<dyn ZoneStore>) -> Result<(), String> {
+        let mut changes: Vec<ZoneChange> = self
+            .store
+            .zones()
+            .into_iter()
+            .map(ZoneChange::AddZone)
+            .collect();
+        changes.extend(
+            self.store
+                .entries()
+                .into_iter()
+                .filter(|entry| !entry.generated && !entry.leased)
+                .map(ZoneChange::Add),
+        );
+        changes.push(ZoneChange::SetLeases(Vec::new()));
+        store.apply(changes)?;
+        self.store = store;
+        self.refresh_reverse_records()
+    }
+
+    pub fn add_zone(&self, apex: Name) -> Result<(), String> {
+        self.store.apply(vec![ZoneChange::AddZone(apex)])?;
+        Ok(())
+    }
+
+    // Drops the zone together with every record at or below its apex.
+    pub fn remove_zone(&self, apex: &Name) -> Result<bool, String> {
+        if !self.store.zones().contains(apex) {
+            return Ok(false);
+        }
+        self.store
+            .apply(vec![ZoneChange::RemoveZone(apex.clone())])?;
+
+        self.refresh_reverse_records()?;
+        Ok(true)
+    }
+
+    pub fn zones(&self) -> Vec<Name> {
+        self.store.zones()
+    }
+
+    // The apex of the declared zone containing name, if any.
+    pub fn find_zone(&self, name: &Name) -> Option<Name> {
+        self.store
+            .zones()
+            .into_iter()
+            .filter(|apex| name.is_subdomain_of(apex))
+            .max_by_key(|apex| apex.label_count())
+    }
+
+    // Inside a declared zone every name is answered here; outside one only
+    // the owners of records are, and the names above them are left to the
+    // rest of the pipeline.
+    pub fn is_authoritative(&self, name: &Name) -> bool {
+        self.find_zone(name).is_some() || self.owns_records(name)
+    }
+
+    // Whether name exists: it is a zone apex, owns records or, inside a
+    // declared zone, has records below it (an empty non-terminal).
+    pub fn has_name(&self, name: &Name) -> bool {
+        if self.store.zones().contains(name) || self.owns_records(name) {
+            return true;
+        }
+        // names below name come right after it in canonical order
+        self.find_zone(name).is_some()
+            && self
+                .store
+                .owners_from(name, 1)
+                .first()
+                .is_some_and(|owner| owner.is_subdomain_of(name))
+    }
+
+    fn owns_records(&self, name: &Name) -> bool {
+        self.store
+            .owners_from(name, 1)
+            .first()
+            .is_some_and(|owner| owner == name)
+    }
+
+    // The serial of the zone at apex, None without a SOA record.
+    pub fn serial(&self, apex: &Name) -> Option<u32> {
+        self.store.serial(apex)
+    }
+
+    pub fn add(&self, record: DnsRecord, weight: Option<u32>) -> Result<(), String> {
+        let reverse = matches!(record.query_type(), QueryType::A | QueryType::AAAA);
+
+        self.store.apply(vec![ZoneChange::Add(ZoneEntry {
+            record,
+            weight,
+            generated: false,
+            leased: false,
+        })])?;
+
+        if reverse && self.auto_reverse.load(Ordering::Relaxed) {
+            self.refresh_reverse_records()?;
+        }
+        Ok(())
+    }
+
+    // Removes the configured records owned by name, only those of qtype if
+    // given. Returns how many went away.
+    pub fn remove(&self, name: &Name, qtype: Option<QueryType>) -> Result<usize, String> {
+        let removed = self
+            .store
+            .apply(vec![ZoneChange::Remove(name.clone(), qtype)])?;
+
+        self.refresh_reverse_records()?;
+        Ok(removed)
+    }
+
+    // Swaps the configured records old for new in a single change, so
+    // lookups see either all of old or all of new. Only the RRsets that
+    // differ are replaced; returns how many those are.
+    pub fn replace(&self, old: &[DnsRecord], new: &[DnsRecord]) -> Result<usize, String> {
+        let rrsets = |records: &[DnsRecord]| {
+            let mut rrsets: BTreeMap<(Name, u16), Vec<DnsRecord>> = BTreeMap::new();
+            for record in records {
+                let key = (record.domain().clone(), record.query_type().to_num());
+                rrsets.entry(key).or_default().push(record.clone());
+            }
+            rrsets
+        };
+        let (old, new) = (rrsets(old), rrsets(new));
+
+        let mut changes = Vec::new();
+        let mut changed = 0;
+        let keys: BTreeSet<&(Name, u16)> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            let before = old.get(key).cloned().unwrap_or_default();
+            let after = new.get(key).cloned().unwrap_or_default();
+            if before.len() == after.len() && before.iter().all(|record| after.contains(record)) {
+                continue;
+            }
+            changes.push(ZoneChange::Remove(
+                key.0.clone(),
+                Some(QueryType::from_num(key.1)),
+            ));
+            changes.extend(after.into_iter().map(|record| {
+                ZoneChange::Add(ZoneEntry {
+                    record,
+                    weight: None,
+                    generated: false,
+                    leased: false,
+                })
+            }));
+            changed += 1;
+        }
+        if changed > 0 {
+            self.store.apply(changes)?;
+            self.refresh_reverse_records()?;
+        }
+        Ok(changed)
+    }
+
+    // Replaces the records from DHCP leases with records.
+    pub fn set_leases(&self, records: Vec<DnsRecord>) -> Result<(), String> {
+        self.store.apply(vec![ZoneChange::SetLeases(records)])?;
+
+        self.refresh_reverse_records()
+    }
+
+    pub fn entries(&self) -> Vec<ZoneEntry> {
+        let mut entries = self.store.entries();
+        entries.sort_by_cached_key(|entry| entry.record.domain().to_ascii());
+        entries
+    }
+
+    pub fn set_auto_reverse(&self, enabled: bool) -> Result<(), String> {
+        self.auto_reverse.store(enabled, Ordering::Relaxed);
+        self.refresh_reverse_records()
+    }
+
+    // Rebuilds the generated PTR records: one for every local A/AAAA record,
+    // unless a PTR for that address was configured explicitly.
+    pub fn refresh_reverse_records(&self) -> Result<(), String> {
+        if !self.auto_reverse.load(Ordering::Relaxed) {
+            self.store
+                .apply(vec![ZoneChange::SetGenerated(Vec::new())])?;
+            return Ok(());
+        }
+
+        let entries: Vec<ZoneEntry> = self
+            .store
+            .entries()
+            .into_iter()
+            .filter(|entry| !entry.generated)
+            .collect();
+
+        let mut generated: Vec<DnsRecord> = Vec::new();
+        for entry in entries.iter() {
+            let (domain, addr, ttl) = match entry.record {
+                DnsRecord::A {
+                    ref domain,
+                    addr,
+                    ttl,
+                } => (domain, IpAddr::V4(addr), ttl),
+                DnsRecord::AAAA {
+                    ref domain,
+                    addr,
+                    ttl,
+                } => (domain, IpAddr::V6(addr), ttl),
+                _ => continue,
+            };
+
+            let record = DnsRecord::PTR {
+                domain: reverse_name(addr),
+                ttl,
+                host: domain.clone(),
+            };
+            let configured = entries.iter().any(|entry| {
+                entry.record.query_type() == QueryType::PTR
+                    && entry.record.domain() == record.domain()
+            });
+            if configured || generated.contains(&record) {
+                continue;
+            }
+            generated.push(record);
+        }
+
+        self.store
+            .apply(vec![ZoneChange::SetGenerated(generated)])?;
+        Ok(())
+    }
+
+    // Returns the records for name/qtype. Unweighted records are always
+    // returned; out of the weighted ones exactly one is picked per call, with
+    // probability proportional to its weight.
+    pub fn lookup(&self, name: &Name, qtype: QueryType) -> Vec<DnsRecord> {
+        let entries = self.store.lookup(name);
+
+        let mut records = Vec::new();
+        let mut weighted = Vec::new();
+        let mut total: u64 = 0;
+
+        for entry in entries.iter() {
+            if entry.record.query_type() != qtype {
+                continue;
+            }
+            match entry.weight {
+                Some(weight) => {
+                    total += weight as u64;
+                    weighted.push((&entry.record, weight as u64));
+                }
+                None => records.push(entry.record.clone()),
+            }
+        }
+
+        if total > 0 {
+            let mut pick = self.rng.lock().unwrap().below(total);
+            for (record, weight) in weighted {
+                if pick < weight {
+                    records.push(record.clone());
+                    break;
+                }
+                pick -= weight;
+            }
+        }
+
+        records
+    }
+
+    // Follows a CNAME that ends the answer to its target, for as long as the
+    // targets are answered here, adding what each of them has of qtype. The
+    // response code is that of the last target (RFC 6604).
+    fn chase(&self, qtype: QueryType, response: &mut DnsPacket) {
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(DnsRecord::CNAME { host, .. }) = response.answers.last() else {
+                return;
+            };
+            let target = host.clone();
+            if !self.is_authoritative(&target)
+                || response
+                    .answers
+                    .iter()
+                    .any(|answer| *answer.domain() == target)
+            {
+                return;
+            }
+            if !self.has_name(&target) {
+                response.header.response_code = ResponseCode::NAMERR;
+                return;
+            }
+            let mut records = self.lookup(&target, qtype);
+            if records.is_empty() {
+                records = self.lookup(&target, QueryType::CNAME);
+            }
+            if records.is_empty() {
+                return;
+            }
+            response.answers.extend(records);
+        }
+    }
+
+    // The zone's NS RRset for answers (unless it is the answer), its SOA for
+    // NXDOMAIN and NODATA.
+    fn add_authority(&self, apex: &Name, answered_ns: bool, response: &mut DnsPacket) {
+        if response.answers.is_empty() {
+            let soa = negative_soa(&self.lookup(apex, QueryType::SOA));
+            response.nameservers.extend(soa);
+        } else if !answered_ns {
+            response
+                .nameservers
+                .extend(self.lookup(apex, QueryType::NS));
+        }
+    }
+}
+
+impl Handler for LocalZones {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn handle(&self, request: &Request, response: &mut DnsPacket, next: Next<'_>) {
+        let question = request.question;
+
+        if !self.is_authoritative(&question.name) {
+            next.run(request, response);
+            return;
+        }
+
+        response.header.authoritative_answer = true;
+
+        if !self.has_name(&question.name) {
+            response.header.response_code = ResponseCode::NAMERR;
+        } else if question.qtype == QueryType::ANY {
+            response.answers.push(minimal_any(&question.name));
+        } else {
+            // answer with the owner name spelled exactly as the client asked
+            response.answers = self.lookup(&question.name, question.qtype);
+            if response.answers.is_empty() && question.qtype != QueryType::CNAME {
+                response.answers = self.lookup(&question.name, QueryType::CNAME);
+            }
+            for answer in response.answers.iter_mut() {
+                answer.set_domain(&question.name);
+            }
+            if question.qtype != QueryType::CNAME {
+                self.chase(question.qtype, response);
+            }
+        }
+
+        // records without a declared zone have no apex to take these from
+        if let Some(apex) = self.find_zone(&question.name) {
+            let answered_ns = question.qtype == QueryType::NS && question.name == apex;
+            self.add_authority(&apex, answered_ns, response);
+        }
+    }
+}
+
+// The in-addr.arpa / ip6.arpa name an address is looked up under.
+pub fn reverse_name(addr: IpAddr) -> Name {
+    let text = match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => {
+            let mut labels = Vec::with_capacity(34);
+            for octet in addr.octets().iter().rev() {
+                labels.push(format!("{:x}", octet & 0xF));
+                labels.push(format!("{:x}", octet >> 4));
+            }
+            labels.push("ip6.arpa".to_string());
+            labels.join(".")
+        }
+    };
+    // well within the limits
+    Name::from_ascii(&text).unwrap()
+}
+
+// A TTL in seconds, or in the units zone files allow: 1w2d, 1h30m, 90s.
+pub fn parse_ttl(text: &str) -> Result<u32, String> {
+    if let Ok(seconds) = text.parse::<u32>() {
+        return Ok(seconds);
+    }
+
+    let bad = || format!("bad TTL {:?}", text);
+    let mut total: u32 = 0;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(bad()),
+        };
+        let value: u32 = digits.parse().map_err(|_| bad())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(bad)?;
+        digits.clear();
+    }
+    if text.is_empty() || !digits.is_empty() {
+        return Err(bad());
+    }
+    Ok(total)
+}
+
+// Builds a record from its presentation form, e.g. ("10", "mail.example.com")
+// for an MX.
+pub fn parse_record(
+    domain: &str,
+    qtype: QueryType,
+    ttl: u32,
+    rdata: &[&str],
+) -> Result<DnsRecord, String> {
+    let domain = Name::from_unicode(domain)?;
+    let field = |i: usize| -> Result<&str, String> {
+        rdata
+            .get(i)
+            .copied()
+            .ok_or_else(|| format!("missing rdata for {:?} record", qtype))
+    };
+
+    match qtype {
+        QueryType::A => Ok(DnsRecord::A {
+            domain,
+            addr: field(0)?
+                .parse::<Ipv4Addr>()
+                .map_err(|e| format!("bad A address: {}", e))?,
+            ttl,
+        }),
+        QueryType::AAAA => Ok(DnsRecord::AAAA {
+            domain,
+            addr: field(0)?
+                .parse::<Ipv6Addr>()
+                .map_err(|e| format!("bad AAAA address: {}", e))?,
+            ttl,
+        }),
+        QueryType::NS => Ok(DnsRecord::NS {
+            domain,
+            ttl,
+            host: Name::from_unicode(field(0)?)?,
+        }),
+        QueryType::CNAME => Ok(DnsRecord::CNAME {
+            domain,
+            ttl,
+            host: Name::from_unicode(field(0)?)?,
+        }),
+        QueryType::SOA => {
+            let timer = |i: usize, what: &str| -> Result<u32, String> {
+                parse_ttl(field(i)?).map_err(|e| format!("bad SOA {}: {}", what, e))
+            };
+            Ok(DnsRecord::SOA {
+                domain,
+                ttl,
+                mname: Name::from_unicode(field(0)?)?,
+                rname: Name::from_unicode(field(1)?)?,
+                serial: field(2)?
+                    .parse::<u32>()
+                    .map_err(|e| format!("bad SOA serial: {}", e))?,
+                refresh: timer(3, "refresh")?,
+                retry: timer(4, "retry")?,
+                expire: timer(5, "expire")?,
+                minimum: timer(6, "minimum")?,
+            })
+        }
+        QueryType::PTR => Ok(DnsRecord::PTR {
+            domain,
+            ttl,
+            host: Name::from_unicode(field(0)?)?,
+        }),
+        QueryType::HINFO => Ok(DnsRecord::HINFO {
+            domain,
+            ttl,
+            cpu: parse_character_string(field(0)?)?,
+            os: parse_character_string(field(1)?)?,
+        }),
+        QueryType::MX => Ok(DnsRecord::MX {
+            domain,
+            ttl,
+            priority: field(0)?
+                .parse::<u16>()
+                .map_err(|e| format!("bad MX priority: {}", e))?,
+            host: Name::from_unicode(field(1)?)?,
+        }),
+        QueryType::TXT => {
+            field(0)?;
+            let data = rdata
+                .iter()
+                .map(|text| parse_character_string(text))
+                .collect::<Result<Vec<Vec<u8>>, String>>()?;
+            Ok(DnsRecord::TXT {
+                domain,
+                ttl,
+                class: QueryClass::IN,
+                data,
+            })
+        }
+        QueryType::NULL
+        | QueryType::OPT
+        | QueryType::SVCB
+        | QueryType::ANY
+        | QueryType::UNKNOWN(_) => Err("unsupported record type".to_string()),
+    }
+}
+
+// A character-string in presentation form, quoted or not, into its bytes:
+// \DDD is the byte with that decimal value, a backslash before any other
+// character stands for that character.
+fn parse_character_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = match text.strip_prefix('"') {
+        Some(rest) => rest
+            .strip_suffix('"')
+            .ok_or_else(|| format!("unterminated string {}", text))?,
+        None => text,
+    };
+
+    let mut data = Vec::new();
+    let mut bytes = inner.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            data.push(byte);
+            continue;
+        }
+        let escaped = bytes
+            .next()
+            .ok_or_else(|| format!("{} ends in a backslash", text))?;
+        if !escaped.is_ascii_digit() {
+            data.push(escaped);
+            continue;
+        }
+        let digits = [Some(escaped), bytes.next(), bytes.next()];
+        let value = digits
+            .iter()
+            .try_fold(0u32, |value, digit| match digit {
+                Some(digit) if digit.is_ascii_digit() => Some(value * 10 + (digit - b'0') as u32),
+                _ => None,
+            })
+            .filter(|&value| value <= 255)
+            .ok_or_else(|| format!("bad \\DDD escape in {}", text))?;
+        data.push(value as u8);
+    }
+    if data.len() > 255 {
+        return Err("character strings are limited to 255 octets".to_string());
+    }
+    Ok(data)
+}
@@ -0,0 +1,947 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::name::Name;
+use crate::zone::ZoneEntry;
+
+// A change to the data behind LocalZones.
+#[derive(Debug, Clone)]
+pub enum ZoneChange {
+    AddZone(Name),
+    // the zone together with every record at or below its apex
+    RemoveZone(Name),
+    // nothing happens if the same entry is there already
+    Add(ZoneEntry),
+    // the configured records owned by the name, only those of the type if
+    // given
+    Remove(Name, Option<QueryType>),
+    // the records from DHCP leases
+    SetLeases(Vec<DnsRecord>),
+    // the PTR records refresh_reverse_records makes up
+    SetGenerated(Vec<DnsRecord>),
+}
+
+// Where LocalZones keeps its zones and records. Reads can't fail; a store
+// that runs into an error reading logs it and returns what it has.
+pub trait ZoneStore: Send + Sync {
+    // The zone apexes in the order they were added.
+    fn zones(&self) -> Vec<Name>;
+
+    // The entries owned by name, of every type.
+    fn lookup(&self, name: &Name) -> Vec<ZoneEntry>;
+
+    // Up to limit owner names in canonical order, from start (included) on,
+    // for walking the names of a zone the way an NSEC chain does.
+    fn owners_from(&self, start: &Name, limit: usize) -> Vec<Name>;
+
+    // Every entry, in no particular order.
+    fn entries(&self) -> Vec<ZoneEntry>;
+
+    // Applies changes all at once, or none of them on error. Returns how
+    // many records were added or removed.
+    fn apply(&self, changes: Vec<ZoneChange>) -> Result<usize, String>;
+
+    // The serial of the zone at apex, from its SOA record.
+    fn serial(&self, apex: &Name) -> Option<u32> {
+        self.lookup(apex)
+            .iter()
+            .find_map(|entry| match entry.record {
+                DnsRecord::SOA { serial, .. } => Some(serial),
+                _ => None,
+            })
+    }
+}
+
+// Where the config has the zones kept.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreLocation {
+    Memory,
+    // a SQLite database file
+    Sqlite(PathBuf),
+    // a libpq connection string
+    Postgres(String),
+}
+
+// Opens the store at location, None for memory, which LocalZones starts
+// out with.
+pub fn open(location: &StoreLocation) -> Result<Option<Box<dyn ZoneStore>>, String> {
+    match location {
+        StoreLocation::Memory => Ok(None),
+        #[cfg(all(unix, feature = "sqlite"))]
+        StoreLocation::Sqlite(path) => Ok(Some(Box::new(SqliteStore::open(path)?))),
+        #[cfg(all(unix, feature = "postgres"))]
+        StoreLocation::Postgres(conninfo) => Ok(Some(Box::new(PostgresStore::open(conninfo)?))),
+        #[allow(unreachable_patterns)]
+        _ => Err("zone-store isn't supported by this build".to_string()),
+    }
+}
+
+fn entry(record: DnsRecord, generated: bool, leased: bool) -> ZoneEntry {
+    ZoneEntry {
+        record,
+        weight: None,
+        generated,
+        leased,
+    }
+}
+
+fn is_configured(entry: &ZoneEntry) -> bool {
+    !entry.generated && !entry.leased
+}
+
+struct MemoryData {
+    apexes: Vec<Name>,
+    // in canonical order, which Name's Ord is
+    entries: BTreeMap<Name, Vec<ZoneEntry>>,
+}
+
+impl MemoryData {
+    fn add(&mut self, new: ZoneEntry) -> usize {
+        let entries = self.entries.entry(new.record.domain().clone()).or_default();
+        if entries.iter().any(|entry| {
+            entry.record == new.record
+                && entry.weight == new.weight
+                && entry.generated == new.generated
+                && entry.leased == new.leased
+        }) {
+            return 0;
+        }
+        entries.push(new);
+        1
+    }
+
+    fn remove_where(&mut self, mut remove: impl FnMut(&ZoneEntry) -> bool) -> usize {
+        let mut removed = 0;
+        self.entries.retain(|_, entries| {
+            let before = entries.len();
+            entries.retain(|entry| !remove(entry));
+            removed += before - entries.len();
+            !entries.is_empty()
+        });
+        removed
+    }
+
+    fn apply(&mut self, change: ZoneChange) -> usize {
+        match change {
+            ZoneChange::AddZone(apex) => {
+                if !self.apexes.contains(&apex) {
+                    self.apexes.push(apex);
+                }
+                0
+            }
+            ZoneChange::RemoveZone(apex) => {
+                self.apexes.retain(|known| *known != apex);
+                self.remove_where(|entry| entry.record.domain().is_subdomain_of(&apex))
+            }
+            ZoneChange::Add(entry) => self.add(entry),
+            ZoneChange::Remove(name, qtype) => {
+                let Some(entries) = self.entries.get_mut(&name) else {
+                    return 0;
+                };
+                let before = entries.len();
+                entries.retain(|entry| {
+                    !is_configured(entry)
+                        || qtype.is_some_and(|qtype| entry.record.query_type() != qtype)
+                });
+                let removed = before - entries.len();
+                if entries.is_empty() {
+                    self.entries.remove(&name);
+                }
+                removed
+            }
+            ZoneChange::SetLeases(records) => {
+                let removed = self.remove_where(|entry| entry.leased);
+                let added: usize = records
+                    .into_iter()
+                    .map(|record| self.add(entry(record, false, true)))
+                    .sum();
+                removed + added
+            }
+            ZoneChange::SetGenerated(records) => {
+                let removed = self.remove_where(|entry| entry.generated);
+                let added: usize = records
+                    .into_iter()
+                    .map(|record| self.add(entry(record, true, false)))
+                    .sum();
+                removed + added
+            }
+        }
+    }
+}
+
+// Everything in memory, as it is unless the config says otherwise.
+pub struct MemoryStore {
+    data: RwLock<MemoryData>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            data: RwLock::new(MemoryData {
+                apexes: Vec::new(),
+                entries: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl ZoneStore for MemoryStore {
+    fn zones(&self) -> Vec<Name> {
+        self.data.read().unwrap().apexes.clone()
+    }
+
+    fn lookup(&self, name: &Name) -> Vec<ZoneEntry> {
+        let data = self.data.read().unwrap();
+        data.entries.get(name).cloned().unwrap_or_default()
+    }
+
+    fn owners_from(&self, start: &Name, limit: usize) -> Vec<Name> {
+        let data = self.data.read().unwrap();
+        data.entries
+            .range::<Name, _>((Bound::Included(start), Bound::Unbounded))
+            .take(limit)
+            .map(|(owner, _)| owner.clone())
+            .collect()
+    }
+
+    fn entries(&self) -> Vec<ZoneEntry> {
+        let data = self.data.read().unwrap();
+        data.entries.values().flatten().cloned().collect()
+    }
+
+    fn apply(&self, changes: Vec<ZoneChange>) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        Ok(changes.into_iter().map(|change| data.apply(change)).sum())
+    }
+}
+
+#[cfg(all(unix, feature = "sqlite"))]
+pub use sqlite_store::SqliteStore;
+
+#[cfg(all(unix, feature = "sqlite"))]
+mod sqlite_store {
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::RwLock;
+
+    use super::ZoneChange;
+    use super::ZoneStore;
+    use crate::BufHandler;
+    use crate::DnsRecord;
+    use crate::name::Name;
+    use crate::sqlite::Connection;
+    use crate::sqlite::Value;
+    use crate::zone::ZoneEntry;
+
+    const SCHEMA: &str = "
+        PRAGMA journal_mode = WAL;
+        CREATE TABLE IF NOT EXISTS zones (
+            apex BLOB NOT NULL PRIMARY KEY,
+            name BLOB NOT NULL,
+            position INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS records (
+            owner BLOB NOT NULL,
+            type INTEGER NOT NULL,
+            record BLOB NOT NULL,
+            weight INTEGER,
+            origin INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS records_by_owner ON records (owner, type);
+    ";
+
+    // what put a record there, the origin column
+    const CONFIGURED: i64 = 0;
+    const LEASED: i64 = 1;
+    const GENERATED: i64 = 2;
+
+    // A name as a key that sorts as the name does canonically (RFC 4034
+    // section 6.1) when compared byte by byte, the way SQLite compares
+    // blobs: the labels from the rightmost one in lowercase, each ended by a
+    // 0 byte, with the label bytes 0 and 1 written as 1 1 and 1 2. The keys
+    // of the names at or below a name start with its key.
+    fn key(name: &Name) -> Vec<u8> {
+        let mut key = Vec::with_capacity(name.wire_len());
+        for label in name.labels().rev() {
+            for &byte in label {
+                match byte.to_ascii_lowercase() {
+                    byte @ (0 | 1) => key.extend_from_slice(&[1, byte + 1]),
+                    byte => key.push(byte),
+                }
+            }
+            key.push(0);
+        }
+        key
+    }
+
+    // The first key after those at or below the name of key, None for the
+    // root, which everything is below.
+    fn key_end(key: &[u8]) -> Option<Vec<u8>> {
+        let (_, labels) = key.split_last()?;
+        let mut end = labels.to_vec();
+        end.push(1);
+        Some(end)
+    }
+
+    fn wire(record: &DnsRecord) -> Result<Vec<u8>, String> {
+        let mut out = BufHandler::new();
+        record.write(&mut out)?;
+        Ok(out.written().to_vec())
+    }
+
+    fn name_wire(name: &Name) -> Vec<u8> {
+        let mut wire = name.wire().to_vec();
+        wire.push(0);
+        wire
+    }
+
+    fn origin(entry: &ZoneEntry) -> i64 {
+        if entry.generated {
+            GENERATED
+        } else if entry.leased {
+            LEASED
+        } else {
+            CONFIGURED
+        }
+    }
+
+    // Zones and records in a SQLite database, so they outlast restarts and
+    // large zones aren't held in memory. Records are kept in wire form, one
+    // row each, looked up by their owner's canonical key.
+    pub struct SqliteStore {
+        db: Mutex<Connection>,
+        // the zones, few enough to keep at hand for every query
+        apexes: RwLock<Vec<Name>>,
+    }
+
+    impl SqliteStore {
+        pub fn open(path: &Path) -> Result<SqliteStore, String> {
+            let db = Connection::open(path)?;
+            db.execute(SCHEMA)
+                .map_err(|e| format!("zone-store {}: {}", path.display(), e))?;
+
+            let mut apexes = Vec::new();
+            let mut rows = db.prepare("SELECT name FROM zones ORDER BY position", &[])?;
+            while rows.step()? {
+                apexes.push(BufHandler::from_bytes(&rows.blob(0)).read_name()?);
+            }
+            drop(rows);
+
+            Ok(SqliteStore {
+                db: Mutex::new(db),
+                apexes: RwLock::new(apexes),
+            })
+        }
+
+        fn entries_where(
+            &self,
+            condition: &str,
+            params: &[Value],
+        ) -> Result<Vec<ZoneEntry>, String> {
+            let db = self.db.lock().unwrap();
+            let mut rows = db.prepare(
+                &format!("SELECT record, weight, origin FROM records {}", condition),
+                params,
+            )?;
+            let mut entries = Vec::new();
+            while rows.step()? {
+                let record = DnsRecord::read(&mut BufHandler::from_bytes(&rows.blob(0)))?;
+                let origin = rows.integer(2);
+                entries.push(ZoneEntry {
+                    record,
+                    weight: rows.integer(1).map(|weight| weight as u32),
+                    generated: origin == Some(GENERATED),
+                    leased: origin == Some(LEASED),
+                });
+            }
+            Ok(entries)
+        }
+
+        fn add(db: &Connection, entry: &ZoneEntry) -> Result<usize, String> {
+            let owner = key(entry.record.domain());
+            let record = wire(&entry.record)?;
+            let weight = match entry.weight {
+                Some(weight) => Value::Integer(weight as i64),
+                None => Value::Null,
+            };
+            let origin = Value::Integer(origin(entry));
+            let mut existing = db.prepare(
+                "SELECT 1 FROM records
+                 WHERE owner = ?1 AND record = ?2 AND weight IS ?3 AND origin = ?4",
+                &[Value::Blob(&owner), Value::Blob(&record), weight, origin],
+            )?;
+            if existing.step()? {
+                return Ok(0);
+            }
+            drop(existing);
+
+            db.run(
+                "INSERT INTO records (owner, type, record, weight, origin)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                &[
+                    Value::Blob(&owner),
+                    Value::Integer(entry.record.query_type().to_num() as i64),
+                    Value::Blob(&record),
+                    weight,
+                    origin,
+                ],
+            )
+        }
+
+        fn replace(db: &Connection, origin: i64, records: Vec<DnsRecord>) -> Result<usize, String> {
+            let mut changed = db.run(
+                "DELETE FROM records WHERE origin = ?1",
+                &[Value::Integer(origin)],
+            )?;
+            for record in records {
+                let entry = ZoneEntry {
+                    record,
+                    weight: None,
+                    generated: origin == GENERATED,
+                    leased: origin == LEASED,
+                };
+                changed += SqliteStore::add(db, &entry)?;
+            }
+            Ok(changed)
+        }
+
+        fn apply_one(
+            db: &Connection,
+            apexes: &mut Vec<Name>,
+            change: ZoneChange,
+        ) -> Result<usize, String> {
+            match change {
+                ZoneChange::AddZone(apex) => {
+                    if !apexes.contains(&apex) {
+                        db.run(
+                            "INSERT INTO zones (apex, name, position)
+                             VALUES (?1, ?2, (SELECT coalesce(max(position), 0) + 1 FROM zones))",
+                            &[Value::Blob(&key(&apex)), Value::Blob(&name_wire(&apex))],
+                        )?;
+                        apexes.push(apex);
+                    }
+                    Ok(0)
+                }
+                ZoneChange::RemoveZone(apex) => {
+                    let start = key(&apex);
+                    db.run("DELETE FROM zones WHERE apex = ?1", &[Value::Blob(&start)])?;
+                    apexes.retain(|known| *known != apex);
+                    match key_end(&start) {
+                        Some(end) => db.run(
+                            "DELETE FROM records WHERE owner >= ?1 AND owner < ?2",
+                            &[Value::Blob(&start), Value::Blob(&end)],
+                        ),
+                        None => db.run("DELETE FROM records", &[]),
+                    }
+                }
+                ZoneChange::Add(entry) => SqliteStore::add(db, &entry),
+                ZoneChange::Remove(name, qtype) => match qtype {
+                    Some(qtype) => db.run(
+                        "DELETE FROM records WHERE owner = ?1 AND type = ?2 AND origin = ?3",
+                        &[
+                            Value::Blob(&key(&name)),
+                            Value::Integer(qtype.to_num() as i64),
+                            Value::Integer(CONFIGURED),
+                        ],
+                    ),
+                    None => db.run(
+                        "DELETE FROM records WHERE owner = ?1 AND origin = ?2",
+                        &[Value::Blob(&key(&name)), Value::Integer(CONFIGURED)],
+                    ),
+                },
+                ZoneChange::SetLeases(records) => SqliteStore::replace(db, LEASED, records),
+                ZoneChange::SetGenerated(records) => SqliteStore::replace(db, GENERATED, records),
+            }
+        }
+    }
+
+    // errors reading are logged, and the query answered from what there is
+    fn logged<T: Default>(result: Result<T, String>) -> T {
+        result.unwrap_or_else(|e| {
+            eprintln!("zone-store: {}", e);
+            T::default()
+        })
+    }
+
+    impl ZoneStore for SqliteStore {
+        fn zones(&self) -> Vec<Name> {
+            self.apexes.read().unwrap().clone()
+        }
+
+        fn lookup(&self, name: &Name) -> Vec<ZoneEntry> {
+            logged(self.entries_where("WHERE owner = ?1", &[Value::Blob(&key(name))]))
+        }
+
+        fn owners_from(&self, start: &Name, limit: usize) -> Vec<Name> {
+            let db = self.db.lock().unwrap();
+            logged((|| {
+                let mut rows = db.prepare(
+                    "SELECT min(record) FROM records WHERE owner >= ?1
+                     GROUP BY owner ORDER BY owner LIMIT ?2",
+                    &[Value::Blob(&key(start)), Value::Integer(limit as i64)],
+                )?;
+                let mut owners = Vec::new();
+                while rows.step()? {
+                    owners.push(BufHandler::from_bytes(&rows.blob(0)).read_name()?);
+                }
+                Ok(owners)
+            })())
+        }
+
+        fn entries(&self) -> Vec<ZoneEntry> {
+            logged(self.entries_where("", &[]))
+        }
+
+        fn apply(&self, changes: Vec<ZoneChange>) -> Result<usize, String> {
+            let db = self.db.lock().unwrap();
+            let mut apexes = self.apexes.write().unwrap();
+            let mut updated = apexes.clone();
+
+            db.execute("BEGIN IMMEDIATE")?;
+            let mut changed = 0;
+            for change in changes {
+                match SqliteStore::apply_one(&db, &mut updated, change) {
+                    Ok(count) => changed += count,
+                    Err(e) => {
+                        let _ = db.execute("ROLLBACK");
+                        return Err(format!("zone-store: {}", e));
+                    }
+                }
+            }
+            if let Err(e) = db.execute("COMMIT") {
+                let _ = db.execute("ROLLBACK");
+                return Err(format!("zone-store: {}", e));
+            }
+            *apexes = updated;
+            Ok(changed)
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "postgres"))]
+pub use postgres_store::PostgresStore;
+
+#[cfg(all(unix, feature = "postgres"))]
+mod postgres_store {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::MemoryStore;
+    use super::ZoneChange;
+    use super::ZoneStore;
+    use super::is_configured;
+    use crate::DnsRecord;
+    use crate::log;
+    use crate::log::LogLevel;
+    use crate::name::Name;
+    use crate::postgres::Connection;
+    use crate::postgres::Notification;
+    use crate::postgres::Rows;
+    use crate::zone::ZoneEntry;
+    use crate::zonefile;
+
+    // The tables are written by provisioning systems as well as the server,
+    // so a record is kept as people write one: the owner, type, TTL and
+    // rdata of a zone file line, with absolute names. Triggers NOTIFY every
+    // change, of a record with its owner. The advisory lock keeps servers
+    // starting together from creating them at the same time.
+    const SCHEMA: &str = "
+        SET client_min_messages = warning;
+        BEGIN;
+        SELECT pg_advisory_xact_lock(hashtext('dns-server schema'));
+        CREATE TABLE IF NOT EXISTS dns_zones (
+            name text NOT NULL PRIMARY KEY,
+            position bigserial
+        );
+        CREATE TABLE IF NOT EXISTS dns_records (
+            id bigserial PRIMARY KEY,
+            name text NOT NULL,
+            type text NOT NULL,
+            ttl integer NOT NULL CHECK (ttl >= 0),
+            data text NOT NULL,
+            weight integer CHECK (weight > 0)
+        );
+        CREATE INDEX IF NOT EXISTS dns_records_by_owner
+            ON dns_records (rtrim(lower(name), '.'));
+        CREATE OR REPLACE FUNCTION dns_server_notify() RETURNS trigger LANGUAGE plpgsql AS $$
+        BEGIN
+            IF TG_TABLE_NAME = 'dns_zones' THEN
+                PERFORM pg_notify('dns_server', 'zones');
+            ELSIF TG_OP = 'TRUNCATE' THEN
+                PERFORM pg_notify('dns_server', 'records');
+            ELSE
+                IF TG_OP <> 'INSERT' THEN
+                    PERFORM pg_notify('dns_server', 'owner ' || rtrim(lower(OLD.name), '.'));
+                END IF;
+                IF TG_OP <> 'DELETE' THEN
+                    PERFORM pg_notify('dns_server', 'owner ' || rtrim(lower(NEW.name), '.'));
+                END IF;
+            END IF;
+            RETURN NULL;
+        END $$;
+        DROP TRIGGER IF EXISTS dns_server_notify ON dns_zones;
+        CREATE TRIGGER dns_server_notify
+            AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON dns_zones
+            FOR EACH STATEMENT EXECUTE FUNCTION dns_server_notify();
+        DROP TRIGGER IF EXISTS dns_server_notify ON dns_records;
+        CREATE TRIGGER dns_server_notify
+            AFTER INSERT OR UPDATE OR DELETE ON dns_records
+            FOR EACH ROW EXECUTE FUNCTION dns_server_notify();
+        DROP TRIGGER IF EXISTS dns_server_truncate ON dns_records;
+        CREATE TRIGGER dns_server_truncate
+            AFTER TRUNCATE ON dns_records
+            FOR EACH STATEMENT EXECUTE FUNCTION dns_server_notify();
+        COMMIT;
+    ";
+
+    const SELECT_RECORDS: &str = "SELECT name, type, ttl, data, weight FROM dns_records";
+
+    // A quiet listening connection is checked this often, so one that died
+    // without a word is noticed.
+    const KEEPALIVE: Duration = Duration::from_secs(30);
+
+    // How long to wait before connecting again once the connection is lost.
+    const RECONNECT: Duration = Duration::from_secs(5);
+
+    // More owners changed at once than this, e.g. by a bulk load, are read
+    // with everything else rather than one by one.
+    const MAX_OWNER_RELOADS: usize = 1000;
+
+    // How the rows name an owner: in lowercase, without the trailing dot.
+    fn key(name: &Name) -> String {
+        name.to_ascii().to_ascii_lowercase()
+    }
+
+    fn parse_row(rows: &Rows, row: usize) -> Result<ZoneEntry, String> {
+        let column = |i| rows.get(row, i).unwrap_or_default();
+        let (name, qtype) = (column(0), column(1));
+        let record = zonefile::parse_one(&name, &qtype, &column(2), &column(3))
+            .map_err(|e| format!("record {} {}: {}", name, qtype, e))?;
+        let weight = rows
+            .get(row, 4)
+            .map(|weight| weight.parse::<u32>())
+            .transpose()
+            .map_err(|_| format!("record {} {}: bad weight", name, qtype))?;
+        Ok(ZoneEntry {
+            record,
+            weight,
+            generated: false,
+            leased: false,
+        })
+    }
+
+    // rows that can't be read are logged and left out
+    fn parse_rows(rows: &Rows) -> Vec<ZoneEntry> {
+        (0..rows.count())
+            .filter_map(|row| {
+                parse_row(rows, row)
+                    .map_err(|e| eprintln!("zone-store: {}", e))
+                    .ok()
+            })
+            .collect()
+    }
+
+    // A connection that gets the notifications of changes.
+    fn listen(conninfo: &str) -> Result<Connection, String> {
+        let db = Connection::open(conninfo)?;
+        db.execute("LISTEN dns_server")?;
+        Ok(db)
+    }
+
+    // Writes change to the tables; the records from leases and the generated
+    // ones stay with the server.
+    fn write(db: &Connection, change: &ZoneChange) -> Result<(), String> {
+        match change {
+            ZoneChange::AddZone(apex) => db.run(
+                "INSERT INTO dns_zones (name) SELECT $1
+                 WHERE NOT EXISTS (SELECT 1 FROM dns_zones WHERE rtrim(lower(name), '.') = $2)",
+                &[Some(&apex.to_ascii()), Some(&key(apex))],
+            ),
+            ZoneChange::RemoveZone(apex) => {
+                let owner = key(apex);
+                db.run(
+                    "DELETE FROM dns_zones WHERE rtrim(lower(name), '.') = $1",
+                    &[Some(&owner)],
+                )?;
+                if apex.is_root() {
+                    db.run("DELETE FROM dns_records", &[])
+                } else {
+                    db.run(
+                        "DELETE FROM dns_records WHERE rtrim(lower(name), '.') = $1
+                         OR right(rtrim(lower(name), '.'), $2::integer) = $3",
+                        &[
+                            Some(&owner),
+                            Some(&(owner.len() + 1).to_string()),
+                            Some(&format!(".{}", owner)),
+                        ],
+                    )
+                }
+            }
+            ZoneChange::Add(entry) => {
+                let record = &entry.record;
+                if matches!(record, DnsRecord::UNKNOWN { .. } | DnsRecord::OPT { .. }) {
+                    return Err(format!(
+                        "{} records can't be written as text",
+                        record.query_type().name()
+                    ));
+                }
+                let weight = entry.weight.map(|weight| weight.to_string());
+                db.run(
+                    "INSERT INTO dns_records (name, type, ttl, data, weight)
+                     SELECT $1, $2, $3::integer, $4, $5::integer
+                     WHERE NOT EXISTS (
+                         SELECT 1 FROM dns_records
+                         WHERE rtrim(lower(name), '.') = $6 AND upper(type) = $2
+                             AND ttl = $3 AND data = $4 AND weight IS NOT DISTINCT FROM $5
+                     )",
+                    &[
+                        Some(&record.domain().to_ascii()),
+                        Some(record.query_type().name()),
+                        Some(&record.ttl().to_string()),
+                        Some(&record.rdata_string()),
+                        weight.as_deref(),
+                        Some(&key(record.domain())),
+                    ],
+                )
+            }
+            ZoneChange::Remove(name, qtype) => match qtype {
+                Some(qtype) => db.run(
+                    "DELETE FROM dns_records WHERE rtrim(lower(name), '.') = $1 AND upper(type) = $2",
+                    &[Some(&key(name)), Some(qtype.name())],
+                ),
+                None => db.run(
+                    "DELETE FROM dns_records WHERE rtrim(lower(name), '.') = $1",
+                    &[Some(&key(name))],
+                ),
+            },
+            ZoneChange::SetLeases(_) | ZoneChange::SetGenerated(_) => Ok(0),
+        }
+        .map(drop)
+    }
+
+    struct Shared {
+        conninfo: String,
+        // for writes, opened again when it stopped working
+        db: Mutex<Option<Connection>>,
+        // what the tables hold, with the records from leases and the
+        // generated ones
+        memory: MemoryStore,
+    }
+
+    impl Shared {
+        fn load_zones(&self, db: &Connection) -> Result<(), String> {
+            let rows = db.query("SELECT name FROM dns_zones ORDER BY position", &[])?;
+            let mut apexes = Vec::new();
+            for row in 0..rows.count() {
+                let name = rows.get(row, 0).unwrap_or_default();
+                match Name::from_unicode(&name) {
+                    Ok(apex) if !apexes.contains(&apex) => apexes.push(apex),
+                    Ok(_) => {}
+                    Err(e) => eprintln!("zone-store: zone {:?}: {}", name, e),
+                }
+            }
+            self.memory.data.write().unwrap().apexes = apexes;
+            Ok(())
+        }
+
+        fn load_all(&self, db: &Connection) -> Result<(), String> {
+            self.load_zones(db)?;
+            let entries = parse_rows(&db.query(SELECT_RECORDS, &[])?);
+            let mut data = self.memory.data.write().unwrap();
+            data.remove_where(is_configured);
+            for entry in entries {
+                data.add(entry);
+            }
+            Ok(())
+        }
+
+        // Reads the records of the owner with key again.
+        fn load_owner(&self, db: &Connection, key: &str) -> Result<(), String> {
+            let owner = match key {
+                "" => Name::root(),
+                key => Name::from_unicode(key)?,
+            };
+            let entries = parse_rows(&db.query(
+                &format!("{} WHERE rtrim(lower(name), '.') = $1", SELECT_RECORDS),
+                &[Some(key)],
+            )?);
+            let mut data = self.memory.data.write().unwrap();
+            data.apply(ZoneChange::Remove(owner, None));
+            for entry in entries {
+                data.add(entry);
+            }
+            Ok(())
+        }
+
+        fn reload(&self, db: &Connection, notifications: Vec<Notification>) -> Result<(), String> {
+            let mut zones = false;
+            let mut everything = false;
+            let mut owners = HashSet::new();
+            for notification in notifications {
+                match notification.payload.as_str() {
+                    "zones" => zones = true,
+                    payload => match payload.strip_prefix("owner ") {
+                        Some(key) => {
+                            owners.insert(key.to_string());
+                        }
+                        None => everything = true,
+                    },
+                }
+            }
+
+            if everything || owners.len() > MAX_OWNER_RELOADS {
+                return self.load_all(db);
+            }
+            if zones {
+                self.load_zones(db)?;
+            }
+            for key in owners {
+                // a name that can't be one has no records to serve
+                if let Err(e) = self.load_owner(db, &key) {
+                    eprintln!("zone-store: {:?}: {}", key, e);
+                }
+            }
+            Ok(())
+        }
+
+        // Follows the changes to the tables until the server stops,
+        // connecting again, and reading everything, when the connection is
+        // lost.
+        fn follow(&self, mut listener: Connection) {
+            loop {
+                let result = listener.wait(KEEPALIVE).and_then(|notifications| {
+                    if notifications.is_empty() {
+                        listener.execute("SELECT 1")
+                    } else {
+                        self.reload(&listener, notifications)
+                    }
+                });
+                if let Err(e) = result {
+                    eprintln!("zone-store postgres: {}", e);
+                    listener = self.reconnect();
+                }
+            }
+        }
+
+        fn reconnect(&self) -> Connection {
+            loop {
+                thread::sleep(RECONNECT);
+                let result = listen(&self.conninfo).and_then(|db| {
+                    self.load_all(&db)?;
+                    Ok(db)
+                });
+                match result {
+                    Ok(db) => {
+                        if log::enabled(LogLevel::Info) {
+                            eprintln!("zone-store postgres: connected again");
+                        }
+                        return db;
+                    }
+                    Err(e) => {
+                        if log::enabled(LogLevel::Info) {
+                            eprintln!("zone-store postgres: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Zones and records in PostgreSQL tables that provisioning systems can
+    // write to directly. They are read into memory when the server starts,
+    // and the owners that changed read again as the tables' triggers tell of
+    // changes, so queries never wait for the database.
+    pub struct PostgresStore {
+        shared: Arc<Shared>,
+    }
+
+    impl PostgresStore {
+        pub fn open(conninfo: &str) -> Result<PostgresStore, String> {
+            let open = || {
+                let db = Connection::open(conninfo)?;
+                db.execute(SCHEMA)?;
+                // listening before reading, so no change is missed
+                let listener = listen(conninfo)?;
+                Ok((db, listener))
+            };
+            let (db, listener) =
+                open().map_err(|e: String| format!("zone-store postgres: {}", e))?;
+
+            let shared = Arc::new(Shared {
+                conninfo: conninfo.to_string(),
+                db: Mutex::new(Some(db)),
+                memory: MemoryStore::new(),
+            });
+            shared
+                .load_all(&listener)
+                .map_err(|e| format!("zone-store postgres: {}", e))?;
+
+            let follower = shared.clone();
+            thread::spawn(move || follower.follow(listener));
+            Ok(PostgresStore { shared })
+        }
+    }
+
+    impl ZoneStore for PostgresStore {
+        fn zones(&self) -> Vec<Name> {
+            self.shared.memory.zones()
+        }
+
+        fn lookup(&self, name: &Name) -> Vec<ZoneEntry> {
+            self.shared.memory.lookup(name)
+        }
+
+        fn owners_from(&self, start: &Name, limit: usize) -> Vec<Name> {
+            self.shared.memory.owners_from(start, limit)
+        }
+
+        fn entries(&self) -> Vec<ZoneEntry> {
+            self.shared.memory.entries()
+        }
+
+        fn apply(&self, changes: Vec<ZoneChange>) -> Result<usize, String> {
+            // held until memory is changed too, so both see the changes in
+            // the same order
+            let mut db = self.shared.db.lock().unwrap();
+            let local = |change: &ZoneChange| {
+                matches!(
+                    change,
+                    ZoneChange::SetLeases(_) | ZoneChange::SetGenerated(_)
+                )
+            };
+            let transaction = |db: &Connection| {
+                db.execute("BEGIN")?;
+                let result = changes
+                    .iter()
+                    .try_for_each(|change| write(db, change))
+                    .and_then(|()| db.execute("COMMIT"));
+                if result.is_err() {
+                    let _ = db.execute("ROLLBACK");
+                }
+                result
+            };
+            if !changes.iter().all(local) {
+                let mut result = match &*db {
+                    Some(db) if db.is_ok() => transaction(db),
+                    _ => Err("not connected".to_string()),
+                };
+                // a connection the database closed while it sat unused only
+                // fails once it's used
+                if result.is_err() && !db.as_ref().is_some_and(Connection::is_ok) {
+                    let new = Connection::open(&self.shared.conninfo)
+                        .map_err(|e| format!("zone-store postgres: {}", e))?;
+                    result = transaction(db.insert(new));
+                }
+                result.map_err(|e| format!("zone-store postgres: {}", e))?;
+            }
+            self.shared.memory.apply(changes)
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::DnsRecord;
+use crate::name::Name;
+use crate::zone::LocalZones;
+use crate::zonefile;
+
+// How long to let an editor finish writing before a changed file is read.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+// How often files are looked at where there's no inotify.
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Whether serial a is newer than b in serial number arithmetic (RFC 1982),
+// which lets serials wrap around.
+fn serial_newer(a: u32, b: u32) -> bool {
+    let diff = a.wrapping_sub(b);
+    diff != 0 && diff < 1 << 31
+}
+
+// The directory a file is in and the file as found there, which is how
+// changes in the directory are reported.
+#[cfg(target_os = "linux")]
+fn in_dir(path: &Path) -> (PathBuf, PathBuf) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file = dir.join(path.file_name().unwrap_or_default());
+    (dir, file)
+}
+
+// A zone served from a master file: "zone <apex> file=<path>".
+pub struct WatchedZone {
+    pub apex: Name,
+    pub path: PathBuf,
+    records: Vec<DnsRecord>,
+    // the zone file and those it includes, as last read
+    files: Vec<PathBuf>,
+}
+
+impl WatchedZone {
+    // Reads the zone's records from path. Fails on any entry that can't be
+    // parsed and on records outside the zone, telling the first problem;
+    // check-zone lists them all.
+    pub fn load(apex: Name, path: &Path) -> Result<WatchedZone, String> {
+        let (records, files) = read(&apex, path)?;
+        Ok(WatchedZone {
+            apex,
+            path: path.to_path_buf(),
+            records,
+            files,
+        })
+    }
+
+    pub fn records(&self) -> &[DnsRecord] {
+        &self.records
+    }
+
+    fn serial(&self, records: &[DnsRecord]) -> Option<u32> {
+        records.iter().find_map(|record| match record {
+            DnsRecord::SOA { domain, serial, .. } if *domain == self.apex => Some(*serial),
+            _ => None,
+        })
+    }
+}
+
+fn read(apex: &Name, path: &Path) -> Result<(Vec<DnsRecord>, Vec<PathBuf>), String> {
+    let zone = zonefile::read(apex, path)?;
+    let mut problems: Vec<String> = zone
+        .errors
+        .iter()
+        .map(|(file, line, e)| format!("{}:{}: {}", file.display(), line, e))
+        .collect();
+    for record in zone.records.iter() {
+        if !record.record.domain().is_subdomain_of(apex) {
+            problems.push(format!(
+                "{}:{}: {} is outside zone {}",
+                path.display(),
+                record.line,
+                record.record.domain(),
+                apex
+            ));
+        }
+    }
+    match problems.len() {
+        0 => {}
+        1 => return Err(problems.remove(0)),
+        more => return Err(format!("{} (and {} more)", problems[0], more - 1)),
+    }
+    let records = zone
+        .records
+        .into_iter()
+        .map(|record| record.record)
+        .collect();
+    Ok((records, zone.files))
+}
+
+// Serves the zones read from files and reads a zone again whenever its file
+// or one it includes changes. A zone whose file has errors, or whose records
+// changed without its SOA serial increasing, keeps what it had; otherwise
+// its changed RRsets are swapped in at once. The cache has no local answers
+// in it, so it's left alone.
+pub struct ZoneWatcher {
+    zones: Vec<WatchedZone>,
+    local: Arc<LocalZones>,
+    notifier: Notifier,
+}
+
+impl ZoneWatcher {
+    // Starts watching, which needs the files' directories to be reachable,
+    // so it has to happen before any chroot.
+    pub fn new(zones: Vec<WatchedZone>, local: Arc<LocalZones>) -> Result<ZoneWatcher, String> {
+        let mut notifier = Notifier::new()?;
+        for file in zones.iter().flat_map(|zone| zone.files.iter()) {
+            notifier.watch(file)?;
+        }
+        Ok(ZoneWatcher {
+            zones,
+            local,
+            notifier,
+        })
+    }
+
+    fn reload(zone: &mut WatchedZone, local: &LocalZones) -> Result<(), String> {
+        let (records, files) = read(&zone.apex, &zone.path)?;
+        zone.files = files;
+        if records.len() == zone.records.len()
+            && records.iter().all(|record| zone.records.contains(record))
+        {
+            return Ok(());
+        }
+
+        let (old, new) = (zone.serial(&zone.records), zone.serial(&records));
+        match (old, new) {
+            (Some(old), Some(new)) if !serial_newer(new, old) => {
+                return Err(format!("serial {} isn't newer than {}", new, old));
+            }
+            (Some(_), None) => return Err("no SOA record".to_string()),
+            _ => {}
+        }
+
+        let changed = local.replace(&zone.records, &records)?;
+        zone.records = records;
+        let serial = |serial: Option<u32>| serial.map_or("none".to_string(), |s| s.to_string());
+        eprintln!(
+            "zone {} reloaded from {}: serial {} -> {}, {} RRsets changed",
+            zone.apex,
+            zone.path.display(),
+            serial(old),
+            serial(new),
+            changed
+        );
+        Ok(())
+    }
+
+    // Waits for changes until the server stops. Runs in the serving
+    // process, after any chroot, so the files are read at their paths as
+    // seen from inside it.
+    pub fn run(&mut self) {
+        loop {
+            let changed = self.notifier.wait();
+            if changed.is_empty() {
+                continue;
+            }
+            thread::sleep(SETTLE_TIME);
+
+            for zone in self.zones.iter_mut() {
+                if !zone.files.iter().any(|file| changed.contains(file)) {
+                    continue;
+                }
+                if let Err(e) = Self::reload(zone, &self.local) {
+                    eprintln!("zone {}: {}, not reloaded", zone.apex, e);
+                }
+                // a file newly included has to be watched too
+                for file in zone.files.iter() {
+                    if let Err(e) = self.notifier.watch(file) {
+                        eprintln!("zone {}: {}", zone.apex, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Tells which of the watched files changed, with inotify on the directories
+// they're in, so files that editors replace rather than write are followed.
+#[cfg(target_os = "linux")]
+struct Notifier {
+    events: std::fs::File,
+    // the directory of every watch descriptor
+    dirs: HashMap<std::ffi::c_int, PathBuf>,
+    // each file as found in its directory, and as it was given
+    files: HashMap<PathBuf, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+    fn new() -> Result<Notifier, String> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { crate::sys::inotify_init1(crate::sys::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(format!("inotify: {}", std::io::Error::last_os_error()));
+        }
+        Ok(Notifier {
+            events: unsafe { std::fs::File::from_raw_fd(fd) },
+            dirs: HashMap::new(),
+            files: HashMap::new(),
+        })
+    }
+
+    fn watch(&mut self, path: &Path) -> Result<(), String> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (dir, file) = in_dir(path);
+        self.files.insert(file, path.to_path_buf());
+        if self.dirs.values().any(|watched| *watched == dir) {
+            return Ok(());
+        }
+        let c_dir = std::ffi::CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| format!("nul in {}", dir.display()))?;
+        let mask = crate::sys::IN_CLOSE_WRITE | crate::sys::IN_MOVED_TO | crate::sys::IN_DELETE;
+        let wd =
+            unsafe { crate::sys::inotify_add_watch(self.events.as_raw_fd(), c_dir.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(format!(
+                "watching {}: {}",
+                dir.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        self.dirs.insert(wd, dir);
+        Ok(())
+    }
+
+    // Blocks until files in the watched directories change and returns those
+    // of them that are watched, as they were given.
+    fn wait(&mut self) -> Vec<PathBuf> {
+        use std::io::Read;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut buf = [0u8; 4096];
+        let len = match self.events.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                eprintln!("inotify: {}", e);
+                thread::sleep(SETTLE_TIME);
+                return Vec::new();
+            }
+        };
+
+        let mut changed = Vec::new();
+        let mut at = 0;
+        while at + crate::sys::INOTIFY_EVENT_SIZE <= len {
+            let field = |i: usize| {
+                let start = at + i * 4;
+                [buf[start], buf[start + 1], buf[start + 2], buf[start + 3]]
+            };
+            let wd = i32::from_ne_bytes(field(0));
+            let name_len = u32::from_ne_bytes(field(3)) as usize;
+            let name_start = at + crate::sys::INOTIFY_EVENT_SIZE;
+            let name = &buf[name_start..(name_start + name_len).min(len)];
+            // the name is padded with nuls
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            at = name_start + name_len;
+
+            let Some(dir) = self.dirs.get(&wd) else {
+                continue;
+            };
+            let file = dir.join(std::ffi::OsStr::from_bytes(name));
+            if let Some(path) = self.files.get(&file)
+                && !changed.contains(path)
+            {
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+}
+
+// Without inotify the files' modification times are looked at every
+// POLL_INTERVAL instead.
+#[cfg(not(target_os = "linux"))]
+struct Notifier {
+    modified: HashMap<PathBuf, Option<std::time::SystemTime>>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Notifier {
+    fn new() -> Result<Notifier, String> {
+        Ok(Notifier {
+            modified: HashMap::new(),
+        })
+    }
+
+    fn modified(path: &Path) -> Option<std::time::SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+
+    fn watch(&mut self, path: &Path) -> Result<(), String> {
+        if !self.modified.contains_key(path) {
+            self.modified
+                .insert(path.to_path_buf(), Self::modified(path));
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Vec<PathBuf> {
+        thread::sleep(POLL_INTERVAL);
+        let mut changed = Vec::new();
+        for (path, modified) in self.modified.iter_mut() {
+            let now = Self::modified(path);
+            if now != *modified {
+                *modified = now;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+}
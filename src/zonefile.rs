@@ -0,0 +1,555 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::DnsRecord;
+use crate::QueryType;
+use crate::calendar::civil_from_days;
+use crate::calendar::unix_now;
+use crate::name::Name;
+use crate::zone::parse_record;
+use crate::zone::parse_ttl;
+
+// Master files (RFC 1035 section 5): one record per entry, with $ORIGIN and
+// $TTL, relative names, @ for the origin, owners carried over from the
+// previous entry when a line starts with a blank, parentheses to spread an
+// entry over several lines, and $INCLUDE when read from a file. Only class
+// IN.
+
+// How deep files may $INCLUDE each other, which stops a file including
+// itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// A record with the line its entry starts on.
+pub struct ZoneRecord {
+    pub line: usize,
+    pub record: DnsRecord,
+}
+
+// One entry's words, which may span several lines.
+struct Entry {
+    line: usize,
+    // starts with a blank, so the owner is left out
+    inherits_owner: bool,
+    words: Vec<String>,
+}
+
+// Splits text into entries. Comments run from ; to the end of the line;
+// quoted strings keep their quotes and escapes so TXT data can hold blanks,
+// ; and quotes.
+fn entries(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut words = Vec::new();
+    let mut start = (0, false);
+    let mut depth = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        if depth == 0 {
+            start = (line_no, line.starts_with([' ', '\t']));
+        }
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => depth += 1,
+                ')' if depth == 0 => return Err((line_no, "unbalanced )".to_string())),
+                ')' => depth -= 1,
+                c if c.is_whitespace() => {}
+                '"' => {
+                    let mut word = String::from('"');
+                    loop {
+                        match chars.next() {
+                            Some('\\') => {
+                                word.push('\\');
+                                word.extend(chars.next());
+                            }
+                            Some('"') => break,
+                            Some(c) => word.push(c),
+                            None => return Err((line_no, "unterminated string".to_string())),
+                        }
+                    }
+                    word.push('"');
+                    words.push(word);
+                }
+                c => {
+                    let mut word = String::from(c);
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || matches!(c, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    words.push(word);
+                }
+            }
+        }
+
+        if depth == 0 && !words.is_empty() {
+            entries.push(Entry {
+                line: start.0,
+                inherits_owner: start.1,
+                words: std::mem::take(&mut words),
+            });
+        }
+    }
+    if depth > 0 {
+        return Err((start.0, "unbalanced (".to_string()));
+    }
+    Ok(entries)
+}
+
+// Splits the rdata of a record given on its own, as config record lines and
+// the API take them, into words the way a zone file would.
+pub fn words(text: &str) -> Result<Vec<String>, String> {
+    let entries = entries(text).map_err(|(_, e)| e)?;
+    Ok(entries.into_iter().flat_map(|entry| entry.words).collect())
+}
+
+// Makes name absolute: @ is the origin, a trailing dot means it already is.
+fn absolute(name: &str, origin: &Name) -> Result<String, String> {
+    let name = if name == "@" {
+        origin.to_ascii()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_root() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin.to_ascii())
+    };
+    Ok(Name::from_unicode(&name)?.to_ascii())
+}
+
+// Positions of the rdata fields holding names, which may be relative.
+fn name_fields(qtype: QueryType) -> &'static [usize] {
+    match qtype {
+        QueryType::NS | QueryType::CNAME | QueryType::PTR => &[0],
+        QueryType::MX => &[1],
+        QueryType::SOA => &[0, 1],
+        _ => &[],
+    }
+}
+
+// Reads an $INCLUDEd file, given as written and with the origin and default
+// TTL it starts with, into its records.
+type Include<'a> = dyn FnMut(&str, &Name, Option<u32>) -> Result<Vec<DnsRecord>, String> + 'a;
+
+// Parses a zone file for origin. Entries that can't be parsed are reported
+// with their line and skipped, so the records are complete only if there are
+// no errors. There's no file to include others relative to, so $INCLUDE is
+// one of those.
+pub fn parse(origin: &Name, text: &str) -> (Vec<ZoneRecord>, Vec<(usize, String)>) {
+    parse_with(origin, None, text, &mut |_, _, _| {
+        Err("$INCLUDE needs a zone file".to_string())
+    })
+}
+
+fn parse_with(
+    origin: &Name,
+    default_ttl: Option<u32>,
+    text: &str,
+    include: &mut Include,
+) -> (Vec<ZoneRecord>, Vec<(usize, String)>) {
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+
+    let entries = match entries(text) {
+        Ok(entries) => entries,
+        Err(e) => return (records, vec![e]),
+    };
+
+    let mut origin = origin.clone();
+    let mut default_ttl = default_ttl;
+    let mut last_ttl = None;
+    let mut last_owner: Option<String> = None;
+
+    for Entry {
+        line,
+        inherits_owner,
+        words,
+    } in entries
+    {
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let result = match words.as_slice() {
+            ["$ORIGIN", name] => absolute(name, &origin).and_then(|name| {
+                origin = Name::from_ascii(&name)?;
+                Ok(())
+            }),
+            ["$TTL", ttl] => parse_ttl(ttl).map(|ttl| {
+                default_ttl = Some(ttl);
+            }),
+            // the included file's origin is its own; this one's carries on
+            // after it
+            ["$INCLUDE", file] => include(file, &origin, default_ttl).map(|included| {
+                records.extend(
+                    included
+                        .into_iter()
+                        .map(|record| ZoneRecord { line, record }),
+                )
+            }),
+            ["$INCLUDE", file, name] => absolute(name, &origin)
+                .and_then(|name| Name::from_ascii(&name))
+                .and_then(|name| include(file, &name, default_ttl))
+                .map(|included| {
+                    records.extend(
+                        included
+                            .into_iter()
+                            .map(|record| ZoneRecord { line, record }),
+                    )
+                }),
+            [directive, ..] if directive.starts_with('$') => {
+                Err(format!("unsupported directive {}", directive))
+            }
+            _ => parse_entry(
+                &words,
+                inherits_owner,
+                &origin,
+                &mut last_owner,
+                default_ttl,
+                &mut last_ttl,
+            )
+            .map(|record| records.push(ZoneRecord { line, record })),
+        };
+        if let Err(e) = result {
+            errors.push((line, e));
+        }
+    }
+
+    (records, errors)
+}
+
+// A zone file as read, with the files it includes.
+pub struct ZoneRead {
+    // those from included files have the line of the $INCLUDE
+    pub records: Vec<ZoneRecord>,
+    // (file, line, message) for each entry that couldn't be parsed
+    pub errors: Vec<(PathBuf, usize, String)>,
+    // the zone file first, then the files it includes
+    pub files: Vec<PathBuf>,
+}
+
+// Reads the zone file at path for origin, with the files it includes, which
+// are relative to the file including them. Fails only if path itself can't
+// be read; an included file that can't be is an error at its $INCLUDE.
+pub fn read(origin: &Name, path: &Path) -> Result<ZoneRead, String> {
+    let mut zone = ZoneRead {
+        records: Vec::new(),
+        errors: Vec::new(),
+        files: Vec::new(),
+    };
+    zone.records = read_file(origin, None, path, 0, &mut zone.errors, &mut zone.files)?;
+    Ok(zone)
+}
+
+fn read_file(
+    origin: &Name,
+    default_ttl: Option<u32>,
+    path: &Path,
+    depth: usize,
+    errors: &mut Vec<(PathBuf, usize, String)>,
+    files: &mut Vec<PathBuf>,
+) -> Result<Vec<ZoneRecord>, String> {
+    files.push(path.to_path_buf());
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut include = |file: &str, origin: &Name, default_ttl| {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err("$INCLUDE nested too deeply".to_string());
+        }
+        let records = read_file(
+            origin,
+            default_ttl,
+            &dir.join(file),
+            depth + 1,
+            errors,
+            files,
+        )?;
+        Ok(records.into_iter().map(|record| record.record).collect())
+    };
+    let (records, file_errors) = parse_with(origin, default_ttl, &text, &mut include);
+    errors.extend(
+        file_errors
+            .into_iter()
+            .map(|(line, e)| (path.to_path_buf(), line, e)),
+    );
+    Ok(records)
+}
+
+// Parses one record given in the parts of a zone file line, with absolute
+// names, e.g. ("www.example.com", "A", "300", "10.0.0.1"), as databases and
+// other servers hand records over.
+pub fn parse_one(owner: &str, qtype: &str, ttl: &str, data: &str) -> Result<DnsRecord, String> {
+    let owner = match owner.trim_end_matches('.') {
+        "" => "@",
+        _ => owner,
+    };
+    let line = format!("{} {} IN {} {}", owner, ttl, qtype, data);
+    let (mut records, errors) = parse(&Name::root(), &line);
+    if let Some((_, e)) = errors.into_iter().next() {
+        return Err(e);
+    }
+    match (records.pop(), records.is_empty()) {
+        (Some(record), true) => Ok(record.record),
+        _ => Err("not a single record".to_string()),
+    }
+}
+
+fn parse_entry(
+    words: &[&str],
+    inherits_owner: bool,
+    origin: &Name,
+    last_owner: &mut Option<String>,
+    default_ttl: Option<u32>,
+    last_ttl: &mut Option<u32>,
+) -> Result<DnsRecord, String> {
+    let mut words = words.iter().copied().peekable();
+
+    let owner = if inherits_owner {
+        last_owner
+            .clone()
+            .ok_or("no owner to carry over from a previous record")?
+    } else {
+        let owner = absolute(words.next().unwrap_or_default(), origin)?;
+        *last_owner = Some(owner.clone());
+        owner
+    };
+
+    // TTL and class may come in either order, and both are optional
+    let mut ttl = None;
+    let qtype = loop {
+        let word = words.next().ok_or("missing record type")?;
+        if ttl.is_none()
+            && word.starts_with(|c: char| c.is_ascii_digit())
+            && let Ok(value) = parse_ttl(word)
+        {
+            ttl = Some(value);
+            continue;
+        }
+        match word.to_ascii_uppercase().as_str() {
+            "IN" => continue,
+            "CH" | "CHAOS" | "HS" | "HESIOD" => {
+                return Err(format!("class {} is not supported", word));
+            }
+            _ => {}
+        }
+        match QueryType::from_name(word) {
+            QueryType::UNKNOWN(_) | QueryType::OPT | QueryType::ANY => {
+                return Err(format!("unknown record type {:?}", word));
+            }
+            qtype => break qtype,
+        }
+    };
+
+    let ttl = ttl
+        .or(default_ttl)
+        .or(*last_ttl)
+        .ok_or("no TTL given and no $TTL before it")?;
+    *last_ttl = Some(ttl);
+
+    let mut rdata: Vec<String> = words.map(str::to_string).collect();
+    for &i in name_fields(qtype) {
+        if let Some(name) = rdata.get_mut(i) {
+            *name = absolute(name, origin)?;
+        }
+    }
+    let rdata: Vec<&str> = rdata.iter().map(String::as_str).collect();
+
+    parse_record(&owner, qtype, ttl, &rdata)
+}
+
+// Today's date as YYYYMMDD, in UTC.
+fn today() -> u32 {
+    let (year, month, day) = civil_from_days(unix_now().div_euclid(86400));
+    (year * 10000 + month * 100 + day) as u32
+}
+
+// Serials written as YYYYMMDDnn should hold a real date that isn't ahead of
+// today, or the next edit has nowhere to go.
+fn check_date_serial(serial: u32) -> Option<String> {
+    let text = serial.to_string();
+    if text.len() != 10 || !(text.starts_with("19") || text.starts_with("20")) {
+        return None;
+    }
+    let date = serial / 100;
+    let (month, day) = (date / 100 % 100, date % 100);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Some(format!(
+            "serial {} looks like YYYYMMDDnn but {} is not a date",
+            serial, date
+        ));
+    }
+    if date > today() {
+        return Some(format!("serial {} is dated in the future", serial));
+    }
+    None
+}
+
+// The problems named-checkzone would point out in a zone that parses.
+fn lint(origin: &Name, records: &[ZoneRecord]) -> Vec<(Option<usize>, String)> {
+    let mut problems = Vec::new();
+
+    // owner name to the types it has, with the line of the first of each
+    let mut names: HashMap<Name, Vec<(QueryType, usize)>> = HashMap::new();
+    for ZoneRecord { line, record } in records {
+        let owner = record.domain().clone();
+        if !owner.is_subdomain_of(origin) {
+            problems.push((
+                Some(*line),
+                format!("{} is outside the zone {}", owner, origin),
+            ));
+            continue;
+        }
+        names
+            .entry(owner)
+            .or_default()
+            .push((record.query_type(), *line));
+    }
+    let types_at = |name: &Name| -> Vec<QueryType> {
+        names
+            .get(name)
+            .map(|types| types.iter().map(|(qtype, _)| *qtype).collect())
+            .unwrap_or_default()
+    };
+
+    let soas: Vec<&ZoneRecord> = records
+        .iter()
+        .filter(|r| r.record.query_type() == QueryType::SOA)
+        .collect();
+    if soas.is_empty() {
+        problems.push((None, format!("no SOA record at {}", origin)));
+    } else if soas.len() > 1 {
+        problems.push((Some(soas[1].line), "more than one SOA record".to_string()));
+    }
+    for ZoneRecord { line, record } in soas {
+        let line = Some(*line);
+        let DnsRecord::SOA {
+            domain,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } = record
+        else {
+            continue;
+        };
+        if domain != origin {
+            problems.push((line, format!("SOA at {} rather than the apex", domain)));
+        }
+        if retry > refresh {
+            problems.push((
+                line,
+                format!("SOA retry {} is longer than refresh {}", retry, refresh),
+            ));
+        }
+        if *expire < refresh.saturating_add(*retry) {
+            problems.push((
+                line,
+                format!(
+                    "SOA expire {} is shorter than refresh + retry ({})",
+                    expire,
+                    refresh.saturating_add(*retry)
+                ),
+            ));
+        }
+        // RFC 2308 recommends one to three hours, and caps it at a day
+        if *minimum > 86400 {
+            problems.push((
+                line,
+                format!("SOA minimum {} is longer than a day", minimum),
+            ));
+        }
+        if let Some(problem) = check_date_serial(*serial) {
+            problems.push((line, problem));
+        }
+    }
+
+    if !types_at(origin).contains(&QueryType::NS) {
+        problems.push((None, format!("no NS records at {}", origin)));
+    }
+
+    for (name, types) in names.iter() {
+        let cnames: Vec<usize> = types
+            .iter()
+            .filter(|(qtype, _)| *qtype == QueryType::CNAME)
+            .map(|(_, line)| *line)
+            .collect();
+        if cnames.len() > 1 {
+            problems.push((Some(cnames[1]), format!("{} has more than one CNAME", name)));
+        }
+        if let Some(&line) = cnames.first()
+            && let Some((qtype, _)) = types.iter().find(|(qtype, _)| *qtype != QueryType::CNAME)
+        {
+            problems.push((
+                Some(line),
+                format!("{} has a CNAME and other data ({})", name, qtype.name()),
+            ));
+        }
+    }
+
+    for ZoneRecord { line, record } in records {
+        let (target, what) = match record {
+            DnsRecord::NS { host, .. } => (host, "NS"),
+            DnsRecord::MX { host, .. } => (host, "MX"),
+            _ => continue,
+        };
+        let target = target.clone();
+        let types = types_at(&target);
+        if types.contains(&QueryType::CNAME) {
+            problems.push((
+                Some(*line),
+                format!("{} target {} is a CNAME", what, target),
+            ));
+        } else if target.is_subdomain_of(origin)
+            && !types.contains(&QueryType::A)
+            && !types.contains(&QueryType::AAAA)
+        {
+            // a name server inside the zone can only be found through glue
+            let problem = if what == "NS" {
+                format!("NS target {} has no glue (A or AAAA records)", target)
+            } else {
+                format!("MX target {} has no A or AAAA records", target)
+            };
+            problems.push((Some(*line), problem));
+        }
+    }
+
+    problems
+}
+
+// Parses and lints the zone file at path for origin, returning every problem
+// as "path:line: message", in file order, those in included files after the
+// zone file's own.
+pub fn check(origin: &Name, path: &str) -> Vec<String> {
+    let zone = match read(origin, Path::new(path)) {
+        Ok(zone) => zone,
+        Err(e) => return vec![e],
+    };
+
+    let mut problems: Vec<(Option<usize>, String)> = Vec::new();
+    let mut included = Vec::new();
+    for (file, line, e) in zone.errors {
+        if file == Path::new(path) {
+            problems.push((Some(line), e));
+        } else {
+            included.push(format!("{}:{}: {}", file.display(), line, e));
+        }
+    }
+    problems.extend(lint(origin, &zone.records));
+
+    // problems with the zone as a whole go last
+    problems.sort_by_key(|(line, _)| line.unwrap_or(usize::MAX));
+    problems
+        .into_iter()
+        .map(|(line, e)| match line {
+            Some(line) => format!("{}:{}: {}", path, line, e),
+            None => format!("{}: {}", path, e),
+        })
+        .chain(included)
+        .collect()
+}